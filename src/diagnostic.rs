@@ -0,0 +1,167 @@
+use crate::emitter::line_column;
+use anyhow::{Error, Result};
+use std::{fmt::Display, ops::Range, path::Path};
+
+/// An error found at `span`, a byte range of the source being compiled.
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub span: Range<usize>,
+    pub error: Error,
+}
+
+impl Diagnostic {
+    /// Locates `error` at `span`, unless a form inside it located the error already.
+    pub fn locate(error: Error, span: Option<Range<usize>>) -> Error {
+        match span {
+            Some(span) if error.downcast_ref::<Diagnostic>().is_none() => {
+                Diagnostic { span, error }.into()
+            }
+            _ => error,
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl std::error::Error for Diagnostic {
+    // The message is the one of `error`, so its chain starts after it.
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
+
+/// The errors found compiling a program, when there are several.
+#[derive(Debug)]
+pub struct Diagnostics(pub Vec<Error>);
+
+impl Diagnostics {
+    /// Fails with `errors` unless there are none, sorted by their location. A single error
+    /// is returned as is.
+    pub fn check(mut errors: Vec<Error>) -> Result<()> {
+        errors.sort_by_key(|error| error.downcast_ref::<Diagnostic>().map(|d| d.span.start));
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.pop().unwrap()),
+            _ => Err(Diagnostics(errors).into()),
+        }
+    }
+}
+
+impl Display for Diagnostics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for error in &self.0 {
+            writeln!(f, "{:#}", error)?;
+        }
+        write!(f, "{} errors", self.0.len())
+    }
+}
+
+impl std::error::Error for Diagnostics {}
+
+/// The errors `error` stands for: those of `Diagnostics`, or itself.
+pub fn errors(error: &Error) -> Vec<&Error> {
+    match error.downcast_ref::<Diagnostics>() {
+        Some(diagnostics) => diagnostics.0.iter().collect(),
+        None => vec![error],
+    }
+}
+
+/// Lists the errors of `error` as `path:line:column: message` lines, followed by their
+/// count. `source` is the content of `path`, which their spans are relative to.
+pub fn report(error: &Error, path: &Path, source: &str) -> String {
+    let errors = errors(error);
+    let mut out = String::new();
+    for error in &errors {
+        match error.downcast_ref::<Diagnostic>() {
+            Some(diagnostic) => {
+                let (line, column) = line_column(source, diagnostic.span.start);
+                out += &format!("{}:{}:{}: {:#}\n", path.display(), line + 1, column + 1, error);
+            }
+            None => out += &format!("{}: {:#}\n", path.display(), error),
+        }
+    }
+    let plural = if errors.len() == 1 { "" } else { "s" };
+    out += &format!("{} error{}\n", errors.len(), plural);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emitter::{emit, Module};
+
+    const SOURCE: &str = "(defn sq: i32 [x: i32] (* x true))
+(defn f: i32 [a: i32]
+    (let [y (+ a undefined)
+          z (* y 2)]
+        (+ z (sq z))))
+(defn g: i32 [] (+ 1 2]))
+(export defn main: i32 [] (+ (f 1) (nope 3)))
+";
+
+    #[test]
+    fn test_recovery() {
+        let error = emit(&mut Module::default(), SOURCE).unwrap_err();
+        let errors = errors(&error)
+            .into_iter()
+            .map(|error| (error.to_string(), error.downcast_ref::<Diagnostic>().unwrap().span.clone()))
+            .collect::<Vec<_>>();
+        // Nothing is reported about `y` and `z`, whose type is unknown.
+        assert_eq!(
+            errors,
+            vec![
+                ("mismatched types for *. found i32 and bool".to_string(), 23..33),
+                ("Symbol undefined not found in this scope".to_string(), 74..83),
+                ("Parse error. Unexpected ]".to_string(), 151..152),
+                ("Unable to find function \"nope\"".to_string(), 190..198),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_malformed_toplevel() {
+        let source = "(defn)\n(export)\n(defn foo)\n()\n(1 2)\n(export defn main: i32 [] 1)\n";
+        let error = emit(&mut Module::default(), source).unwrap_err();
+        let errors = errors(&error).into_iter().map(|error| error.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                "A symbol with type annotaion is expected after 'defn'",
+                "Failed to compile function. 'defn' is expected after 'export'",
+                "Function args vector is required after 'defn'",
+                "Top level form must be function decl or global variable, found List([])",
+                "Top level form must be function decl or global variable, \
+                 found List([NumberLiteral(\"1\"), NumberLiteral(\"2\")])",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_single_error() {
+        let source = "(defn f: i32 [] (+ 1 x))";
+        let error = emit(&mut Module::default(), source).unwrap_err();
+        assert_eq!(error.to_string(), "Symbol x not found in this scope");
+        assert_eq!(
+            report(&error, Path::new("f.wisp"), source),
+            "f.wisp:1:22: Symbol x not found in this scope\n1 error\n"
+        );
+    }
+
+    #[test]
+    fn test_report() {
+        let error = emit(&mut Module::default(), SOURCE).unwrap_err();
+        assert_eq!(
+            report(&error, Path::new("m.wisp"), SOURCE),
+            "m.wisp:1:24: mismatched types for *. found i32 and bool
+m.wisp:3:18: Symbol undefined not found in this scope
+m.wisp:6:23: Parse error. Unexpected ]
+m.wisp:7:36: Unable to find function \"nope\"
+4 errors
+"
+        );
+    }
+}
@@ -0,0 +1,658 @@
+use super::{
+    conversion::resolve_target_type,
+    expression::{integer_literal_as, intrinsic_operator, parse_number_literal},
+    *,
+};
+use crate::{env::Env, parser::AST, resolver::Type};
+use std::{cell::RefCell, cmp::Ordering, rc::Rc};
+
+/// A value known at compile time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConstValue {
+    I32(i32),
+    U32(u32),
+    I64(i64),
+    U64(u64),
+    F32(f32),
+    Bool(bool),
+}
+
+impl ConstValue {
+    pub fn from_opcode(opcode: &OpCode, t: &Type) -> Option<Self> {
+        Some(match (opcode, t) {
+            (OpCode::I32Const(v), Type::I32) => ConstValue::I32(*v),
+            (OpCode::I32Const(v), Type::U32) => ConstValue::U32(*v as u32),
+            (OpCode::I32Const(v), Type::Bool) => ConstValue::Bool(*v != 0),
+            (OpCode::I64Const(v), Type::I64) => ConstValue::I64(*v),
+            (OpCode::I64Const(v), Type::U64) => ConstValue::U64(*v as u64),
+            (OpCode::F32Const(v), Type::F32) => ConstValue::F32(*v),
+            _ => return None,
+        })
+    }
+
+    fn from_global(value: GlobalValue, t: &Type) -> Option<Self> {
+        let opcode = match value {
+            GlobalValue::I32(v) => OpCode::I32Const(v),
+            GlobalValue::I64(v) => OpCode::I64Const(v),
+            GlobalValue::F32(v) => OpCode::F32Const(v),
+        };
+        Self::from_opcode(&opcode, t)
+    }
+
+    pub fn get_type(&self) -> Type {
+        match self {
+            ConstValue::I32(_) => Type::I32,
+            ConstValue::U32(_) => Type::U32,
+            ConstValue::I64(_) => Type::I64,
+            ConstValue::U64(_) => Type::U64,
+            ConstValue::F32(_) => Type::F32,
+            ConstValue::Bool(_) => Type::Bool,
+        }
+    }
+
+    pub fn opcode(&self) -> OpCode {
+        match *self {
+            ConstValue::I32(v) => OpCode::I32Const(v),
+            ConstValue::U32(v) => OpCode::I32Const(v as i32),
+            ConstValue::I64(v) => OpCode::I64Const(v),
+            ConstValue::U64(v) => OpCode::I64Const(v as i64),
+            ConstValue::F32(v) => OpCode::F32Const(v),
+            ConstValue::Bool(b) => OpCode::I32Const(if b { 1 } else { 0 }),
+        }
+    }
+
+    pub fn global_value(&self) -> GlobalValue {
+        match self.opcode() {
+            OpCode::I32Const(v) => GlobalValue::I32(v),
+            OpCode::I64Const(v) => GlobalValue::I64(v),
+            OpCode::F32Const(v) => GlobalValue::F32(v),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Applies `$f` to the operands when both are integers of the same type.
+macro_rules! int_binop {
+    ($l:expr, $r:expr, |$a:ident, $b:ident| $f:expr) => {
+        match ($l, $r) {
+            (ConstValue::I32($a), ConstValue::I32($b)) => ConstValue::I32($f),
+            (ConstValue::U32($a), ConstValue::U32($b)) => ConstValue::U32($f),
+            (ConstValue::I64($a), ConstValue::I64($b)) => ConstValue::I64($f),
+            (ConstValue::U64($a), ConstValue::U64($b)) => ConstValue::U64($f),
+            _ => return None,
+        }
+    };
+}
+
+fn retype_literal(ast: &AST, t: &Type) -> Option<ConstValue> {
+    integer_literal_as(ast, t).and_then(|opcode| ConstValue::from_opcode(&opcode, t))
+}
+
+/// Mirrors `unify_operands` in `intrinsic_ops`.
+fn unify(
+    left: (ConstValue, Option<&AST>),
+    right: (ConstValue, &AST),
+    promote_float: bool,
+) -> Option<(ConstValue, ConstValue)> {
+    let ((l, left_ast), (r, right_ast)) = (left, right);
+    if l.get_type() == r.get_type() {
+        return Some((l, r));
+    }
+    match (l, r) {
+        (ConstValue::I32(a), ConstValue::F32(_)) if promote_float => {
+            return Some((ConstValue::F32(a as f32), r))
+        }
+        (ConstValue::F32(_), ConstValue::I32(b)) if promote_float => {
+            return Some((l, ConstValue::F32(b as f32)))
+        }
+        _ => (),
+    }
+    if let Some(r) = retype_literal(right_ast, &l.get_type()) {
+        return Some((l, r));
+    }
+    let l = left_ast.and_then(|ast| retype_literal(ast, &r.get_type()))?;
+    Some((l, r))
+}
+
+fn eval_arithmetic(op: &IntrinsicOperator, l: ConstValue, r: ConstValue) -> Option<ConstValue> {
+    if let (ConstValue::F32(a), ConstValue::F32(b)) = (l, r) {
+        return Some(ConstValue::F32(match op {
+            IntrinsicOperator::Add => a + b,
+            IntrinsicOperator::Sub => a - b,
+            IntrinsicOperator::Mul => a * b,
+            IntrinsicOperator::Div => a / b,
+            _ => return None,
+        }));
+    }
+    Some(match op {
+        IntrinsicOperator::Add => int_binop!(l, r, |a, b| a.wrapping_add(b)),
+        IntrinsicOperator::Sub => int_binop!(l, r, |a, b| a.wrapping_sub(b)),
+        IntrinsicOperator::Mul => int_binop!(l, r, |a, b| a.wrapping_mul(b)),
+        // Division by zero and overflow trap at runtime, so they are left unfolded.
+        IntrinsicOperator::Div => int_binop!(l, r, |a, b| a.checked_div(b)?),
+        _ => return None,
+    })
+}
+
+fn eval_comparison(op: &IntrinsicOperator, l: ConstValue, r: ConstValue) -> Option<bool> {
+    let ordering = match (l, r) {
+        (ConstValue::I32(a), ConstValue::I32(b)) => a.partial_cmp(&b),
+        (ConstValue::U32(a), ConstValue::U32(b)) => a.partial_cmp(&b),
+        (ConstValue::I64(a), ConstValue::I64(b)) => a.partial_cmp(&b),
+        (ConstValue::U64(a), ConstValue::U64(b)) => a.partial_cmp(&b),
+        (ConstValue::F32(a), ConstValue::F32(b)) => a.partial_cmp(&b),
+        _ => return None,
+    };
+    Some(match op {
+        IntrinsicOperator::Eq => ordering == Some(Ordering::Equal),
+        IntrinsicOperator::Gt => ordering == Some(Ordering::Greater),
+        IntrinsicOperator::Ge => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+        IntrinsicOperator::Lt => ordering == Some(Ordering::Less),
+        IntrinsicOperator::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+        _ => return None,
+    })
+}
+
+fn eval_integer_binop(op: &IntrinsicOperator, l: ConstValue, r: ConstValue) -> Option<ConstValue> {
+    Some(match op {
+        IntrinsicOperator::Rem => int_binop!(l, r, |a, b| a.checked_rem(b)?),
+        IntrinsicOperator::Mod => match (l, r) {
+            // Floored, as emitted: the remainder plus the divisor when their signs differ.
+            (ConstValue::I32(a), ConstValue::I32(b)) => {
+                let r = a.checked_rem(b)?;
+                ConstValue::I32(if r != 0 && (r ^ b) < 0 { r + b } else { r })
+            }
+            (ConstValue::I64(a), ConstValue::I64(b)) => {
+                let r = a.checked_rem(b)?;
+                ConstValue::I64(if r != 0 && (r ^ b) < 0 { r + b } else { r })
+            }
+            _ => int_binop!(l, r, |a, b| a.checked_rem(b)?),
+        },
+        IntrinsicOperator::BitAnd => int_binop!(l, r, |a, b| a & b),
+        IntrinsicOperator::BitOr => int_binop!(l, r, |a, b| a | b),
+        IntrinsicOperator::BitXor => int_binop!(l, r, |a, b| a ^ b),
+        IntrinsicOperator::Shl => int_binop!(l, r, |a, b| a.wrapping_shl(b as u32)),
+        // Arithmetic for signed types and logical for unsigned types, as `shr` is emitted.
+        IntrinsicOperator::Shr => int_binop!(l, r, |a, b| a.wrapping_shr(b as u32)),
+        IntrinsicOperator::UShr => match (l, r) {
+            (ConstValue::I32(a), ConstValue::I32(b)) => {
+                ConstValue::I32((a as u32).wrapping_shr(b as u32) as i32)
+            }
+            (ConstValue::I64(a), ConstValue::I64(b)) => {
+                ConstValue::I64((a as u64).wrapping_shr(b as u32) as i64)
+            }
+            _ => int_binop!(l, r, |a, b| a.wrapping_shr(b as u32)),
+        },
+        IntrinsicOperator::Rotl => int_binop!(l, r, |a, b| a.rotate_left(b as u32 % 64)),
+        IntrinsicOperator::Rotr => int_binop!(l, r, |a, b| a.rotate_right(b as u32 % 64)),
+        _ => return None,
+    })
+}
+
+fn eval_integer_unop(op: &IntrinsicOperator, v: ConstValue) -> Option<ConstValue> {
+    macro_rules! int_unop {
+        (|$a:ident| $f:expr) => {
+            match v {
+                ConstValue::I32($a) => ConstValue::I32($f as i32),
+                ConstValue::U32($a) => ConstValue::U32($f as u32),
+                ConstValue::I64($a) => ConstValue::I64($f as i64),
+                ConstValue::U64($a) => ConstValue::U64($f as u64),
+                _ => return None,
+            }
+        };
+    }
+    Some(match op {
+        IntrinsicOperator::BitNot => int_unop!(|a| !a),
+        IntrinsicOperator::Clz => int_unop!(|a| a.leading_zeros()),
+        IntrinsicOperator::Ctz => int_unop!(|a| a.trailing_zeros()),
+        IntrinsicOperator::Popcnt => int_unop!(|a| a.count_ones()),
+        _ => return None,
+    })
+}
+
+fn eval_intrinsic(
+    module: &Module,
+    op: IntrinsicOperator,
+    args: &[AST],
+    env: &Rc<RefCell<Env>>,
+) -> Option<ConstValue> {
+    let values = args
+        .iter()
+        .map(|arg| eval_const(module, arg, env))
+        .collect::<Option<Vec<_>>>()?;
+    match op {
+        IntrinsicOperator::BitNot
+        | IntrinsicOperator::Clz
+        | IntrinsicOperator::Ctz
+        | IntrinsicOperator::Popcnt => match values[..] {
+            [v] => eval_integer_unop(&op, v),
+            _ => None,
+        },
+        IntrinsicOperator::Add
+        | IntrinsicOperator::Sub
+        | IntrinsicOperator::Mul
+        | IntrinsicOperator::Div
+            if values.len() == 1 =>
+        {
+            match (&op, values[0]) {
+                (IntrinsicOperator::Add | IntrinsicOperator::Mul, ConstValue::Bool(_)) => None,
+                (IntrinsicOperator::Add | IntrinsicOperator::Mul, v) => Some(v),
+                (IntrinsicOperator::Sub, ConstValue::F32(v)) => Some(ConstValue::F32(-v)),
+                (IntrinsicOperator::Sub, ConstValue::I32(v)) => {
+                    Some(ConstValue::I32(v.wrapping_neg()))
+                }
+                (IntrinsicOperator::Sub, ConstValue::I64(v)) => {
+                    Some(ConstValue::I64(v.wrapping_neg()))
+                }
+                _ => None,
+            }
+        }
+        IntrinsicOperator::Add
+        | IntrinsicOperator::Sub
+        | IntrinsicOperator::Mul
+        | IntrinsicOperator::Div
+        | IntrinsicOperator::Rem
+        | IntrinsicOperator::Mod
+        | IntrinsicOperator::BitAnd
+        | IntrinsicOperator::BitOr
+        | IntrinsicOperator::BitXor
+        | IntrinsicOperator::Shl
+        | IntrinsicOperator::Shr
+        | IntrinsicOperator::UShr
+        | IntrinsicOperator::Rotl
+        | IntrinsicOperator::Rotr => {
+            let is_arithmetic = matches!(
+                op,
+                IntrinsicOperator::Add
+                    | IntrinsicOperator::Sub
+                    | IntrinsicOperator::Mul
+                    | IntrinsicOperator::Div
+            );
+            let is_variadic = is_arithmetic
+                || matches!(
+                    op,
+                    IntrinsicOperator::BitAnd
+                        | IntrinsicOperator::BitOr
+                        | IntrinsicOperator::BitXor
+                );
+            if !is_variadic && values.len() != 2 {
+                return None;
+            }
+            let mut acc = values[0];
+            for (i, value) in values.iter().enumerate().skip(1) {
+                let left_ast = if i == 1 { Some(&args[0]) } else { None };
+                let (l, r) = unify((acc, left_ast), (*value, &args[i]), is_arithmetic)?;
+                acc = if is_arithmetic {
+                    eval_arithmetic(&op, l, r)?
+                } else {
+                    eval_integer_binop(&op, l, r)?
+                };
+            }
+            if !is_arithmetic && !acc.get_type().is_integer() {
+                return None;
+            }
+            Some(acc)
+        }
+        IntrinsicOperator::Eq
+        | IntrinsicOperator::Gt
+        | IntrinsicOperator::Ge
+        | IntrinsicOperator::Lt
+        | IntrinsicOperator::Le
+        | IntrinsicOperator::And
+        | IntrinsicOperator::Or => {
+            if values.len() < 2 {
+                return None;
+            }
+            // Each adjacent pair is compared and the results are combined with `and`.
+            let mut result = true;
+            for i in 0..values.len() - 1 {
+                let pair = match (values[i], values[i + 1]) {
+                    (ConstValue::Bool(a), ConstValue::Bool(b)) => match op {
+                        IntrinsicOperator::Eq => a == b,
+                        IntrinsicOperator::And => a & b,
+                        IntrinsicOperator::Or => a | b,
+                        _ => return None,
+                    },
+                    (ConstValue::Bool(_), _) => return None,
+                    (l, r) => {
+                        let (l, r) = unify((l, Some(&args[i])), (r, &args[i + 1]), true)?;
+                        eval_comparison(&op, l, r)?
+                    }
+                };
+                result &= pair;
+            }
+            Some(ConstValue::Bool(result))
+        }
+        _ => None,
+    }
+}
+
+fn eval_float_intrinsic(func: IntrinsicFunction, values: &[ConstValue]) -> Option<ConstValue> {
+    let floats = values
+        .iter()
+        .map(|v| match *v {
+            ConstValue::F32(f) => Some(f),
+            ConstValue::I32(i) => Some(i as f32),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(ConstValue::F32(match (func, &floats[..]) {
+        (IntrinsicFunction::Sqrt, [a]) => a.sqrt(),
+        (IntrinsicFunction::Abs, [a]) => a.abs(),
+        (IntrinsicFunction::Floor, [a]) => a.floor(),
+        (IntrinsicFunction::Ceil, [a]) => a.ceil(),
+        (IntrinsicFunction::Trunc, [a]) => a.trunc(),
+        (IntrinsicFunction::Nearest, [a]) => a.round_ties_even(),
+        // Wasm differs from Rust for NaN and signed zeros, so those are left unfolded.
+        (IntrinsicFunction::Min | IntrinsicFunction::Max, [a, b])
+            if a.is_nan() || b.is_nan() || (*a == 0.0 && *b == 0.0) =>
+        {
+            return None
+        }
+        (IntrinsicFunction::Min, [a, b]) => a.min(*b),
+        (IntrinsicFunction::Max, [a, b]) => a.max(*b),
+        (IntrinsicFunction::Copysign, [a, b]) => a.copysign(*b),
+        _ => return None,
+    }))
+}
+
+/// Mirrors the instructions chosen by `emit_conversion`.
+fn eval_conversion(name: &str, value: ConstValue, target: &Type) -> Option<ConstValue> {
+    use ConstValue::*;
+    Some(match (name, value, target) {
+        (_, v, t) if v.get_type() == *t && name == "as" => v,
+        ("as", I32(v), Type::U32) => U32(v as u32),
+        ("as", U32(v), Type::I32) => I32(v as i32),
+        ("as", I64(v), Type::U64) => U64(v as u64),
+        ("as", U64(v), Type::I64) => I64(v as i64),
+        ("as", I32(v), Type::I64) => I64(v as i64),
+        ("as", I32(v), Type::U64) => U64(v as i64 as u64),
+        ("as", U32(v), Type::I64) => I64(v as i64),
+        ("as", U32(v), Type::U64) => U64(v as u64),
+        ("as", I64(v), Type::I32) => I32(v as i32),
+        ("as", I64(v), Type::U32) => U32(v as u32),
+        ("as", U64(v), Type::I32) => I32(v as i32),
+        ("as", U64(v), Type::U32) => U32(v as u32),
+        ("as", I32(v), Type::F32) => F32(v as f32),
+        ("as", U32(v), Type::F32) => F32(v as f32),
+        ("as", I64(v), Type::F32) => F32(v as f32),
+        ("as", U64(v), Type::F32) => F32(v as f32),
+        // Rust's float to integer casts saturate just like `trunc_sat`.
+        ("as", F32(v), Type::I32) => I32(v as i32),
+        ("as", F32(v), Type::U32) => U32(v as u32),
+        ("as", F32(v), Type::I64) => I64(v as i64),
+        ("as", F32(v), Type::U64) => U64(v as u64),
+        // `trunc-as` traps on NaN and out of range values, which are left unfolded.
+        ("trunc-as", F32(v), Type::I32) if v > -2147483904.0 && v < 2147483648.0 => I32(v as i32),
+        ("trunc-as", F32(v), Type::U32) if v > -1.0 && v < 4294967296.0 => U32(v as u32),
+        ("trunc-as", F32(v), Type::I64)
+            if (-9223372036854775808.0..9223372036854775808.0).contains(&v) =>
+        {
+            I64(v as i64)
+        }
+        ("trunc-as", F32(v), Type::U64) if v > -1.0 && v < 18446744073709551616.0 => U64(v as u64),
+        ("reinterpret", F32(v), Type::I32) => I32(v.to_bits() as i32),
+        ("reinterpret", F32(v), Type::U32) => U32(v.to_bits()),
+        ("reinterpret", I32(v), Type::F32) => F32(f32::from_bits(v as u32)),
+        ("reinterpret", U32(v), Type::F32) => F32(f32::from_bits(v)),
+        _ => return None,
+    })
+}
+
+/// Evaluates `ast` at compile time when it consists only of literals, immutable globals
+/// and pure intrinsics. Returns `None` for anything else, including expressions that
+/// would trap or fail to type-check, so that the emitter reports them as usual.
+pub(super) fn eval_const(module: &Module, ast: &AST, env: &Rc<RefCell<Env>>) -> Option<ConstValue> {
+    match ast {
+        AST::NumberLiteral(literal) => {
+            let (opcode, t) = parse_number_literal(literal).ok()?;
+            ConstValue::from_opcode(&opcode, &t)
+        }
+        AST::BoolLiteral(b) => Some(ConstValue::Bool(*b)),
+        AST::Symbol(name) => {
+            let variable = env.borrow().get(name)?;
+            let index = match variable.pointer {
+                Pointer::Global(index) => index,
+                Pointer::Local(_) => return None,
+            };
+            let globals = module.globals.borrow();
+            match globals.get(*name) {
+                Some((i, global)) if *i == index && !global.is_mutable => {
+                    ConstValue::from_global(global.value, &variable.t)
+                }
+                _ => None,
+            }
+        }
+        AST::List(list) if !list.is_empty() => {
+            if let Some(op) = intrinsic_operator(&list[0]) {
+                if list.len() < 2 {
+                    return None;
+                }
+                return eval_intrinsic(module, op, &list[1..], env);
+            }
+            let name = match list[0] {
+                AST::Symbol(name) => name,
+                _ => return None,
+            };
+            match name {
+                "if" if list.len() == 4 => {
+                    let condition = eval_const(module, &list[1], env)?;
+                    let true_value = eval_const(module, &list[2], env)?;
+                    let false_value = eval_const(module, &list[3], env)?;
+                    if true_value.get_type() != false_value.get_type() {
+                        return None;
+                    }
+                    match condition {
+                        ConstValue::Bool(true) => Some(true_value),
+                        ConstValue::Bool(false) => Some(false_value),
+                        _ => None,
+                    }
+                }
+                "as" | "trunc-as" | "reinterpret" if list.len() == 3 => {
+                    let target = resolve_target_type(name, &list[1]).ok()?;
+                    let value = eval_const(module, &list[2], env)?;
+                    eval_conversion(name, value, &target)
+                }
+                _ => {
+                    let func = IntrinsicFunction::from_name(name)?;
+                    let values = list[1..]
+                        .iter()
+                        .map(|arg| eval_const(module, arg, env))
+                        .collect::<Option<Vec<_>>>()?;
+                    eval_float_intrinsic(func, &values)
+                }
+            }
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn optimized_module() -> Module {
+        Module::with_options(CompileOptions {
+            opt_level: 1,
+            ..CompileOptions::debug()
+        })
+    }
+
+    #[test]
+    fn test_fold_expressions() {
+        let module = &mut optimized_module();
+        emit(
+            module,
+            "
+        (export defn area: f32 []
+            (* 2.5 (/ (+ 1 (- 2 1)) 2)))
+        (export defn mask: u32 [a: u32]
+            (bit-and a (- (shl 1u32 4) 1)))
+        (export defn check: bool []
+            (< 1 2 (as i32 (sqrt 16.0))))
+        (export defn trap: i32 []
+            (/ 1 0))
+        ",
+        )
+        .unwrap();
+        let functions = module.functions.borrow();
+        assert_eq!(
+            functions["area"].1.body,
+            vec![OpCode::F32Const(2.5), OpCode::End]
+        );
+        assert_eq!(
+            functions["mask"].1.body,
+            vec![
+                OpCode::LocalGet(0),
+                OpCode::I32Const(15),
+                OpCode::I32And,
+                OpCode::End
+            ]
+        );
+        assert_eq!(
+            functions["check"].1.body,
+            vec![OpCode::I32Const(1), OpCode::End]
+        );
+        assert_eq!(
+            functions["trap"].1.body,
+            vec![
+                OpCode::I32Const(1),
+                OpCode::I32Const(0),
+                OpCode::I32DivS,
+                OpCode::End
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fold_if_and_globals() {
+        let module = &mut optimized_module();
+        emit(
+            module,
+            "
+        (define width: i32 (* 4 8))
+        (define debug: bool (> width 16))
+        (defmut counter: i32 0)
+        (export defn pick: i32 [a: i32]
+            (if debug (+ a width) 0))
+        (export defn count: i32 []
+            (+ counter width))
+        ",
+        )
+        .unwrap();
+        let functions = module.functions.borrow();
+        assert_eq!(
+            functions["pick"].1.body,
+            vec![
+                OpCode::LocalGet(0),
+                OpCode::I32Const(32),
+                OpCode::I32Add,
+                OpCode::End
+            ]
+        );
+        assert_eq!(
+            functions["count"].1.body,
+            vec![
+                OpCode::GlobalGet(2),
+                OpCode::I32Const(32),
+                OpCode::I32Add,
+                OpCode::End
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fold_floored_mod() {
+        let module = &mut optimized_module();
+        emit(
+            module,
+            "
+        (define min32: i32 (- (- 0 2147483647) 1))
+        (define min64: i64 (- (- 0 9223372036854775807i64) 1))
+        (export defn a: i32 [] (mod 2147483646 2147483647))
+        (export defn b: i32 [] (mod (- 0 2147483647) min32))
+        (export defn c: i32 [] (mod min32 2147483647))
+        (export defn d: i32 [] (mod 2147483647 min32))
+        (export defn e: i32 [] (mod (- 0 7) 2))
+        (export defn f: i64 [] (mod min64 9223372036854775807i64))
+        (export defn g: i64 [] (mod (- 0 9223372036854775807i64) min64))
+        ",
+        )
+        .unwrap();
+        let functions = module.functions.borrow();
+        for (name, expected) in [
+            ("a", OpCode::I32Const(i32::MAX - 1)),
+            ("b", OpCode::I32Const(-i32::MAX)),
+            ("c", OpCode::I32Const(i32::MAX - 1)),
+            ("d", OpCode::I32Const(-1)),
+            ("e", OpCode::I32Const(1)),
+            ("f", OpCode::I64Const(i64::MAX - 1)),
+            ("g", OpCode::I64Const(-i64::MAX)),
+        ] {
+            assert_eq!(functions[name].1.body, vec![expected, OpCode::End], "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_fold_if_keeps_local_order() {
+        for condition in ["true", "false"] {
+            let module = &mut optimized_module();
+            let source = format!(
+                "(export defn pick: i32 [a: i32]
+                    (if {}
+                        (let [x (as i64 a)] (as i32 x))
+                        (let [y (as f32 a)] (as i32 y))))",
+                condition
+            );
+            emit(module, &source).unwrap();
+            let body = &module.functions.borrow()["pick"].1.body;
+            // The k-th declaration is the local after the argument, whichever branch
+            // declared it.
+            let decls = body
+                .iter()
+                .filter_map(|code| match code {
+                    OpCode::LocalDecl(t) => Some(*t),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            let local = body
+                .iter()
+                .find_map(|code| match code {
+                    OpCode::LocalSet(index) | OpCode::LocalTee(index) => Some(*index),
+                    _ => None,
+                })
+                .unwrap();
+            let expected = if condition == "true" {
+                WasmPrimitiveType::I64
+            } else {
+                WasmPrimitiveType::F32
+            };
+            assert_eq!(decls[local as usize - 1], expected);
+        }
+    }
+
+    #[test]
+    fn test_no_folding_without_opt_level() {
+        let module = &mut Module::default();
+        emit(
+            module,
+            "
+        (define size: i32 (+ 1 2))
+        (defn add: i32 []
+            (+ size 1))
+        ",
+        )
+        .unwrap();
+        assert_eq!(module.globals.borrow()["size"].1.value, GlobalValue::I32(3));
+        assert_eq!(
+            module.functions.borrow()["add"].1.body,
+            vec![
+                OpCode::GlobalGet(2),
+                OpCode::I32Const(1),
+                OpCode::I32Add,
+                OpCode::End
+            ]
+        );
+        assert!(emit(&mut Module::default(), "(define bad: i32 (+ 1 true))").is_err());
+    }
+}
@@ -0,0 +1,196 @@
+use super::*;
+use crate::{emitter::expression::emit_obj, env::Env, parser::AST, resolver::Type};
+use anyhow::{bail, ensure, Result};
+use std::{cell::RefCell, rc::Rc};
+
+pub(super) fn resolve_target_type(name: &str, ast: &AST) -> Result<Rc<Type>> {
+    let type_ast = match ast {
+        AST::Symbol(name) => primitive_type_from_name(name),
+        _ => None,
+    };
+    match type_ast {
+        Some(type_ast) => resolve_type(&type_ast, &TypeEnv::default()),
+        None => bail!("a type is expected after '{}', found {:?}", name, ast),
+    }
+}
+
+/// Instructions for `as`. Float to integer conversions saturate instead of trapping.
+fn as_codes(source: &Type, target: &Type) -> Option<Vec<OpCode>> {
+    Some(match (source, target) {
+        (s, t) if s == t => vec![],
+        // Signedness only changes how the bits are interpreted.
+        (Type::I32, Type::U32)
+        | (Type::U32, Type::I32)
+        | (Type::I64, Type::U64)
+        | (Type::U64, Type::I64) => vec![],
+        (Type::I32, Type::I64 | Type::U64) => vec![OpCode::I64ExtendI32S],
+        (Type::U32, Type::I64 | Type::U64) => vec![OpCode::I64ExtendI32U],
+        (Type::I64 | Type::U64, Type::I32 | Type::U32) => vec![OpCode::I32WrapI64],
+        (Type::I32, Type::F32) => vec![OpCode::F32ConvertI32S],
+        (Type::U32, Type::F32) => vec![OpCode::F32ConvertI32U],
+        (Type::I64, Type::F32) => vec![OpCode::F32ConvertI64S],
+        (Type::U64, Type::F32) => vec![OpCode::F32ConvertI64U],
+        (Type::F32, Type::I32) => vec![OpCode::I32TruncSatF32S],
+        (Type::F32, Type::U32) => vec![OpCode::I32TruncSatF32U],
+        (Type::F32, Type::I64) => vec![OpCode::I64TruncSatF32S],
+        (Type::F32, Type::U64) => vec![OpCode::I64TruncSatF32U],
+        _ => return None,
+    })
+}
+
+/// Instructions for `trunc-as`, which traps on NaN or when the value is out of range.
+fn trunc_codes(source: &Type, target: &Type) -> Option<Vec<OpCode>> {
+    Some(match (source, target) {
+        (Type::F32, Type::I32) => vec![OpCode::I32TruncF32S],
+        (Type::F32, Type::U32) => vec![OpCode::I32TruncF32U],
+        (Type::F32, Type::I64) => vec![OpCode::I64TruncF32S],
+        (Type::F32, Type::U64) => vec![OpCode::I64TruncF32U],
+        _ => return None,
+    })
+}
+
+/// Instructions for `reinterpret`, which keeps the bits and changes the type.
+fn reinterpret_codes(source: &Type, target: &Type) -> Option<Vec<OpCode>> {
+    Some(match (source, target) {
+        (Type::F32, Type::I32 | Type::U32) => vec![OpCode::I32ReinterpretF32],
+        (Type::I32 | Type::U32, Type::F32) => vec![OpCode::F32ReinterpretI32],
+        _ => return None,
+    })
+}
+
+/// Emits `(as type value)`, `(trunc-as type value)` and `(reinterpret type value)`,
+/// the explicit conversions between numeric types.
+pub(super) fn emit_conversion(
+    module: &mut Module,
+    codes: &mut Vec<OpCode>,
+    name: &str,
+    args: &[AST],
+    env: Rc<RefCell<Env>>,
+) -> Result<Rc<Type>> {
+    ensure!(args.len() == 2, "{} expects 2 forms, found {}", name, args.len());
+    let target_type = resolve_target_type(name, &args[0])?;
+    let source_type = emit_obj(module, codes, &args[1], env)?;
+    let conversion = match name {
+        "as" => as_codes(&source_type, &target_type),
+        "trunc-as" => trunc_codes(&source_type, &target_type),
+        "reinterpret" => reinterpret_codes(&source_type, &target_type),
+        _ => unreachable!(),
+    };
+    match conversion {
+        Some(mut conversion_codes) => codes.append(&mut conversion_codes),
+        None => bail!(
+            "cannot convert {} to {} with {}",
+            source_type,
+            target_type,
+            name
+        ),
+    }
+    Ok(target_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signedness() {
+        let module = &mut Module::default();
+        emit(
+            module,
+            "
+        (defn checksum: u32 [a: i32 b: u32]
+            (+ (as u32 a) b 1))
+        (defn half: i64 [a: u64]
+            (as i64 (/ a 2)))
+        ",
+        )
+        .unwrap();
+        let functions = module.functions.borrow();
+        assert_eq!(
+            functions["checksum"].1.body,
+            vec![
+                OpCode::LocalGet(0),
+                OpCode::LocalGet(1),
+                OpCode::I32Add,
+                OpCode::I32Const(1),
+                OpCode::I32Add,
+                OpCode::End
+            ]
+        );
+        assert_eq!(
+            functions["half"].1.body,
+            vec![
+                OpCode::LocalGet(0),
+                OpCode::I64Const(2),
+                OpCode::I64DivU,
+                OpCode::End
+            ]
+        );
+    }
+
+    #[test]
+    fn test_numeric_conversions() {
+        let module = &mut Module::default();
+        emit(
+            module,
+            "
+        (defn to-int: i32 [a: f32]
+            (+ (as i32 a) (trunc-as i32 a)))
+        (defn widen: i64 [a: i32 b: u32]
+            (+ (as i64 a) (as i64 b)))
+        (defn narrow: u32 [a: u64]
+            (as u32 a))
+        (defn to-float: f32 [a: u64]
+            (as f32 a))
+        (defn bits: u32 [a: f32]
+            (reinterpret u32 a))
+        ",
+        )
+        .unwrap();
+        let functions = module.functions.borrow();
+        assert_eq!(
+            functions["to-int"].1.body,
+            vec![
+                OpCode::LocalGet(0),
+                OpCode::I32TruncSatF32S,
+                OpCode::LocalGet(0),
+                OpCode::I32TruncF32S,
+                OpCode::I32Add,
+                OpCode::End
+            ]
+        );
+        assert_eq!(
+            functions["widen"].1.body,
+            vec![
+                OpCode::LocalGet(0),
+                OpCode::I64ExtendI32S,
+                OpCode::LocalGet(1),
+                OpCode::I64ExtendI32U,
+                OpCode::I64Add,
+                OpCode::End
+            ]
+        );
+        assert_eq!(
+            functions["narrow"].1.body,
+            vec![OpCode::LocalGet(0), OpCode::I32WrapI64, OpCode::End]
+        );
+        assert_eq!(
+            functions["to-float"].1.body,
+            vec![OpCode::LocalGet(0), OpCode::F32ConvertI64U, OpCode::End]
+        );
+        assert_eq!(
+            functions["bits"].1.body,
+            vec![OpCode::LocalGet(0), OpCode::I32ReinterpretF32, OpCode::End]
+        );
+    }
+
+    #[test]
+    fn test_invalid_conversion() {
+        let module = &mut Module::default();
+        assert!(emit(module, "(defn f: bool [a: i32] (as bool a))").is_err());
+        let module = &mut Module::default();
+        assert!(emit(module, "(defn f: i32 [a: i32] (trunc-as i32 a))").is_err());
+        let module = &mut Module::default();
+        assert!(emit(module, "(defn f: f32 [a: i64] (reinterpret f32 a))").is_err());
+    }
+}
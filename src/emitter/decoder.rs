@@ -0,0 +1,689 @@
+use super::*;
+use anyhow::{bail, ensure, Context, Result};
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        let byte = *self
+            .bytes
+            .get(self.pos)
+            .with_context(|| format!("unexpected end of input at offset {}", self.pos))?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        ensure!(
+            self.pos + len <= self.bytes.len(),
+            "unexpected end of input at offset {}",
+            self.pos
+        );
+        let bytes = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let mut rest = &self.bytes[self.pos..];
+        let n = leb128::read::unsigned(&mut rest)?;
+        self.pos = self.bytes.len() - rest.len();
+        Ok(u32::try_from(n)?)
+    }
+
+    fn i64(&mut self) -> Result<i64> {
+        let mut rest = &self.bytes[self.pos..];
+        let n = leb128::read::signed(&mut rest)?;
+        self.pos = self.bytes.len() - rest.len();
+        Ok(n)
+    }
+
+    fn i32(&mut self) -> Result<i32> {
+        Ok(i32::try_from(self.i64()?)?)
+    }
+
+    fn f32(&mut self) -> Result<f32> {
+        Ok(f32::from_le_bytes(self.bytes(4)?.try_into()?))
+    }
+
+    fn name(&mut self) -> Result<String> {
+        let len = self.u32()? as usize;
+        Ok(String::from_utf8(self.bytes(len)?.to_vec())?)
+    }
+
+    fn primitive_type(&mut self) -> Result<WasmPrimitiveType> {
+        Ok(match self.byte()? {
+            0x7F => WasmPrimitiveType::I32,
+            0x7E => WasmPrimitiveType::I64,
+            0x7D => WasmPrimitiveType::F32,
+            t => bail!("unsupported value type 0x{:02X}, only i32, i64 and f32 are supported", t),
+        })
+    }
+
+    fn block_type(&mut self) -> Result<Option<WasmPrimitiveType>> {
+        if self.bytes.get(self.pos) == Some(&0x40) {
+            self.pos += 1;
+            return Ok(None);
+        }
+        Ok(Some(self.primitive_type()?))
+    }
+
+    /// `(offset, alignment)` of a load or store, which the binary stores the other way around.
+    fn memarg(&mut self) -> Result<(u32, u32)> {
+        let alignment = self.u32()?;
+        // The alignment is a power of two no wider than the 64-bit accesses.
+        ensure!(alignment < 32, "invalid alignment 2^{}", alignment);
+        let offset = self.u32()?;
+        Ok((offset, alignment))
+    }
+}
+
+fn decode_simple_opcode(byte: u8) -> Option<OpCode> {
+    Some(match byte {
+        0x00 => OpCode::Unreachable,
+        0x05 => OpCode::Else,
+        0x0B => OpCode::End,
+        0x1A => OpCode::Drop,
+        0x1B => OpCode::Select,
+        0x46 => OpCode::I32Eq,
+        0x47 => OpCode::I32Ne,
+        0x48 => OpCode::I32LtS,
+        0x49 => OpCode::I32LtU,
+        0x4A => OpCode::I32GtS,
+        0x4B => OpCode::I32GtU,
+        0x4C => OpCode::I32LeS,
+        0x4D => OpCode::I32LeU,
+        0x4E => OpCode::I32GeS,
+        0x4F => OpCode::I32GeU,
+        0x51 => OpCode::I64Eq,
+        0x52 => OpCode::I64Ne,
+        0x53 => OpCode::I64LtS,
+        0x54 => OpCode::I64LtU,
+        0x55 => OpCode::I64GtS,
+        0x56 => OpCode::I64GtU,
+        0x57 => OpCode::I64LeS,
+        0x58 => OpCode::I64LeU,
+        0x59 => OpCode::I64GeS,
+        0x5A => OpCode::I64GeU,
+        0x5B => OpCode::F32Eq,
+        0x5D => OpCode::F32Lt,
+        0x5E => OpCode::F32Gt,
+        0x5F => OpCode::F32Le,
+        0x60 => OpCode::F32Ge,
+        0x67 => OpCode::I32Clz,
+        0x68 => OpCode::I32Ctz,
+        0x69 => OpCode::I32Popcnt,
+        0x6A => OpCode::I32Add,
+        0x6B => OpCode::I32Sub,
+        0x6C => OpCode::I32Mul,
+        0x6D => OpCode::I32DivS,
+        0x6E => OpCode::I32DivU,
+        0x6F => OpCode::I32RemS,
+        0x70 => OpCode::I32RemU,
+        0x71 => OpCode::I32And,
+        0x72 => OpCode::I32Or,
+        0x73 => OpCode::I32Xor,
+        0x74 => OpCode::I32Shl,
+        0x75 => OpCode::I32ShrS,
+        0x76 => OpCode::I32ShrU,
+        0x77 => OpCode::I32Rotl,
+        0x78 => OpCode::I32Rotr,
+        0x79 => OpCode::I64Clz,
+        0x7A => OpCode::I64Ctz,
+        0x7B => OpCode::I64Popcnt,
+        0x7C => OpCode::I64Add,
+        0x7D => OpCode::I64Sub,
+        0x7E => OpCode::I64Mul,
+        0x7F => OpCode::I64DivS,
+        0x80 => OpCode::I64DivU,
+        0x81 => OpCode::I64RemS,
+        0x82 => OpCode::I64RemU,
+        0x83 => OpCode::I64And,
+        0x84 => OpCode::I64Or,
+        0x85 => OpCode::I64Xor,
+        0x86 => OpCode::I64Shl,
+        0x87 => OpCode::I64ShrS,
+        0x88 => OpCode::I64ShrU,
+        0x89 => OpCode::I64Rotl,
+        0x8A => OpCode::I64Rotr,
+        0x8B => OpCode::F32Abs,
+        0x8C => OpCode::F32Neg,
+        0x8D => OpCode::F32Ceil,
+        0x8E => OpCode::F32Floor,
+        0x8F => OpCode::F32Trunc,
+        0x90 => OpCode::F32Nearest,
+        0x91 => OpCode::F32Sqrt,
+        0x92 => OpCode::F32Add,
+        0x93 => OpCode::F32Sub,
+        0x94 => OpCode::F32Mul,
+        0x95 => OpCode::F32Div,
+        0x96 => OpCode::F32Min,
+        0x97 => OpCode::F32Max,
+        0x98 => OpCode::F32Copysign,
+        0xA7 => OpCode::I32WrapI64,
+        0xA8 => OpCode::I32TruncF32S,
+        0xA9 => OpCode::I32TruncF32U,
+        0xAC => OpCode::I64ExtendI32S,
+        0xAD => OpCode::I64ExtendI32U,
+        0xAE => OpCode::I64TruncF32S,
+        0xAF => OpCode::I64TruncF32U,
+        0xB2 => OpCode::F32ConvertI32S,
+        0xB3 => OpCode::F32ConvertI32U,
+        0xB4 => OpCode::F32ConvertI64S,
+        0xB5 => OpCode::F32ConvertI64U,
+        0xBC => OpCode::I32ReinterpretF32,
+        0xBE => OpCode::F32ReinterpretI32,
+        _ => return None,
+    })
+}
+
+fn decode_opcode(reader: &mut Reader) -> Result<OpCode> {
+    let byte = reader.byte()?;
+    if let Some(opcode) = decode_simple_opcode(byte) {
+        return Ok(opcode);
+    }
+    Ok(match byte {
+        0x03 => OpCode::Loop(reader.block_type()?),
+        0x04 => OpCode::If(reader.block_type()?),
+        0x0C => OpCode::Br(reader.u32()?),
+        0x10 => OpCode::Call(reader.u32()?),
+        0x12 => OpCode::ReturnCall(reader.u32()?),
+        0x20 => OpCode::LocalGet(reader.u32()?),
+        0x21 => OpCode::LocalSet(reader.u32()?),
+        0x22 => OpCode::LocalTee(reader.u32()?),
+        0x23 => OpCode::GlobalGet(reader.u32()?),
+        0x24 => OpCode::GlobalSet(reader.u32()?),
+        0x28 | 0x29 | 0x2A | 0x2D | 0x36 | 0x37 | 0x38 | 0x3A => {
+            let (offset, alignment) = reader.memarg()?;
+            match byte {
+                0x28 => OpCode::I32Load { offset, alignment },
+                0x29 => OpCode::I64Load { offset, alignment },
+                0x2A => OpCode::F32Load { offset, alignment },
+                0x2D => OpCode::I32Load8U { offset, alignment },
+                0x36 => OpCode::I32Store { offset, alignment },
+                0x37 => OpCode::I64Store { offset, alignment },
+                0x38 => OpCode::F32Store { offset, alignment },
+                _ => OpCode::I32Store8 { offset, alignment },
+            }
+        }
+        0x41 => OpCode::I32Const(reader.i32()?),
+        0x42 => OpCode::I64Const(reader.i64()?),
+        0x43 => OpCode::F32Const(reader.f32()?),
+        0xFC => match reader.u32()? {
+            0x00 => OpCode::I32TruncSatF32S,
+            0x01 => OpCode::I32TruncSatF32U,
+            0x04 => OpCode::I64TruncSatF32S,
+            0x05 => OpCode::I64TruncSatF32U,
+            n => bail!("unsupported opcode 0xFC {}", n),
+        },
+        // Such as `block`, which wisp never emits.
+        _ => bail!(
+            "unsupported opcode 0x{:02X}, only the instructions wisp emits are supported",
+            byte
+        ),
+    })
+}
+
+/// Decodes a function body into `LocalDecl`s followed by its instructions.
+fn decode_function_body(reader: &mut Reader) -> Result<Vec<OpCode>> {
+    let mut body = Vec::new();
+    for _ in 0..reader.u32()? {
+        let count = reader.u32()?;
+        let t = reader.primitive_type()?;
+        body.extend((0..count).map(|_| OpCode::LocalDecl(t)));
+    }
+    while !reader.is_empty() {
+        body.push(decode_opcode(reader)?);
+    }
+    Ok(body)
+}
+
+fn to_type(types: &[WasmPrimitiveType]) -> Rc<Type> {
+    Rc::new(match types {
+        [] => Type::Unit,
+        [WasmPrimitiveType::I32, ..] => Type::I32,
+        [WasmPrimitiveType::I64, ..] => Type::I64,
+        [WasmPrimitiveType::F32, ..] => Type::F32,
+    })
+}
+
+fn decode_global(reader: &mut Reader) -> Result<Global> {
+    let t = reader.primitive_type()?;
+    let is_mutable = reader.byte()? == 1;
+    let value = match (t, decode_opcode(reader)?) {
+        (WasmPrimitiveType::I32, OpCode::I32Const(v)) => GlobalValue::I32(v),
+        (WasmPrimitiveType::I64, OpCode::I64Const(v)) => GlobalValue::I64(v),
+        (WasmPrimitiveType::F32, OpCode::F32Const(v)) => GlobalValue::F32(v),
+        (_, init) => bail!("unsupported global initializer {:?}", init),
+    };
+    ensure!(
+        decode_opcode(reader)? == OpCode::End,
+        "expected end of global initializer"
+    );
+    Ok(Global { is_mutable, value })
+}
+
+fn decode_name_map(reader: &mut Reader) -> Result<HashMap<u32, String>> {
+    (0..reader.u32()?)
+        .map(|_| Ok((reader.u32()?, reader.name()?)))
+        .collect()
+}
+
+#[derive(Default)]
+struct Names {
+    functions: HashMap<u32, String>,
+    locals: HashMap<u32, HashMap<u32, String>>,
+    globals: HashMap<u32, String>,
+}
+
+/// Reads the function, local and global name subsections of a `name` section.
+fn decode_name_section(reader: &mut Reader) -> Result<Names> {
+    let mut names = Names::default();
+    while !reader.is_empty() {
+        let id = reader.byte()?;
+        let size = reader.u32()? as usize;
+        let subsection = &mut Reader::new(reader.bytes(size)?);
+        match id {
+            0x01 => names.functions = decode_name_map(subsection)?,
+            0x02 => {
+                for _ in 0..subsection.u32()? {
+                    let index = subsection.u32()?;
+                    names.locals.insert(index, decode_name_map(subsection)?);
+                }
+            }
+            0x07 => names.globals = decode_name_map(subsection)?,
+            _ => (),
+        }
+    }
+    Ok(names)
+}
+
+fn decode_memory_limits(reader: &mut Reader, memory: &mut Memory) -> Result<()> {
+    let flags = reader.byte()?;
+    ensure!(flags & !0x03 == 0, "unsupported memory flags 0x{:02X}", flags);
+    memory.initial = reader.u32()?;
+    memory.max = if flags & 0x01 != 0 {
+        Some(reader.u32()?)
+    } else {
+        None
+    };
+    memory.shared = flags & 0x02 != 0;
+    Ok(())
+}
+
+fn export_name(exports: &[Export], export_type: ExportKind, index: u32) -> Option<String> {
+    exports
+        .iter()
+        .find(|export| export.export_type == export_type && export.index == index)
+        .map(|export| export.name.clone())
+}
+
+/// Decodes a Wasm binary into a `Module`, and its memory if it defines or imports one.
+/// Names come from the `name` section when there is one; otherwise functions and globals
+/// are named after their export, or `func<index>` and `global<index>`. Custom sections
+/// other than `name` are skipped, and the sections the emitter never writes are errors.
+pub fn decode(bytes: &[u8]) -> Result<(Module, Option<Memory>)> {
+    let reader = &mut Reader::new(bytes);
+    ensure!(
+        reader.bytes(4)? == [0x00, 0x61, 0x73, 0x6d],
+        "not a Wasm binary"
+    );
+    ensure!(
+        reader.bytes(4)? == [0x01, 0x00, 0x00, 0x00],
+        "unsupported Wasm version"
+    );
+
+    let mut signatures = Vec::new();
+    let mut function_signatures = Vec::new();
+    let mut imported_functions = 0;
+    let mut functions = Vec::new();
+    let mut globals = Vec::new();
+    let mut exports = Vec::new();
+    let mut memory = None;
+    let mut start = None;
+    let mut data = Vec::new();
+    let mut names = Names::default();
+    while !reader.is_empty() {
+        let id = reader.byte()?;
+        let size = reader.u32()? as usize;
+        let section = &mut Reader::new(reader.bytes(size)?);
+        match id {
+            0x00 => {
+                if section.name()? == "name" {
+                    names = decode_name_section(section)?;
+                }
+            }
+            0x01 => {
+                for _ in 0..section.u32()? {
+                    ensure!(
+                        section.byte()? == SignatureType::Func as u8,
+                        "unsupported type"
+                    );
+                    let params = (0..section.u32()?)
+                        .map(|_| section.primitive_type())
+                        .collect::<Result<Vec<_>>>()?;
+                    let results = (0..section.u32()?)
+                        .map(|_| section.primitive_type())
+                        .collect::<Result<Vec<_>>>()?;
+                    signatures.push(Signature {
+                        sig_type: SignatureType::Func,
+                        params,
+                        results,
+                    });
+                }
+            }
+            0x02 => {
+                for _ in 0..section.u32()? {
+                    let module_name = section.name()?;
+                    let name = section.name()?;
+                    match section.byte()? {
+                        0x00 => {
+                            let signature_index = section.u32()?;
+                            let signature = signatures
+                                .get(signature_index as usize)
+                                .with_context(|| format!("unknown type {}", signature_index))?;
+                            functions.push((
+                                imported_functions,
+                                Function {
+                                    signature_index,
+                                    arg_types: signature.params.iter().map(|t| to_type(&[*t])).collect(),
+                                    result_type: to_type(&signature.results),
+                                    body: Vec::new(),
+                                    inline: false,
+                                    local_names: Vec::new(),
+                                    import: Some((module_name, name)),
+                                },
+                            ));
+                            imported_functions += 1;
+                        }
+                        0x02 => {
+                            ensure!(memory.is_none(), "unsupported number of memories");
+                            let memory = memory.insert(Memory {
+                                import: true,
+                                ..Memory::default()
+                            });
+                            decode_memory_limits(section, memory)?;
+                        }
+                        kind => bail!("unsupported import kind 0x{:02X}", kind),
+                    }
+                }
+            }
+            0x03 => {
+                for _ in 0..section.u32()? {
+                    function_signatures.push(section.u32()?);
+                }
+            }
+            0x05 => {
+                for _ in 0..section.u32()? {
+                    ensure!(memory.is_none(), "unsupported number of memories");
+                    decode_memory_limits(section, memory.insert(Memory::default()))?;
+                }
+            }
+            0x06 => {
+                for _ in 0..section.u32()? {
+                    globals.push(decode_global(section)?);
+                }
+            }
+            0x07 => {
+                for _ in 0..section.u32()? {
+                    let name = section.name()?;
+                    let export_type = match section.byte()? {
+                        0x00 => ExportKind::Func,
+                        0x02 => ExportKind::Memory,
+                        0x03 => ExportKind::Global,
+                        kind => bail!("unsupported export kind 0x{:02X}", kind),
+                    };
+                    exports.push(Export {
+                        export_type,
+                        name,
+                        index: section.u32()?,
+                    });
+                }
+            }
+            0x08 => start = Some(section.u32()?),
+            0x0B => {
+                ensure!(section.u32()? == 1, "unsupported number of data segments");
+                ensure!(
+                    section.u32()? == 0 && section.byte()? == 0x41,
+                    "unsupported data segment"
+                );
+                let memory = memory.as_mut().context("data segment without a memory")?;
+                memory.stack_base = section.i32()? as u32;
+                ensure!(section.byte()? == 0x0B, "unsupported data segment");
+                let len = section.u32()? as usize;
+                data = section.bytes(len)?.to_vec();
+            }
+            0x0A => {
+                let count = section.u32()? as usize;
+                ensure!(
+                    count == function_signatures.len(),
+                    "function and code sections disagree"
+                );
+                for (i, signature_index) in function_signatures.iter().enumerate() {
+                    let size = section.u32()? as usize;
+                    let body = decode_function_body(&mut Reader::new(section.bytes(size)?))?;
+                    let signature = signatures
+                        .get(*signature_index as usize)
+                        .with_context(|| format!("unknown type {}", signature_index))?;
+                    functions.push((
+                        imported_functions + i as u32,
+                        Function {
+                            signature_index: *signature_index,
+                            arg_types: signature.params.iter().map(|t| to_type(&[*t])).collect(),
+                            result_type: to_type(&signature.results),
+                            body,
+                            inline: false,
+                            local_names: Vec::new(),
+                            import: None,
+                        },
+                    ));
+                }
+            }
+            id => bail!("unsupported section {}", id),
+        }
+    }
+
+    let module = Module::default();
+    for (index, mut func) in functions {
+        let name = match names.functions.remove(&index) {
+            Some(name) => name,
+            None => match &func.import {
+                Some((_, name)) => name.clone(),
+                None => export_name(&exports, ExportKind::Func, index)
+                    .unwrap_or_else(|| format!("func{}", index)),
+            },
+        };
+        let mut local_names = names
+            .locals
+            .remove(&index)
+            .unwrap_or_default()
+            .into_iter()
+            .collect::<Vec<_>>();
+        local_names.sort_by_key(|(index, _)| *index);
+        func.local_names = local_names;
+        module.functions.borrow_mut().insert(name, (index, func));
+    }
+    for (index, global) in globals.into_iter().enumerate() {
+        let index = index as u32;
+        let name = match names.globals.remove(&index) {
+            Some(name) => name,
+            None => export_name(&exports, ExportKind::Global, index)
+                .unwrap_or_else(|| format!("global{}", index)),
+        };
+        module.globals.borrow_mut().insert(name, (index, global));
+    }
+    let mut module = module;
+    module.signatures = signatures
+        .into_iter()
+        .enumerate()
+        .map(|(index, signature)| (signature, index as u16))
+        .collect();
+    module.exports = exports;
+    module.memory = memory.clone().unwrap_or_default();
+    module.start = start;
+    module.data = data;
+    Ok((module, memory))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufWriter;
+
+    /// The body with its `LocalDecl`s moved to the front, as the encoder declares them.
+    fn declared_up_front(body: &[OpCode]) -> Vec<OpCode> {
+        let (mut decls, codes): (Vec<_>, Vec<_>) = body
+            .iter()
+            .cloned()
+            .partition(|code| matches!(code, OpCode::LocalDecl(_)));
+        decls.extend(codes);
+        decls
+    }
+
+    const SOURCE: &str = "
+        (defmut counter: i64 0)
+        (define scale: f32 2.5)
+        (defn sum: i32 [n: i32 acc: i32]
+            (if (= n 0) acc (sum (- n 1) (+ acc n))))
+        (export defn main: f32 [arr: [f32] i: i32 x: i64]
+            (assert (>= i 0) \"i must not be negative\")
+            (let [total (sum i 0)
+                  wide (+ x 1)]
+                (set-at! arr 0 (as f32 wide))
+                (* scale (get arr i) (as f32 (trunc-as u32 (sqrt 2.0))) (as f32 total))))
+        ";
+
+    fn round_trip(options: CompileOptions) -> (Module, Module) {
+        let mut bytes = Vec::new();
+        compile_into_wasm(
+            &mut BufWriter::new(&mut bytes),
+            SOURCE,
+            &options,
+            Path::new("round_trip.wisp"),
+        )
+        .unwrap();
+        let (decoded, memory) = decode(&bytes).unwrap();
+        assert_eq!(memory.as_ref(), Some(&decoded.memory));
+        let module = &mut Module::with_options(options);
+        emit(module, SOURCE).unwrap();
+        (std::mem::take(module), decoded)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let (module, decoded) = round_trip(CompileOptions {
+            name_section: false,
+            ..CompileOptions::default()
+        });
+        assert_eq!(decoded.signatures, module.signatures);
+        assert_eq!(decoded.exports, module.exports);
+        let functions = module.functions.borrow();
+        let decoded_functions = decoded.functions.borrow();
+        for (decoded_name, name) in [("__wisp_panic", "__wisp_panic"), ("func1", "sum"), ("main", "main")] {
+            let (decoded_index, decoded_func) = &decoded_functions[decoded_name];
+            let (index, func) = &functions[name];
+            assert_eq!(decoded_index, index);
+            assert_eq!(decoded_func.signature_index, func.signature_index);
+            assert_eq!(decoded_func.result_type, func.result_type);
+            assert_eq!(decoded_func.body, declared_up_front(&func.body));
+            assert_eq!(decoded_func.import, func.import);
+        }
+        let globals = module.globals.borrow();
+        let decoded_globals = decoded.globals.borrow();
+        assert_eq!(decoded_globals["global0"].1, globals["__stack_pointer"].1);
+        assert_eq!(decoded_globals["global1"].1, globals["__heap_base"].1);
+        assert_eq!(decoded_globals["global2"].1, globals["counter"].1);
+        assert_eq!(decoded_globals["global3"].1, globals["scale"].1);
+        assert_eq!(decoded.memory, module.memory);
+        assert_eq!(decoded.data, module.data);
+    }
+
+    #[test]
+    fn test_round_trip_stack_base() {
+        let (module, decoded) = round_trip(CompileOptions {
+            memory: Memory {
+                import: true,
+                stack_base: 1024,
+                ..Memory::default()
+            },
+            ..CompileOptions::default()
+        });
+        assert_eq!(decoded.memory.stack_base, 1024);
+        assert_eq!(decoded.memory, module.memory);
+        assert_eq!(decoded.data, module.data);
+    }
+
+    #[test]
+    fn test_name_section() {
+        let (module, decoded) = round_trip(CompileOptions::default());
+        let functions = module.functions.borrow();
+        let decoded_functions = decoded.functions.borrow();
+        assert_eq!(decoded_functions.len(), functions.len());
+        for (name, (index, func)) in functions.iter() {
+            let (decoded_index, decoded_func) = &decoded_functions[name];
+            assert_eq!(decoded_index, index);
+            assert_eq!(decoded_func.local_names, func.local_names);
+        }
+        assert_eq!(
+            functions["main"]
+                .1
+                .local_names
+                .iter()
+                .map(|(_, name)| name.as_str())
+                .collect::<Vec<_>>(),
+            ["arr", "i", "x", "total", "wide"]
+        );
+        let globals = module.globals.borrow();
+        let decoded_globals = decoded.globals.borrow();
+        for (name, (index, global)) in globals.iter() {
+            assert_eq!(decoded_globals[name].0, *index);
+            assert_eq!(decoded_globals[name].1, *global);
+        }
+    }
+
+    #[test]
+    fn test_invalid_binary() {
+        assert!(decode(b"\0asm").is_err());
+        assert!(decode(b"wasm\x01\0\0\0").is_err());
+        assert!(decode(b"\0asm\x01\0\0\0\x0A\x04\x01\x02\0\xFF").is_err());
+        // i32.load with an alignment of 2^32
+        let error = decode(b"\0asm\x01\0\0\0\x03\x02\x01\0\x0A\x07\x01\x05\0\x28\x20\0\x0B")
+            .unwrap_err();
+        assert_eq!(error.to_string(), "invalid alignment 2^32");
+    }
+
+    #[test]
+    fn test_sections() {
+        // A type section alone.
+        let (module, memory) = decode(b"\0asm\x01\0\0\0\x01\x04\x01\x60\0\0").unwrap();
+        assert_eq!(module.signatures.len(), 1);
+        assert_eq!(memory, None);
+        // A table section.
+        let error = decode(b"\0asm\x01\0\0\0\x04\x04\x01\x70\0\0").unwrap_err();
+        assert_eq!(error.to_string(), "unsupported section 4");
+        // A function whose body is `block end`.
+        let error = decode(b"\0asm\x01\0\0\0\x01\x04\x01\x60\0\0\x03\x02\x01\0\x0A\x06\x01\x04\0\x02\x40\x0B")
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "unsupported opcode 0x02, only the instructions wisp emits are supported"
+        );
+        // A function returning f64.
+        let error = decode(b"\0asm\x01\0\0\0\x01\x05\x01\x60\0\x01\x7C").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "unsupported value type 0x7C, only i32, i64 and f32 are supported"
+        );
+    }
+}
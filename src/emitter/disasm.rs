@@ -0,0 +1,403 @@
+use super::*;
+use std::fmt::Write;
+
+fn type_name(t: WasmPrimitiveType) -> &'static str {
+    match t {
+        WasmPrimitiveType::I32 => "i32",
+        WasmPrimitiveType::I64 => "i64",
+        WasmPrimitiveType::F32 => "f32",
+    }
+}
+
+fn block_suffix(t: &Option<WasmPrimitiveType>) -> String {
+    match t {
+        Some(t) => format!(" (result {})", type_name(*t)),
+        None => String::new(),
+    }
+}
+
+fn memarg(name: &str, offset: u32, alignment: u32) -> String {
+    format!("{} offset={} align={}", name, offset, 1u32 << alignment)
+}
+
+/// The text format of an instruction, e.g. `local.get 0` or `i32.load offset=4 align=4`.
+fn instruction(code: &OpCode) -> String {
+    let name = match code {
+        OpCode::Loop(t) => return format!("loop{}", block_suffix(t)),
+        OpCode::If(t) => return format!("if{}", block_suffix(t)),
+        OpCode::Br(depth) => return format!("br {}", depth),
+        OpCode::LocalGet(i) => return format!("local.get {}", i),
+        OpCode::LocalSet(i) => return format!("local.set {}", i),
+        OpCode::LocalTee(i) => return format!("local.tee {}", i),
+        OpCode::GlobalGet(i) => return format!("global.get {}", i),
+        OpCode::GlobalSet(i) => return format!("global.set {}", i),
+        OpCode::LocalDecl(t) => return format!("(local {})", type_name(*t)),
+        OpCode::SourceLoc(offset) => return format!(";; source offset {}", offset),
+        OpCode::Call(i) => return format!("call {}", i),
+        OpCode::ReturnCall(i) => return format!("return_call {}", i),
+        OpCode::I32Store { offset, alignment } => return memarg("i32.store", *offset, *alignment),
+        OpCode::I32Store8 { offset, alignment } => {
+            return memarg("i32.store8", *offset, *alignment)
+        }
+        OpCode::I32Load { offset, alignment } => return memarg("i32.load", *offset, *alignment),
+        OpCode::I32Load8U { offset, alignment } => {
+            return memarg("i32.load8_u", *offset, *alignment)
+        }
+        OpCode::I64Store { offset, alignment } => return memarg("i64.store", *offset, *alignment),
+        OpCode::I64Load { offset, alignment } => return memarg("i64.load", *offset, *alignment),
+        OpCode::F32Store { offset, alignment } => return memarg("f32.store", *offset, *alignment),
+        OpCode::F32Load { offset, alignment } => return memarg("f32.load", *offset, *alignment),
+        OpCode::I32Const(n) => return format!("i32.const {}", n),
+        OpCode::I64Const(n) => return format!("i64.const {}", n),
+        OpCode::F32Const(n) => return format!("f32.const {:?}", n),
+        OpCode::Unreachable => "unreachable",
+        OpCode::Else => "else",
+        OpCode::Drop => "drop",
+        OpCode::Select => "select",
+        OpCode::End => "end",
+        OpCode::I32Add => "i32.add",
+        OpCode::I32Sub => "i32.sub",
+        OpCode::I32Mul => "i32.mul",
+        OpCode::I32DivS => "i32.div_s",
+        OpCode::I32DivU => "i32.div_u",
+        OpCode::I32RemS => "i32.rem_s",
+        OpCode::I32RemU => "i32.rem_u",
+        OpCode::I32Xor => "i32.xor",
+        OpCode::I32Eq => "i32.eq",
+        OpCode::I32Ne => "i32.ne",
+        OpCode::I32GtS => "i32.gt_s",
+        OpCode::I32GtU => "i32.gt_u",
+        OpCode::I32GeS => "i32.ge_s",
+        OpCode::I32GeU => "i32.ge_u",
+        OpCode::I32And => "i32.and",
+        OpCode::I32Or => "i32.or",
+        OpCode::I32Shl => "i32.shl",
+        OpCode::I32ShrS => "i32.shr_s",
+        OpCode::I32ShrU => "i32.shr_u",
+        OpCode::I32Rotl => "i32.rotl",
+        OpCode::I32Rotr => "i32.rotr",
+        OpCode::I32Clz => "i32.clz",
+        OpCode::I32Ctz => "i32.ctz",
+        OpCode::I32Popcnt => "i32.popcnt",
+        OpCode::I32LtS => "i32.lt_s",
+        OpCode::I32LtU => "i32.lt_u",
+        OpCode::I32LeS => "i32.le_s",
+        OpCode::I32LeU => "i32.le_u",
+        OpCode::I64Add => "i64.add",
+        OpCode::I64Sub => "i64.sub",
+        OpCode::I64Mul => "i64.mul",
+        OpCode::I64DivS => "i64.div_s",
+        OpCode::I64DivU => "i64.div_u",
+        OpCode::I64RemS => "i64.rem_s",
+        OpCode::I64RemU => "i64.rem_u",
+        OpCode::I64And => "i64.and",
+        OpCode::I64Or => "i64.or",
+        OpCode::I64Xor => "i64.xor",
+        OpCode::I64Shl => "i64.shl",
+        OpCode::I64ShrS => "i64.shr_s",
+        OpCode::I64ShrU => "i64.shr_u",
+        OpCode::I64Rotl => "i64.rotl",
+        OpCode::I64Rotr => "i64.rotr",
+        OpCode::I64Clz => "i64.clz",
+        OpCode::I64Ctz => "i64.ctz",
+        OpCode::I64Popcnt => "i64.popcnt",
+        OpCode::I64Eq => "i64.eq",
+        OpCode::I64Ne => "i64.ne",
+        OpCode::I64LtS => "i64.lt_s",
+        OpCode::I64LtU => "i64.lt_u",
+        OpCode::I64GtS => "i64.gt_s",
+        OpCode::I64GtU => "i64.gt_u",
+        OpCode::I64LeS => "i64.le_s",
+        OpCode::I64LeU => "i64.le_u",
+        OpCode::I64GeS => "i64.ge_s",
+        OpCode::I64GeU => "i64.ge_u",
+        OpCode::F32Add => "f32.add",
+        OpCode::F32Sub => "f32.sub",
+        OpCode::F32Mul => "f32.mul",
+        OpCode::F32Div => "f32.div",
+        OpCode::F32Eq => "f32.eq",
+        OpCode::F32Gt => "f32.gt",
+        OpCode::F32Ge => "f32.ge",
+        OpCode::F32Lt => "f32.lt",
+        OpCode::F32Le => "f32.le",
+        OpCode::F32Abs => "f32.abs",
+        OpCode::F32Neg => "f32.neg",
+        OpCode::F32Ceil => "f32.ceil",
+        OpCode::F32Floor => "f32.floor",
+        OpCode::F32Trunc => "f32.trunc",
+        OpCode::F32Nearest => "f32.nearest",
+        OpCode::F32Sqrt => "f32.sqrt",
+        OpCode::F32Min => "f32.min",
+        OpCode::F32Max => "f32.max",
+        OpCode::F32Copysign => "f32.copysign",
+        OpCode::F32ConvertI32S => "f32.convert_i32_s",
+        OpCode::F32ConvertI32U => "f32.convert_i32_u",
+        OpCode::F32ConvertI64S => "f32.convert_i64_s",
+        OpCode::F32ConvertI64U => "f32.convert_i64_u",
+        OpCode::I32WrapI64 => "i32.wrap_i64",
+        OpCode::I32TruncF32S => "i32.trunc_f32_s",
+        OpCode::I32TruncF32U => "i32.trunc_f32_u",
+        OpCode::I64ExtendI32S => "i64.extend_i32_s",
+        OpCode::I64ExtendI32U => "i64.extend_i32_u",
+        OpCode::I64TruncF32S => "i64.trunc_f32_s",
+        OpCode::I64TruncF32U => "i64.trunc_f32_u",
+        OpCode::I32TruncSatF32S => "i32.trunc_sat_f32_s",
+        OpCode::I32TruncSatF32U => "i32.trunc_sat_f32_u",
+        OpCode::I64TruncSatF32S => "i64.trunc_sat_f32_s",
+        OpCode::I64TruncSatF32U => "i64.trunc_sat_f32_u",
+        OpCode::I32ReinterpretF32 => "i32.reinterpret_f32",
+        OpCode::F32ReinterpretI32 => "f32.reinterpret_i32",
+    };
+    name.to_string()
+}
+
+fn type_list(keyword: &str, types: &[WasmPrimitiveType]) -> String {
+    if types.is_empty() {
+        return String::new();
+    }
+    let names = types.iter().map(|t| type_name(*t)).collect::<Vec<_>>();
+    format!(" ({} {})", keyword, names.join(" "))
+}
+
+fn global_value(value: &GlobalValue) -> String {
+    match value {
+        GlobalValue::I32(n) => format!("i32.const {}", n),
+        GlobalValue::I64(n) => format!("i64.const {}", n),
+        GlobalValue::F32(n) => format!("f32.const {:?}", n),
+    }
+}
+
+/// Prints a module in a WebAssembly text format-like listing, with everything referred
+/// to by index and each function body laid out flat, one instruction per line, indented
+/// by block depth.
+/// `data` as the contents of a string literal, with bytes outside of printable ASCII
+/// escaped in hex.
+fn data_string(data: &[u8]) -> String {
+    data.iter()
+        .map(|byte| match byte {
+            b'"' | b'\\' => format!("\\{}", *byte as char),
+            0x20..=0x7E => (*byte as char).to_string(),
+            _ => format!("\\{:02x}", byte),
+        })
+        .collect()
+}
+
+/// The text format of `module`, with `memory` if it has one.
+pub fn disassemble(module: &Module, memory: Option<&Memory>) -> String {
+    let mut out = String::new();
+    let mut signatures = module.signatures.iter().collect::<Vec<_>>();
+    signatures.sort_by_key(|(_, index)| **index);
+    let signatures = signatures
+        .into_iter()
+        .map(|(signature, _)| signature)
+        .collect::<Vec<_>>();
+
+    writeln!(out, "(module").unwrap();
+    for (index, signature) in signatures.iter().enumerate() {
+        writeln!(
+            out,
+            "  (type {} (func{}{}))",
+            index,
+            type_list("param", &signature.params),
+            type_list("result", &signature.results)
+        )
+        .unwrap();
+    }
+
+    let functions = module.functions.borrow();
+    let mut functions = functions.iter().collect::<Vec<_>>();
+    functions.sort_by_key(|(_, (index, _))| *index);
+    for (name, (index, func)) in &functions {
+        if let Some((module_name, field)) = &func.import {
+            writeln!(
+                out,
+                "  (import \"{}\" \"{}\" (func {} ;; {}\n    (type {})))",
+                module_name, field, index, name, func.signature_index
+            )
+            .unwrap();
+        }
+    }
+
+    if let Some(memory) = memory {
+        let mut limits = memory.initial.to_string();
+        if let Some(max) = memory.max {
+            write!(limits, " {}", max).unwrap();
+        }
+        if memory.shared {
+            limits.push_str(" shared");
+        }
+        if memory.import {
+            writeln!(out, "  (import \"env\" \"memory\" (memory 0 {}))", limits).unwrap();
+        } else {
+            writeln!(out, "  (memory 0 {})", limits).unwrap();
+        }
+    }
+
+    let globals = module.globals.borrow();
+    let mut globals = globals.iter().collect::<Vec<_>>();
+    globals.sort_by_key(|(_, (index, _))| *index);
+    for (name, (index, global)) in globals {
+        let t = type_name(match global.value {
+            GlobalValue::I32(_) => WasmPrimitiveType::I32,
+            GlobalValue::I64(_) => WasmPrimitiveType::I64,
+            GlobalValue::F32(_) => WasmPrimitiveType::F32,
+        });
+        let t = if global.is_mutable {
+            format!("(mut {})", t)
+        } else {
+            t.to_string()
+        };
+        writeln!(
+            out,
+            "  (global {} ;; {}\n    {} ({}))",
+            index,
+            name,
+            t,
+            global_value(&global.value)
+        )
+        .unwrap();
+    }
+
+    for export in &module.exports {
+        let kind = match export.export_type {
+            ExportKind::Func => "func",
+            ExportKind::Memory => "memory",
+            ExportKind::Global => "global",
+        };
+        writeln!(
+            out,
+            "  (export \"{}\" ({} {}))",
+            export.name, kind, export.index
+        )
+        .unwrap();
+    }
+
+    if let Some(start) = module.start {
+        writeln!(out, "  (start {})", start).unwrap();
+    }
+
+    for (name, (index, func)) in functions {
+        if func.import.is_some() {
+            continue;
+        }
+        let signature = signatures.get(func.signature_index as usize);
+        writeln!(
+            out,
+            "  (func {} ;; {}\n    (type {}){}{}",
+            index,
+            name,
+            func.signature_index,
+            signature.map_or(String::new(), |s| type_list("param", &s.params)),
+            signature.map_or(String::new(), |s| type_list("result", &s.results))
+        )
+        .unwrap();
+        let mut depth = 2;
+        for code in &func.body {
+            if matches!(code, OpCode::End | OpCode::Else) {
+                depth -= 1;
+            }
+            // The trailing `end` of the body is implied by the closing paren.
+            if depth > 1 {
+                write!(out, "{}{}", "  ".repeat(depth), instruction(code)).unwrap();
+                let local_name = match code {
+                    OpCode::LocalGet(i) | OpCode::LocalSet(i) | OpCode::LocalTee(i) => func
+                        .local_names
+                        .iter()
+                        .find(|(index, _)| index == i)
+                        .map(|(_, name)| name),
+                    _ => None,
+                };
+                match local_name {
+                    Some(name) => writeln!(out, " ;; {}", name).unwrap(),
+                    None => writeln!(out).unwrap(),
+                }
+            }
+            if matches!(code, OpCode::Loop(_) | OpCode::If(_) | OpCode::Else) {
+                depth += 1;
+            }
+        }
+        writeln!(out, "  )").unwrap();
+    }
+
+    if !module.data.is_empty() {
+        writeln!(
+            out,
+            "  (data (i32.const {}) \"{}\")",
+            memory.unwrap_or(&module.memory).stack_base,
+            data_string(&module.data)
+        )
+        .unwrap();
+    }
+    writeln!(out, ")").unwrap();
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::decoder::decode, *};
+
+    #[test]
+    fn test_disassemble() {
+        let module = &mut Module::default();
+        emit(
+            module,
+            "
+        (define scale: f32 2.5)
+        (export defn pick: f32 [a: i32 b: f32]
+            (assert (>= a 0) \"a is \\negative\")
+            (if (= a 0) scale (* b 2.0)))
+        ",
+        )
+        .unwrap();
+        assert_eq!(
+            disassemble(module, Some(&module.memory)),
+            "(module
+  (type 0 (func (param i32 f32) (result f32)))
+  (type 1 (func (param i32 i32 i32)))
+  (import \"env\" \"__wisp_panic\" (func 0 ;; __wisp_panic
+    (type 1)))
+  (memory 0 16)
+  (global 0 ;; __stack_pointer
+    (mut i32) (i32.const 16))
+  (global 1 ;; __heap_base
+    i32 (i32.const 65536))
+  (global 2 ;; scale
+    f32 (f32.const 2.5))
+  (export \"pick\" (func 1))
+  (export \"memory\" (memory 0))
+  (func 1 ;; pick
+    (type 0) (param i32 f32) (result f32)
+    local.get 0 ;; a
+    i32.const 0
+    i32.ge_s
+    if
+    else
+      i32.const 0
+      i32.const 14
+      i32.const 4
+      call 0
+      unreachable
+    end
+    local.get 0 ;; a
+    i32.const 0
+    i32.eq
+    if (result f32)
+      global.get 2
+    else
+      local.get 1 ;; b
+      f32.const 2.0
+      f32.mul
+    end
+  )
+  (data (i32.const 0) \"a is \\\\negative\")
+)
+"
+        );
+    }
+
+    #[test]
+    fn test_without_memory() {
+        let (module, memory) = decode(b"\0asm\x01\0\0\0\x01\x04\x01\x60\0\0").unwrap();
+        assert_eq!(disassemble(&module, memory.as_ref()), "(module\n  (type 0 (func))\n)\n");
+    }
+}
@@ -1,4 +1,4 @@
-use super::*;
+use super::{source_map::build_source_map, validator::validate, *};
 use std::io::Write;
 
 pub fn encode_leb128<N: Into<u64>>(writer: &mut impl Write, n: N) -> Result<usize, std::io::Error> {
@@ -19,7 +19,8 @@ pub fn encode_string(writer: &mut impl Write, name: &str) -> Result<usize, std::
 }
 
 use crate::emitter::{
-    emit, Export, ExportKind, Function, Module, OpCode, Signature, WasmPrimitiveType,
+    CompileOptions, Export, ExportKind, Function, Memory, Module, OpCode, Signature,
+    WasmPrimitiveType,
 };
 use anyhow::Result;
 use std::io::BufWriter;
@@ -27,6 +28,7 @@ use std::io::BufWriter;
 fn encode_global(writer: &mut impl Write, global: &Global) -> Result<()> {
     let primitive_type = match global.value {
         GlobalValue::I32(_) => WasmPrimitiveType::I32,
+        GlobalValue::I64(_) => WasmPrimitiveType::I64,
         GlobalValue::F32(_) => WasmPrimitiveType::F32,
     };
     writer.write(&[primitive_type as u8, if global.is_mutable { 1 } else { 0 }])?;
@@ -35,6 +37,10 @@ fn encode_global(writer: &mut impl Write, global: &Global) -> Result<()> {
             writer.write(&[0x41])?; // i32.const
             encode_s_leb128(writer, v as i32)?;
         }
+        GlobalValue::I64(v) => {
+            writer.write(&[0x42])?; // i64.const
+            encode_s_leb128(writer, v)?;
+        }
         GlobalValue::F32(v) => {
             writer.write(&[0x43])?; // f32.const
             writer.write(&v.to_le_bytes())?;
@@ -86,14 +92,19 @@ fn encode_export(writer: &mut impl Write, export: &Export) -> Result<()> {
     encode_string(writer, &export.name)?;
     writer.write(&[match export.export_type {
         ExportKind::Func => 0x00,
+        ExportKind::Memory => 0x02,
+        ExportKind::Global => 0x03,
     }])?;
-    encode_leb128(writer, export.func_index)?;
+    encode_leb128(writer, export.index)?;
     Ok(())
 }
 
-fn encode_function_body(writer: &mut impl Write, func: &Function) -> Result<()> {
-    let mut i32_locals: u8 = 0;
-    let mut f32_locals: u8 = 0;
+/// Encodes the body of `func` and returns where each source location starts, as pairs of
+/// a byte offset in `writer` and a source offset.
+fn encode_function_body(writer: &mut Vec<u8>, func: &Function) -> Result<Vec<(usize, u32)>> {
+    let mut i32_locals: u32 = 0;
+    let mut i64_locals: u32 = 0;
+    let mut f32_locals: u32 = 0;
     let mut opcodes = Vec::new();
     for opcode in &func.body {
         match opcode {
@@ -101,6 +112,9 @@ fn encode_function_body(writer: &mut impl Write, func: &Function) -> Result<()>
                 WasmPrimitiveType::I32 => {
                     i32_locals += 1;
                 }
+                WasmPrimitiveType::I64 => {
+                    i64_locals += 1;
+                }
                 WasmPrimitiveType::F32 => {
                     f32_locals += 1;
                 }
@@ -111,7 +125,7 @@ fn encode_function_body(writer: &mut impl Write, func: &Function) -> Result<()>
         }
     }
     // First, bundle local decls.
-    let local_decl_count = [i32_locals > 0, f32_locals > 0]
+    let local_decl_count = [i32_locals > 0, i64_locals > 0, f32_locals > 0]
         .iter()
         .filter(|x| **x)
         .count();
@@ -120,14 +134,29 @@ fn encode_function_body(writer: &mut impl Write, func: &Function) -> Result<()>
         encode_leb128(writer, i32_locals)?; // local type count
         writer.write(&[0x7f])?; // i32
     }
+    if i64_locals > 0 {
+        encode_leb128(writer, i64_locals)?; // local type count
+        writer.write(&[0x7e])?;
+    }
     if f32_locals > 0 {
         encode_leb128(writer, f32_locals)?; // local type count
         writer.write(&[0x7d])?;
     }
 
+    let mut locations: Vec<(usize, u32)> = Vec::new();
+    let mut source_offset = None;
     for opcode in &opcodes {
+        if let OpCode::SourceLoc(offset) = opcode {
+            source_offset = Some(*offset);
+            continue;
+        }
+        if let Some(offset) = source_offset.take() {
+            if locations.last().map(|(_, last)| *last) != Some(offset) {
+                locations.push((writer.len(), offset));
+            }
+        }
         match opcode {
-            OpCode::LocalDecl(_) => unreachable!(),
+            OpCode::LocalDecl(_) | OpCode::SourceLoc(_) => unreachable!(),
             OpCode::LocalGet(n) => {
                 writer.write(&[0x20])?;
                 encode_leb128(writer, *n)?;
@@ -168,6 +197,16 @@ fn encode_function_body(writer: &mut impl Write, func: &Function) -> Result<()>
                 encode_leb128(writer, *alignment)?;
                 encode_leb128(writer, *offset)?;
             }
+            OpCode::I64Load { offset, alignment } => {
+                writer.write(&[0x29])?;
+                encode_leb128(writer, *alignment)?;
+                encode_leb128(writer, *offset)?;
+            }
+            OpCode::I64Store { offset, alignment } => {
+                writer.write(&[0x37])?;
+                encode_leb128(writer, *alignment)?;
+                encode_leb128(writer, *offset)?;
+            }
             OpCode::F32Load { offset, alignment } => {
                 writer.write(&[0x2A])?;
                 encode_leb128(writer, *alignment)?;
@@ -186,10 +225,43 @@ fn encode_function_body(writer: &mut impl Write, func: &Function) -> Result<()>
                 writer.write(&[0x41])?;
                 encode_s_leb128(writer, *n)?;
             }
+            OpCode::I64Const(n) => {
+                writer.write(&[0x42])?;
+                encode_s_leb128(writer, *n)?;
+            }
             OpCode::Call(index) => {
                 writer.write(&[0x10])?;
                 encode_leb128(writer, *index)?;
             }
+            OpCode::I32TruncSatF32S => {
+                writer.write(&[0xFC, 0x00])?;
+            }
+            OpCode::I32TruncSatF32U => {
+                writer.write(&[0xFC, 0x01])?;
+            }
+            OpCode::I64TruncSatF32S => {
+                writer.write(&[0xFC, 0x04])?;
+            }
+            OpCode::I64TruncSatF32U => {
+                writer.write(&[0xFC, 0x05])?;
+            }
+            OpCode::ReturnCall(index) => {
+                writer.write(&[0x12])?;
+                encode_leb128(writer, *index)?;
+            }
+            OpCode::Br(depth) => {
+                writer.write(&[0x0C])?;
+                encode_leb128(writer, *depth)?;
+            }
+            OpCode::Loop(primitive_type) => {
+                writer.write(&[
+                    0x03,
+                    match *primitive_type {
+                        Some(pt) => pt as u8,
+                        None => 0x40,
+                    },
+                ])?;
+            }
             OpCode::If(primitive_type) => {
                 writer.write(&[
                     0x04,
@@ -202,8 +274,12 @@ fn encode_function_body(writer: &mut impl Write, func: &Function) -> Result<()>
             _ => {
                 writer.write(&[match opcode {
                     OpCode::If(_)
+                    | OpCode::Loop(_)
+                    | OpCode::Br(_)
+                    | OpCode::ReturnCall(_)
                     | OpCode::F32Const(_)
                     | OpCode::I32Const(_)
+                    | OpCode::I64Const(_)
                     | OpCode::LocalGet(_)
                     | OpCode::LocalSet(_)
                     | OpCode::LocalTee(_)
@@ -214,40 +290,114 @@ fn encode_function_body(writer: &mut impl Write, func: &Function) -> Result<()>
                     | OpCode::I32Store {offset: _, alignment: _}
                     | OpCode::I32Load8U { offset: _, alignment: _ }
                     | OpCode::I32Store8 { offset: _, alignment: _ }
+                    | OpCode::I64Load { offset: _, alignment: _ }
+                    | OpCode::I64Store { offset: _, alignment: _ }
                     | OpCode::F32Load {offset: _, alignment: _}
                     | OpCode::F32Store {offset: _, alignment: _}
-                    | OpCode::LocalDecl(_) => unreachable!(),
+                    | OpCode::I32TruncSatF32S
+                    | OpCode::I32TruncSatF32U
+                    | OpCode::I64TruncSatF32S
+                    | OpCode::I64TruncSatF32U
+                    | OpCode::LocalDecl(_)
+                    | OpCode::SourceLoc(_) => unreachable!(),
+                    OpCode::Unreachable => 0x00,
                     OpCode::Else => 0x05,
                     OpCode::Drop => 0x1A,
+                    OpCode::Select => 0x1B,
                     OpCode::End => 0x0B,
                     OpCode::I32Eq => 0x46,
+                    OpCode::I32Ne => 0x47,
                     OpCode::I32LtS => 0x48,
+                    OpCode::I32LtU => 0x49,
                     OpCode::I32GtS => 0x4A,
+                    OpCode::I32GtU => 0x4B,
                     OpCode::I32LeS => 0x4C,
+                    OpCode::I32LeU => 0x4D,
                     OpCode::I32GeS => 0x4E,
+                    OpCode::I32GeU => 0x4F,
+                    OpCode::I64Eq => 0x51,
+                    OpCode::I64Ne => 0x52,
+                    OpCode::I64LtS => 0x53,
+                    OpCode::I64LtU => 0x54,
+                    OpCode::I64GtS => 0x55,
+                    OpCode::I64GtU => 0x56,
+                    OpCode::I64LeS => 0x57,
+                    OpCode::I64LeU => 0x58,
+                    OpCode::I64GeS => 0x59,
+                    OpCode::I64GeU => 0x5A,
                     OpCode::F32Eq => 0x5B,
                     OpCode::F32Gt => 0x5E,
                     OpCode::F32Ge => 0x60,
                     OpCode::F32Lt => 0x5D,
                     OpCode::F32Le => 0x5F,
+                    OpCode::I32Clz => 0x67,
+                    OpCode::I32Ctz => 0x68,
+                    OpCode::I32Popcnt => 0x69,
                     OpCode::I32Add => 0x6A,
                     OpCode::I32Sub => 0x6B,
                     OpCode::I32Mul => 0x6C,
                     OpCode::I32DivS => 0x6D,
+                    OpCode::I32DivU => 0x6E,
+                    OpCode::I32RemS => 0x6F,
+                    OpCode::I32RemU => 0x70,
                     OpCode::I32And => 0x71,
                     OpCode::I32Or => 0x72,
                     OpCode::I32Xor => 0x73,
+                    OpCode::I32Shl => 0x74,
+                    OpCode::I32ShrS => 0x75,
+                    OpCode::I32ShrU => 0x76,
+                    OpCode::I32Rotl => 0x77,
+                    OpCode::I32Rotr => 0x78,
+                    OpCode::I64Clz => 0x79,
+                    OpCode::I64Ctz => 0x7A,
+                    OpCode::I64Popcnt => 0x7B,
+                    OpCode::I64Add => 0x7C,
+                    OpCode::I64Sub => 0x7D,
+                    OpCode::I64Mul => 0x7E,
+                    OpCode::I64DivS => 0x7F,
+                    OpCode::I64DivU => 0x80,
+                    OpCode::I64RemS => 0x81,
+                    OpCode::I64RemU => 0x82,
+                    OpCode::I64And => 0x83,
+                    OpCode::I64Or => 0x84,
+                    OpCode::I64Xor => 0x85,
+                    OpCode::I64Shl => 0x86,
+                    OpCode::I64ShrS => 0x87,
+                    OpCode::I64ShrU => 0x88,
+                    OpCode::I64Rotl => 0x89,
+                    OpCode::I64Rotr => 0x8A,
+                    OpCode::F32Abs => 0x8B,
                     OpCode::F32Neg => 0x8C,
+                    OpCode::F32Ceil => 0x8D,
+                    OpCode::F32Floor => 0x8E,
+                    OpCode::F32Trunc => 0x8F,
+                    OpCode::F32Nearest => 0x90,
+                    OpCode::F32Sqrt => 0x91,
                     OpCode::F32Add => 0x92,
                     OpCode::F32Sub => 0x93,
                     OpCode::F32Mul => 0x94,
                     OpCode::F32Div => 0x95,
+                    OpCode::F32Min => 0x96,
+                    OpCode::F32Max => 0x97,
+                    OpCode::F32Copysign => 0x98,
+                    OpCode::I32WrapI64 => 0xA7,
+                    OpCode::I32TruncF32S => 0xA8,
+                    OpCode::I32TruncF32U => 0xA9,
+                    OpCode::I64ExtendI32S => 0xAC,
+                    OpCode::I64ExtendI32U => 0xAD,
+                    OpCode::I64TruncF32S => 0xAE,
+                    OpCode::I64TruncF32U => 0xAF,
                     OpCode::F32ConvertI32S => 0xB2,
+                    OpCode::F32ConvertI32U => 0xB3,
+                    OpCode::F32ConvertI64S => 0xB4,
+                    OpCode::F32ConvertI64U => 0xB5,
+                    OpCode::I32ReinterpretF32 => 0xBC,
+                    OpCode::F32ReinterpretI32 => 0xBE,
                 }])?;
             }
         }
     }
-    Ok(())
+    Ok(locations)
 }
 
 fn encode_type_section(writer: &mut impl Write, signatures: Vec<&Signature>) -> Result<()> {
@@ -278,13 +428,52 @@ fn encode_function_section(writer: &mut impl Write, functions: &Vec<&Function>)
     Ok(())
 }
 
-fn encode_memory_section(writer: &mut impl Write) -> Result<()> {
+fn encode_memory_limits(writer: &mut impl Write, memory: &Memory) -> Result<()> {
+    let flags = match (memory.max, memory.shared) {
+        (None, _) => 0x00,
+        (Some(_), false) => 0x01,
+        (Some(_), true) => 0x03,
+    };
+    writer.write_all(&[flags])?;
+    encode_leb128(writer, memory.initial)?;
+    if let Some(max) = memory.max {
+        encode_leb128(writer, max)?;
+    }
+    Ok(())
+}
+
+fn encode_import_section(
+    writer: &mut impl Write,
+    functions: &[&Function],
+    memory: &Memory,
+) -> Result<()> {
+    writer.write_all(&[0x02])?; // section import: 2
+    let import_section = &mut Vec::new();
+    encode_leb128(import_section, (functions.len() + memory.import as usize) as u64)?; // num imports
+    for func in functions {
+        let (module_name, name) = func.import.as_ref().unwrap();
+        encode_string(import_section, module_name)?;
+        encode_string(import_section, name)?;
+        import_section.push(0x00); // import kind: function
+        encode_leb128(import_section, func.signature_index)?;
+    }
+    if memory.import {
+        encode_string(import_section, "env")?;
+        encode_string(import_section, "memory")?;
+        import_section.push(0x02); // import kind: memory
+        encode_memory_limits(import_section, memory)?;
+    }
+    encode_leb128(writer, import_section.len() as u64)?;
+    writer.write_all(import_section)?;
+    Ok(())
+}
+
+fn encode_memory_section(writer: &mut impl Write, memory: &Memory) -> Result<()> {
     writer.write(&[0x05])?;
     let memory_section = &mut Vec::new();
     let num_memories : u64 = 1;
     encode_leb128(memory_section, num_memories)?;
-    memory_section.push(0); // flags
-    memory_section.push(0x10); // initial
+    encode_memory_limits(memory_section, memory)?;
     encode_leb128(writer, memory_section.len() as u64)?;
     writer.write(memory_section)?;
     Ok(())
@@ -304,28 +493,190 @@ fn encode_export_section(writer: &mut impl Write, exports: &Vec<&Export>) -> Res
     Ok(())
 }
 
-fn encode_code_section(writer: &mut impl Write, functions: &Vec<&Function>) -> Result<()> {
+fn encode_start_section(writer: &mut impl Write, start: u32) -> Result<()> {
+    writer.write_all(&[0x08])?; // section start: 8
+    let mut start_section = Vec::new();
+    encode_leb128(&mut start_section, start)?;
+    encode_leb128(writer, start_section.len() as u64)?;
+    writer.write_all(&start_section)?;
+    Ok(())
+}
+
+/// Encodes the code section and returns the source locations of its instructions, as
+/// pairs of a byte offset from the start of the section and a source offset.
+fn encode_code_section(
+    writer: &mut impl Write,
+    functions: &Vec<&Function>,
+) -> Result<Vec<(usize, u32)>> {
     writer.write(&[0x0A])?; // section code: 10
     let mut code_section = Vec::new();
+    let mut locations = Vec::new();
     let num_functions = functions.len();
     encode_leb128(&mut code_section, num_functions as u64)?;
     for func in functions {
         let mut func_body_bytes = Vec::new();
-        encode_function_body(&mut func_body_bytes, func)?;
+        let body_locations = encode_function_body(&mut func_body_bytes, func)?;
         // write func body size
         encode_leb128(&mut code_section, func_body_bytes.len() as u64)?;
+        let body_start = code_section.len();
+        locations.extend(
+            body_locations
+                .into_iter()
+                .map(|(offset, source_offset)| (body_start + offset, source_offset)),
+        );
         code_section.write(&func_body_bytes)?;
     }
     let section_size = code_section.len();
-    encode_leb128(writer, section_size as u64)?;
+    let header_size = 1 + encode_leb128(writer, section_size as u64)?;
     writer.write(&code_section)?;
+    Ok(locations
+        .into_iter()
+        .map(|(offset, source_offset)| (header_size + offset, source_offset))
+        .collect())
+}
+
+/// Encodes `data` as a single segment placed at the bottom of the stack.
+fn encode_data_section(writer: &mut impl Write, memory: &Memory, data: &[u8]) -> Result<()> {
+    writer.write_all(&[0x0B])?; // section data: 11
+    let data_section = &mut Vec::new();
+    encode_leb128(data_section, 1u32)?; // num segments
+    data_section.push(0x00); // active segment of memory 0
+    data_section.push(0x41); // i32.const
+    encode_s_leb128(data_section, memory.stack_base as i32)?;
+    data_section.push(0x0B); // end
+    encode_leb128(data_section, data.len() as u64)?;
+    data_section.write_all(data)?;
+    encode_leb128(writer, data_section.len() as u64)?;
+    writer.write_all(data_section)?;
     Ok(())
 }
 
-pub fn compile_into_wasm<W: Write>(writer: &mut BufWriter<W>, source: &str) -> Result<()> {
-    let module = &mut Module::default();
-    emit(module, source).unwrap();
+fn encode_name_map(writer: &mut impl Write, names: &[(u32, &str)]) -> Result<()> {
+    encode_leb128(writer, names.len() as u64)?;
+    for (index, name) in names {
+        encode_leb128(writer, *index)?;
+        encode_string(writer, name)?;
+    }
+    Ok(())
+}
 
+fn encode_name_subsection(writer: &mut impl Write, id: u8, subsection: &[u8]) -> Result<()> {
+    writer.write_all(&[id])?;
+    encode_leb128(writer, subsection.len() as u64)?;
+    writer.write_all(subsection)?;
+    Ok(())
+}
+
+fn encode_name_section(writer: &mut impl Write, module: &Module) -> Result<()> {
+    writer.write_all(&[0x00])?; // section custom: 0
+    let name_section = &mut Vec::new();
+    encode_string(name_section, "name")?;
+
+    let module_funcs = module.functions.borrow();
+    let mut functions = module_funcs
+        .iter()
+        .map(|(name, (index, func))| (*index, name.as_str(), func))
+        .collect::<Vec<_>>();
+    functions.sort_by_key(|(index, _, _)| *index);
+
+    // Function names: 1
+    let function_names = functions
+        .iter()
+        .map(|(index, name, _)| (*index, *name))
+        .collect::<Vec<_>>();
+    let subsection = &mut Vec::new();
+    encode_name_map(subsection, &function_names)?;
+    encode_name_subsection(name_section, 0x01, subsection)?;
+
+    // Local names: 2
+    let subsection = &mut Vec::new();
+    encode_leb128(subsection, functions.len() as u64)?;
+    for (index, _, func) in &functions {
+        encode_leb128(subsection, *index)?;
+        let local_names = func
+            .local_names
+            .iter()
+            .map(|(index, name)| (*index, name.as_str()))
+            .collect::<Vec<_>>();
+        encode_name_map(subsection, &local_names)?;
+    }
+    encode_name_subsection(name_section, 0x02, subsection)?;
+
+    // Global names: 7
+    let module_globals = module.globals.borrow();
+    let mut global_names = module_globals
+        .iter()
+        .map(|(name, (index, _))| (*index, name.as_str()))
+        .collect::<Vec<_>>();
+    global_names.sort_by_key(|(index, _)| *index);
+    let subsection = &mut Vec::new();
+    encode_name_map(subsection, &global_names)?;
+    encode_name_subsection(name_section, 0x07, subsection)?;
+
+    encode_leb128(writer, name_section.len() as u64)?;
+    writer.write_all(name_section)?;
+    Ok(())
+}
+
+fn encode_source_mapping_url_section(writer: &mut impl Write, url: &str) -> Result<()> {
+    writer.write_all(&[0x00])?; // section custom: 0
+    let section = &mut Vec::new();
+    encode_string(section, "sourceMappingURL")?;
+    encode_string(section, url)?;
+    encode_leb128(writer, section.len() as u64)?;
+    writer.write_all(section)?;
+    Ok(())
+}
+
+/// Compiles `source`, read from `source_path`, resolving the files it requires relative
+/// to it.
+pub fn compile_into_wasm<W: Write>(
+    writer: &mut BufWriter<W>,
+    source: &str,
+    options: &CompileOptions,
+    source_path: &Path,
+) -> Result<()> {
+    let module = &mut Module::with_options(options.clone());
+    emit_with_path(module, source, source_path)?;
+    validate(module)?;
+    let mut bytes = Vec::new();
+    encode_module(&mut bytes, module, None)?;
+    writer.write_all(&bytes)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Compiles like `compile_into_wasm` with source locations recorded, and returns
+/// the source map of the module. `source_map_url` is written into a `sourceMappingURL`
+/// section for runtimes to find the map with.
+pub fn compile_with_source_map<W: Write>(
+    writer: &mut BufWriter<W>,
+    source: &str,
+    options: &CompileOptions,
+    source_path: &Path,
+    source_map_url: &str,
+) -> Result<String> {
+    let module = &mut Module::with_options(CompileOptions {
+        source_map: true,
+        ..options.clone()
+    });
+    emit_with_path(module, source, source_path)?;
+    validate(module)?;
+    let mut bytes = Vec::new();
+    let locations = encode_module(&mut bytes, module, Some(source_map_url))?;
+    writer.write_all(&bytes)?;
+    writer.flush()?;
+    let source_name = source_path.display().to_string();
+    Ok(build_source_map(&source_name, &module.source, &locations))
+}
+
+/// Encodes `module` and returns the source locations of its code, as pairs of a byte
+/// offset in `writer` and a source offset.
+fn encode_module(
+    writer: &mut Vec<u8>,
+    module: &Module,
+    source_map_url: Option<&str>,
+) -> Result<Vec<(usize, u32)>> {
     let mut signatures_with_index = module.signatures.iter().collect::<Vec<_>>();
     signatures_with_index.sort_by(|a, b| a.1.partial_cmp(b.1).unwrap());
     let signatures = signatures_with_index
@@ -335,25 +686,30 @@ pub fn compile_into_wasm<W: Write>(writer: &mut BufWriter<W>, source: &str) -> R
     let module_funcs = module.functions.borrow();
     let mut functions_with_index = module_funcs.iter().map(|x| x.1).collect::<Vec<_>>();
     functions_with_index.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
-    let functions = functions_with_index
+    // Imported functions have the lowest indices.
+    let (imports, functions): (Vec<_>, Vec<_>) = functions_with_index
         .iter()
         .map(|x| &x.1)
-        .collect::<Vec<_>>();
+        .partition(|func| func.import.is_some());
 
     writer.write(&[0x00, 0x61, 0x73, 0x6d])?; // WASM magic number
     writer.write(&[0x01, 0x00, 0x00, 0x00])?; // WASM binary version
 
     // Type section
     encode_type_section(writer, signatures)?;
-    writer.flush()?;
+
+    // Import section
+    if !imports.is_empty() || module.memory.import {
+        encode_import_section(writer, &imports, &module.memory)?;
+    }
 
     // Function section
     encode_function_section(writer, &functions)?;
-    writer.flush()?;
 
     // Memory section
-    encode_memory_section(writer)?;
-    writer.flush()?;
+    if !module.memory.import {
+        encode_memory_section(writer, &module.memory)?;
+    }
 
     // Global section
     let module_globals = module.globals.borrow();
@@ -361,20 +717,41 @@ pub fn compile_into_wasm<W: Write>(writer: &mut BufWriter<W>, source: &str) -> R
     globals_with_index.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
     let globals = globals_with_index.iter().map(|x| &x.1).collect::<Vec<_>>();
     encode_global_section(writer, &globals)?;
-    writer.flush()?;
 
     // Export section
     encode_export_section(
         writer,
         &module.exports.iter().map(|x| x).collect::<Vec<_>>(),
     )?;
-    writer.flush()?;
+
+    // Start section
+    if let Some(start) = module.start {
+        encode_start_section(writer, start)?;
+    }
 
     // Code section
-    encode_code_section(writer, &functions)?;
-    writer.flush()?;
+    let code_start = writer.len();
+    let locations = encode_code_section(writer, &functions)?;
 
-    Ok(())
+    // Data section
+    if !module.data.is_empty() {
+        encode_data_section(writer, &module.memory, &module.data)?;
+    }
+
+    // Name section
+    if module.options.name_section {
+        encode_name_section(writer, module)?;
+    }
+
+    // Source map URL section
+    if let Some(url) = source_map_url {
+        encode_source_mapping_url_section(writer, url)?;
+    }
+
+    Ok(locations
+        .into_iter()
+        .map(|(offset, source_offset)| (code_start + offset, source_offset))
+        .collect())
 }
 
 #[cfg(test)]
@@ -476,7 +853,9 @@ mod tests {
                 &mut writer,
                 "(defn calc : f32
                 [a : f32 b : i32]
-                  (* 10 (/ (+ a (- b 1)) 2))",
+                  (* 10 (/ (+ a (- b 1)) 2)))",
+                &CompileOptions::default(),
+                Path::new("calc.wisp"),
             )
             .unwrap();
         }
@@ -504,16 +883,25 @@ mod tests {
                 0x00, // flag
                 0x10, // initial size
                 0x06, // global section
-                0x09, // section size
-                0x01, // num globals
+                0x0D, // section size
+                0x02, // num globals
                 0x7f, // i32,
                 0x01, // mutable
                 0x41, // i32.const
-                0x80, 0x80, 0xc0, 0x00, // stack pointer value,
+                0x00, // stack pointer value,
+                0x0b, // end
+                0x7f, // i32,
+                0x00, // immutable
+                0x41, // i32.const
+                0x80, 0x80, 0x04, // heap base value,
                 0x0b, // end
                 0x07, // export section
-                0x01, // section size,
-                0x00, // num exports
+                0x0A, // section size,
+                0x01, // num exports
+                0x06, // string length
+                0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, // "memory"
+                0x02, // export kind: memory
+                0x00, // memory index
                 0x0A, // code section
                 0x15, // section size
                 0x01, // num functions
@@ -532,6 +920,27 @@ mod tests {
                 0x95, // f32.div
                 0x94, // f32.mul
                 0x0B, // END
+                0x00, // custom section
+                0x3A, // section size
+                0x04, b'n', b'a', b'm', b'e', // section name
+                0x01, // function names
+                0x07, // subsection size
+                0x01, // num names
+                0x00, 0x04, b'c', b'a', b'l', b'c', // 0: calc
+                0x02, // local names
+                0x09, // subsection size
+                0x01, // num functions
+                0x00, // function index
+                0x02, // num names
+                0x00, 0x01, b'a', // 0: a
+                0x01, 0x01, b'b', // 1: b
+                0x07, // global names
+                0x1F, // subsection size
+                0x02, // num names
+                0x00, 0x0F, // 0: __stack_pointer
+                b'_', b'_', b's', b't', b'a', b'c', b'k', b'_', b'p', b'o', b'i', b'n', b't', b'e', b'r',
+                0x01, 0x0B, // 1: __heap_base
+                b'_', b'_', b'h', b'e', b'a', b'p', b'_', b'b', b'a', b's', b'e',
             ]
         );
     }
@@ -1,8 +1,40 @@
-use super::{*, special_forms::{emit_if, emit_let}, intrinsic_ops::emit_intrinsic_exp, vector::*};
-use crate::{env::Env, parser::AST, resolver::Type};
-use anyhow::{bail, Result, Context};
+use super::{*, special_forms::{emit_if, emit_let}, intrinsic_ops::{emit_float_intrinsic, emit_intrinsic_exp}, vector::*, conversion::emit_conversion, constant::eval_const, inline::{emit_inlined_call, should_inline}};
+use crate::{diagnostic::Diagnostic, env::Env, parser::AST, resolver::Type};
+use anyhow::{bail, ensure, Context, Result};
 use std::{cell::RefCell, rc::Rc};
 
+pub(super) fn intrinsic_operator(ast: &AST) -> Option<IntrinsicOperator> {
+    Some(match ast {
+        AST::Add => IntrinsicOperator::Add,
+        AST::Sub => IntrinsicOperator::Sub,
+        AST::Mul => IntrinsicOperator::Mul,
+        AST::Div => IntrinsicOperator::Div,
+        AST::Eq => IntrinsicOperator::Eq,
+        AST::Gt => IntrinsicOperator::Gt,
+        AST::Ge => IntrinsicOperator::Ge,
+        AST::Lt => IntrinsicOperator::Lt,
+        AST::Le => IntrinsicOperator::Le,
+        AST::And => IntrinsicOperator::And,
+        AST::Or => IntrinsicOperator::Or,
+        AST::Not => IntrinsicOperator::Not,
+        AST::Mod => IntrinsicOperator::Mod,
+        AST::Rem => IntrinsicOperator::Rem,
+        AST::BitAnd => IntrinsicOperator::BitAnd,
+        AST::BitOr => IntrinsicOperator::BitOr,
+        AST::BitXor => IntrinsicOperator::BitXor,
+        AST::BitNot => IntrinsicOperator::BitNot,
+        AST::Shl => IntrinsicOperator::Shl,
+        AST::Shr => IntrinsicOperator::Shr,
+        AST::UShr => IntrinsicOperator::UShr,
+        AST::Rotl => IntrinsicOperator::Rotl,
+        AST::Rotr => IntrinsicOperator::Rotr,
+        AST::Clz => IntrinsicOperator::Clz,
+        AST::Ctz => IntrinsicOperator::Ctz,
+        AST::Popcnt => IntrinsicOperator::Popcnt,
+        _ => return None,
+    })
+}
+
 pub(super) fn emit_list(
     module: &mut Module,
     codes: &mut Vec<OpCode>,
@@ -25,34 +57,49 @@ pub(super) fn emit_list(
                 | AST::Le
                 | AST::And
                 | AST::Or
-                | AST::Not => {
-                    let op = match first {
-                        AST::Add => IntrinsicOperator::Add,
-                        AST::Sub => IntrinsicOperator::Sub,
-                        AST::Mul => IntrinsicOperator::Mul,
-                        AST::Div => IntrinsicOperator::Div,
-                        AST::Eq => IntrinsicOperator::Eq,
-                        AST::Gt => IntrinsicOperator::Gt,
-                        AST::Ge => IntrinsicOperator::Ge,
-                        AST::Lt => IntrinsicOperator::Lt,
-                        AST::Le => IntrinsicOperator::Le,
-                        AST::And => IntrinsicOperator::And,
-                        AST::Or => IntrinsicOperator::Or,
-                        AST::Not => IntrinsicOperator::Not,
-                        _ => unreachable!(),
-                    };
+                | AST::Not
+                | AST::Mod
+                | AST::Rem
+                | AST::BitAnd
+                | AST::BitOr
+                | AST::BitXor
+                | AST::BitNot
+                | AST::Shl
+                | AST::Shr
+                | AST::UShr
+                | AST::Rotl
+                | AST::Rotr
+                | AST::Clz
+                | AST::Ctz
+                | AST::Popcnt => {
+                    let op = intrinsic_operator(first).unwrap();
                     emit_intrinsic_exp(module, op, codes, &list[1..], env)?
                 }
                 AST::Symbol(name) => {
                     match *name {
                         "let" => emit_let(module, codes, ast, env)?,
                         "if" => emit_if(module, codes, ast, env)?,
+                        "as" | "trunc-as" | "reinterpret" => {
+                            emit_conversion(module, codes, name, &list[1..], env)?
+                        }
+                        "len" => emit_len(module, codes, &list[1..], env)?,
+                        "get" => emit_get(module, codes, &list[1..], env)?,
+                        "set-at!" => emit_set_at(module, codes, &list[1..], env)?,
+                        "panic" => panic::emit_panic(module, codes, ast)?,
+                        "assert" => panic::emit_assert(module, codes, ast, env)?,
+                        "assert=" => testing::emit_assert_eq(module, codes, ast, env)?,
+                        _ if IntrinsicFunction::from_name(name).is_some() => {
+                            let func = IntrinsicFunction::from_name(name).unwrap();
+                            emit_float_intrinsic(module, func, codes, &list[1..], env)?
+                        }
                         _ => {
                             // emit function call
                             let module_functions = module.functions.clone();
-                            let module_func_refmut = module_functions.borrow_mut();
-                            let (index, func) = module_func_refmut
-                                .get(*name)
+                            let module_funcs = module_functions.borrow();
+                            let qualified_name = env.borrow().qualify(name);
+                            let (index, func) = module_funcs
+                                .get(&qualified_name)
+                                .or_else(|| module_funcs.get(*name))
                                 .with_context(|| format!("Unable to find function {:?}", &name))?;
                             emit_function_call(module, codes, *index as u32, func, &list[1..], env)?
                         }
@@ -63,6 +110,8 @@ pub(super) fn emit_list(
                     emit_index_get(module, codes, index, &list[1], env)?
                 }
                 AST::Module(_)
+                | AST::StringLiteral(_)
+                | AST::Keyword(_)
                 | AST::BoolLiteral(_)
                 | AST::SymbolWithAnnotation(_, _)
                 | AST::List(_)
@@ -83,33 +132,135 @@ pub(super) fn emit_function_call(
     args: &[AST],
     env: Rc<RefCell<Env>>,
 ) -> Result<Rc<Type>> {
-    for arg in args {
-        emit_obj(module, codes, arg, env.clone())?;
+    ensure!(
+        args.len() == func.arg_types.len(),
+        "expected {} arguments, found {}",
+        func.arg_types.len(),
+        args.len()
+    );
+    for (arg, param_type) in args.iter().zip(&func.arg_types) {
+        if let Some(opcode) = integer_literal_as(arg, param_type) {
+            codes.push(opcode);
+            continue;
+        }
+        let arg_type = emit_obj(module, codes, arg, env.clone())?;
+        match (&*arg_type, &**param_type) {
+            (a, p) if a == p => (),
+            (Type::I32, Type::F32) => codes.push(OpCode::F32ConvertI32S),
+            _ => bail!(
+                "mismatched argument type. expected {}, found {}",
+                param_type,
+                arg_type
+            ),
+        }
+    }
+    if should_inline(&module.options, index, func) {
+        emit_inlined_call(codes, func, &env);
+    } else {
+        codes.push(OpCode::Call(index));
     }
-    codes.push(OpCode::Call(index as u32));
     Ok(func.result_type.clone())
 }
 
+/// Splits a number literal into its digits and type suffix, e.g. `10u32` into `10` and `u32`.
+pub(super) fn split_number_suffix(literal: &str) -> (&str, &str) {
+    match literal.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(i) => (&literal[..i], &literal[i..]),
+        None => (literal, ""),
+    }
+}
+
+pub(super) fn parse_number_literal(literal: &str) -> Result<(OpCode, Type)> {
+    let (digits, suffix) = split_number_suffix(literal);
+    Ok(match suffix {
+        "" => {
+            if let Ok(i32_val) = digits.parse::<i32>() {
+                (OpCode::I32Const(i32_val), Type::I32)
+            } else if let Ok(f32_val) = digits.parse::<f32>() {
+                (OpCode::F32Const(f32_val), Type::F32)
+            } else {
+                bail!("Failed to parse number");
+            }
+        }
+        "i32" => (OpCode::I32Const(digits.parse::<i32>()?), Type::I32),
+        "u32" => (OpCode::I32Const(digits.parse::<u32>()? as i32), Type::U32),
+        "i64" => (OpCode::I64Const(digits.parse::<i64>()?), Type::I64),
+        "u64" => (OpCode::I64Const(digits.parse::<u64>()? as i64), Type::U64),
+        "f32" => (OpCode::F32Const(digits.parse::<f32>()?), Type::F32),
+        _ => bail!("unknown number suffix {:?} in {}", suffix, literal),
+    })
+}
+
+/// Returns the constant for `ast` as an integer of type `t` when `ast` is an integer
+/// literal without suffix whose value fits in `t`.
+pub(super) fn integer_literal_as(ast: &AST, t: &Type) -> Option<OpCode> {
+    let n = match ast {
+        AST::NumberLiteral(literal) if split_number_suffix(literal).1.is_empty() => {
+            literal.parse::<i64>().ok()?
+        }
+        _ => return None,
+    };
+    match t {
+        Type::I32 => i32::try_from(n).ok().map(OpCode::I32Const),
+        Type::U32 => u32::try_from(n).ok().map(|n| OpCode::I32Const(n as i32)),
+        Type::I64 => Some(OpCode::I64Const(n)),
+        Type::U64 => u64::try_from(n).ok().map(|n| OpCode::I64Const(n as i64)),
+        _ => None,
+    }
+}
+
 pub(super) fn emit_obj(
     module: &mut Module,
     codes: &mut Vec<OpCode>,
     ast: &AST,
     env: Rc<RefCell<Env>>,
 ) -> Result<Rc<Type>> {
+    let recovered = module.recovered;
+    let error = match emit_form(module, codes, ast, env) {
+        Ok(t) => return Ok(t),
+        // Errors are located at the innermost form they come from.
+        Err(error) => Diagnostic::locate(error, module.spans.get(ast)),
+    };
+    if !recovers(module) {
+        return Err(error);
+    }
+    recover(module, error, recovered);
+    codes.push(OpCode::Unreachable);
+    Ok(Rc::new(Type::Error))
+}
+
+fn emit_form(
+    module: &mut Module,
+    codes: &mut Vec<OpCode>,
+    ast: &AST,
+    env: Rc<RefCell<Env>>,
+) -> Result<Rc<Type>> {
+    if module.options.opt_level > 0 {
+        if let Some(value) = eval_const(module, ast, &env) {
+            codes.push(value.opcode());
+            return Ok(Rc::new(value.get_type()));
+        }
+    }
     match ast {
-        AST::List(_) => return emit_list(module, codes, ast, env),
+        AST::List(_) => {
+            let source_loc = source_map::source_loc(module, ast);
+            if source_loc.is_none() {
+                return emit_list(module, codes, ast, env);
+            }
+            let outer_source_loc = std::mem::replace(&mut module.source_loc, source_loc.clone());
+            codes.extend(source_loc);
+            let t = emit_list(module, codes, ast, env);
+            // What follows belongs to the enclosing form again.
+            codes.extend(outer_source_loc.clone());
+            module.source_loc = outer_source_loc;
+            t
+        }
         AST::Vector(v) => emit_vector(module, codes, v, env),
         // TODO: Infer type
         AST::NumberLiteral(literal) => {
-            if let Ok(i32_val) = literal.parse::<i32>() {
-                codes.push(OpCode::I32Const(i32_val));
-                return Ok(Rc::new(Type::I32));
-            } else if let Ok(f32_val) = literal.parse::<f32>() {
-                codes.push(OpCode::F32Const(f32_val));
-                return Ok(Rc::new(Type::F32));
-            } else {
-                bail!("Failed to parse number");
-            }
+            let (opcode, t) = parse_number_literal(literal)?;
+            codes.push(opcode);
+            Ok(Rc::new(t))
         }
         AST::BoolLiteral(b) => {
             codes.push(OpCode::I32Const(if *b { 1 } else { 0 }));
@@ -117,16 +268,23 @@ pub(super) fn emit_obj(
         }
         AST::Symbol(name) => match (*env.clone()).borrow().get(name) {
             None => bail!("Symbol {} not found in this scope", name),
-            Some(variable) => match variable.pointer {
-                Pointer::Local(index) => {
-                    codes.push(OpCode::LocalGet(index));
-                    return Ok(variable.t.clone());
+            Some(variable) => {
+                // The errors caused by a value of unknown type follow from the one that
+                // left it unknown.
+                if *variable.t == Type::Error {
+                    module.recovered += 1;
+                }
+                if module.options.symbol_types {
+                    if let Some(span) = module.spans.get(ast) {
+                        module.symbol_types.push((span, variable.t.clone()));
+                    }
                 }
-                Pointer::Global(index) => {
-                    codes.push(OpCode::GlobalGet(index));
-                    return Ok(variable.t.clone())
+                match variable.pointer {
+                    Pointer::Local(index) => codes.push(OpCode::LocalGet(index)),
+                    Pointer::Global(index) => codes.push(OpCode::GlobalGet(index)),
                 }
-            },
+                Ok(variable.t.clone())
+            }
         },
         _ => bail!("Cannot evaluate {:?}", ast)
     }
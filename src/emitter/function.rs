@@ -3,145 +3,294 @@ use crate::{env::Env, parser::AST, resolver::Type};
 use anyhow::{bail, ensure, Result};
 use std::{cell::RefCell, rc::Rc};
 
-pub(super) fn emit_func(module: &mut Module, ast: &AST, env: Rc<RefCell<Env>>) -> Result<()> {
-    if let AST::List(func_list) = ast {
-        let mut slice = &func_list[..];
-        let (is_export, name, result_type_ast, args, forms) = match func_list[0] {
-            AST::Symbol(s) => {
-                let is_export = if s == "export" {
-                    ensure!(
-                        slice[1] == AST::Symbol("defn"),
-                        "Failed to compile function. 'defn' is expected after 'export'"
-                    );
-                    slice = &slice[2..];
-                    true
-                } else {
-                    ensure!(
-                        s == "defn",
-                        "Failed to compile function. func list must start with 'export' or 'defn'"
-                    );
-                    slice = &slice[1..];
-                    false
-                };
-                let (name, type_ast) = match &slice[0] {
-                    AST::SymbolWithAnnotation(s, type_ast) => (*s, type_ast),
-                    AST::Symbol(s) => (*s, &TypeAST::Unit),
-                    _ => bail!("A symbol with type annotaion is expected after 'defn'"),
-                };
-                let mut args = Vec::new();
-                match &slice[1] {
-                    AST::Vector(list) => {
-                        for arg in list {
-                            args.push(match arg {
-                                AST::SymbolWithAnnotation(name, type_ast) => (*name, type_ast),
-                                _ => {
-                                    bail!("Function argument should be a symbol annotated with ':'")
-                                }
-                            });
-                        }
+struct FuncHeader<'a> {
+    is_export: bool,
+    is_inline: bool,
+    name: &'a str,
+    result_type_ast: &'a TypeAST,
+    args: Vec<(&'a str, &'a TypeAST)>,
+    forms: &'a [AST<'a>],
+}
+
+fn parse_func_header<'a>(ast: &'a AST<'a>) -> Result<FuncHeader<'a>> {
+    let func_list = match ast {
+        AST::List(func_list) => func_list,
+        _ => bail!("Invalid argument."),
+    };
+    let mut slice = &func_list[..];
+    match func_list.first() {
+        Some(AST::Symbol(s)) => {
+            let is_export = if *s == "export" {
+                ensure!(
+                    slice.get(1) == Some(&AST::Symbol("defn")),
+                    "Failed to compile function. 'defn' is expected after 'export'"
+                );
+                slice = &slice[2..];
+                true
+            } else {
+                ensure!(
+                    *s == "defn",
+                    "Failed to compile function. func list must start with 'export' or 'defn'"
+                );
+                slice = &slice[1..];
+                false
+            };
+            let (name, type_ast) = match slice.first() {
+                Some(AST::SymbolWithAnnotation(s, type_ast)) => (*s, type_ast),
+                Some(AST::Symbol(s)) => (*s, &TypeAST::Unit),
+                _ => bail!("A symbol with type annotaion is expected after 'defn'"),
+            };
+            let mut args = Vec::new();
+            match slice.get(1) {
+                Some(AST::Vector(list)) => {
+                    for arg in list {
+                        args.push(match arg {
+                            AST::SymbolWithAnnotation(name, type_ast) => (*name, type_ast),
+                            _ => {
+                                bail!("Function argument should be a symbol annotated with ':'")
+                            }
+                        });
                     }
-                    _ => bail!("Function args vector is required after 'defn'"),
-                };
-                let forms = Vec::from(&slice[2..]);
-                (is_export, name, type_ast, args, forms)
-            }
-            _ => todo!(),
-        };
-
-        // TODO: Impl type symbol functionality
-        let empty_type_env = TypeEnv::default();
-
-        let func_index = (*module.functions.clone()).borrow().len() as u32;
-        let new_env = Rc::new(RefCell::new(Env::extend(env.clone())));
-        let mut local_index = 0;
-        for arg in &args {
-            new_env.borrow_mut().set(
-                arg.0,
-                Variable {
-                    pointer: Pointer::Local(local_index),
-                    t: resolve_type(arg.1, &empty_type_env)?,
-                },
-            );
-            local_index += 1;
+                }
+                _ => bail!("Function args vector is required after 'defn'"),
+            };
+            let mut forms = &slice[2..];
+            let is_inline = match forms.first() {
+                Some(AST::List(attribute)) if attribute[..] == [AST::Symbol("inline")] => {
+                    forms = &forms[1..];
+                    true
+                }
+                _ => false,
+            };
+            ensure!(!forms.is_empty(), "function {} has no body", name);
+            Ok(FuncHeader {
+                is_export,
+                is_inline,
+                name,
+                result_type_ast: type_ast,
+                args,
+                forms,
+            })
         }
+        _ => bail!("Failed to compile function. func list must start with 'export' or 'defn'"),
+    }
+}
+
+/// Registers a function with an empty body, so that it can be called before its
+/// definition is emitted.
+pub(super) fn declare_func(module: &mut Module, ast: &AST, env: &Rc<RefCell<Env>>) -> Result<()> {
+    let header = parse_func_header(ast)?;
+    // Calls to these names always go to the intrinsic.
+    ensure!(
+        IntrinsicFunction::from_name(header.name).is_none(),
+        "{} is an intrinsic function and cannot be redefined",
+        header.name
+    );
+    let name = env.borrow().qualify(header.name);
+    ensure!(
+        !module.functions.borrow().contains_key(&name),
+        "redefinition of function {}",
+        name
+    );
 
-        // Resolve arg types and func return type
-        let arg_types = args
+    // TODO: Impl type symbol functionality
+    let empty_type_env = TypeEnv::default();
+
+    let func_index = (*module.functions.clone()).borrow().len() as u32;
+    let arg_types = header
+        .args
+        .iter()
+        .map(|(_, type_ast)| resolve_type(type_ast, &empty_type_env))
+        .collect::<Result<Vec<Rc<Type>>>>()?;
+    let result_type = resolve_type(header.result_type_ast, &empty_type_env)?;
+
+    let signature = Signature {
+        sig_type: SignatureType::Func,
+        params: arg_types
             .iter()
-            .map(|(_, type_ast)| resolve_type(type_ast, &empty_type_env))
-            .collect::<Result<Vec<Rc<Type>>>>()?;
-        let result_type = resolve_type(result_type_ast, &empty_type_env)?;
+            .flat_map(|types| get_primitive_types(types.clone()).into_iter().flatten())
+            .collect::<Vec<_>>(),
+        results: get_primitive_types(result_type.clone())
+            .into_iter()
+            .flatten()
+            .collect(),
+    };
+    let signature_index = signature_index(module, signature);
 
-        let mut func_body = Vec::new();
+    module.functions.borrow_mut().insert(
+        name,
+        (
+            func_index,
+            Function {
+                arg_types,
+                result_type,
+                signature_index: signature_index as u32,
+                body: Vec::new(),
+                inline: header.is_inline,
+                local_names: Vec::new(),
+                import: None,
+            },
+        ),
+    );
+    Ok(())
+}
 
-        let scope_result_type = emit_scope(module, &mut func_body, &forms, new_env)?;
+/// The index of `signature` in the type section, added to it if needed.
+pub(super) fn signature_index(module: &mut Module, signature: Signature) -> u16 {
+    match module.signatures.get(&signature) {
+        Some(index) => *index,
+        None => {
+            let index = module.signatures.len() as u16;
+            module.signatures.insert(signature, index);
+            index
+        }
+    }
+}
 
-        if *result_type == Type::Unit {
-            let stack_cnt = get_primitive_types(scope_result_type).len();
-            // Drop unused result
-            for _ in 0..stack_cnt {
-                func_body.push(OpCode::Drop);
+/// The names of the locals of a function, renumbered as `locals::allocate_locals`
+/// returned. A slot shared by several locals keeps the name of the first one.
+pub(super) fn allocated_local_names(env: &Env, new_indices: &[Option<u32>]) -> Vec<(u32, String)> {
+    let mut local_names = Vec::<(u32, String)>::new();
+    for (index, local_name) in env.local_names() {
+        if let Some(Some(index)) = new_indices.get(index as usize) {
+            if local_names.iter().all(|(i, _)| i != index) {
+                local_names.push((*index, local_name));
             }
-        } else if *scope_result_type != *result_type {
-            // Validate return type
-            bail!(
-                "mismatched return type. Expected `{:?}`, but found `{:?}`",
-                result_type_ast,
-                scope_result_type,
-            )
         }
+    }
+    local_names.sort_by_key(|(index, _)| *index);
+    local_names
+}
 
-        func_body.push(OpCode::End);
-
-        let signature = Signature {
+/// Adds a function without parameters or results, such as the start function, whose
+/// `body` was emitted in `env`, and returns its index.
+pub(super) fn add_generated_func(module: &mut Module, name: String, env: &Env, mut body: Vec<OpCode>) -> u32 {
+    if module.options.opt_level > 0 {
+        body = peephole::optimize(body, peephole::DEFAULT_RULES);
+    }
+    let new_indices = locals::allocate_locals(&mut body, 0, module.options.opt_level > 0);
+    let local_names = allocated_local_names(env, &new_indices);
+    let signature_index = signature_index(
+        module,
+        Signature {
             sig_type: SignatureType::Func,
-            params: arg_types
-                .iter()
-                .flat_map(|types| {
-                    get_primitive_types(types.clone())
-                        .iter()
-                        .filter(|x| x.is_some())
-                        .map(|x| x.unwrap())
-                        .collect::<Vec<_>>()
-                })
-                .collect::<Vec<_>>(),
-            results: get_primitive_types(resolve_type(result_type_ast, &empty_type_env)?)
-                .iter()
-                .filter(|x| x.is_some())
-                .map(|x| x.unwrap())
-                .collect(),
-        };
-        let signature_index = match module.signatures.get(&signature) {
-            Some(index) => *index,
-            None => {
-                let index = module.signatures.len() as u16;
-                module.signatures.insert(signature.clone(), index);
-                index
-            }
-        };
-
-        module.functions.borrow_mut().insert(
-            name.to_string(),
-            (
-                func_index,
-                Function {
-                    arg_types,
-                    result_type,
-                    signature_index: signature_index as u32,
-                    body: func_body,
-                },
-            ),
+            params: Vec::new(),
+            results: Vec::new(),
+        },
+    );
+
+    let mut functions = module.functions.borrow_mut();
+    let index = functions.len() as u32;
+    functions.insert(
+        name,
+        (
+            index,
+            Function {
+                signature_index: signature_index as u32,
+                arg_types: Vec::new(),
+                result_type: Rc::new(Type::Unit),
+                body,
+                inline: false,
+                local_names,
+                import: None,
+            },
+        ),
+    );
+    index
+}
+
+/// Emits the body of a function registered by `declare_func`.
+pub(super) fn emit_func(module: &mut Module, ast: &AST, env: Rc<RefCell<Env>>) -> Result<()> {
+    let FuncHeader {
+        is_export,
+        name,
+        result_type_ast,
+        args,
+        forms,
+        ..
+    } = parse_func_header(ast)?;
+    let qualified_name = env.borrow().qualify(name);
+    let (func_index, arg_types, result_type) = {
+        let functions = module.functions.borrow();
+        let (index, func) = &functions[&qualified_name];
+        (*index, func.arg_types.clone(), func.result_type.clone())
+    };
+
+    let new_env = Rc::new(RefCell::new(Env::extend_function(env.clone())));
+    for (arg, t) in args.iter().zip(&arg_types) {
+        let local_index = new_env.borrow().new_local();
+        new_env.borrow_mut().set(
+            arg.0,
+            Variable {
+                pointer: Pointer::Local(local_index),
+                t: t.clone(),
+            },
         );
+    }
 
-        if is_export {
-            module.exports.push(Export {
-                export_type: ExportKind::Func,
-                name: name.to_string(),
-                func_index: func_index as u32,
-            });
+    module.source_loc = source_map::source_loc(module, ast);
+    let mut func_body = Vec::from_iter(module.source_loc.clone());
+
+    let scope_result_type = emit_scope(module, &mut func_body, forms, new_env.clone())?;
+
+    if *result_type == Type::Unit {
+        let stack_cnt = get_primitive_types(scope_result_type)
+            .iter()
+            .filter(|x| x.is_some())
+            .count();
+        // Drop unused result
+        for _ in 0..stack_cnt {
+            func_body.push(OpCode::Drop);
         }
-    } else {
-        bail!("Invalid argument.");
+    } else if *scope_result_type != *result_type && *scope_result_type != Type::Never {
+        // Validate return type
+        bail!(
+            "mismatched return type. Expected `{:?}`, but found `{:?}`",
+            result_type_ast,
+            scope_result_type,
+        )
+    }
+
+    func_body.push(OpCode::End);
+    module.source_loc = None;
+
+    tail_call::optimize_tail_calls(
+        &mut func_body,
+        func_index,
+        args.len() as u32,
+        get_primitive_types(result_type.clone())
+            .first()
+            .copied()
+            .flatten(),
+        &module.options,
+    );
+    if module.options.opt_level > 0 {
+        func_body = peephole::optimize(func_body, peephole::DEFAULT_RULES);
+    }
+    let new_indices = locals::allocate_locals(
+        &mut func_body,
+        args.len() as u32,
+        module.options.opt_level > 0,
+    );
+    let local_names = allocated_local_names(&new_env.borrow(), &new_indices);
+
+    let mut functions = module.functions.borrow_mut();
+    let func = &mut functions.get_mut(&qualified_name).unwrap().1;
+    func.body = func_body;
+    func.local_names = local_names;
+
+    // Exports keep the unqualified name.
+    if is_export {
+        ensure!(
+            module.exports.iter().all(|export| export.name != name),
+            "duplicate export {}",
+            name
+        );
+        module.exports.push(Export {
+            export_type: ExportKind::Func,
+            name: name.to_string(),
+            index: func_index,
+        });
     }
     Ok(())
 }
@@ -218,11 +367,31 @@ mod tests {
         .unwrap();
         assert_eq!(
             module.exports,
-            vec![Export {
-                export_type: ExportKind::Func,
-                func_index: 0,
-                name: "addTwo".to_string()
-            }]
+            vec![
+                Export {
+                    export_type: ExportKind::Func,
+                    index: 0,
+                    name: "addTwo".to_string()
+                },
+                Export {
+                    export_type: ExportKind::Memory,
+                    index: 0,
+                    name: "memory".to_string()
+                }
+            ]
+        )
+    }
+    #[test]
+    fn test_intrinsic_name() {
+        let module = &mut Module::default();
+        let error = emit(
+            module,
+            "(defn min: i32 [a: i32 b: i32] (if (< a b) a b))",
         )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "min is an intrinsic function and cannot be redefined"
+        );
     }
 }
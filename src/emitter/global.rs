@@ -1,12 +1,28 @@
-use super::*;
+use super::{
+    constant::{eval_const, ConstValue},
+    expression::integer_literal_as,
+    *,
+};
 use crate::{env::Env, parser::AST, resolver::Type};
-use anyhow::{bail, ensure, Context, Result};
+use anyhow::{bail, ensure, Result};
 use std::{cell::RefCell, rc::Rc};
 
+fn zero_value(t: &Rc<Type>) -> Result<GlobalValue> {
+    Ok(match get_primitive_types(t.clone()).as_slice() {
+        [Some(WasmPrimitiveType::I32)] => GlobalValue::I32(0),
+        [Some(WasmPrimitiveType::I64)] => GlobalValue::I64(0),
+        [Some(WasmPrimitiveType::F32)] => GlobalValue::F32(0.0),
+        _ => bail!("a global cannot be of type {}", t),
+    })
+}
+
+/// Defines a global. One whose value cannot be computed at compile time is mutable and
+/// set by the start function.
 pub(super) fn emit_global(
     module: &mut Module,
     forms: &[AST],
     is_mutable: bool,
+    is_export: bool,
     env: Rc<RefCell<Env>>,
 ) -> Result<()> {
     ensure!(forms.len() == 2, "expect two arugments");
@@ -19,37 +35,54 @@ pub(super) fn emit_global(
     // TODO: Impl type symbol functionality
     let empty_type_env = TypeEnv::default();
     let resolved_type = resolve_type(t, &empty_type_env)?;
-    let mut globals = module.globals.borrow_mut();
-    let index = globals.len() as u32;
-
-    let value = match *resolved_type {
-        Type::I32 => match value_ast {
-            AST::NumberLiteral(numstr) => GlobalValue::I32(
-                numstr
-                    .parse::<i32>()
-                    .with_context(|| format!("failed to parse number"))?,
-            ),
-            _ => bail!("number literal expected"),
-        },
-        Type::F32 => match value_ast {
-            AST::NumberLiteral(numstr) => GlobalValue::F32(
-                numstr
-                    .parse::<f32>()
-                    .with_context(|| format!("failed to parse nubmer"))?,
-            ),
-            _ => bail!("number literal expected"),
-        },
-        Type::Bool => match value_ast {
-            AST::BoolLiteral(b) => GlobalValue::I32(if *b { 1 } else { 0 }),
-            _ => bail!("bool literal expected"),
-        },
-        Type::Unit => bail!("Only primitive literals are supported for global variable for now"),
-        Type::Array(_) => todo!(),
+    let value = match integer_literal_as(value_ast, &resolved_type) {
+        Some(opcode) => ConstValue::from_opcode(&opcode, &resolved_type),
+        None => eval_const(module, value_ast, &env),
     };
-    globals.insert(name.to_string(), (index, Global { is_mutable, value }));
+    let value = match (value, &*resolved_type) {
+        (None, _) => None,
+        (Some(value), t) if value.get_type() == *t => Some(value),
+        (Some(ConstValue::I32(v)), Type::F32) => Some(ConstValue::F32(v as f32)),
+        (Some(value), t) => bail!("mismatched types. expected {}, found {}", t, value.get_type()),
+    };
+    let qualified_name = env.borrow().qualify(name);
+    let index = {
+        let mut globals = module.globals.borrow_mut();
+        let index = globals.len() as u32;
+        let global = match value {
+            Some(value) => Global {
+                is_mutable,
+                value: value.global_value(),
+            },
+            // Set by the start function.
+            None => Global {
+                is_mutable: true,
+                value: zero_value(&resolved_type)?,
+            },
+        };
+        globals.insert(qualified_name.clone(), (index, global));
+        index
+    };
+    if value.is_none() {
+        start::emit_initializer(module, index, &resolved_type, value_ast, &env)?;
+    }
+
+    // Exports keep the unqualified name.
+    if is_export {
+        ensure!(
+            module.exports.iter().all(|export| export.name != name),
+            "duplicate export {}",
+            name
+        );
+        module.exports.push(Export {
+            export_type: ExportKind::Global,
+            name: name.to_string(),
+            index,
+        });
+    }
 
     env.borrow_mut().set(
-        name,
+        &qualified_name,
         Variable {
             pointer: Pointer::Global(index as u32),
             t: resolved_type,
@@ -147,6 +180,7 @@ mod tests {
                 AST::NumberLiteral("10"),
             ],
             false,
+            false,
             env.clone(),
         )
         .unwrap();
@@ -160,6 +194,47 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_export_global() {
+        let module = &mut Module::with_options(CompileOptions {
+            memory_export: Some("mem".to_string()),
+            ..CompileOptions::release()
+        });
+        emit(
+            module,
+            "
+        (define unused: i32 1)
+        (export define limit: i32 10)
+        (export defmut count: f32 0.0)
+        ",
+        )
+        .unwrap();
+        let globals = module.globals.borrow();
+        assert_eq!(globals["limit"].0, 2);
+        assert_eq!(globals["count"].0, 3);
+        assert!(!globals.contains_key("unused"));
+        assert_eq!(
+            module.exports,
+            vec![
+                Export {
+                    export_type: ExportKind::Global,
+                    name: "limit".to_string(),
+                    index: 2
+                },
+                Export {
+                    export_type: ExportKind::Global,
+                    name: "count".to_string(),
+                    index: 3
+                },
+                Export {
+                    export_type: ExportKind::Memory,
+                    name: "mem".to_string(),
+                    index: 0
+                }
+            ]
+        );
+    }
+
     #[test]
     fn test_get_global() {
         let source = "
@@ -0,0 +1,178 @@
+use super::*;
+use crate::env::Env;
+use std::{cell::RefCell, rc::Rc};
+
+fn param_types(func: &Function) -> Option<Vec<WasmPrimitiveType>> {
+    func.arg_types
+        .iter()
+        .map(|t| match get_primitive_types(t.clone())[..] {
+            [Some(primitive_type)] => Some(primitive_type),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The body without its trailing `end`.
+fn inlined_codes(func: &Function) -> &[OpCode] {
+    &func.body[..func.body.len() - 1]
+}
+
+/// Whether the k-th `LocalDecl` of the body declares local `params + k`, which is
+/// what the local remapping relies on.
+fn has_ordered_locals(func: &Function, params: usize) -> bool {
+    let decls = func
+        .body
+        .iter()
+        .filter(|code| matches!(code, OpCode::LocalDecl(_)))
+        .count();
+    func.body.iter().all(|code| match code {
+        OpCode::LocalGet(i) | OpCode::LocalSet(i) | OpCode::LocalTee(i) => {
+            (*i as usize) < params + decls
+        }
+        _ => true,
+    })
+}
+
+/// Decides whether a call to the function at `index` is replaced with its body. Functions
+/// with an `(inline)` attribute are inlined unless they are recursive, and from `opt_level`
+/// 2 on leaf functions no bigger than `inline_threshold` instructions are inlined too.
+pub(super) fn should_inline(options: &CompileOptions, index: u32, func: &Function) -> bool {
+    // The body is still empty while the function itself is being emitted.
+    if options.opt_level == 0 || func.body.is_empty() {
+        return false;
+    }
+    let codes = inlined_codes(func);
+    let is_small_leaf = options.opt_level >= 2
+        && codes
+            .iter()
+            .filter(|code| !matches!(code, OpCode::LocalDecl(_) | OpCode::SourceLoc(_)))
+            .count()
+            <= options.inline_threshold
+        && !codes.iter().any(|code| matches!(code, OpCode::Call(_)));
+    // A `return_call` would return from the caller instead.
+    if codes
+        .iter()
+        .any(|code| matches!(code, OpCode::ReturnCall(_)))
+    {
+        return false;
+    }
+    if !func.inline && !is_small_leaf {
+        return false;
+    }
+    match param_types(func) {
+        Some(params) => {
+            !codes.contains(&OpCode::Call(index)) && has_ordered_locals(func, params.len())
+        }
+        None => false,
+    }
+}
+
+/// Emits the body of `func` in place of a call, with the arguments already on the stack.
+/// The arguments and the callee's locals are moved to fresh locals of the caller.
+pub(super) fn emit_inlined_call(codes: &mut Vec<OpCode>, func: &Function, env: &Rc<RefCell<Env>>) {
+    let params = param_types(func).unwrap();
+    let body = inlined_codes(func);
+    let decls = body
+        .iter()
+        .filter_map(|code| match code {
+            OpCode::LocalDecl(t) => Some(*t),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let locals = (0..params.len() + decls.len())
+        .map(|_| env.borrow().new_local())
+        .collect::<Vec<_>>();
+
+    for t in &params {
+        codes.push(OpCode::LocalDecl(*t));
+    }
+    for local in locals[..params.len()].iter().rev() {
+        codes.push(OpCode::LocalSet(*local));
+    }
+    for code in body {
+        codes.push(match code {
+            OpCode::LocalGet(i) => OpCode::LocalGet(locals[*i as usize]),
+            OpCode::LocalSet(i) => OpCode::LocalSet(locals[*i as usize]),
+            OpCode::LocalTee(i) => OpCode::LocalTee(locals[*i as usize]),
+            code => code.clone(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inline() {
+        let module = &mut Module::with_options(CompileOptions::release());
+        emit(
+            module,
+            "
+        (defn add_two: i32 [a: i32]
+            (+ a 2))
+        (defn scaled: f32 [x: f32 y: f32]
+            (inline)
+            (let [s (* x y)]
+                (+ s s s s s s)))
+        (export defn main: f32 [b: i32 c: f32]
+            (scaled (add_two b) c))
+        ",
+        )
+        .unwrap();
+        let functions = module.functions.borrow();
+        assert_eq!(functions.len(), 1);
+        assert_eq!(
+            functions["main"].1.body,
+            vec![
+                OpCode::LocalDecl(WasmPrimitiveType::I32),
+                OpCode::LocalDecl(WasmPrimitiveType::F32),
+                OpCode::LocalDecl(WasmPrimitiveType::F32),
+                OpCode::LocalGet(0),
+                OpCode::LocalTee(2),
+                OpCode::I32Const(2),
+                OpCode::I32Add,
+                OpCode::F32ConvertI32S,
+                OpCode::LocalGet(1),
+                OpCode::LocalSet(3),
+                OpCode::LocalTee(4),
+                OpCode::LocalGet(3),
+                OpCode::F32Mul,
+                OpCode::LocalTee(3),
+                OpCode::LocalGet(3),
+                OpCode::F32Add,
+                OpCode::LocalGet(3),
+                OpCode::F32Add,
+                OpCode::LocalGet(3),
+                OpCode::F32Add,
+                OpCode::LocalGet(3),
+                OpCode::F32Add,
+                OpCode::LocalGet(3),
+                OpCode::F32Add,
+                OpCode::End
+            ]
+        );
+    }
+
+    #[test]
+    fn test_inline_threshold() {
+        let module = &mut Module::with_options(CompileOptions {
+            inline_threshold: 2,
+            ..CompileOptions::release()
+        });
+        emit(
+            module,
+            "
+        (defn add_two: i32 [a: i32]
+            (+ a 2))
+        (export defn main: i32 [b: i32]
+            (add_two b))
+        ",
+        )
+        .unwrap();
+        assert_eq!(
+            module.functions.borrow()["main"].1.body,
+            vec![OpCode::LocalGet(0), OpCode::Call(0), OpCode::End]
+        );
+    }
+}
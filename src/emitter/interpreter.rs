@@ -0,0 +1,740 @@
+use super::*;
+use std::fmt::Display;
+
+/// Most frames on the call stack before a `call stack exhausted` trap.
+const MAX_FRAMES: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    I32(i32),
+    I64(i64),
+    F32(f32),
+}
+
+impl Value {
+    fn zero(t: WasmPrimitiveType) -> Value {
+        match t {
+            WasmPrimitiveType::I32 => Value::I32(0),
+            WasmPrimitiveType::I64 => Value::I64(0),
+            WasmPrimitiveType::F32 => Value::F32(0.0),
+        }
+    }
+}
+
+impl From<GlobalValue> for Value {
+    fn from(value: GlobalValue) -> Self {
+        match value {
+            GlobalValue::I32(v) => Value::I32(v),
+            GlobalValue::I64(v) => Value::I64(v),
+            GlobalValue::F32(v) => Value::F32(v),
+        }
+    }
+}
+
+/// A runtime error. `source_offset` is that of the form being executed, when the module
+/// was emitted with source locations.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trap {
+    pub message: String,
+    pub source_offset: Option<u32>,
+}
+
+impl Display for Trap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for Trap {}
+
+/// A function body prepared for execution.
+struct Code {
+    params: usize,
+    results: usize,
+    /// Types of the locals following the parameters.
+    locals: Vec<WasmPrimitiveType>,
+    body: Vec<OpCode>,
+    /// Module and field of an imported function, which the instance provides.
+    import: Option<(String, String)>,
+    /// The `End` of each `Loop`, `If` and `Else`, by position.
+    ends: HashMap<usize, usize>,
+    /// The `Else` of each `If` that has one.
+    elses: HashMap<usize, usize>,
+}
+
+impl Code {
+    fn new(func: &Function, signature: &Signature) -> Code {
+        let mut locals = Vec::new();
+        let mut ends = HashMap::new();
+        let mut elses = HashMap::new();
+        let mut open = Vec::new();
+        for (pos, code) in func.body.iter().enumerate() {
+            match code {
+                OpCode::LocalDecl(t) => locals.push(*t),
+                OpCode::Loop(_) | OpCode::If(_) => open.push(pos),
+                OpCode::Else => {
+                    if let Some(start) = open.last() {
+                        elses.insert(*start, pos);
+                    }
+                }
+                OpCode::End => {
+                    // The last `End` closes the function.
+                    if let Some(start) = open.pop() {
+                        ends.insert(start, pos);
+                        if let Some(else_pos) = elses.get(&start) {
+                            ends.insert(*else_pos, pos);
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+        Code {
+            params: signature.params.len(),
+            results: signature.results.len(),
+            locals,
+            body: func.body.clone(),
+            import: func.import.clone(),
+            ends,
+            elses,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Label {
+    is_loop: bool,
+    /// Position of the `Loop` or `If`.
+    start: usize,
+    /// Height of the value stack when the block was entered.
+    height: usize,
+    /// Number of values a branch out of the block carries.
+    arity: usize,
+}
+
+struct Frame {
+    code: Rc<Code>,
+    pc: usize,
+    locals: Vec<Value>,
+    labels: Vec<Label>,
+    /// Height of the value stack below the arguments.
+    height: usize,
+    source_offset: Option<u32>,
+}
+
+/// An instance of a module, run by interpreting the instructions of its functions. The
+/// memory is created by the instance, whether or not the module imports it.
+pub struct Instance {
+    codes: HashMap<u32, Rc<Code>>,
+    exports: HashMap<String, u32>,
+    globals: Vec<Value>,
+    memory: Vec<u8>,
+    stack: Vec<Value>,
+    frames: Vec<Frame>,
+}
+
+/// Pops the operands of a binary operator and pushes `$result` computed from them.
+macro_rules! binop {
+    ($self:ident, $pop:ident, $value:ident, |$a:ident, $b:ident| $result:expr) => {{
+        let $b = $self.$pop()?;
+        let $a = $self.$pop()?;
+        let result = $result;
+        $self.stack.push(Value::$value(result));
+    }};
+}
+
+/// Pops the operand of a unary operator and pushes `$result` computed from it.
+macro_rules! unop {
+    ($self:ident, $pop:ident, $value:ident, |$a:ident| $result:expr) => {{
+        let $a = $self.$pop()?;
+        let result = $result;
+        $self.stack.push(Value::$value(result));
+    }};
+}
+
+fn min(a: f32, b: f32) -> f32 {
+    if a.is_nan() || b.is_nan() {
+        f32::NAN
+    } else if a == b {
+        // -0 is less than 0.
+        if a.is_sign_negative() {
+            a
+        } else {
+            b
+        }
+    } else {
+        a.min(b)
+    }
+}
+
+fn max(a: f32, b: f32) -> f32 {
+    if a.is_nan() || b.is_nan() {
+        f32::NAN
+    } else if a == b {
+        if a.is_sign_positive() {
+            a
+        } else {
+            b
+        }
+    } else {
+        a.max(b)
+    }
+}
+
+impl Instance {
+    /// Instantiates `module` and runs its start function.
+    pub fn new(module: &Module) -> Result<Instance, Trap> {
+        let signatures = module
+            .signatures
+            .iter()
+            .map(|(signature, index)| (*index as u32, signature))
+            .collect::<HashMap<_, _>>();
+        let codes = module
+            .functions
+            .borrow()
+            .values()
+            .map(|(index, func)| {
+                let signature = signatures[&func.signature_index];
+                (*index, Rc::new(Code::new(func, signature)))
+            })
+            .collect();
+        let exports = module
+            .exports
+            .iter()
+            .filter(|export| export.export_type == ExportKind::Func)
+            .map(|export| (export.name.clone(), export.index))
+            .collect();
+        let mut globals = module
+            .globals
+            .borrow()
+            .values()
+            .map(|(index, global)| (*index, global.value.into()))
+            .collect::<Vec<_>>();
+        globals.sort_by_key(|(index, _)| *index);
+        let globals = globals.into_iter().map(|(_, value)| value).collect();
+        let mut memory = vec![0; module.memory.initial as usize * PAGE_SIZE as usize];
+        let data_start = module.memory.stack_base as usize;
+        memory[data_start..data_start + module.data.len()].copy_from_slice(&module.data);
+        let mut instance = Instance {
+            codes,
+            exports,
+            globals,
+            memory,
+            stack: Vec::new(),
+            frames: Vec::new(),
+        };
+        if let Some(start) = module.start {
+            instance.run(start, &[])?;
+        }
+        Ok(instance)
+    }
+
+    /// Calls the exported function `name` and returns its results.
+    pub fn invoke(&mut self, name: &str, args: &[Value]) -> Result<Vec<Value>, Trap> {
+        match self.exports.get(name) {
+            Some(index) => self.run(*index, args),
+            None => Err(self.trap(format!("no exported function {}", name))),
+        }
+    }
+
+    fn run(&mut self, index: u32, args: &[Value]) -> Result<Vec<Value>, Trap> {
+        self.stack.extend_from_slice(args);
+        let result = self.enter(index).and_then(|results| {
+            while !self.frames.is_empty() {
+                self.step()?;
+            }
+            Ok(results)
+        });
+        match result {
+            Ok(results) => Ok(self.stack.split_off(self.stack.len() - results)),
+            Err(trap) => {
+                self.frames.clear();
+                self.stack.clear();
+                Err(trap)
+            }
+        }
+    }
+
+    fn trap(&self, message: impl Into<String>) -> Trap {
+        Trap {
+            message: message.into(),
+            source_offset: self.frames.last().and_then(|frame| frame.source_offset),
+        }
+    }
+
+    fn frame(&mut self) -> &mut Frame {
+        self.frames.last_mut().unwrap()
+    }
+
+    /// Calls function `index` with the arguments on the stack and returns the number of
+    /// values it results in.
+    fn enter(&mut self, index: u32) -> Result<usize, Trap> {
+        let code = match self.codes.get(&index) {
+            Some(code) => code.clone(),
+            None => return Err(self.trap(format!("call to unknown function {}", index))),
+        };
+        if self.frames.len() >= MAX_FRAMES {
+            return Err(self.trap("call stack exhausted"));
+        }
+        if self.stack.len() < code.params {
+            return Err(self.trap("missing arguments"));
+        }
+        let height = self.stack.len() - code.params;
+        if let Some((module_name, name)) = &code.import {
+            let args = self.stack.split_off(height);
+            self.call_host(module_name, name, &args)?;
+            return Ok(code.results);
+        }
+        let mut locals = self.stack.split_off(height);
+        locals.extend(code.locals.iter().map(|t| Value::zero(*t)));
+        let results = code.results;
+        self.frames.push(Frame {
+            code,
+            pc: 0,
+            locals,
+            labels: Vec::new(),
+            height,
+            source_offset: None,
+        });
+        Ok(results)
+    }
+
+    /// Runs an imported function. Only the panic hook is provided, which traps with the
+    /// message it is given, and the line it is given unless the trap has a source offset.
+    fn call_host(&mut self, module_name: &str, name: &str, args: &[Value]) -> Result<(), Trap> {
+        match (module_name, name, args) {
+            ("env", panic::PANIC_HOOK, [Value::I32(address), Value::I32(len), Value::I32(line)]) => {
+                let start = *address as u32 as usize;
+                let message = match self.memory.get(start..start + *len as u32 as usize) {
+                    Some(bytes) => String::from_utf8_lossy(bytes).to_string(),
+                    None => return Err(self.trap("out of bounds memory access")),
+                };
+                let trap = self.trap(format!("panicked: {}", message));
+                match (trap.source_offset, line) {
+                    (None, 1..) => Err(self.trap(format!("panicked at line {}: {}", line, message))),
+                    _ => Err(trap),
+                }
+            }
+            _ => Err(self.trap(format!("unknown import {}.{}", module_name, name))),
+        }
+    }
+
+    /// Returns from the current function, leaving its results on the stack.
+    fn leave(&mut self) {
+        let frame = self.frames.pop().unwrap();
+        let results = self.stack.split_off(self.stack.len() - frame.code.results);
+        self.stack.truncate(frame.height);
+        self.stack.extend(results);
+    }
+
+    fn branch(&mut self, depth: u32) {
+        let depth = depth as usize;
+        let frame = self.frame();
+        if depth >= frame.labels.len() {
+            self.leave();
+            return;
+        }
+        let label = frame.labels[frame.labels.len() - 1 - depth];
+        if label.is_loop {
+            // Back to the start of the loop, which stays entered.
+            frame.labels.truncate(frame.labels.len() - depth);
+            frame.pc = label.start + 1;
+            self.stack.truncate(label.height);
+        } else {
+            frame.labels.truncate(frame.labels.len() - 1 - depth);
+            frame.pc = frame.code.ends[&label.start] + 1;
+            let values = self.stack.split_off(self.stack.len() - label.arity);
+            self.stack.truncate(label.height);
+            self.stack.extend(values);
+        }
+    }
+
+    fn pop(&mut self) -> Result<Value, Trap> {
+        match self.stack.pop() {
+            Some(value) => Ok(value),
+            None => Err(self.trap("value stack underflow")),
+        }
+    }
+
+    fn pop_i32(&mut self) -> Result<i32, Trap> {
+        match self.pop()? {
+            Value::I32(v) => Ok(v),
+            value => Err(self.trap(format!("expected i32, found {:?}", value))),
+        }
+    }
+
+    fn pop_i64(&mut self) -> Result<i64, Trap> {
+        match self.pop()? {
+            Value::I64(v) => Ok(v),
+            value => Err(self.trap(format!("expected i64, found {:?}", value))),
+        }
+    }
+
+    fn pop_f32(&mut self) -> Result<f32, Trap> {
+        match self.pop()? {
+            Value::F32(v) => Ok(v),
+            value => Err(self.trap(format!("expected f32, found {:?}", value))),
+        }
+    }
+
+    fn divisor<T: PartialEq + Default>(&self, b: T) -> Result<T, Trap> {
+        if b == T::default() {
+            Err(self.trap("integer divide by zero"))
+        } else {
+            Ok(b)
+        }
+    }
+
+    /// Truncates `a` towards zero, trapping unless the result is within `min..max`.
+    fn trunc(&self, a: f32, min: f32, max: f32) -> Result<f32, Trap> {
+        if a.is_nan() {
+            return Err(self.trap("invalid conversion to integer"));
+        }
+        let t = a.trunc();
+        if t < min || t >= max {
+            return Err(self.trap("integer overflow"));
+        }
+        Ok(t)
+    }
+
+    /// The address of `size` bytes at `offset` from the address on the stack.
+    fn address(&mut self, offset: u32, size: usize) -> Result<usize, Trap> {
+        let base = self.pop_i32()? as u32 as u64;
+        let address = base + offset as u64;
+        if address + size as u64 > self.memory.len() as u64 {
+            return Err(self.trap("out of bounds memory access"));
+        }
+        Ok(address as usize)
+    }
+
+    fn load<const N: usize>(&mut self, offset: u32) -> Result<[u8; N], Trap> {
+        let address = self.address(offset, N)?;
+        Ok(self.memory[address..address + N].try_into().unwrap())
+    }
+
+    fn store(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Trap> {
+        let address = self.address(offset, bytes.len())?;
+        self.memory[address..address + bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn step(&mut self) -> Result<(), Trap> {
+        let frame = self.frame();
+        let pos = frame.pc;
+        let code = frame.code.body[pos].clone();
+        frame.pc += 1;
+        match code {
+            OpCode::Unreachable => return Err(self.trap("unreachable")),
+            OpCode::Loop(t) | OpCode::If(t) => {
+                let is_loop = matches!(code, OpCode::Loop(_));
+                let condition = if is_loop { 1 } else { self.pop_i32()? };
+                let height = self.stack.len();
+                let frame = self.frame();
+                frame.labels.push(Label {
+                    is_loop,
+                    start: pos,
+                    height,
+                    arity: if is_loop { 0 } else { t.is_some() as usize },
+                });
+                if condition == 0 {
+                    frame.pc = match frame.code.elses.get(&pos) {
+                        Some(else_pos) => else_pos + 1,
+                        None => frame.code.ends[&pos],
+                    };
+                }
+            }
+            OpCode::Else => {
+                let frame = self.frame();
+                frame.pc = frame.code.ends[&pos];
+            }
+            OpCode::End => {
+                if self.frame().labels.pop().is_none() {
+                    self.leave();
+                }
+            }
+            OpCode::Br(depth) => self.branch(depth),
+            OpCode::Drop => {
+                self.pop()?;
+            }
+            OpCode::Select => {
+                let condition = self.pop_i32()?;
+                let second = self.pop()?;
+                let first = self.pop()?;
+                self.stack.push(if condition != 0 { first } else { second });
+            }
+            OpCode::LocalGet(i) => {
+                let value = self.frame().locals[i as usize];
+                self.stack.push(value);
+            }
+            OpCode::LocalSet(i) => {
+                let value = self.pop()?;
+                self.frame().locals[i as usize] = value;
+            }
+            OpCode::LocalTee(i) => {
+                let value = self.pop()?;
+                self.stack.push(value);
+                self.frame().locals[i as usize] = value;
+            }
+            OpCode::GlobalGet(i) => self.stack.push(self.globals[i as usize]),
+            OpCode::GlobalSet(i) => self.globals[i as usize] = self.pop()?,
+            OpCode::LocalDecl(_) => (),
+            OpCode::SourceLoc(offset) => self.frame().source_offset = Some(offset),
+            OpCode::Call(index) => {
+                self.enter(index)?;
+            }
+            OpCode::ReturnCall(index) => {
+                let params = match self.codes.get(&index) {
+                    Some(code) => code.params,
+                    None => return Err(self.trap(format!("call to unknown function {}", index))),
+                };
+                let args = self.stack.split_off(self.stack.len() - params);
+                let frame = self.frames.pop().unwrap();
+                self.stack.truncate(frame.height);
+                self.stack.extend(args);
+                self.enter(index)?;
+            }
+            OpCode::I32Store { offset, .. } => {
+                let value = self.pop_i32()?;
+                self.store(offset, &value.to_le_bytes())?;
+            }
+            OpCode::I32Store8 { offset, .. } => {
+                let value = self.pop_i32()?;
+                self.store(offset, &[value as u8])?;
+            }
+            OpCode::I32Load { offset, .. } => {
+                let bytes = self.load(offset)?;
+                self.stack.push(Value::I32(i32::from_le_bytes(bytes)));
+            }
+            OpCode::I32Load8U { offset, .. } => {
+                let [byte] = self.load(offset)?;
+                self.stack.push(Value::I32(byte as i32));
+            }
+            OpCode::I64Store { offset, .. } => {
+                let value = self.pop_i64()?;
+                self.store(offset, &value.to_le_bytes())?;
+            }
+            OpCode::I64Load { offset, .. } => {
+                let bytes = self.load(offset)?;
+                self.stack.push(Value::I64(i64::from_le_bytes(bytes)));
+            }
+            OpCode::F32Store { offset, .. } => {
+                let value = self.pop_f32()?;
+                self.store(offset, &value.to_le_bytes())?;
+            }
+            OpCode::F32Load { offset, .. } => {
+                let bytes = self.load(offset)?;
+                self.stack.push(Value::F32(f32::from_le_bytes(bytes)));
+            }
+            OpCode::I32Const(v) => self.stack.push(Value::I32(v)),
+            OpCode::I64Const(v) => self.stack.push(Value::I64(v)),
+            OpCode::F32Const(v) => self.stack.push(Value::F32(v)),
+
+            OpCode::I32Add => binop!(self, pop_i32, I32, |a, b| a.wrapping_add(b)),
+            OpCode::I32Sub => binop!(self, pop_i32, I32, |a, b| a.wrapping_sub(b)),
+            OpCode::I32Mul => binop!(self, pop_i32, I32, |a, b| a.wrapping_mul(b)),
+            OpCode::I32DivS => binop!(self, pop_i32, I32, |a, b| {
+                match a.checked_div(self.divisor(b)?) {
+                    Some(v) => v,
+                    None => return Err(self.trap("integer overflow")),
+                }
+            }),
+            OpCode::I32DivU => binop!(self, pop_i32, I32, |a, b| {
+                (a as u32 / self.divisor(b)? as u32) as i32
+            }),
+            OpCode::I32RemS => binop!(self, pop_i32, I32, |a, b| a.wrapping_rem(self.divisor(b)?)),
+            OpCode::I32RemU => binop!(self, pop_i32, I32, |a, b| {
+                (a as u32 % self.divisor(b)? as u32) as i32
+            }),
+            OpCode::I32And => binop!(self, pop_i32, I32, |a, b| a & b),
+            OpCode::I32Or => binop!(self, pop_i32, I32, |a, b| a | b),
+            OpCode::I32Xor => binop!(self, pop_i32, I32, |a, b| a ^ b),
+            OpCode::I32Shl => binop!(self, pop_i32, I32, |a, b| a.wrapping_shl(b as u32)),
+            OpCode::I32ShrS => binop!(self, pop_i32, I32, |a, b| a.wrapping_shr(b as u32)),
+            OpCode::I32ShrU => binop!(self, pop_i32, I32, |a, b| {
+                (a as u32).wrapping_shr(b as u32) as i32
+            }),
+            OpCode::I32Rotl => binop!(self, pop_i32, I32, |a, b| a.rotate_left(b as u32 % 32)),
+            OpCode::I32Rotr => binop!(self, pop_i32, I32, |a, b| a.rotate_right(b as u32 % 32)),
+            OpCode::I32Clz => unop!(self, pop_i32, I32, |a| a.leading_zeros() as i32),
+            OpCode::I32Ctz => unop!(self, pop_i32, I32, |a| a.trailing_zeros() as i32),
+            OpCode::I32Popcnt => unop!(self, pop_i32, I32, |a| a.count_ones() as i32),
+            OpCode::I32Eq => binop!(self, pop_i32, I32, |a, b| (a == b) as i32),
+            OpCode::I32Ne => binop!(self, pop_i32, I32, |a, b| (a != b) as i32),
+            OpCode::I32GtS => binop!(self, pop_i32, I32, |a, b| (a > b) as i32),
+            OpCode::I32GtU => binop!(self, pop_i32, I32, |a, b| (a as u32 > b as u32) as i32),
+            OpCode::I32GeS => binop!(self, pop_i32, I32, |a, b| (a >= b) as i32),
+            OpCode::I32GeU => binop!(self, pop_i32, I32, |a, b| (a as u32 >= b as u32) as i32),
+            OpCode::I32LtS => binop!(self, pop_i32, I32, |a, b| (a < b) as i32),
+            OpCode::I32LtU => binop!(self, pop_i32, I32, |a, b| ((a as u32) < b as u32) as i32),
+            OpCode::I32LeS => binop!(self, pop_i32, I32, |a, b| (a <= b) as i32),
+            OpCode::I32LeU => binop!(self, pop_i32, I32, |a, b| (a as u32 <= b as u32) as i32),
+
+            OpCode::I64Add => binop!(self, pop_i64, I64, |a, b| a.wrapping_add(b)),
+            OpCode::I64Sub => binop!(self, pop_i64, I64, |a, b| a.wrapping_sub(b)),
+            OpCode::I64Mul => binop!(self, pop_i64, I64, |a, b| a.wrapping_mul(b)),
+            OpCode::I64DivS => binop!(self, pop_i64, I64, |a, b| {
+                match a.checked_div(self.divisor(b)?) {
+                    Some(v) => v,
+                    None => return Err(self.trap("integer overflow")),
+                }
+            }),
+            OpCode::I64DivU => binop!(self, pop_i64, I64, |a, b| {
+                (a as u64 / self.divisor(b)? as u64) as i64
+            }),
+            OpCode::I64RemS => binop!(self, pop_i64, I64, |a, b| a.wrapping_rem(self.divisor(b)?)),
+            OpCode::I64RemU => binop!(self, pop_i64, I64, |a, b| {
+                (a as u64 % self.divisor(b)? as u64) as i64
+            }),
+            OpCode::I64And => binop!(self, pop_i64, I64, |a, b| a & b),
+            OpCode::I64Or => binop!(self, pop_i64, I64, |a, b| a | b),
+            OpCode::I64Xor => binop!(self, pop_i64, I64, |a, b| a ^ b),
+            OpCode::I64Shl => binop!(self, pop_i64, I64, |a, b| a.wrapping_shl(b as u32)),
+            OpCode::I64ShrS => binop!(self, pop_i64, I64, |a, b| a.wrapping_shr(b as u32)),
+            OpCode::I64ShrU => binop!(self, pop_i64, I64, |a, b| {
+                (a as u64).wrapping_shr(b as u32) as i64
+            }),
+            OpCode::I64Rotl => binop!(self, pop_i64, I64, |a, b| a.rotate_left((b % 64) as u32)),
+            OpCode::I64Rotr => binop!(self, pop_i64, I64, |a, b| a.rotate_right((b % 64) as u32)),
+            OpCode::I64Clz => unop!(self, pop_i64, I64, |a| a.leading_zeros() as i64),
+            OpCode::I64Ctz => unop!(self, pop_i64, I64, |a| a.trailing_zeros() as i64),
+            OpCode::I64Popcnt => unop!(self, pop_i64, I64, |a| a.count_ones() as i64),
+            OpCode::I64Eq => binop!(self, pop_i64, I32, |a, b| (a == b) as i32),
+            OpCode::I64Ne => binop!(self, pop_i64, I32, |a, b| (a != b) as i32),
+            OpCode::I64GtS => binop!(self, pop_i64, I32, |a, b| (a > b) as i32),
+            OpCode::I64GtU => binop!(self, pop_i64, I32, |a, b| (a as u64 > b as u64) as i32),
+            OpCode::I64GeS => binop!(self, pop_i64, I32, |a, b| (a >= b) as i32),
+            OpCode::I64GeU => binop!(self, pop_i64, I32, |a, b| (a as u64 >= b as u64) as i32),
+            OpCode::I64LtS => binop!(self, pop_i64, I32, |a, b| (a < b) as i32),
+            OpCode::I64LtU => binop!(self, pop_i64, I32, |a, b| ((a as u64) < b as u64) as i32),
+            OpCode::I64LeS => binop!(self, pop_i64, I32, |a, b| (a <= b) as i32),
+            OpCode::I64LeU => binop!(self, pop_i64, I32, |a, b| (a as u64 <= b as u64) as i32),
+
+            OpCode::F32Add => binop!(self, pop_f32, F32, |a, b| a + b),
+            OpCode::F32Sub => binop!(self, pop_f32, F32, |a, b| a - b),
+            OpCode::F32Mul => binop!(self, pop_f32, F32, |a, b| a * b),
+            OpCode::F32Div => binop!(self, pop_f32, F32, |a, b| a / b),
+            OpCode::F32Min => binop!(self, pop_f32, F32, |a, b| min(a, b)),
+            OpCode::F32Max => binop!(self, pop_f32, F32, |a, b| max(a, b)),
+            OpCode::F32Copysign => binop!(self, pop_f32, F32, |a, b| a.copysign(b)),
+            OpCode::F32Eq => binop!(self, pop_f32, I32, |a, b| (a == b) as i32),
+            OpCode::F32Gt => binop!(self, pop_f32, I32, |a, b| (a > b) as i32),
+            OpCode::F32Ge => binop!(self, pop_f32, I32, |a, b| (a >= b) as i32),
+            OpCode::F32Lt => binop!(self, pop_f32, I32, |a, b| (a < b) as i32),
+            OpCode::F32Le => binop!(self, pop_f32, I32, |a, b| (a <= b) as i32),
+            OpCode::F32Abs => unop!(self, pop_f32, F32, |a| a.abs()),
+            OpCode::F32Neg => unop!(self, pop_f32, F32, |a| -a),
+            OpCode::F32Ceil => unop!(self, pop_f32, F32, |a| a.ceil()),
+            OpCode::F32Floor => unop!(self, pop_f32, F32, |a| a.floor()),
+            OpCode::F32Trunc => unop!(self, pop_f32, F32, |a| a.trunc()),
+            OpCode::F32Nearest => unop!(self, pop_f32, F32, |a| a.round_ties_even()),
+            OpCode::F32Sqrt => unop!(self, pop_f32, F32, |a| a.sqrt()),
+
+            OpCode::F32ConvertI32S => unop!(self, pop_i32, F32, |a| a as f32),
+            OpCode::F32ConvertI32U => unop!(self, pop_i32, F32, |a| a as u32 as f32),
+            OpCode::F32ConvertI64S => unop!(self, pop_i64, F32, |a| a as f32),
+            OpCode::F32ConvertI64U => unop!(self, pop_i64, F32, |a| a as u64 as f32),
+            OpCode::I32WrapI64 => unop!(self, pop_i64, I32, |a| a as i32),
+            OpCode::I32TruncF32S => unop!(self, pop_f32, I32, |a| {
+                self.trunc(a, -2147483648.0, 2147483648.0)? as i32
+            }),
+            OpCode::I32TruncF32U => unop!(self, pop_f32, I32, |a| {
+                self.trunc(a, 0.0, 4294967296.0)? as u32 as i32
+            }),
+            OpCode::I64ExtendI32S => unop!(self, pop_i32, I64, |a| a as i64),
+            OpCode::I64ExtendI32U => unop!(self, pop_i32, I64, |a| a as u32 as i64),
+            OpCode::I64TruncF32S => unop!(self, pop_f32, I64, |a| {
+                self.trunc(a, -9223372036854775808.0, 9223372036854775808.0)? as i64
+            }),
+            OpCode::I64TruncF32U => unop!(self, pop_f32, I64, |a| {
+                self.trunc(a, 0.0, 18446744073709551616.0)? as u64 as i64
+            }),
+            // Casts from floats saturate like the `trunc_sat` instructions.
+            OpCode::I32TruncSatF32S => unop!(self, pop_f32, I32, |a| a as i32),
+            OpCode::I32TruncSatF32U => unop!(self, pop_f32, I32, |a| a as u32 as i32),
+            OpCode::I64TruncSatF32S => unop!(self, pop_f32, I64, |a| a as i64),
+            OpCode::I64TruncSatF32U => unop!(self, pop_f32, I64, |a| a as u64 as i64),
+            OpCode::I32ReinterpretF32 => unop!(self, pop_f32, I32, |a| a.to_bits() as i32),
+            OpCode::F32ReinterpretI32 => unop!(self, pop_i32, F32, |a| f32::from_bits(a as u32)),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::validator::validate, *};
+
+    fn instantiate(options: CompileOptions, source: &str) -> Instance {
+        let module = &mut Module::with_options(options);
+        emit(module, source).unwrap();
+        validate(module).unwrap();
+        Instance::new(module).unwrap()
+    }
+
+    const SOURCE: &str = "
+        (define scale: f32 (as f32 (twice 2)))
+        (defn twice: i32 [x: i32] (* x 2))
+        (export defn fact: i64 [n: i64]
+            (if (= n (as i64 0))
+                (as i64 1)
+                (* n (fact (- n (as i64 1))))))
+        (export defn sum: i32 [n: i32 acc: i32]
+            (if (= n 0) acc (sum (- n 1) (+ acc n))))
+        (export defn pick: f32 [i: i32]
+            (let [xs [1.5 2.5 (* scale 0.5)]]
+                (get xs i)))
+        (export defn divide: i32 [a: i32 b: i32]
+            (/ a b))
+        (export defn checked: i32 [x: i32]
+            (assert (> x 0) \"x must be positive\")
+            x)
+        (export defn bits: i32 [x: i32]
+            (+ (popcnt x) (rotl x 4) (trunc-as i32 (sqrt 16.0))))
+        ";
+
+    #[test]
+    fn test_invoke() {
+        for options in [CompileOptions::debug(), CompileOptions::release()] {
+            let instance = &mut instantiate(options, SOURCE);
+            assert_eq!(instance.invoke("fact", &[Value::I64(20)]), Ok(vec![Value::I64(2432902008176640000)]));
+            assert_eq!(
+                instance.invoke("sum", &[Value::I32(100_000), Value::I32(0)]),
+                Ok(vec![Value::I32(705082704)])
+            );
+            assert_eq!(instance.invoke("pick", &[Value::I32(2)]), Ok(vec![Value::F32(2.0)]));
+            assert_eq!(instance.invoke("divide", &[Value::I32(-7), Value::I32(2)]), Ok(vec![Value::I32(-3)]));
+            assert_eq!(instance.invoke("bits", &[Value::I32(0x11)]), Ok(vec![Value::I32(0x110 + 2 + 4)]));
+        }
+    }
+
+    #[test]
+    fn test_traps() {
+        let instance = &mut instantiate(
+            CompileOptions {
+                source_map: true,
+                ..CompileOptions::debug()
+            },
+            SOURCE,
+        );
+        let trap = instance.invoke("divide", &[Value::I32(1), Value::I32(0)]).unwrap_err();
+        assert_eq!(trap.message, "integer divide by zero");
+        assert_eq!(trap.source_offset, SOURCE.find("(/ a b)").map(|offset| offset as u32));
+        let trap = instance
+            .invoke("divide", &[Value::I32(i32::MIN), Value::I32(-1)])
+            .unwrap_err();
+        assert_eq!(trap.message, "integer overflow");
+        // Out of bounds array accesses trap with `unreachable` in debug builds.
+        let trap = instance.invoke("pick", &[Value::I32(3)]).unwrap_err();
+        assert_eq!(trap.message, "unreachable");
+        let trap = instance.invoke("checked", &[Value::I32(0)]).unwrap_err();
+        assert_eq!(trap.message, "panicked: x must be positive");
+        assert_eq!(trap.source_offset, SOURCE.find("(assert").map(|offset| offset as u32));
+        let instance = &mut instantiate(CompileOptions::debug(), SOURCE);
+        let trap = instance.invoke("checked", &[Value::I32(0)]).unwrap_err();
+        assert_eq!(trap.message, "panicked at line 16: x must be positive");
+        let trap = instance.invoke("fact", &[Value::I64(1_000_000)]).unwrap_err();
+        assert_eq!(trap.message, "call stack exhausted");
+        // The instance is still usable after a trap.
+        assert_eq!(instance.invoke("divide", &[Value::I32(9), Value::I32(3)]), Ok(vec![Value::I32(3)]));
+    }
+}
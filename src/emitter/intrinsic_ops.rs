@@ -1,7 +1,137 @@
 use std::{cell::RefCell, rc::Rc};
 use crate::{parser::AST, resolver::Type, env::Env, emitter::expression::*};
 use super::*;
-use anyhow::{Result, ensure, bail};
+use anyhow::{Context, Result, ensure, bail};
+
+fn arithmetic_opcode(op: &IntrinsicOperator, t: &Type) -> Option<OpCode> {
+    Some(match (t, op) {
+        (Type::I32 | Type::U32, IntrinsicOperator::Add) => OpCode::I32Add,
+        (Type::I32 | Type::U32, IntrinsicOperator::Sub) => OpCode::I32Sub,
+        (Type::I32 | Type::U32, IntrinsicOperator::Mul) => OpCode::I32Mul,
+        (Type::I32, IntrinsicOperator::Div) => OpCode::I32DivS,
+        (Type::U32, IntrinsicOperator::Div) => OpCode::I32DivU,
+        (Type::I64 | Type::U64, IntrinsicOperator::Add) => OpCode::I64Add,
+        (Type::I64 | Type::U64, IntrinsicOperator::Sub) => OpCode::I64Sub,
+        (Type::I64 | Type::U64, IntrinsicOperator::Mul) => OpCode::I64Mul,
+        (Type::I64, IntrinsicOperator::Div) => OpCode::I64DivS,
+        (Type::U64, IntrinsicOperator::Div) => OpCode::I64DivU,
+        (Type::F32, IntrinsicOperator::Add) => OpCode::F32Add,
+        (Type::F32, IntrinsicOperator::Sub) => OpCode::F32Sub,
+        (Type::F32, IntrinsicOperator::Mul) => OpCode::F32Mul,
+        (Type::F32, IntrinsicOperator::Div) => OpCode::F32Div,
+        _ => return None,
+    })
+}
+
+fn comparison_opcode(op: &IntrinsicOperator, t: &Type) -> Option<OpCode> {
+    Some(match (t, op) {
+        (Type::I32 | Type::U32, IntrinsicOperator::Eq) => OpCode::I32Eq,
+        (Type::I32, IntrinsicOperator::Gt) => OpCode::I32GtS,
+        (Type::I32, IntrinsicOperator::Ge) => OpCode::I32GeS,
+        (Type::I32, IntrinsicOperator::Lt) => OpCode::I32LtS,
+        (Type::I32, IntrinsicOperator::Le) => OpCode::I32LeS,
+        (Type::U32, IntrinsicOperator::Gt) => OpCode::I32GtU,
+        (Type::U32, IntrinsicOperator::Ge) => OpCode::I32GeU,
+        (Type::U32, IntrinsicOperator::Lt) => OpCode::I32LtU,
+        (Type::U32, IntrinsicOperator::Le) => OpCode::I32LeU,
+        (Type::I64 | Type::U64, IntrinsicOperator::Eq) => OpCode::I64Eq,
+        (Type::I64, IntrinsicOperator::Gt) => OpCode::I64GtS,
+        (Type::I64, IntrinsicOperator::Ge) => OpCode::I64GeS,
+        (Type::I64, IntrinsicOperator::Lt) => OpCode::I64LtS,
+        (Type::I64, IntrinsicOperator::Le) => OpCode::I64LeS,
+        (Type::U64, IntrinsicOperator::Gt) => OpCode::I64GtU,
+        (Type::U64, IntrinsicOperator::Ge) => OpCode::I64GeU,
+        (Type::U64, IntrinsicOperator::Lt) => OpCode::I64LtU,
+        (Type::U64, IntrinsicOperator::Le) => OpCode::I64LeU,
+        (Type::F32, IntrinsicOperator::Eq) => OpCode::F32Eq,
+        (Type::F32, IntrinsicOperator::Gt) => OpCode::F32Gt,
+        (Type::F32, IntrinsicOperator::Ge) => OpCode::F32Ge,
+        (Type::F32, IntrinsicOperator::Lt) => OpCode::F32Lt,
+        (Type::F32, IntrinsicOperator::Le) => OpCode::F32Le,
+        _ => return None,
+    })
+}
+
+fn integer_opcode(op: &IntrinsicOperator, t: &Type) -> Option<OpCode> {
+    Some(match (t, op) {
+        (Type::I32, IntrinsicOperator::Rem) => OpCode::I32RemS,
+        (Type::U32, IntrinsicOperator::Rem) => OpCode::I32RemU,
+        (Type::I32 | Type::U32, IntrinsicOperator::BitAnd) => OpCode::I32And,
+        (Type::I32 | Type::U32, IntrinsicOperator::BitOr) => OpCode::I32Or,
+        (Type::I32 | Type::U32, IntrinsicOperator::BitXor) => OpCode::I32Xor,
+        (Type::I32 | Type::U32, IntrinsicOperator::Shl) => OpCode::I32Shl,
+        (Type::I32, IntrinsicOperator::Shr) => OpCode::I32ShrS,
+        (Type::U32, IntrinsicOperator::Shr) => OpCode::I32ShrU,
+        (Type::I32 | Type::U32, IntrinsicOperator::UShr) => OpCode::I32ShrU,
+        (Type::I32 | Type::U32, IntrinsicOperator::Rotl) => OpCode::I32Rotl,
+        (Type::I32 | Type::U32, IntrinsicOperator::Rotr) => OpCode::I32Rotr,
+        (Type::I32 | Type::U32, IntrinsicOperator::Clz) => OpCode::I32Clz,
+        (Type::I32 | Type::U32, IntrinsicOperator::Ctz) => OpCode::I32Ctz,
+        (Type::I32 | Type::U32, IntrinsicOperator::Popcnt) => OpCode::I32Popcnt,
+        (Type::I64, IntrinsicOperator::Rem) => OpCode::I64RemS,
+        (Type::U64, IntrinsicOperator::Rem) => OpCode::I64RemU,
+        (Type::I64 | Type::U64, IntrinsicOperator::BitAnd) => OpCode::I64And,
+        (Type::I64 | Type::U64, IntrinsicOperator::BitOr) => OpCode::I64Or,
+        (Type::I64 | Type::U64, IntrinsicOperator::BitXor) => OpCode::I64Xor,
+        (Type::I64 | Type::U64, IntrinsicOperator::Shl) => OpCode::I64Shl,
+        (Type::I64, IntrinsicOperator::Shr) => OpCode::I64ShrS,
+        (Type::U64, IntrinsicOperator::Shr) => OpCode::I64ShrU,
+        (Type::I64 | Type::U64, IntrinsicOperator::UShr) => OpCode::I64ShrU,
+        (Type::I64 | Type::U64, IntrinsicOperator::Rotl) => OpCode::I64Rotl,
+        (Type::I64 | Type::U64, IntrinsicOperator::Rotr) => OpCode::I64Rotr,
+        (Type::I64 | Type::U64, IntrinsicOperator::Clz) => OpCode::I64Clz,
+        (Type::I64 | Type::U64, IntrinsicOperator::Ctz) => OpCode::I64Ctz,
+        (Type::I64 | Type::U64, IntrinsicOperator::Popcnt) => OpCode::I64Popcnt,
+        _ => return None,
+    })
+}
+
+/// Reconciles the types of two adjacent operands.
+/// `left_codes` ends with the left operand and `right_codes` holds only the right one.
+/// An i32 is promoted to f32 when `promote_float` is set, and an integer literal
+/// without suffix takes the integer type of the other side. Signed and unsigned
+/// integers are never mixed implicitly.
+fn unify_operands(
+    op: &IntrinsicOperator,
+    left_codes: &mut Vec<OpCode>,
+    left: (Option<&AST>, Rc<Type>),
+    right_codes: &mut Vec<OpCode>,
+    right: (&AST, Rc<Type>),
+    promote_float: bool,
+) -> Result<Rc<Type>> {
+    let (left_ast, left_type) = left;
+    let (right_ast, right_type) = right;
+    if *left_type == *right_type {
+        return Ok(left_type);
+    }
+    match (&*left_type, &*right_type) {
+        (Type::I32, Type::F32) if promote_float => {
+            left_codes.push(OpCode::F32ConvertI32S);
+            return Ok(right_type);
+        }
+        (Type::F32, Type::I32) if promote_float => {
+            right_codes.push(OpCode::F32ConvertI32S);
+            return Ok(left_type);
+        }
+        _ => (),
+    }
+    if let Some(code) = integer_literal_as(right_ast, &left_type) {
+        right_codes.clear();
+        right_codes.push(code);
+        return Ok(left_type);
+    }
+    if let Some(code) = left_ast.and_then(|ast| integer_literal_as(ast, &right_type)) {
+        left_codes.pop();
+        left_codes.push(code);
+        return Ok(right_type);
+    }
+    bail!(
+        "mismatched types for {}. found {} and {}",
+        op,
+        left_type,
+        right_type
+    )
+}
 
 pub(super) fn emit_intrinsic_exp(
     module: &mut Module,
@@ -11,43 +141,70 @@ pub(super) fn emit_intrinsic_exp(
     env: Rc<RefCell<Env>>,
 ) -> Result<Rc<Type>> {
     ensure!(args.len() > 0);
+    if is_integer_operator(&op) {
+        return emit_integer_exp(module, op, codes, args, env);
+    }
     if args.len() == 1 {
         let arg = &args[0];
         match op {
-            IntrinsicOperator::Add => match *emit_obj(module, codes, arg, env)? {
-                Type::Bool | Type::Unit | Type::Array(_) => {
-                    bail!("Invalid argument for unary op. expected numeric type")
-                }
-                Type::I32 => Ok(Rc::new(Type::I32)),
-                Type::F32 => Ok(Rc::new(Type::F32)),
-            },
-            IntrinsicOperator::Sub => match *emit_obj(module, codes, arg, env)? {
-                Type::Bool | Type::Unit | Type::Array(_) => {
-                    bail!("Invalid argument for unary op. expected numeric type")
-                }
-                Type::I32 => {
-                    codes.push(OpCode::I32Const(-1));
-                    codes.push(OpCode::I32Xor);
-                    Ok(Rc::new(Type::I32))
+            IntrinsicOperator::Add => {
+                let arg_type = emit_obj(module, codes, arg, env)?;
+                match *arg_type {
+                    Type::Bool | Type::Unit | Type::Array(_) => {
+                        bail!("Invalid argument for unary op. expected numeric type")
+                    }
+                    _ => Ok(arg_type),
                 }
-                Type::F32 => {
-                    codes.push(OpCode::F32Neg);
-                    Ok(Rc::new(Type::F32))
+            }
+            IntrinsicOperator::Sub => {
+                // Integers are negated as 0 - x, so the operand is emitted after the zero.
+                let mut operand = Vec::new();
+                match *emit_obj(module, &mut operand, arg, env)? {
+                    Type::Bool | Type::Unit | Type::Array(_) | Type::Error | Type::Never => {
+                        codes.append(&mut operand);
+                        bail!("Invalid argument for unary op. expected numeric type")
+                    }
+                    ref t @ (Type::U32 | Type::U64) => {
+                        codes.append(&mut operand);
+                        bail!("cannot negate unsigned type {}", t)
+                    }
+                    Type::I64 => {
+                        codes.push(OpCode::I64Const(0));
+                        codes.append(&mut operand);
+                        codes.push(OpCode::I64Sub);
+                        Ok(Rc::new(Type::I64))
+                    }
+                    Type::I32 => {
+                        codes.push(OpCode::I32Const(0));
+                        codes.append(&mut operand);
+                        codes.push(OpCode::I32Sub);
+                        Ok(Rc::new(Type::I32))
+                    }
+                    Type::F32 => {
+                        codes.append(&mut operand);
+                        codes.push(OpCode::F32Neg);
+                        Ok(Rc::new(Type::F32))
+                    }
                 }
-            },
-            IntrinsicOperator::Mul => match *emit_obj(module, codes, arg, env)? {
-                Type::Bool | Type::Unit | Type::Array(_) => {
-                    bail!("Invalid argument for unary op. expected numeric type")
+            }
+            IntrinsicOperator::Mul => {
+                let arg_type = emit_obj(module, codes, arg, env)?;
+                match *arg_type {
+                    Type::Bool | Type::Unit | Type::Array(_) | Type::Error | Type::Never => {
+                        bail!("Invalid argument for unary op. expected numeric type")
+                    }
+                    _ => Ok(arg_type),
                 }
-                Type::I32 => Ok(Rc::new(Type::I32)),
-                Type::F32 => Ok(Rc::new(Type::F32)),
-            },
+            }
             IntrinsicOperator::Div => {
                 codes.push(OpCode::F32Const(1.0));
                 match *emit_obj(module, codes, arg, env)? {
-                    Type::Bool | Type::Unit | Type::Array(_) => {
+                    Type::Bool | Type::Unit | Type::Array(_) | Type::Error | Type::Never => {
                         bail!("Invalid argument for unary op. expected numeric type")
                     }
+                    ref t @ (Type::U32 | Type::I64 | Type::U64) => {
+                        bail!("unary / is not supported for {}", t)
+                    }
                     Type::I32 => {
                         codes.push(OpCode::F32ConvertI32S);
                         codes.push(OpCode::I32DivS);
@@ -69,6 +226,7 @@ pub(super) fn emit_intrinsic_exp(
             | IntrinsicOperator::Not => {
                 bail!("Comp operators cannot evaluated with 1 arg");
             }
+            _ => unreachable!("integer operators are emitted by emit_integer_exp"),
         }
     } else {
         match op {
@@ -79,52 +237,18 @@ pub(super) fn emit_intrinsic_exp(
                 let last_codes = codes;
                 let current_codes = &mut Vec::new();
                 let mut last_result = emit_obj(module, last_codes, &args[0], env.clone())?;
-                for arg in &args[1..] {
+                for (i, arg) in args.iter().enumerate().skip(1) {
                     let current_result = emit_obj(module, current_codes, arg, env.clone())?;
-                    let opcode = match *last_result {
-                        Type::Bool | Type::Unit | Type::Array(_) => bail!("hoge"),
-                        Type::I32 => match *current_result {
-                            Type::Bool | Type::Unit | Type::Array(_) => bail!("hoge"),
-                            Type::I32 => match op {
-                                IntrinsicOperator::Add => OpCode::I32Add,
-                                IntrinsicOperator::Sub => OpCode::I32Sub,
-                                IntrinsicOperator::Mul => OpCode::I32Mul,
-                                IntrinsicOperator::Div => OpCode::I32DivS,
-                                _ => unreachable!(),
-                            },
-                            Type::F32 => {
-                                last_codes.push(OpCode::F32ConvertI32S);
-                                last_result = current_result;
-                                match op {
-                                    IntrinsicOperator::Add => OpCode::F32Add,
-                                    IntrinsicOperator::Sub => OpCode::F32Sub,
-                                    IntrinsicOperator::Mul => OpCode::F32Mul,
-                                    IntrinsicOperator::Div => OpCode::F32Div,
-                                    _ => unreachable!(),
-                                }
-                            }
-                        },
-                        Type::F32 => match *current_result {
-                            Type::Bool | Type::Unit | Type::Array(_) => bail!("hoge"),
-                            Type::I32 => {
-                                current_codes.push(OpCode::F32ConvertI32S);
-                                match op {
-                                    IntrinsicOperator::Add => OpCode::F32Add,
-                                    IntrinsicOperator::Sub => OpCode::F32Sub,
-                                    IntrinsicOperator::Mul => OpCode::F32Mul,
-                                    IntrinsicOperator::Div => OpCode::F32Div,
-                                    _ => unreachable!(),
-                                }
-                            }
-                            Type::F32 => match op {
-                                IntrinsicOperator::Add => OpCode::F32Add,
-                                IntrinsicOperator::Sub => OpCode::F32Sub,
-                                IntrinsicOperator::Mul => OpCode::F32Mul,
-                                IntrinsicOperator::Div => OpCode::F32Div,
-                                _ => unreachable!(),
-                            },
-                        },
-                    };
+                    last_result = unify_operands(
+                        &op,
+                        last_codes,
+                        (if i == 1 { Some(&args[0]) } else { None }, last_result),
+                        current_codes,
+                        (arg, current_result),
+                        true,
+                    )?;
+                    let opcode = arithmetic_opcode(&op, &last_result)
+                        .with_context(|| format!("cannot calc {} for {}", op, last_result))?;
                     last_codes.append(current_codes);
                     last_codes.push(opcode);
                 }
@@ -162,53 +286,19 @@ pub(super) fn emit_intrinsic_exp(
                                 }
                             };
                         }
-                        Type::I32 => match *right_type {
-                            Type::Bool | Type::Unit | Type::Array(_) => bail!("hoge"),
-                            Type::F32 => {
-                                left_codes.push(OpCode::F32ConvertI32S);
-                                match op {
-                                    IntrinsicOperator::Eq => right_codes.push(OpCode::F32Eq),
-                                    IntrinsicOperator::Gt => right_codes.push(OpCode::F32Gt),
-                                    IntrinsicOperator::Ge => right_codes.push(OpCode::F32Ge),
-                                    IntrinsicOperator::Lt => right_codes.push(OpCode::F32Lt),
-                                    IntrinsicOperator::Le => right_codes.push(OpCode::F32Le),
-                                    _ => bail!("cannot calc {} for numeric types", op),
-                                }
-                            }
-                            Type::I32 => match op {
-                                IntrinsicOperator::Eq => right_codes.push(OpCode::I32Eq),
-                                IntrinsicOperator::Gt => right_codes.push(OpCode::I32GtS),
-                                IntrinsicOperator::Ge => right_codes.push(OpCode::I32GeS),
-                                IntrinsicOperator::Lt => right_codes.push(OpCode::I32LtS),
-                                IntrinsicOperator::Le => right_codes.push(OpCode::I32LeS),
-                                _ => bail!("cannot calc {} for numeric types", op),
-                            },
-                        },
-                        Type::F32 => match *right_type {
-                            Type::Bool | Type::Unit | Type::Array(_) => bail!("hoge"),
-                            Type::F32 => {
-                                left_codes.push(OpCode::F32ConvertI32S);
-                                match op {
-                                    IntrinsicOperator::Eq => right_codes.push(OpCode::F32Eq),
-                                    IntrinsicOperator::Gt => right_codes.push(OpCode::F32Gt),
-                                    IntrinsicOperator::Ge => right_codes.push(OpCode::F32Ge),
-                                    IntrinsicOperator::Lt => right_codes.push(OpCode::F32Lt),
-                                    IntrinsicOperator::Le => right_codes.push(OpCode::F32Le),
-                                    _ => bail!("cannot calc {} for numeric types", op),
-                                }
-                            }
-                            Type::I32 => {
-                                right_codes.push(OpCode::F32ConvertI32S);
-                                match op {
-                                    IntrinsicOperator::Eq => right_codes.push(OpCode::F32Eq),
-                                    IntrinsicOperator::Gt => right_codes.push(OpCode::F32Gt),
-                                    IntrinsicOperator::Ge => right_codes.push(OpCode::F32Ge),
-                                    IntrinsicOperator::Lt => right_codes.push(OpCode::F32Lt),
-                                    IntrinsicOperator::Le => right_codes.push(OpCode::F32Le),
-                                    _ => bail!("cannot calc {} for numeric types", op),
-                                }
-                            }
-                        },
+                        _ => {
+                            let operand_type = unify_operands(
+                                &op,
+                                left_codes,
+                                (Some(left), left_type),
+                                right_codes,
+                                (right, right_type),
+                                true,
+                            )?;
+                            right_codes.push(comparison_opcode(&op, &operand_type).with_context(
+                                || format!("cannot calc {} for numeric types", op),
+                            )?);
+                        }
                     }
                     if i > 0 {
                         right_codes.push(OpCode::I32And)
@@ -217,13 +307,195 @@ pub(super) fn emit_intrinsic_exp(
                 }
                 Ok(Rc::new(Type::Bool))
             }
+            _ => unreachable!("integer operators are emitted by emit_integer_exp"),
+        }
+    }
+}
+
+fn is_integer_operator(op: &IntrinsicOperator) -> bool {
+    matches!(
+        op,
+        IntrinsicOperator::Mod
+            | IntrinsicOperator::Rem
+            | IntrinsicOperator::BitAnd
+            | IntrinsicOperator::BitOr
+            | IntrinsicOperator::BitXor
+            | IntrinsicOperator::BitNot
+            | IntrinsicOperator::Shl
+            | IntrinsicOperator::Shr
+            | IntrinsicOperator::UShr
+            | IntrinsicOperator::Rotl
+            | IntrinsicOperator::Rotr
+            | IntrinsicOperator::Clz
+            | IntrinsicOperator::Ctz
+            | IntrinsicOperator::Popcnt
+    )
+}
+
+/// Emits remainder, bitwise, shift and bit counting operators, which accept only integers.
+pub(super) fn emit_integer_exp(
+    module: &mut Module,
+    op: IntrinsicOperator,
+    codes: &mut Vec<OpCode>,
+    args: &[AST],
+    env: Rc<RefCell<Env>>,
+) -> Result<Rc<Type>> {
+    match op {
+        IntrinsicOperator::BitNot
+        | IntrinsicOperator::Clz
+        | IntrinsicOperator::Ctz
+        | IntrinsicOperator::Popcnt => {
+            ensure!(args.len() == 1, "{} expects 1 arg, found {}", op, args.len())
+        }
+        IntrinsicOperator::BitAnd | IntrinsicOperator::BitOr | IntrinsicOperator::BitXor => (),
+        _ => ensure!(args.len() == 2, "{} expects 2 args, found {}", op, args.len()),
+    }
+    let mut operand_type = emit_obj(module, codes, &args[0], env.clone())?;
+    // With more operands, an unsuffixed literal may still take the type of the others.
+    ensure!(
+        args.len() > 1 || operand_type.is_integer(),
+        "{} expects integer operands, found {}",
+        op,
+        operand_type
+    );
+    match op {
+        IntrinsicOperator::BitNot => {
+            codes.push(if operand_type.is_64bit() {
+                OpCode::I64Const(-1)
+            } else {
+                OpCode::I32Const(-1)
+            });
+            codes.push(integer_opcode(&IntrinsicOperator::BitXor, &operand_type).unwrap());
+            return Ok(operand_type);
+        }
+        IntrinsicOperator::Clz | IntrinsicOperator::Ctz | IntrinsicOperator::Popcnt => {
+            codes.push(integer_opcode(&op, &operand_type).unwrap());
+            return Ok(operand_type);
+        }
+        _ => (),
+    }
+    for (i, arg) in args.iter().enumerate().skip(1) {
+        let arg_codes = &mut Vec::new();
+        let arg_type = emit_obj(module, arg_codes, arg, env.clone())?;
+        operand_type = unify_operands(
+            &op,
+            codes,
+            (if i == 1 { Some(&args[0]) } else { None }, operand_type),
+            arg_codes,
+            (arg, arg_type),
+            false,
+        )?;
+        ensure!(
+            operand_type.is_integer(),
+            "{} expects integer operands, found {}",
+            op,
+            operand_type
+        );
+        codes.append(arg_codes);
+        let rem = integer_opcode(&IntrinsicOperator::Rem, &operand_type).unwrap();
+        if op == IntrinsicOperator::Mod && !operand_type.is_unsigned() {
+            // Floored modulo, whose result takes the sign of the divisor. With r = a rem b,
+            // it is r + b when r != 0 and (r xor b) < 0, which cannot overflow, and r otherwise.
+            let divisor_local = env.borrow().new_local();
+            let rem_local = env.borrow().new_local();
+            let primitive_type = get_primitive_types(operand_type.clone())[0].unwrap();
+            let zero = if operand_type.is_64bit() {
+                OpCode::I64Const(0)
+            } else {
+                OpCode::I32Const(0)
+            };
+            codes.push(OpCode::LocalDecl(primitive_type));
+            codes.push(OpCode::LocalDecl(primitive_type));
+            codes.push(OpCode::LocalTee(divisor_local));
+            codes.push(rem);
+            codes.push(OpCode::LocalTee(rem_local));
+            codes.push(OpCode::LocalGet(divisor_local));
+            codes.push(arithmetic_opcode(&IntrinsicOperator::Add, &operand_type).unwrap());
+            codes.push(OpCode::LocalGet(rem_local));
+            codes.push(OpCode::LocalGet(rem_local));
+            codes.push(zero.clone());
+            codes.push(if operand_type.is_64bit() {
+                OpCode::I64Ne
+            } else {
+                OpCode::I32Ne
+            });
+            codes.push(OpCode::LocalGet(rem_local));
+            codes.push(OpCode::LocalGet(divisor_local));
+            codes.push(integer_opcode(&IntrinsicOperator::BitXor, &operand_type).unwrap());
+            codes.push(zero);
+            codes.push(comparison_opcode(&IntrinsicOperator::Lt, &operand_type).unwrap());
+            codes.push(OpCode::I32And);
+            codes.push(OpCode::Select);
+        } else if op == IntrinsicOperator::Mod {
+            codes.push(rem);
+        } else {
+            codes.push(integer_opcode(&op, &operand_type).unwrap());
         }
     }
+    Ok(operand_type)
+}
+
+fn emit_float_operand(
+    module: &mut Module,
+    func: IntrinsicFunction,
+    codes: &mut Vec<OpCode>,
+    arg: &AST,
+    env: Rc<RefCell<Env>>,
+) -> Result<()> {
+    let t = emit_obj(module, codes, arg, env)?;
+    match *t {
+        Type::F32 => (),
+        Type::I32 => codes.push(OpCode::F32ConvertI32S),
+        _ => bail!("{} expects f32 operands, found {}", func, t),
+    }
+    Ok(())
+}
+
+/// Emits the f32 math functions. i32 operands are promoted as in arithmetic.
+pub(super) fn emit_float_intrinsic(
+    module: &mut Module,
+    func: IntrinsicFunction,
+    codes: &mut Vec<OpCode>,
+    args: &[AST],
+    env: Rc<RefCell<Env>>,
+) -> Result<Rc<Type>> {
+    let arity = match func {
+        IntrinsicFunction::Min | IntrinsicFunction::Max | IntrinsicFunction::Copysign => 2,
+        _ => 1,
+    };
+    ensure!(
+        args.len() == arity,
+        "{} expects {} args, found {}",
+        func,
+        arity,
+        args.len()
+    );
+    for arg in args {
+        emit_float_operand(module, func, codes, arg, env.clone())?;
+    }
+    codes.push(match func {
+        IntrinsicFunction::Sqrt => OpCode::F32Sqrt,
+        IntrinsicFunction::Abs => OpCode::F32Abs,
+        IntrinsicFunction::Floor => OpCode::F32Floor,
+        IntrinsicFunction::Ceil => OpCode::F32Ceil,
+        IntrinsicFunction::Trunc => OpCode::F32Trunc,
+        IntrinsicFunction::Nearest => OpCode::F32Nearest,
+        IntrinsicFunction::Min => OpCode::F32Min,
+        IntrinsicFunction::Max => OpCode::F32Max,
+        IntrinsicFunction::Copysign => OpCode::F32Copysign,
+    });
+    Ok(Rc::new(Type::F32))
 }
 
 #[cfg(test)]
 mod tests {
-    use super::*;
+    use super::{
+        super::{
+            interpreter::{Instance, Value},
+            validator::validate,
+        },
+        *,
+    };
 
     #[test]
     fn test_arithmetic_ops() {
@@ -244,7 +516,10 @@ mod tests {
                         (/ 10 20 30.0))",
         )
         .unwrap();
-        assert_eq!(module.exports, []);
+        assert!(module
+            .exports
+            .iter()
+            .all(|export| export.export_type != ExportKind::Func));
         assert_eq!(module.signatures.len(), 2);
         let module_functions = module.functions.borrow_mut();
         assert_eq!(
@@ -253,6 +528,9 @@ mod tests {
                 arg_types: vec![Rc::new(Type::F32), Rc::new(Type::I32)],
                 result_type: Rc::new(Type::F32),
                 signature_index: 0,
+                inline: false,
+                local_names: vec![(0, "a".to_string()), (1, "b".to_string())],
+                import: None,
                 body: vec![
                     OpCode::I32Const(10),
                     OpCode::F32ConvertI32S,
@@ -341,15 +619,340 @@ mod tests {
         let module_functions = module.functions.borrow_mut();
         assert_eq!(
             module_functions["neg_i32"].1.body,
+            vec![
+                OpCode::I32Const(0),
+                OpCode::LocalGet(0),
+                OpCode::I32Sub,
+                OpCode::End
+            ]
+        )
+    }
+
+    #[test]
+    fn test_integer_negation() {
+        let source = "
+        (export defn neg32: i32 [n: i32] (- n))
+        (export defn neg64: i64 [n: i64] (- n))
+        (export defn neg_five: i32 [] (- 5))
+        (export defn neg_zero: i32 [] (- 0))
+        ";
+        for options in [CompileOptions::debug(), CompileOptions::release()] {
+            let module = &mut Module::with_options(options);
+            emit(module, source).unwrap();
+            validate(module).unwrap();
+            let instance = &mut Instance::new(module).unwrap();
+            assert_eq!(instance.invoke("neg_five", &[]), Ok(vec![Value::I32(-5)]));
+            assert_eq!(instance.invoke("neg_zero", &[]), Ok(vec![Value::I32(0)]));
+            for (n, expected) in [(5, -5), (-5, 5), (0, 0), (i32::MIN, i32::MIN)] {
+                assert_eq!(
+                    instance.invoke("neg32", &[Value::I32(n)]),
+                    Ok(vec![Value::I32(expected)]),
+                    "(- {})",
+                    n
+                );
+            }
+            for (n, expected) in [(5, -5), (0, 0), (i64::MIN, i64::MIN)] {
+                assert_eq!(
+                    instance.invoke("neg64", &[Value::I64(n)]),
+                    Ok(vec![Value::I64(expected)]),
+                    "(- {})",
+                    n
+                );
+            }
+        }
+    }
+    
+    #[test]
+    fn test_integer_ops() {
+        let module = &mut Module::default();
+        emit(
+            module,
+            "
+        (defn pack: i32 [a: i32 b: i32]
+            (bit-or (shl a 16) (bit-and b 65535)))
+        (defn hash: i32 [h: i32 c: i32]
+            (bit-xor (rotl h 5) (ushr c 2) (bit-not (shr c 1))))
+        (defn bits: i32 [n: i32]
+            (+ (clz n) (ctz n) (popcnt n)))
+        (defn wrap: i32 [a: i32 b: i32]
+            (+ (rem a b) (mod a b)))
+        ",
+        )
+        .unwrap();
+        let functions = module.functions.borrow();
+        assert_eq!(
+            functions["pack"].1.body,
+            vec![
+                OpCode::LocalGet(0),
+                OpCode::I32Const(16),
+                OpCode::I32Shl,
+                OpCode::LocalGet(1),
+                OpCode::I32Const(65535),
+                OpCode::I32And,
+                OpCode::I32Or,
+                OpCode::End
+            ]
+        );
+        assert_eq!(
+            functions["hash"].1.body,
             vec![
                 OpCode::LocalGet(0),
+                OpCode::I32Const(5),
+                OpCode::I32Rotl,
+                OpCode::LocalGet(1),
+                OpCode::I32Const(2),
+                OpCode::I32ShrU,
+                OpCode::I32Xor,
+                OpCode::LocalGet(1),
+                OpCode::I32Const(1),
+                OpCode::I32ShrS,
                 OpCode::I32Const(-1),
                 OpCode::I32Xor,
+                OpCode::I32Xor,
                 OpCode::End
             ]
+        );
+        assert_eq!(
+            functions["bits"].1.body,
+            vec![
+                OpCode::LocalGet(0),
+                OpCode::I32Clz,
+                OpCode::LocalGet(0),
+                OpCode::I32Ctz,
+                OpCode::I32Add,
+                OpCode::LocalGet(0),
+                OpCode::I32Popcnt,
+                OpCode::I32Add,
+                OpCode::End
+            ]
+        );
+        assert_eq!(
+            functions["wrap"].1.body,
+            vec![
+                OpCode::LocalGet(0),
+                OpCode::LocalGet(1),
+                OpCode::I32RemS,
+                OpCode::LocalGet(0),
+                OpCode::LocalGet(1),
+                OpCode::LocalDecl(WasmPrimitiveType::I32),
+                OpCode::LocalDecl(WasmPrimitiveType::I32),
+                OpCode::LocalTee(2),
+                OpCode::I32RemS,
+                OpCode::LocalTee(3),
+                OpCode::LocalGet(2),
+                OpCode::I32Add,
+                OpCode::LocalGet(3),
+                OpCode::LocalGet(3),
+                OpCode::I32Const(0),
+                OpCode::I32Ne,
+                OpCode::LocalGet(3),
+                OpCode::LocalGet(2),
+                OpCode::I32Xor,
+                OpCode::I32Const(0),
+                OpCode::I32LtS,
+                OpCode::I32And,
+                OpCode::Select,
+                OpCode::I32Add,
+                OpCode::End
+            ]
+        );
+    }
+
+    #[test]
+    fn test_floored_mod_extremes() {
+        let source = "
+        (export defn mod32: i32 [a: i32 b: i32] (mod a b))
+        (export defn mod64: i64 [a: i64 b: i64] (mod a b))
+        ";
+        let module = &mut Module::default();
+        emit(module, source).unwrap();
+        assert_eq!(
+            module.functions.borrow()["mod64"].1.body[2..7],
+            [
+                OpCode::LocalDecl(WasmPrimitiveType::I64),
+                OpCode::LocalDecl(WasmPrimitiveType::I64),
+                OpCode::LocalTee(2),
+                OpCode::I64RemS,
+                OpCode::LocalTee(3),
+            ]
+        );
+        for options in [CompileOptions::debug(), CompileOptions::release()] {
+            let module = &mut Module::with_options(options);
+            emit(module, source).unwrap();
+            validate(module).unwrap();
+            let instance = &mut Instance::new(module).unwrap();
+            for (a, b, expected) in [
+                (i32::MAX - 1, i32::MAX, i32::MAX - 1),
+                (-i32::MAX, i32::MIN, -i32::MAX),
+                (i32::MIN, i32::MAX, i32::MAX - 1),
+                (i32::MAX, i32::MIN, -1),
+                (i32::MIN, i32::MIN, 0),
+                (-7, 2, 1),
+                (7, -2, -1),
+            ] {
+                assert_eq!(
+                    instance.invoke("mod32", &[Value::I32(a), Value::I32(b)]),
+                    Ok(vec![Value::I32(expected)]),
+                    "(mod {} {})",
+                    a,
+                    b
+                );
+            }
+            for (a, b, expected) in [
+                (i64::MAX - 1, i64::MAX, i64::MAX - 1),
+                (-i64::MAX, i64::MIN, -i64::MAX),
+                (i64::MIN, i64::MAX, i64::MAX - 1),
+                (i64::MAX, i64::MIN, -1),
+                (i64::MIN, i64::MIN, 0),
+            ] {
+                assert_eq!(
+                    instance.invoke("mod64", &[Value::I64(a), Value::I64(b)]),
+                    Ok(vec![Value::I64(expected)]),
+                    "(mod {} {})",
+                    a,
+                    b
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_integer_ops_reject_floats() {
+        let module = &mut Module::default();
+        assert!(emit(module, "(defn f: i32 [a: f32] (shl a 1))").is_err());
+        let module = &mut Module::default();
+        assert!(emit(module, "(defn f: i32 [] (bit-and 1 2.0))").is_err());
+        let module = &mut Module::default();
+        assert!(emit(module, "(defn f: i32 [a: i32] (rem a))").is_err());
+    }
+
+    #[test]
+    fn test_unsigned_ops() {
+        let module = &mut Module::default();
+        emit(
+            module,
+            "
+        (defn less: bool [a: u32 b: u32]
+            (< a b))
+        (defn shift: u32 [a: u32]
+            (rem (shr a 3) 7))
+        (defn wide: u64 [a: u64 b: u64]
+            (mod (bit-xor a b) 4294967296))
+        ",
         )
+        .unwrap();
+        let functions = module.functions.borrow();
+        assert_eq!(
+            functions["less"].1.body,
+            vec![
+                OpCode::LocalGet(0),
+                OpCode::LocalGet(1),
+                OpCode::I32LtU,
+                OpCode::End
+            ]
+        );
+        assert_eq!(
+            functions["shift"].1.body,
+            vec![
+                OpCode::LocalGet(0),
+                OpCode::I32Const(3),
+                OpCode::I32ShrU,
+                OpCode::I32Const(7),
+                OpCode::I32RemU,
+                OpCode::End
+            ]
+        );
+        assert_eq!(
+            functions["wide"].1.body,
+            vec![
+                OpCode::LocalGet(0),
+                OpCode::LocalGet(1),
+                OpCode::I64Xor,
+                OpCode::I64Const(4294967296),
+                OpCode::I64RemU,
+                OpCode::End
+            ]
+        );
     }
-    
+
+    #[test]
+    fn test_signed_unsigned_mismatch() {
+        let module = &mut Module::default();
+        assert!(emit(module, "(defn f: bool [a: i32 b: u32] (< a b))").is_err());
+        let module = &mut Module::default();
+        assert!(emit(module, "(defn f: u32 [a: u32] (- a))").is_err());
+        let module = &mut Module::default();
+        assert!(emit(module, "(defn f: u32 [a: u32] (+ a -1))").is_err());
+    }
+
+    #[test]
+    fn test_float_intrinsics() {
+        let module = &mut Module::default();
+        emit(
+            module,
+            "
+        (defn length: f32 [x: f32 y: f32]
+            (sqrt (+ (* x x) (* y y))))
+        (defn clamp: f32 [v: f32 lo: i32]
+            (min (max v lo) 1.0))
+        (defn round: f32 [v: f32]
+            (copysign (nearest (abs v)) (floor (ceil (trunc v)))))
+        ",
+        )
+        .unwrap();
+        let functions = module.functions.borrow();
+        assert_eq!(
+            functions["length"].1.body,
+            vec![
+                OpCode::LocalGet(0),
+                OpCode::LocalGet(0),
+                OpCode::F32Mul,
+                OpCode::LocalGet(1),
+                OpCode::LocalGet(1),
+                OpCode::F32Mul,
+                OpCode::F32Add,
+                OpCode::F32Sqrt,
+                OpCode::End
+            ]
+        );
+        assert_eq!(
+            functions["clamp"].1.body,
+            vec![
+                OpCode::LocalGet(0),
+                OpCode::LocalGet(1),
+                OpCode::F32ConvertI32S,
+                OpCode::F32Max,
+                OpCode::F32Const(1.0),
+                OpCode::F32Min,
+                OpCode::End
+            ]
+        );
+        assert_eq!(
+            functions["round"].1.body,
+            vec![
+                OpCode::LocalGet(0),
+                OpCode::F32Abs,
+                OpCode::F32Nearest,
+                OpCode::LocalGet(0),
+                OpCode::F32Trunc,
+                OpCode::F32Ceil,
+                OpCode::F32Floor,
+                OpCode::F32Copysign,
+                OpCode::End
+            ]
+        );
+    }
+
+    #[test]
+    fn test_float_intrinsics_type_error() {
+        let module = &mut Module::default();
+        assert!(emit(module, "(defn f: f32 [a: bool] (sqrt a))").is_err());
+        let module = &mut Module::default();
+        assert!(emit(module, "(defn f: f32 [a: u32] (abs a))").is_err());
+        let module = &mut Module::default();
+        assert!(emit(module, "(defn f: f32 [a: f32] (min a))").is_err());
+    }
+
     #[test]
     fn test_bool() {
         let module = &mut Module::default();
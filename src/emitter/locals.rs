@@ -0,0 +1,213 @@
+use super::*;
+
+/// The order in which the encoder declares the locals of each type.
+const DECL_ORDER: [WasmPrimitiveType; 3] = [
+    WasmPrimitiveType::I32,
+    WasmPrimitiveType::I64,
+    WasmPrimitiveType::F32,
+];
+
+struct Interval {
+    local: usize,
+    t: WasmPrimitiveType,
+    start: usize,
+    end: usize,
+    /// Read before its first write, so it relies on the zero initial value.
+    reads_initial: bool,
+}
+
+fn local_index(code: &OpCode) -> Option<u32> {
+    match code {
+        OpCode::LocalGet(i) | OpCode::LocalSet(i) | OpCode::LocalTee(i) => Some(*i),
+        _ => None,
+    }
+}
+
+/// Assigns the locals declared in `body` to slots and renumbers them so that locals of
+/// the same type are adjacent, in the order the encoder declares them. With `reuse`,
+/// locals whose live ranges don't overlap share a slot. The body is left untouched
+/// when it is already laid out that way. Returns the new index of each local, `None`
+/// for locals that were never used and so dropped.
+///
+/// The k-th `LocalDecl` declares local `params + k`. The only loops wrap a whole body
+/// for self tail calls and only carry parameters over, so a local is live from its first
+/// to its last appearance in the body.
+pub(super) fn allocate_locals(
+    body: &mut Vec<OpCode>,
+    params: u32,
+    reuse: bool,
+) -> Vec<Option<u32>> {
+    let decls = body
+        .iter()
+        .filter_map(|code| match code {
+            OpCode::LocalDecl(t) => Some(*t),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let mut intervals: Vec<Option<Interval>> = (0..decls.len()).map(|_| None).collect();
+    for (pos, code) in body.iter().enumerate() {
+        let index = match local_index(code) {
+            Some(index) if index >= params => (index - params) as usize,
+            _ => continue,
+        };
+        if index >= decls.len() {
+            // A local without a declaration; its type is unknown.
+            return (0..params + decls.len() as u32).map(Some).collect();
+        }
+        match &mut intervals[index] {
+            Some(interval) => interval.end = pos,
+            slot => {
+                *slot = Some(Interval {
+                    local: index,
+                    t: decls[index],
+                    start: pos,
+                    end: pos,
+                    reads_initial: matches!(code, OpCode::LocalGet(_)),
+                })
+            }
+        }
+    }
+
+    // Slots per type, each holding the position after which it is free again.
+    let mut slots: Vec<Vec<usize>> = vec![Vec::new(); DECL_ORDER.len()];
+    let mut assigned = vec![None; decls.len()];
+    let mut live = intervals.into_iter().flatten().collect::<Vec<_>>();
+    live.sort_by_key(|interval| interval.start);
+    for interval in &live {
+        let group = DECL_ORDER.iter().position(|t| *t == interval.t).unwrap();
+        let free = if reuse && !interval.reads_initial {
+            slots[group].iter().position(|end| *end < interval.start)
+        } else {
+            None
+        };
+        let slot = match free {
+            Some(slot) => slot,
+            None => {
+                slots[group].push(0);
+                slots[group].len() - 1
+            }
+        };
+        // Only a slot whose local is never read before written can be handed on.
+        slots[group][slot] = if interval.reads_initial {
+            usize::MAX
+        } else {
+            interval.end
+        };
+        assigned[interval.local] = Some((group, slot));
+    }
+
+    let mut group_base = params as usize;
+    let mut bases = Vec::new();
+    for group in &slots {
+        bases.push(group_base);
+        group_base += group.len();
+    }
+    let new_indices = (0..params)
+        .map(Some)
+        .chain(
+            assigned
+                .iter()
+                .map(|slot| slot.map(|(group, slot)| (bases[group] + slot) as u32)),
+        )
+        .collect::<Vec<_>>();
+    let new_index = |index: u32| new_indices[index as usize].unwrap();
+    let unchanged = slots.iter().map(|group| group.len()).sum::<usize>() == decls.len()
+        && live.len() == decls.len()
+        && (0..decls.len() as u32).all(|i| new_index(params + i) == params + i);
+    if unchanged {
+        return new_indices;
+    }
+
+    let mut allocated = Vec::with_capacity(body.len());
+    for (group, t) in slots.iter().zip(DECL_ORDER) {
+        allocated.extend(group.iter().map(|_| OpCode::LocalDecl(t)));
+    }
+    for code in body.drain(..) {
+        allocated.push(match code {
+            OpCode::LocalDecl(_) => continue,
+            OpCode::LocalGet(i) => OpCode::LocalGet(new_index(i)),
+            OpCode::LocalSet(i) => OpCode::LocalSet(new_index(i)),
+            OpCode::LocalTee(i) => OpCode::LocalTee(new_index(i)),
+            code => code,
+        });
+    }
+    *body = allocated;
+    new_indices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_group_by_type() {
+        let module = &mut Module::default();
+        emit(
+            module,
+            "
+        (defn mixed: f32 [a: i32]
+            (let [x 1.5 y (+ a 1)]
+                (+ x y)))
+        ",
+        )
+        .unwrap();
+        assert_eq!(
+            module.functions.borrow()["mixed"].1.body,
+            vec![
+                OpCode::LocalDecl(WasmPrimitiveType::I32),
+                OpCode::LocalDecl(WasmPrimitiveType::F32),
+                OpCode::F32Const(1.5),
+                OpCode::LocalSet(2),
+                OpCode::LocalGet(0),
+                OpCode::I32Const(1),
+                OpCode::I32Add,
+                OpCode::LocalSet(1),
+                OpCode::LocalGet(2),
+                OpCode::LocalGet(1),
+                OpCode::F32ConvertI32S,
+                OpCode::F32Add,
+                OpCode::End
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reuse_slots() {
+        let mut body = vec![
+            OpCode::LocalDecl(WasmPrimitiveType::I32),
+            OpCode::I32Const(1),
+            OpCode::LocalSet(1),
+            OpCode::LocalGet(1),
+            OpCode::Drop,
+            OpCode::LocalDecl(WasmPrimitiveType::I32),
+            OpCode::LocalGet(0),
+            OpCode::LocalSet(2),
+            OpCode::LocalGet(2),
+            OpCode::LocalDecl(WasmPrimitiveType::I32),
+            OpCode::LocalGet(3),
+            OpCode::I32Add,
+            OpCode::End,
+        ];
+        assert_eq!(
+            allocate_locals(&mut body, 1, true),
+            vec![Some(0), Some(1), Some(1), Some(2)]
+        );
+        assert_eq!(
+            body,
+            vec![
+                OpCode::LocalDecl(WasmPrimitiveType::I32),
+                OpCode::LocalDecl(WasmPrimitiveType::I32),
+                OpCode::I32Const(1),
+                OpCode::LocalSet(1),
+                OpCode::LocalGet(1),
+                OpCode::Drop,
+                OpCode::LocalGet(0),
+                OpCode::LocalSet(1),
+                OpCode::LocalGet(1),
+                OpCode::LocalGet(2),
+                OpCode::I32Add,
+                OpCode::End
+            ]
+        );
+    }
+}
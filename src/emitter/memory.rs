@@ -0,0 +1,184 @@
+use super::*;
+use anyhow::Context;
+
+/// Most pages a memory with 32-bit addresses can have.
+const MAX_PAGES: u32 = 65536;
+
+fn check_layout(memory: &Memory) -> Result<()> {
+    ensure!(
+        memory.initial <= MAX_PAGES,
+        "initial memory of {} pages exceeds the limit of {} pages",
+        memory.initial,
+        MAX_PAGES
+    );
+    if let Some(max) = memory.max {
+        ensure!(
+            max <= MAX_PAGES,
+            "maximum memory of {} pages exceeds the limit of {} pages",
+            max,
+            MAX_PAGES
+        );
+        ensure!(
+            memory.initial <= max,
+            "initial memory of {} pages exceeds the maximum of {} pages",
+            memory.initial,
+            max
+        );
+    }
+    ensure!(
+        !memory.shared || memory.max.is_some(),
+        "shared memory needs a maximum size"
+    );
+    ensure!(
+        !(memory.import || memory.shared) || memory.stack_base != 0,
+        "imported or shared memory needs a stack base other than 0"
+    );
+    ensure!(
+        memory.stack_base.is_multiple_of(8),
+        "stack base {} is not a multiple of 8",
+        memory.stack_base
+    );
+    ensure!(
+        memory.stack_base as u64 + memory.stack_size as u64
+            <= memory.initial as u64 * PAGE_SIZE as u64,
+        "stack of {} bytes at {} does not fit in {} pages of memory",
+        memory.stack_size,
+        memory.stack_base,
+        memory.initial
+    );
+    Ok(())
+}
+
+fn number_option(key: &str, value: &AST) -> Result<u32> {
+    match value {
+        AST::NumberLiteral(literal) => literal
+            .parse()
+            .with_context(|| format!("invalid value {} for :{}", literal, key)),
+        _ => bail!(":{} expects a number", key),
+    }
+}
+
+fn bool_option(key: &str, value: &AST) -> Result<bool> {
+    match value {
+        AST::BoolLiteral(b) => Ok(*b),
+        _ => bail!(":{} expects true or false", key),
+    }
+}
+
+/// `(memory :initial 4 :max 64)` overrides the memory layout given by the compile
+/// options. `:shared`, `:import`, `:stack-base` and `:stack-size` are also accepted, with
+/// the meaning of the fields of `Memory`.
+fn emit_memory(memory: &mut Memory, forms: &[AST]) -> Result<()> {
+    for pair in forms.chunks(2) {
+        match pair {
+            [AST::Keyword(key), value] => match *key {
+                "initial" => memory.initial = number_option(key, value)?,
+                "max" => memory.max = Some(number_option(key, value)?),
+                "stack-base" => memory.stack_base = number_option(key, value)?,
+                "stack-size" => memory.stack_size = number_option(key, value)?,
+                "shared" => memory.shared = bool_option(key, value)?,
+                "import" => memory.import = bool_option(key, value)?,
+                _ => bail!("unknown memory option :{}", key),
+            },
+            _ => bail!("memory expects `:option value` pairs"),
+        }
+    }
+    Ok(())
+}
+
+/// Applies the `memory` forms of the main file and sets the builtin globals from the
+/// resulting layout.
+pub(super) fn emit_layout(module: &mut Module, toplevels: &[AST]) -> Result<()> {
+    for toplevel in toplevels {
+        if let AST::List(list) = toplevel {
+            if let Some(AST::Symbol("memory")) = list.first() {
+                emit_memory(&mut module.memory, &list[1..])?;
+            }
+        }
+    }
+    let memory = &module.memory;
+    check_layout(memory)?;
+    let mut globals = module.globals.borrow_mut();
+    globals.get_mut(STACK_POINTER.1).unwrap().1.value =
+        GlobalValue::I32(memory.stack_base as i32);
+    globals.get_mut(HEAP_BASE.1).unwrap().1.value = GlobalValue::I32(memory.heap_base() as i32);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory() {
+        // Shared memory needs the maximum given by the form.
+        let module = &mut Module::with_options(CompileOptions {
+            memory: Memory {
+                shared: true,
+                ..Memory::default()
+            },
+            ..CompileOptions::default()
+        });
+        emit(
+            module,
+            "(memory :initial 4 :max 64 :stack-base 1024 :stack-size 4096)
+            (defn heap: i32 [] __heap_base)",
+        )
+        .unwrap();
+        assert_eq!(
+            module.memory,
+            Memory {
+                initial: 4,
+                max: Some(64),
+                shared: true,
+                import: false,
+                stack_base: 1024,
+                stack_size: 4096,
+            }
+        );
+        let globals = module.globals.borrow();
+        assert_eq!(globals["__stack_pointer"].1.value, GlobalValue::I32(1024));
+        assert_eq!(globals["__heap_base"].1.value, GlobalValue::I32(5120));
+        assert_eq!(
+            module.functions.borrow()["heap"].1.body,
+            vec![OpCode::GlobalGet(HEAP_BASE.0), OpCode::End]
+        );
+    }
+
+    #[test]
+    fn test_invalid_memory() {
+        for (source, message) in [
+            (
+                "(memory :initial 4 :max 2)",
+                "initial memory of 4 pages exceeds the maximum of 2 pages",
+            ),
+            (
+                "(memory :shared true :stack-base 1024)",
+                "shared memory needs a maximum size",
+            ),
+            (
+                "(memory :import true)",
+                "imported or shared memory needs a stack base other than 0",
+            ),
+            (
+                "(memory :shared true :initial 1 :max 1)",
+                "imported or shared memory needs a stack base other than 0",
+            ),
+            ("(memory :stack-base 4)", "stack base 4 is not a multiple of 8"),
+            (
+                "(memory :initial 1 :stack-size 65537)",
+                "stack of 65537 bytes at 0 does not fit in 1 pages of memory",
+            ),
+            (
+                "(memory :initial 1 :stack-base 8)",
+                "stack of 65536 bytes at 8 does not fit in 1 pages of memory",
+            ),
+            ("(memory :initial)", "memory expects `:option value` pairs"),
+            ("(memory :pages 1)", "unknown memory option :pages"),
+        ] {
+            let module = &mut Module::default();
+            let error = emit(module, source).unwrap_err();
+            assert_eq!(error.to_string(), message);
+        }
+    }
+}
@@ -1,32 +1,62 @@
+mod constant;
+mod conversion;
+pub mod decoder;
+pub mod disasm;
 pub mod encoder;
 mod expression;
 mod function;
+mod inline;
+pub mod interpreter;
 mod intrinsic_ops;
+mod locals;
+mod memory;
+mod namespace;
+mod panic;
+mod start;
+mod peephole;
+mod source_map;
 mod special_forms;
+mod tail_call;
+pub mod testing;
+mod tree_shake;
+mod validator;
 mod global;
 mod vector;
 
 pub use encoder::compile_into_wasm;
+pub use source_map::line_column;
 
 use crate::{
+    diagnostic::{Diagnostic, Diagnostics},
     env::{Env, Pointer, Variable},
-    parser::{parse_source, TypeAST, AST},
+    parser::{parse_source_recovering, primitive_type_from_name, Spans, TypeAST, AST},
     resolver::{get_primitive_types, resolve_type, Type, TypeEnv},
 };
 
-use anyhow::{anyhow, bail, Result};
-use std::{cell::RefCell, collections::HashMap, fmt::Display, hash::Hash, rc::Rc};
+use anyhow::{anyhow, bail, ensure, Error, Result};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::Display,
+    hash::Hash,
+    ops::Range,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
 
 use self::{function::*, special_forms::*, global::*};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ExportKind {
     Func,
+    Memory,
+    Global,
 }
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
 pub enum WasmPrimitiveType {
     I32 = 0x7F,
+    I64 = 0x7E,
     F32 = 0x7D,
 }
 
@@ -34,7 +64,8 @@ pub enum WasmPrimitiveType {
 pub struct Export {
     pub export_type: ExportKind,
     pub name: String,
-    pub func_index: u32,
+    /// Index of the exported function, memory or global, by `export_type`.
+    pub index: u32,
 }
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
@@ -55,25 +86,79 @@ pub struct Function {
     pub arg_types: Vec<Rc<Type>>,
     pub result_type: Rc<Type>,
     pub body: Vec<OpCode>,
+    /// Declared with an `(inline)` attribute.
+    pub inline: bool,
+    /// Names of the arguments and `let` variables, by local index.
+    pub local_names: Vec<(u32, String)>,
+    /// Module and field the function is imported from, in which case it has no body.
+    pub import: Option<(String, String)>,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum GlobalValue {
     I32(i32),
+    I64(i64),
     F32(f32),
 }
 
+/// Size of a Wasm page in bytes.
+pub const PAGE_SIZE: u32 = 65536;
+
+/// Layout of the linear memory. The stack takes `stack_size` bytes from `stack_base` and
+/// grows upwards, and the heap starts where it ends.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Memory {
+    /// Initial size in pages.
+    pub initial: u32,
+    /// Maximum size in pages, if any.
+    pub max: Option<u32>,
+    /// Share the memory between threads, which needs a maximum size.
+    pub shared: bool,
+    /// Import the memory as `env.memory` instead of defining it.
+    pub import: bool,
+    /// Bottom of the stack. The data of the module is placed there, and `__stack_pointer`
+    /// starts past it. Imported and shared memory can hold the data of others at 0, so
+    /// they need it set elsewhere.
+    pub stack_base: u32,
+    /// Size of the stack in bytes.
+    pub stack_size: u32,
+}
+
+impl Memory {
+    /// Value of `__heap_base`, the first address past the stack.
+    pub fn heap_base(&self) -> u32 {
+        self.stack_base + self.stack_size
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Memory {
+            initial: 16,
+            max: None,
+            shared: false,
+            import: false,
+            stack_base: 0,
+            stack_size: PAGE_SIZE,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Global {
     is_mutable: bool,
     value: GlobalValue,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub enum OpCode {
+    Unreachable,
+    Loop(Option<WasmPrimitiveType>),
     If(Option<WasmPrimitiveType>),
     Else,
+    Br(u32),
     Drop,
+    Select,
     End,
     LocalGet(u32),
     LocalSet(u32),
@@ -82,27 +167,78 @@ pub enum OpCode {
     #[allow(dead_code)]
     GlobalSet(u32),
     LocalDecl(WasmPrimitiveType),
+    /// Marks the following instructions as emitted from the source at this byte offset.
+    /// Encodes to nothing.
+    SourceLoc(u32),
     Call(u32),
+    ReturnCall(u32),
     I32Store { offset: u32, alignment: u32 },
     I32Store8 { offset: u32, alignment: u32 },
     I32Load { offset: u32, alignment: u32 },
     I32Load8U { offset: u32, alignment: u32 },
+    I64Store { offset: u32, alignment: u32 },
+    I64Load { offset: u32, alignment: u32 },
     F32Store { offset: u32, alignment: u32 },
     F32Load { offset: u32, alignment: u32 },
     I32Const(i32),
+    I64Const(i64),
     F32Const(f32),
     I32Add,
     I32Sub,
     I32Mul,
     I32DivS,
+    I32DivU,
+    I32RemS,
+    I32RemU,
     I32Xor,
     I32Eq,
+    I32Ne,
     I32GtS,
+    I32GtU,
     I32GeS,
+    I32GeU,
     I32And,
     I32Or,
+    I32Shl,
+    I32ShrS,
+    I32ShrU,
+    I32Rotl,
+    I32Rotr,
+    I32Clz,
+    I32Ctz,
+    I32Popcnt,
     I32LtS,
+    I32LtU,
     I32LeS,
+    I32LeU,
+    I64Add,
+    I64Sub,
+    I64Mul,
+    I64DivS,
+    I64DivU,
+    I64RemS,
+    I64RemU,
+    I64And,
+    I64Or,
+    I64Xor,
+    I64Shl,
+    I64ShrS,
+    I64ShrU,
+    I64Rotl,
+    I64Rotr,
+    I64Clz,
+    I64Ctz,
+    I64Popcnt,
+    I64Eq,
+    I64Ne,
+    I64LtS,
+    I64LtU,
+    I64GtS,
+    I64GtU,
+    I64LeS,
+    I64LeU,
+    I64GeS,
+    I64GeU,
     F32Add,
     F32Sub,
     F32Mul,
@@ -112,8 +248,110 @@ pub enum OpCode {
     F32Ge,
     F32Lt,
     F32Le,
+    F32Abs,
     F32Neg,
+    F32Ceil,
+    F32Floor,
+    F32Trunc,
+    F32Nearest,
+    F32Sqrt,
+    F32Min,
+    F32Max,
+    F32Copysign,
     F32ConvertI32S,
+    F32ConvertI32U,
+    F32ConvertI64S,
+    F32ConvertI64U,
+    I32WrapI64,
+    I32TruncF32S,
+    I32TruncF32U,
+    I64ExtendI32S,
+    I64ExtendI32U,
+    I64TruncF32S,
+    I64TruncF32U,
+    I32TruncSatF32S,
+    I32TruncSatF32U,
+    I64TruncSatF32S,
+    I64TruncSatF32U,
+    I32ReinterpretF32,
+    F32ReinterpretI32,
+}
+
+#[derive(Debug, Clone)]
+pub struct CompileOptions {
+    /// Trap with `unreachable` when an array index is out of range.
+    pub bounds_check: bool,
+    /// Optimization level. `0` emits the program as written and `1` and above fold
+    /// constant expressions, run the peephole pass over function bodies and remove
+    /// functions and globals unreachable from the exports. `2` also inlines small leaf
+    /// functions.
+    pub opt_level: u8,
+    /// Maximum body size, in instructions, of functions inlined without an `(inline)`
+    /// attribute.
+    pub inline_threshold: usize,
+    /// Emit `return_call` for calls in tail position, which needs a runtime supporting
+    /// the tail call proposal. Self tail calls always become loops.
+    pub tail_calls: bool,
+    /// Report what the optimizations removed on stderr.
+    pub verbose: bool,
+    /// Write function, local and global names into a `name` custom section.
+    pub name_section: bool,
+    /// Mark instructions with the source location of the form they were emitted from,
+    /// for source maps.
+    pub source_map: bool,
+    /// Name to export the linear memory under, if any.
+    pub memory_export: Option<String>,
+    /// Memory layout, unless the program sets one with a `memory` form.
+    pub memory: Memory,
+    /// Emit the `deftest` forms, which are otherwise ignored.
+    pub tests: bool,
+    /// Report the failures of `assert` and `panic` to the host through
+    /// `env.__wisp_panic` before trapping. Without it they are a bare `unreachable`.
+    pub panic_messages: bool,
+    /// Record the type of every symbol resolved through the environment in
+    /// `Module::symbol_types`, for editors.
+    pub symbol_types: bool,
+}
+
+impl CompileOptions {
+    pub fn debug() -> Self {
+        CompileOptions {
+            bounds_check: true,
+            opt_level: 0,
+            inline_threshold: 8,
+            tail_calls: false,
+            verbose: false,
+            name_section: true,
+            source_map: false,
+            memory_export: Some("memory".to_string()),
+            memory: Memory::default(),
+            tests: false,
+            panic_messages: true,
+            symbol_types: false,
+        }
+    }
+    pub fn release() -> Self {
+        CompileOptions {
+            bounds_check: false,
+            opt_level: 2,
+            inline_threshold: 8,
+            tail_calls: false,
+            verbose: false,
+            name_section: true,
+            source_map: false,
+            memory_export: Some("memory".to_string()),
+            memory: Memory::default(),
+            tests: false,
+            panic_messages: true,
+            symbol_types: false,
+        }
+    }
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions::debug()
+    }
 }
 
 #[derive(Debug, Default)]
@@ -122,6 +360,43 @@ pub struct Module {
     pub exports: Vec<Export>,
     pub functions: Rc<RefCell<HashMap<String, (u32, Function)>>>,
     pub globals: Rc<RefCell<HashMap<String, (u32, Global)>>>,
+    pub memory: Memory,
+    pub options: CompileOptions,
+    /// The source being emitted. The AST borrows from it, so `SourceLoc` offsets are
+    /// relative to its start.
+    pub source: Rc<str>,
+    /// The byte ranges of the nodes of `source` while it is being emitted.
+    pub(super) spans: Spans,
+    /// The `SourceLoc` of the form being emitted.
+    pub(super) source_loc: Option<OpCode>,
+    /// The files being emitted, each required by the one before it.
+    pub(super) files: Vec<PathBuf>,
+    /// The namespace of every file required so far.
+    pub(super) namespaces: HashMap<PathBuf, String>,
+    /// The index of the function run when the module is instantiated.
+    pub start: Option<u32>,
+    /// The code of the start function emitted so far.
+    pub(super) initializers: Option<start::Initializers>,
+    /// Bytes placed at `memory.stack_base` when the module is instantiated, such as the
+    /// messages of `panic`.
+    pub data: Vec<u8>,
+    /// With `symbol_types`, the symbols of `source` resolved through the environment and
+    /// their type.
+    pub symbol_types: Vec<(Range<usize>, Rc<Type>)>,
+    /// The errors of the main file found so far, which the compiler goes on after.
+    pub(super) errors: Vec<Error>,
+    /// How many times the compiler went on after an error, including the errors not
+    /// reported because they follow from another one.
+    pub(super) recovered: usize,
+}
+
+impl Module {
+    pub fn with_options(options: CompileOptions) -> Self {
+        Module {
+            options,
+            ..Default::default()
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -138,6 +413,20 @@ enum IntrinsicOperator {
     And,
     Or,
     Not,
+    Mod,
+    Rem,
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    Shl,
+    Shr,
+    UShr,
+    Rotl,
+    Rotr,
+    Clz,
+    Ctz,
+    Popcnt,
 }
 
 impl Display for IntrinsicOperator {
@@ -158,6 +447,71 @@ impl Display for IntrinsicOperator {
                 IntrinsicOperator::And => "and",
                 IntrinsicOperator::Or => "or",
                 IntrinsicOperator::Not => "not",
+                IntrinsicOperator::Mod => "mod",
+                IntrinsicOperator::Rem => "rem",
+                IntrinsicOperator::BitAnd => "bit-and",
+                IntrinsicOperator::BitOr => "bit-or",
+                IntrinsicOperator::BitXor => "bit-xor",
+                IntrinsicOperator::BitNot => "bit-not",
+                IntrinsicOperator::Shl => "shl",
+                IntrinsicOperator::Shr => "shr",
+                IntrinsicOperator::UShr => "ushr",
+                IntrinsicOperator::Rotl => "rotl",
+                IntrinsicOperator::Rotr => "rotr",
+                IntrinsicOperator::Clz => "clz",
+                IntrinsicOperator::Ctz => "ctz",
+                IntrinsicOperator::Popcnt => "popcnt",
+            }
+        )
+    }
+}
+
+/// Built-in functions on f32 that map to a single Wasm instruction.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum IntrinsicFunction {
+    Sqrt,
+    Abs,
+    Floor,
+    Ceil,
+    Trunc,
+    Nearest,
+    Min,
+    Max,
+    Copysign,
+}
+
+impl IntrinsicFunction {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "sqrt" => IntrinsicFunction::Sqrt,
+            "abs" => IntrinsicFunction::Abs,
+            "floor" => IntrinsicFunction::Floor,
+            "ceil" => IntrinsicFunction::Ceil,
+            "trunc" => IntrinsicFunction::Trunc,
+            "nearest" => IntrinsicFunction::Nearest,
+            "min" => IntrinsicFunction::Min,
+            "max" => IntrinsicFunction::Max,
+            "copysign" => IntrinsicFunction::Copysign,
+            _ => return None,
+        })
+    }
+}
+
+impl Display for IntrinsicFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                IntrinsicFunction::Sqrt => "sqrt",
+                IntrinsicFunction::Abs => "abs",
+                IntrinsicFunction::Floor => "floor",
+                IntrinsicFunction::Ceil => "ceil",
+                IntrinsicFunction::Trunc => "trunc",
+                IntrinsicFunction::Nearest => "nearest",
+                IntrinsicFunction::Min => "min",
+                IntrinsicFunction::Max => "max",
+                IntrinsicFunction::Copysign => "copysign",
             }
         )
     }
@@ -165,11 +519,24 @@ impl Display for IntrinsicOperator {
 
 fn emit_toplevel(module: &mut Module, ast: &AST, env: Rc<RefCell<Env>>) -> Result<()> {
     match ast {
-        AST::List(list) => match list.first().unwrap() {
-            AST::Symbol(s) => match *s {
-                "defn" | "export" => emit_func(module, ast, env),
-                "define" => emit_global(module, &list[1..], false, env),
-                "defmut" => emit_global(module, &list[1..], true, env),
+        AST::List(list) => match list.first() {
+            Some(AST::Symbol(s)) => match *s {
+                "export" => match list.get(1) {
+                    Some(AST::Symbol("define")) => emit_global(module, &list[2..], false, true, env),
+                    Some(AST::Symbol("defmut")) => emit_global(module, &list[2..], true, true, env),
+                    _ => emit_func(module, ast, env),
+                },
+                "defn" => emit_func(module, ast, env),
+                "init" => start::emit_init(module, &list[1..], &env),
+                "deftest" => testing::emit_test(module, ast, &env),
+                // Emitted before the other forms of the file.
+                "ns" | "require" => Ok(()),
+                "memory" => {
+                    ensure!(module.files.len() <= 1, "memory can only be configured in the main file");
+                    Ok(())
+                }
+                "define" => emit_global(module, &list[1..], false, false, env),
+                "defmut" => emit_global(module, &list[1..], true, false, env),
                 _ => bail!("Top level form must be function decl or global variable, found {:?}", s),
             },
             _ => bail!("Top level form must be function decl or global variable, found {:?}", ast),
@@ -178,30 +545,164 @@ fn emit_toplevel(module: &mut Module, ast: &AST, env: Rc<RefCell<Env>>) -> Resul
     }
 }
 
-const STACK_POINTER: (u32, Global) = (0, Global {
-    is_mutable: true,
-    value: GlobalValue::I32(1048576)
-});
+const STACK_POINTER: (u32, &str) = (0, "__stack_pointer");
+const HEAP_BASE: (u32, &str) = (1, "__heap_base");
 
-fn emit_builtin_vars(module: &mut Module) -> Result<()> {
-    module.globals.borrow_mut().insert("__stack_pointer".to_string(),  STACK_POINTER);
+fn emit_builtin_vars(module: &mut Module, env: &Rc<RefCell<Env>>) -> Result<()> {
+    {
+        // Both are set from the memory layout.
+        let mut globals = module.globals.borrow_mut();
+        globals.insert(STACK_POINTER.1.to_string(), (STACK_POINTER.0, Global {
+            is_mutable: true,
+            value: GlobalValue::I32(0),
+        }));
+        globals.insert(HEAP_BASE.1.to_string(), (HEAP_BASE.0, Global {
+            is_mutable: false,
+            value: GlobalValue::I32(0),
+        }));
+    }
+    env.borrow_mut().set(HEAP_BASE.1, Variable {
+        pointer: Pointer::Global(HEAP_BASE.0),
+        t: Rc::new(Type::I32),
+    });
+    module.memory = module.options.memory.clone();
     Ok(())
 }
 
-fn emit_module(module: &mut Module, ast: &AST) -> Result<()> {
-    let env = Env::create();
-    emit_builtin_vars(module)?;
+/// Whether errors are recorded in `module.errors` to go on compiling, which is only done in
+/// the main file as the spans of the errors are relative to it.
+fn recovers(module: &Module) -> bool {
+    module.files.len() <= 1
+}
+
+/// Records `error`, raised by a form emitted since `module.recovered` was `recovered`,
+/// unless it follows from an error recovered from inside the form.
+fn recover(module: &mut Module, error: Error, recovered: usize) {
+    if module.recovered == recovered {
+        module.errors.push(error);
+    }
+    module.recovered += 1;
+}
+
+/// Locates the error of `toplevel`, if it failed, and records it to go on with the next
+/// forms when recovering.
+fn check_toplevel(module: &mut Module, toplevel: &AST, recovered: usize, result: Result<()>) -> Result<()> {
+    let error = match result {
+        Ok(()) => return Ok(()),
+        Err(error) => Diagnostic::locate(error, module.spans.get(toplevel)),
+    };
+    if !recovers(module) {
+        return Err(error);
+    }
+    recover(module, error, recovered);
+    Ok(())
+}
+
+/// Emits the toplevel forms of a file, after the files it requires.
+fn emit_toplevels(module: &mut Module, ast: &AST, env: &Rc<RefCell<Env>>) -> Result<()> {
     let toplevels = match ast {
         AST::Module(tops) => tops,
         _ => return Err(anyhow!("Invalid argument.")),
     };
+    // The memory layout comes first, as code reading the globals set from it may be
+    // folded.
+    if module.files.len() <= 1 {
+        memory::emit_layout(module, toplevels)?;
+    }
     for toplevel in toplevels {
-        emit_toplevel(module, toplevel, env.clone())?;
+        if let AST::List(list) = toplevel {
+            let recovered = module.recovered;
+            let result = match list.first() {
+                Some(AST::Symbol("ns")) => namespace::emit_ns(&list[1..], env),
+                Some(AST::Symbol("require")) => namespace::emit_require(module, &list[1..], env),
+                _ => Ok(()),
+            };
+            check_toplevel(module, toplevel, recovered, result)?;
+        }
+    }
+    // Declare functions up front so that they can call each other regardless of order.
+    let mut undeclared = Vec::new();
+    for (i, toplevel) in toplevels.iter().enumerate() {
+        if let AST::List(list) = toplevel {
+            if let [AST::Symbol("defn"), ..] | [AST::Symbol("export"), AST::Symbol("defn"), ..] =
+                list.as_slice()
+            {
+                let recovered = module.recovered;
+                let result = declare_func(module, toplevel, env);
+                if result.is_err() {
+                    undeclared.push(i);
+                }
+                check_toplevel(module, toplevel, recovered, result)?;
+            }
+        }
+    }
+    for (i, toplevel) in toplevels.iter().enumerate() {
+        // The error of a function that could not be declared is reported already.
+        if undeclared.contains(&i) {
+            continue;
+        }
+        let recovered = module.recovered;
+        let result = emit_toplevel(module, toplevel, env.clone());
+        check_toplevel(module, toplevel, recovered, result)?;
+    }
+    Ok(())
+}
+
+fn emit_module(module: &mut Module, ast: &AST) -> Result<()> {
+    let env = Env::create();
+    emit_builtin_vars(module, &env)?;
+    emit_toplevels(module, ast, &env)?;
+    if !module.errors.is_empty() {
+        return Ok(());
+    }
+    start::emit_start_function(module)?;
+    tree_shake::imports_first(module);
+    panic::emit_data_layout(module)?;
+    if module.options.opt_level > 0 {
+        let removed = tree_shake::remove_unused(module);
+        if module.options.verbose {
+            for name in &removed.functions {
+                eprintln!("removed unused function {}", name);
+            }
+            for name in &removed.globals {
+                eprintln!("removed unused global {}", name);
+            }
+        }
+    }
+    if let Some(name) = module.options.memory_export.clone() {
+        ensure!(
+            module.exports.iter().all(|export| export.name != name),
+            "duplicate export {}",
+            name
+        );
+        module.exports.push(Export {
+            export_type: ExportKind::Memory,
+            name,
+            index: 0,
+        });
     }
     Ok(())
 }
 
+/// Emits the program in `source`. Fails with every error found in it, which are
+/// `Diagnostics` when there are several.
 pub fn emit(module: &mut Module, source: &str) -> Result<()> {
-    let module_ast = parse_source(source)?;
-    emit_module(module, &module_ast)
+    module.source = source.into();
+    let source = module.source.clone();
+    let (module_ast, spans, errors) = parse_source_recovering(&source);
+    module.spans = spans;
+    module.errors = errors;
+    let result = emit_module(module, &module_ast);
+    // The nodes are dropped with `module_ast`.
+    module.spans = Spans::default();
+    let mut errors = std::mem::take(&mut module.errors);
+    errors.extend(result.err());
+    Diagnostics::check(errors)
+}
+
+/// Like `emit`, with `require` forms resolved relative to `path`, the file `source` was
+/// read from.
+pub fn emit_with_path(module: &mut Module, source: &str, path: &Path) -> Result<()> {
+    namespace::set_root_file(module, path);
+    emit(module, source)
 }
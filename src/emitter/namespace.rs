@@ -0,0 +1,210 @@
+use super::*;
+use crate::parser::parse_source;
+use anyhow::{ensure, Context};
+use std::path::{Path, PathBuf};
+
+/// `(ns name)` names the namespace of the file it appears in.
+pub(super) fn emit_ns(forms: &[AST], env: &Rc<RefCell<Env>>) -> Result<()> {
+    match forms {
+        [AST::Symbol(name)] => {
+            ensure!(
+                !name.contains('/'),
+                "namespace {} must not contain `/`",
+                name
+            );
+            env.borrow_mut().set_namespace(name);
+            Ok(())
+        }
+        _ => bail!("ns expects a namespace name"),
+    }
+}
+
+/// The absolute path of a file, so that each file is loaded once however it is referred
+/// to.
+fn normalize(path: &Path) -> Result<PathBuf> {
+    path.canonicalize()
+        .with_context(|| format!("cannot read {}", path.display()))
+}
+
+/// `(require "file.wisp" :as alias)` emits the file, relative to the including one,
+/// into the module unless it has been already, and lets the includer refer to its
+/// definitions as `alias/name`, or by its namespace without `:as`. A file without an
+/// `ns` form is in the namespace named after the file.
+pub(super) fn emit_require(
+    module: &mut Module,
+    forms: &[AST],
+    env: &Rc<RefCell<Env>>,
+) -> Result<()> {
+    let (file, alias) = match forms {
+        [AST::StringLiteral(file)] => (*file, None),
+        [AST::StringLiteral(file), AST::Keyword("as"), AST::Symbol(alias)] => (*file, Some(*alias)),
+        _ => bail!("require expects a file name and an optional `:as` alias"),
+    };
+    let base = module
+        .files
+        .last()
+        .and_then(|path| path.parent())
+        .unwrap_or(Path::new(""));
+    let path = normalize(&base.join(file))?;
+    if let Some(pos) = module.files.iter().position(|f| *f == path) {
+        let cycle = module.files[pos..]
+            .iter()
+            .chain([&path])
+            .map(|f| f.display().to_string())
+            .collect::<Vec<_>>();
+        bail!("import cycle: {}", cycle.join(" -> "));
+    }
+
+    let namespace = match module.namespaces.get(&path) {
+        Some(namespace) => namespace.clone(),
+        None => {
+            let source = std::fs::read_to_string(&path)
+                .with_context(|| format!("cannot read {}", path.display()))?;
+            let file_name = path.file_stem().unwrap().to_string_lossy().to_string();
+            let outer = env.borrow_mut().enter_namespace(Some(file_name));
+            module.files.push(path.clone());
+            let result = parse_source(&source).and_then(|ast| emit_toplevels(module, &ast, env));
+            module.files.pop();
+            let namespace = env.borrow().namespace().unwrap().to_string();
+            env.borrow_mut().exit_namespace(outer);
+            // Spans are relative to the required file, so only its path is kept.
+            let result = result.map_err(|error| match error.downcast::<Diagnostic>() {
+                Ok(diagnostic) => diagnostic.error,
+                Err(error) => error,
+            });
+            result.with_context(|| format!("in {}", path.display()))?;
+            module.namespaces.insert(path, namespace.clone());
+            namespace
+        }
+    };
+    env.borrow_mut()
+        .add_alias(alias.unwrap_or(&namespace), &namespace);
+    Ok(())
+}
+
+/// Registers `path` as the file `emit` is given the source of, for `require` to resolve
+/// files against.
+pub(super) fn set_root_file(module: &mut Module, path: &Path) {
+    let path = normalize(path).unwrap_or_else(|_| path.to_path_buf());
+    module.files = vec![path];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory of test files, removed when dropped.
+    struct TestDir(PathBuf);
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Writes `files` to a directory of their own, named after the test and the process
+    /// so that concurrent runs do not share it.
+    fn write_files(test: &str, files: &[(&str, &str)]) -> TestDir {
+        let dir = std::env::temp_dir().join(format!("wisp_{}_{}", test, std::process::id()));
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        for (name, source) in files {
+            std::fs::write(dir.join(name), source).unwrap();
+        }
+        TestDir(dir)
+    }
+
+    #[test]
+    fn test_require() {
+        let dir = write_files(
+            "test_require",
+            &[
+                (
+                    "lib/math.wisp",
+                    "(ns m2)
+                    (define scale: i32 3)
+                    (defn square: i32 [x: i32] (* x x))
+                    (defn scaled: i32 [x: i32] (* scale (square x)))",
+                ),
+                (
+                    "geometry.wisp",
+                    "(require \"lib/math.wisp\" :as m)
+                    (defn area: i32 [w: i32] (m/scaled w))",
+                ),
+            ],
+        );
+        let module = &mut Module::default();
+        let main = dir.0.join("main.wisp");
+        set_root_file(module, &main);
+        emit(
+            module,
+            "(require \"geometry.wisp\")
+            (require \"lib/math.wisp\" :as math)
+            (defn square: i32 [x: i32] x)
+            (export defn main: i32 [x: i32] (+ (geometry/area x) (math/square x) (square x)))",
+        )
+        .unwrap();
+        let functions = module.functions.borrow();
+        let index = |name: &str| functions[name].0;
+        assert_eq!(
+            functions["m2/scaled"].1.body,
+            vec![
+                OpCode::GlobalGet(2),
+                OpCode::LocalGet(0),
+                OpCode::Call(index("m2/square")),
+                OpCode::I32Mul,
+                OpCode::End
+            ]
+        );
+        assert_eq!(
+            functions["geometry/area"].1.body,
+            vec![
+                OpCode::LocalGet(0),
+                OpCode::Call(index("m2/scaled")),
+                OpCode::End
+            ]
+        );
+        assert_eq!(
+            functions["main"].1.body,
+            vec![
+                OpCode::LocalGet(0),
+                OpCode::Call(index("geometry/area")),
+                OpCode::LocalGet(0),
+                OpCode::Call(index("m2/square")),
+                OpCode::I32Add,
+                OpCode::LocalGet(0),
+                OpCode::Call(index("square")),
+                OpCode::I32Add,
+                OpCode::End
+            ]
+        );
+        assert_eq!(module.globals.borrow()["m2/scale"].0, 2);
+        assert_eq!(module.exports[0].name, "main");
+    }
+
+    #[test]
+    fn test_import_cycle() {
+        let dir = write_files(
+            "test_import_cycle",
+            &[
+                ("a.wisp", "(require \"lib/b.wisp\")"),
+                ("lib/b.wisp", "(require \"../a.wisp\")"),
+            ],
+        );
+        let module = &mut Module::default();
+        set_root_file(module, &dir.0.join("main.wisp"));
+        let error = emit(module, "(require \"a.wisp\")").unwrap_err();
+        let a = dir.0.join("a.wisp").canonicalize().unwrap();
+        let b = dir.0.join("lib/b.wisp").canonicalize().unwrap();
+        assert_eq!(
+            format!("{:#}", error),
+            format!(
+                "in {}: in {}: import cycle: {} -> {} -> {}",
+                a.display(),
+                b.display(),
+                a.display(),
+                b.display(),
+                a.display()
+            )
+        );
+    }
+}
@@ -0,0 +1,224 @@
+use super::{expression::emit_obj, *};
+use anyhow::{bail, ensure};
+
+/// Name of the function imported from `env` that `assert` and `panic` report failures
+/// to. It takes the address and length of the message and the line of the failing form.
+pub(super) const PANIC_HOOK: &str = "__wisp_panic";
+
+/// The index of the panic hook, imported when first used.
+fn panic_hook(module: &mut Module) -> Result<u32> {
+    if let Some((index, func)) = module.functions.borrow().get(PANIC_HOOK) {
+        ensure!(func.import.is_some(), "{} is reserved for the panic hook", PANIC_HOOK);
+        return Ok(*index);
+    }
+    let signature_index = signature_index(
+        module,
+        Signature {
+            sig_type: SignatureType::Func,
+            params: vec![WasmPrimitiveType::I32; 3],
+            results: Vec::new(),
+        },
+    );
+    let mut functions = module.functions.borrow_mut();
+    let index = functions.len() as u32;
+    functions.insert(
+        PANIC_HOOK.to_string(),
+        (
+            index,
+            Function {
+                signature_index: signature_index as u32,
+                arg_types: (0..3).map(|_| Rc::new(Type::I32)).collect(),
+                result_type: Rc::new(Type::Unit),
+                body: Vec::new(),
+                inline: false,
+                local_names: Vec::new(),
+                import: Some(("env".to_string(), PANIC_HOOK.to_string())),
+            },
+        ),
+    );
+    Ok(index)
+}
+
+/// The address of `message` in the data of the module, added to it unless it is there
+/// already.
+fn data_address(module: &mut Module, message: &str) -> u32 {
+    let bytes = message.as_bytes();
+    let offset = match module.data.windows(bytes.len().max(1)).position(|w| w == bytes) {
+        Some(offset) => offset,
+        None => {
+            module.data.extend_from_slice(bytes);
+            module.data.len() - bytes.len()
+        }
+    };
+    module.memory.stack_base + offset as u32
+}
+
+/// Emits a trap, reported to the host with `message` unless panic messages are off.
+pub(super) fn emit_failure(module: &mut Module, codes: &mut Vec<OpCode>, message: &str, ast: &AST) -> Result<()> {
+    if module.options.panic_messages {
+        let hook = panic_hook(module)?;
+        let address = data_address(module, message);
+        codes.push(OpCode::I32Const(address as i32));
+        codes.push(OpCode::I32Const(message.len() as i32));
+        codes.push(OpCode::I32Const(source_map::source_line(module, ast) as i32));
+        codes.push(OpCode::Call(hook));
+    }
+    codes.push(OpCode::Unreachable);
+    Ok(())
+}
+
+/// `(panic "message")` stops the program with `message`. As it never returns, it fits
+/// where a value of any type is expected, such as a branch of an `if`.
+pub(super) fn emit_panic(module: &mut Module, codes: &mut Vec<OpCode>, ast: &AST) -> Result<Rc<Type>> {
+    let message = match ast {
+        AST::List(list) => match &list[1..] {
+            [AST::StringLiteral(message)] => *message,
+            _ => bail!("panic expects a message string"),
+        },
+        _ => bail!("Invalid argument."),
+    };
+    emit_failure(module, codes, message, ast)?;
+    Ok(Rc::new(Type::Never))
+}
+
+/// `(assert cond "message")` stops the program with `message` unless `cond` holds. The
+/// message is optional.
+pub(super) fn emit_assert(
+    module: &mut Module,
+    codes: &mut Vec<OpCode>,
+    ast: &AST,
+    env: Rc<RefCell<Env>>,
+) -> Result<Rc<Type>> {
+    let (condition, message) = match ast {
+        AST::List(list) => match &list[1..] {
+            [condition] => (condition, "assertion failed"),
+            [condition, AST::StringLiteral(message)] => (condition, *message),
+            _ => bail!("assert expects a condition and an optional message string"),
+        },
+        _ => bail!("Invalid argument."),
+    };
+    ensure!(
+        *emit_obj(module, codes, condition, env)? == Type::Bool,
+        "assert expects a bool condition"
+    );
+    codes.push(OpCode::If(None));
+    codes.push(OpCode::Else);
+    emit_failure(module, codes, message, ast)?;
+    codes.push(OpCode::End);
+    Ok(Rc::new(Type::Unit))
+}
+
+/// Moves the stack pointer past the data placed at the bottom of the stack, keeping it
+/// 8-byte aligned.
+pub(super) fn emit_data_layout(module: &mut Module) -> Result<()> {
+    let memory = &module.memory;
+    let data_end = memory.stack_base as u64 + (module.data.len() as u64).next_multiple_of(8);
+    ensure!(
+        data_end <= memory.heap_base() as u64,
+        "data of {} bytes does not fit in the stack of {} bytes",
+        module.data.len(),
+        memory.stack_size
+    );
+    module.globals.borrow_mut().get_mut(STACK_POINTER.1).unwrap().1.value =
+        GlobalValue::I32(data_end as i32);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::validator::validate, *};
+
+    const SOURCE: &str = "
+        (defn check: i32 [x: i32]
+            (assert (> x 0) \"x must be positive\")
+            (if (> x 9) (panic \"x must be positive\") (panic \"too big\"))
+            x)
+        (export defn main: i32 [] (check 1))
+        ";
+
+    #[test]
+    fn test_panic() {
+        let module = &mut Module::default();
+        emit(module, SOURCE).unwrap();
+        let functions = module.functions.borrow();
+        let (hook, panic_hook) = &functions[PANIC_HOOK];
+        // Imports come first.
+        assert_eq!(*hook, 0);
+        assert_eq!(panic_hook.import, Some(("env".to_string(), PANIC_HOOK.to_string())));
+        assert_eq!(module.data, b"x must be positivetoo big");
+        assert_eq!(module.globals.borrow()[STACK_POINTER.1].1.value, GlobalValue::I32(32));
+        assert_eq!(
+            functions["check"].1.body[..12],
+            [
+                OpCode::LocalGet(0),
+                OpCode::I32Const(0),
+                OpCode::I32GtS,
+                OpCode::If(None),
+                OpCode::Else,
+                OpCode::I32Const(0),
+                OpCode::I32Const(18),
+                OpCode::I32Const(3),
+                OpCode::Call(0),
+                OpCode::Unreachable,
+                OpCode::End,
+                OpCode::LocalGet(0),
+            ]
+        );
+        // The second message reuses the first one.
+        let calls = functions["check"].1.body.windows(4)
+            .filter_map(|codes| match codes {
+                [OpCode::I32Const(address), OpCode::I32Const(len), _, OpCode::Call(0)] => Some((*address, *len)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(calls, vec![(0, 18), (0, 18), (18, 7)]);
+        assert_eq!(functions["main"].1.body, vec![OpCode::I32Const(1), OpCode::Call(1), OpCode::End]);
+    }
+
+    #[test]
+    fn test_panic_in_branch() {
+        let module = &mut Module::default();
+        emit(
+            module,
+            "
+        (export defn checked: i32 [x: i32]
+            (if (> x 0) x (panic \"x must be positive\")))
+        (export defn fail: i64 [x: i32]
+            (if (> x 0) (panic \"too big\") (panic \"too small\")))
+        ",
+        )
+        .unwrap();
+        validate(module).unwrap();
+        let functions = module.functions.borrow();
+        assert_eq!(functions["checked"].1.body[3], OpCode::If(Some(WasmPrimitiveType::I32)));
+        assert_eq!(functions["fail"].1.body[3], OpCode::If(None));
+        assert!(functions["fail"].1.body.ends_with(&[OpCode::End, OpCode::Unreachable, OpCode::End]));
+        let module = &mut Module::default();
+        let error = emit(module, "(defn f: i32 [] (let [x (panic \"x\")] 1))").unwrap_err();
+        assert_eq!(error.to_string(), "cannot bind x to a value of type !");
+    }
+
+    #[test]
+    fn test_bare_panic() {
+        let module = &mut Module::with_options(CompileOptions {
+            panic_messages: false,
+            ..CompileOptions::default()
+        });
+        emit(module, SOURCE).unwrap();
+        let functions = module.functions.borrow();
+        assert!(!functions.contains_key(PANIC_HOOK));
+        assert!(module.data.is_empty());
+        assert_eq!(
+            functions["check"].1.body[..7],
+            [
+                OpCode::LocalGet(0),
+                OpCode::I32Const(0),
+                OpCode::I32GtS,
+                OpCode::If(None),
+                OpCode::Else,
+                OpCode::Unreachable,
+                OpCode::End,
+            ]
+        );
+    }
+}
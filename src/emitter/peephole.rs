@@ -0,0 +1,225 @@
+use super::*;
+
+/// Matches a window at the start of `codes` and returns how many instructions it
+/// consumed together with their replacement. A rule must always shrink the window.
+pub type PeepholeRule = fn(&[OpCode]) -> Option<(usize, Vec<OpCode>)>;
+
+/// The rules applied to every function body when optimizing.
+pub const DEFAULT_RULES: &[PeepholeRule] = &[
+    local_set_get,
+    drop_pure_push,
+    identity_operand,
+    merge_global_adjust,
+];
+
+/// Rewrites `codes` with `rules` until none of them matches anymore.
+pub fn optimize(mut codes: Vec<OpCode>, rules: &[PeepholeRule]) -> Vec<OpCode> {
+    loop {
+        let mut changed = false;
+        let mut optimized = Vec::with_capacity(codes.len());
+        let mut i = 0;
+        'window: while i < codes.len() {
+            for rule in rules {
+                if let Some((consumed, replacement)) = rule(&codes[i..]) {
+                    optimized.extend(replacement);
+                    i += consumed;
+                    changed = true;
+                    continue 'window;
+                }
+            }
+            optimized.push(codes[i].clone());
+            i += 1;
+        }
+        codes = optimized;
+        if !changed {
+            return codes;
+        }
+    }
+}
+
+/// `local.set n; local.get n` => `local.tee n`
+fn local_set_get(codes: &[OpCode]) -> Option<(usize, Vec<OpCode>)> {
+    match codes {
+        [OpCode::LocalSet(a), OpCode::LocalGet(b), ..] if a == b => {
+            Some((2, vec![OpCode::LocalTee(*a)]))
+        }
+        _ => None,
+    }
+}
+
+/// A value pushed without side effects and dropped right away is removed, and
+/// `local.tee n; drop` becomes `local.set n`.
+fn drop_pure_push(codes: &[OpCode]) -> Option<(usize, Vec<OpCode>)> {
+    match codes {
+        [OpCode::LocalTee(n), OpCode::Drop, ..] => Some((2, vec![OpCode::LocalSet(*n)])),
+        [push, OpCode::Drop, ..] if is_pure_push(push) => Some((2, vec![])),
+        _ => None,
+    }
+}
+
+/// Operations with an identity operand, such as `i32.const 0; i32.add`, are removed.
+fn identity_operand(codes: &[OpCode]) -> Option<(usize, Vec<OpCode>)> {
+    match codes {
+        [OpCode::I32Const(0), OpCode::I32Add
+        | OpCode::I32Sub
+        | OpCode::I32Or
+        | OpCode::I32Xor
+        | OpCode::I32Shl
+        | OpCode::I32ShrS
+        | OpCode::I32ShrU, ..]
+        | [OpCode::I64Const(0), OpCode::I64Add
+        | OpCode::I64Sub
+        | OpCode::I64Or
+        | OpCode::I64Xor
+        | OpCode::I64Shl
+        | OpCode::I64ShrS
+        | OpCode::I64ShrU, ..]
+        | [OpCode::I32Const(1), OpCode::I32Mul | OpCode::I32DivS | OpCode::I32DivU, ..]
+        | [OpCode::I64Const(1), OpCode::I64Mul | OpCode::I64DivS | OpCode::I64DivU, ..] => {
+            Some((2, vec![]))
+        }
+        _ => None,
+    }
+}
+
+fn is_pure_push(code: &OpCode) -> bool {
+    matches!(
+        code,
+        OpCode::I32Const(_)
+            | OpCode::I64Const(_)
+            | OpCode::F32Const(_)
+            | OpCode::LocalGet(_)
+            | OpCode::GlobalGet(_)
+    )
+}
+
+/// An i32 global bumped and then adjusted back, as happens when a scope allocates on
+/// the stack and restores the stack pointer on exit, is set only once. A single pure
+/// push that doesn't read the global may sit between the two.
+fn merge_global_adjust(codes: &[OpCode]) -> Option<(usize, Vec<OpCode>)> {
+    let (a, g) = match codes {
+        [OpCode::I32Const(a), OpCode::I32Add, OpCode::GlobalSet(g), ..] => (*a, *g),
+        _ => return None,
+    };
+    let between = match codes.get(3) {
+        Some(code) if is_pure_push(code) && *code != OpCode::GlobalGet(g) => 1,
+        _ => 0,
+    };
+    match &codes[3 + between..] {
+        [OpCode::GlobalGet(g2), OpCode::I32Const(b), OpCode::I32Sub, OpCode::GlobalSet(g3), ..]
+            if *g2 == g && *g3 == g =>
+        {
+            let mut replacement = vec![
+                OpCode::I32Const(a.wrapping_sub(*b)),
+                OpCode::I32Add,
+                OpCode::GlobalSet(g),
+            ];
+            replacement.extend_from_slice(&codes[3..3 + between]);
+            Some((7 + between, replacement))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rules() {
+        assert_eq!(
+            optimize(
+                vec![
+                    OpCode::I32Const(1),
+                    OpCode::LocalSet(0),
+                    OpCode::LocalGet(0),
+                    OpCode::I32Const(0),
+                    OpCode::I32Add,
+                    OpCode::I64Const(3),
+                    OpCode::Drop,
+                    OpCode::End,
+                ],
+                DEFAULT_RULES
+            ),
+            vec![OpCode::I32Const(1), OpCode::LocalTee(0), OpCode::End]
+        );
+        assert_eq!(
+            optimize(
+                vec![
+                    OpCode::LocalGet(0),
+                    OpCode::I32Const(8),
+                    OpCode::I32Add,
+                    OpCode::GlobalSet(0),
+                    OpCode::LocalGet(0),
+                    OpCode::Drop,
+                    OpCode::GlobalGet(0),
+                    OpCode::I32Const(8),
+                    OpCode::I32Sub,
+                    OpCode::GlobalSet(0),
+                    OpCode::End,
+                ],
+                DEFAULT_RULES
+            ),
+            vec![OpCode::LocalGet(0), OpCode::GlobalSet(0), OpCode::End]
+        );
+    }
+
+    #[test]
+    fn test_optimize_function() {
+        let module = &mut Module::with_options(CompileOptions {
+            opt_level: 1,
+            ..CompileOptions::release()
+        });
+        emit(
+            module,
+            "
+        (export defn fill []
+            [1 2]
+            0)
+        (export defn inc: i32 [a: i32]
+            (let [b (+ a 1)] b))
+        ",
+        )
+        .unwrap();
+        let functions = module.functions.borrow();
+        assert_eq!(
+            functions["fill"].1.body,
+            vec![
+                OpCode::LocalDecl(WasmPrimitiveType::I32),
+                OpCode::GlobalGet(0),
+                OpCode::LocalTee(0),
+                OpCode::I32Const(2),
+                OpCode::I32Store {
+                    offset: 0,
+                    alignment: 2
+                },
+                OpCode::LocalGet(0),
+                OpCode::I32Const(1),
+                OpCode::I32Store {
+                    offset: 4,
+                    alignment: 2
+                },
+                OpCode::LocalGet(0),
+                OpCode::I32Const(2),
+                OpCode::I32Store {
+                    offset: 8,
+                    alignment: 2
+                },
+                OpCode::LocalGet(0),
+                OpCode::GlobalSet(0),
+                OpCode::End
+            ]
+        );
+        assert_eq!(
+            functions["inc"].1.body,
+            vec![
+                OpCode::LocalGet(0),
+                OpCode::I32Const(1),
+                OpCode::I32Add,
+                OpCode::LocalDecl(WasmPrimitiveType::I32),
+                OpCode::LocalTee(1),
+                OpCode::End
+            ]
+        );
+    }
+}
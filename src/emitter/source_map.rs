@@ -0,0 +1,180 @@
+use super::*;
+
+/// The `SourceLoc` marking the code emitted for `ast`, when source maps are enabled.
+pub(super) fn source_loc(module: &Module, ast: &AST) -> Option<OpCode> {
+    if !module.options.source_map {
+        return None;
+    }
+    module
+        .spans
+        .get(ast)
+        .and_then(|span| u32::try_from(span.start).ok())
+        .map(OpCode::SourceLoc)
+}
+
+/// 1-based line of `ast`, or 0 when it does not come from the main file.
+pub(super) fn source_line(module: &Module, ast: &AST) -> u32 {
+    module.spans.get(ast).map_or(0, |span| {
+        line_column(&module.source, span.start).0 as u32 + 1
+    })
+}
+
+/// 0-based line and column of a byte offset, the column counted in characters.
+pub fn line_column(source: &str, offset: usize) -> (i64, i64) {
+    let before = &source[..offset];
+    let line = before.matches('\n').count();
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (line as i64, before[line_start..].chars().count() as i64)
+}
+
+const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Appends `value` as a Base64 VLQ, as used by the `mappings` field.
+fn encode_vlq(out: &mut String, value: i64) {
+    let mut vlq = if value < 0 {
+        ((-value) << 1) | 1
+    } else {
+        value << 1
+    };
+    loop {
+        let mut digit = vlq & 0x1F;
+        vlq >>= 5;
+        if vlq > 0 {
+            digit |= 0x20;
+        }
+        out.push(BASE64[digit as usize] as char);
+        if vlq == 0 {
+            break;
+        }
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Builds a version 3 source map for a Wasm module. Each location pairs the byte offset
+/// of an instruction in the module with the source offset it was emitted from. As the
+/// binary has no lines, every mapping is on line 0 with the module offset as its column.
+pub(super) fn build_source_map(
+    source_name: &str,
+    source: &str,
+    locations: &[(usize, u32)],
+) -> String {
+    let mut mappings = String::new();
+    let (mut last_offset, mut last_line, mut last_column) = (0, 0, 0);
+    for (i, (offset, source_offset)) in locations.iter().enumerate() {
+        let (line, column) = line_column(source, *source_offset as usize);
+        if i > 0 {
+            mappings.push(',');
+        }
+        encode_vlq(&mut mappings, *offset as i64 - last_offset);
+        // The only source.
+        encode_vlq(&mut mappings, 0);
+        encode_vlq(&mut mappings, line - last_line);
+        encode_vlq(&mut mappings, column - last_column);
+        (last_offset, last_line, last_column) = (*offset as i64, line, column);
+    }
+    format!(
+        "{{\"version\":3,\"sources\":[{}],\"sourcesContent\":[{}],\"names\":[],\"mappings\":{}}}",
+        json_string(source_name),
+        json_string(source),
+        json_string(&mappings)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emitter::encoder::compile_with_source_map;
+    use std::{io::BufWriter, path::Path};
+
+    #[test]
+    fn test_vlq() {
+        let mut out = String::new();
+        for value in [0, 1, -1, 15, 16, -16, 1000] {
+            encode_vlq(&mut out, value);
+            out.push(',');
+        }
+        assert_eq!(out, "A,C,D,e,gB,hB,w+B,");
+    }
+
+    #[test]
+    fn test_source_locations() {
+        let module = &mut Module::with_options(CompileOptions {
+            source_map: true,
+            ..CompileOptions::default()
+        });
+        emit(
+            module,
+            "(defn f: i32 [a: i32]
+  (+ a
+     (* a 2)))",
+        )
+        .unwrap();
+        assert_eq!(
+            module.functions.borrow()["f"].1.body,
+            vec![
+                OpCode::SourceLoc(0),
+                OpCode::SourceLoc(24),
+                OpCode::LocalGet(0),
+                OpCode::SourceLoc(34),
+                OpCode::LocalGet(0),
+                OpCode::I32Const(2),
+                OpCode::I32Mul,
+                OpCode::SourceLoc(24),
+                OpCode::I32Add,
+                OpCode::SourceLoc(0),
+                OpCode::End
+            ]
+        );
+    }
+
+    #[test]
+    fn test_source_map() {
+        let source = "(export defn f: i32 [a: i32]\n  (* a 2))";
+        let mut bytes = Vec::new();
+        let map = compile_with_source_map(
+            &mut BufWriter::new(&mut bytes),
+            source,
+            &CompileOptions::default(),
+            Path::new("f.wisp"),
+            "f.wasm.map",
+        )
+        .unwrap();
+        // local.get 0, i32.const 2, i32.mul, end
+        let body = [0x20, 0x00, 0x41, 0x02, 0x6C, 0x0B];
+        let body_start = bytes.windows(body.len()).position(|w| w == body).unwrap();
+        // `(* a 2)` at 1:2 up to the `end`, which belongs to the function at 0:0.
+        let mut mappings = String::new();
+        for field in [body_start as i64, 0, 1, 2] {
+            encode_vlq(&mut mappings, field);
+        }
+        mappings.push(',');
+        for field in [5, 0, -1, -2] {
+            encode_vlq(&mut mappings, field);
+        }
+        assert_eq!(
+            map,
+            format!(
+                "{{\"version\":3,\"sources\":[\"f.wisp\"],\"sourcesContent\":[{}],\"names\":[],\"mappings\":\"{}\"}}",
+                json_string(source),
+                mappings
+            )
+        );
+        assert!(bytes.ends_with(b"\x10sourceMappingURL\x0Af.wasm.map"));
+    }
+}
@@ -1,5 +1,6 @@
 use std::{cell::RefCell, rc::Rc};
 use crate::{parser::AST, resolver::Type, env::Env, emitter::expression::emit_obj};
+use super::constant::{eval_const, ConstValue};
 use super::*;
 use anyhow::{Result, ensure, bail};
 
@@ -63,7 +64,19 @@ pub(super) fn emit_let(
                     AST::Symbol(variable_name) => {
                         let value = &bindings[i * 2 + 1];
                         let value_type = emit_obj(module, codes, value, new_env.clone())?;
-                        let local_index = (*new_env.clone()).borrow().count_local_vars() as u32;
+                        // Each local is declared right after it is allocated, so a value
+                        // without a Wasm type must not take one.
+                        let primitive_type = match get_primitive_types(value_type.clone())[0] {
+                            Some(t) => t,
+                            // The value failed to compile, so the local is never read.
+                            None if *value_type == Type::Error => WasmPrimitiveType::I32,
+                            None => bail!(
+                                "cannot bind {} to a value of type {}",
+                                variable_name,
+                                value_type
+                            ),
+                        };
+                        let local_index = new_env.borrow().new_local();
                         let pointer = Pointer::Local(local_index);
                         // prohibit local var redefinition
                         match new_env.borrow_mut().set(
@@ -76,25 +89,8 @@ pub(super) fn emit_let(
                             None => (),
                             Some(_) => bail!("redefinition of {}", variable_name),
                         }
-                        match *value_type {
-                            Type::F32 => {
-                                codes.push(OpCode::LocalDecl(WasmPrimitiveType::F32));
-                                codes.push(OpCode::LocalSet(local_index));
-                            }
-                            Type::I32 => {
-                                codes.push(OpCode::LocalDecl(WasmPrimitiveType::I32));
-                                codes.push(OpCode::LocalSet(local_index));
-                            }
-                            Type::Bool => {
-                                codes.push(OpCode::LocalDecl(WasmPrimitiveType::I32));
-                                codes.push(OpCode::LocalSet(local_index));
-                            }
-                            Type::Array(_) => {
-                                codes.push(OpCode::LocalDecl(WasmPrimitiveType::I32)); // pointer
-                                codes.push(OpCode::LocalSet(local_index));
-                            },
-                            Type::Unit => {},
-                        }
+                        codes.push(OpCode::LocalDecl(primitive_type));
+                        codes.push(OpCode::LocalSet(local_index));
                     }
                     AST::SymbolWithAnnotation(_, _) => {
                         todo!("Impl local decl with type annotation");
@@ -134,33 +130,79 @@ pub(super) fn emit_if(
     let bool_exp = &forms[0];
     let true_exp = &forms[1];
     let false_exp = &forms[2];
+    let constant_condition = if module.options.opt_level > 0 {
+        match eval_const(module, bool_exp, &env) {
+            Some(ConstValue::Bool(b)) => Some(b),
+            _ => None,
+        }
+    } else {
+        None
+    };
+    let condition_codes = &mut Vec::new();
     ensure!(
-        *emit_obj(module, codes, bool_exp, env.clone())? == Type::Bool,
+        *emit_obj(module, condition_codes, bool_exp, env.clone())? == Type::Bool,
         "first form of if must be bool expression"
     );
-    let temp_codes = &mut Vec::new();
-    let true_form_type = emit_obj(module, temp_codes, true_exp, env.clone())?;
-    temp_codes.push(OpCode::Else);
-    let false_form_type = emit_obj(module, temp_codes, false_exp, env.clone())?;
+    let true_codes = &mut Vec::new();
+    let true_form_type = emit_obj(module, true_codes, true_exp, env.clone())?;
+    let false_codes = &mut Vec::new();
+    let false_form_type = emit_obj(module, false_codes, false_exp, env.clone())?;
 
     // ToDo: Improve flexibility
-    ensure!(
-        *true_form_type == *false_form_type,
-        "mismatched types. found {} and {}",
-        true_form_type,
-        false_form_type
-    );
+    let form_type = match (&*true_form_type, &*false_form_type) {
+        (Type::Never, _) => false_form_type,
+        (_, Type::Never) => true_form_type,
+        _ => {
+            ensure!(
+                *true_form_type == *false_form_type,
+                "mismatched types. found {} and {}",
+                true_form_type,
+                false_form_type
+            );
+            true_form_type
+        }
+    };
 
-    let primitive_type = get_primitive_types(true_form_type.clone());
+    if let Some(b) = constant_condition {
+        // Only the taken branch is emitted, but locals declared by the other one keep
+        // their indices, so their declarations are kept.
+        let (live_codes, dead_codes) = if b {
+            (true_codes, false_codes)
+        } else {
+            (false_codes, true_codes)
+        };
+        let dead_decls = dead_codes
+            .drain(..)
+            .filter(|code| matches!(code, OpCode::LocalDecl(_)))
+            .collect::<Vec<_>>();
+        // Declarations stay in the order their locals were allocated.
+        if b {
+            codes.append(live_codes);
+            codes.extend(dead_decls);
+        } else {
+            codes.extend(dead_decls);
+            codes.append(live_codes);
+        }
+        return Ok(form_type);
+    }
+
+    let primitive_type = get_primitive_types(form_type.clone());
     if primitive_type.len() != 1 {
         unimplemented!("tuple is not implemented");
     }
+    codes.append(condition_codes);
     codes.push(OpCode::If(
-        *get_primitive_types(true_form_type.clone()).first().unwrap(),
+        *get_primitive_types(form_type.clone()).first().unwrap(),
     ));
-    codes.append(temp_codes);
+    codes.append(true_codes);
+    codes.push(OpCode::Else);
+    codes.append(false_codes);
     codes.push(OpCode::End);
-    Ok(true_form_type.clone())
+    if *form_type == Type::Never {
+        // Neither branch returns, but the block has no result to say so.
+        codes.push(OpCode::Unreachable);
+    }
+    Ok(form_type)
 }
 
 #[cfg(test)]
@@ -198,6 +240,22 @@ mod tests {
         )
     }
     #[test]
+    fn test_let_without_value() {
+        let module = &mut Module::default();
+        let error = emit(
+            module,
+            "
+            (defn noop [] 0)
+            (defn f: i64 []
+                (let [u (noop)
+                      n 1i64]
+                    n))
+        ",
+        )
+        .unwrap_err();
+        assert_eq!(error.to_string(), "cannot bind u to a value of type ()");
+    }
+    #[test]
     fn test_if() {
         let module = &mut Module::default();
         emit(
@@ -0,0 +1,171 @@
+use super::{expression::emit_obj, *};
+use anyhow::{bail, ensure};
+
+/// Name of the function generated to run the initializers.
+const START_FUNCTION: &str = "__start";
+
+/// Code run when the module is instantiated: the `init` forms and the initializers of
+/// the globals that cannot be evaluated at compile time, in the order they were defined.
+#[derive(Debug)]
+pub(crate) struct Initializers {
+    env: Rc<RefCell<Env>>,
+    body: Vec<OpCode>,
+}
+
+/// Runs `emit` over the body of the start function.
+fn with_initializers(
+    module: &mut Module,
+    env: &Rc<RefCell<Env>>,
+    emit: impl FnOnce(&mut Module, &mut Vec<OpCode>, Rc<RefCell<Env>>) -> Result<()>,
+) -> Result<()> {
+    let mut initializers = module.initializers.take().unwrap_or_else(|| Initializers {
+        env: Rc::new(RefCell::new(Env::extend_function(env.clone()))),
+        body: Vec::new(),
+    });
+    let result = emit(module, &mut initializers.body, initializers.env.clone());
+    module.initializers = Some(initializers);
+    result
+}
+
+/// Emits `value` into the start function, which stores it into global `index` of type
+/// `t`. Arrays are allocated on the stack and never freed.
+pub(super) fn emit_initializer(
+    module: &mut Module,
+    index: u32,
+    t: &Rc<Type>,
+    value: &AST,
+    env: &Rc<RefCell<Env>>,
+) -> Result<()> {
+    with_initializers(module, env, |module, body, env| {
+        let value_type = emit_obj(module, body, value, env)?;
+        match (&*value_type, &**t) {
+            (a, b) if a == b => (),
+            (Type::I32, Type::F32) => body.push(OpCode::F32ConvertI32S),
+            _ => bail!("mismatched types. expected {}, found {}", t, value_type),
+        }
+        body.push(OpCode::GlobalSet(index));
+        Ok(())
+    })
+}
+
+/// `(init forms...)` runs `forms` when the module is instantiated, after the
+/// initializers of the globals defined before it.
+pub(super) fn emit_init(module: &mut Module, forms: &[AST], env: &Rc<RefCell<Env>>) -> Result<()> {
+    ensure!(!forms.is_empty(), "init expects at least one form");
+    with_initializers(module, env, |module, body, env| {
+        let scope = Rc::new(RefCell::new(Env::extend(env)));
+        let t = emit_scope(module, body, forms, scope)?;
+        for _ in get_primitive_types(t).iter().flatten() {
+            body.push(OpCode::Drop);
+        }
+        Ok(())
+    })
+}
+
+/// Adds the start function running the initializers, if there are any.
+pub(super) fn emit_start_function(module: &mut Module) -> Result<()> {
+    let Initializers { env, mut body } = match module.initializers.take() {
+        Some(initializers) => initializers,
+        None => return Ok(()),
+    };
+    ensure!(
+        !module.functions.borrow().contains_key(START_FUNCTION),
+        "{} is reserved for the start function",
+        START_FUNCTION
+    );
+    body.push(OpCode::End);
+    let index = add_generated_func(module, START_FUNCTION.to_string(), &env.borrow(), body);
+    module.start = Some(index);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_start_function() {
+        let module = &mut Module::default();
+        emit(
+            module,
+            "
+            (defn twice: i32 [x: i32] (* x 2))
+            (define base: i32 (twice 4))
+            (define limit: i32 (+ base 1))
+            (define size: i32 3)
+            (define table: [i32] [base size])
+            (init (twice limit))
+            ",
+        )
+        .unwrap();
+        let functions = module.functions.borrow();
+        let (index, start) = &functions["__start"];
+        assert_eq!(module.start, Some(*index));
+        let globals = module.globals.borrow();
+        let global = |name: &str| globals[name].0;
+        assert_eq!(
+            globals["base"].1,
+            Global {
+                is_mutable: true,
+                value: GlobalValue::I32(0)
+            }
+        );
+        assert_eq!(
+            globals["size"].1,
+            Global {
+                is_mutable: false,
+                value: GlobalValue::I32(3)
+            }
+        );
+        assert_eq!(
+            start.body,
+            vec![
+                OpCode::I32Const(4),
+                OpCode::Call(functions["twice"].0),
+                OpCode::GlobalSet(global("base")),
+                OpCode::GlobalGet(global("base")),
+                OpCode::I32Const(1),
+                OpCode::I32Add,
+                OpCode::GlobalSet(global("limit")),
+                OpCode::LocalDecl(WasmPrimitiveType::I32),
+                OpCode::GlobalGet(STACK_POINTER.0),
+                OpCode::LocalTee(0),
+                OpCode::I32Const(2),
+                OpCode::I32Store {
+                    offset: 0,
+                    alignment: 2
+                },
+                OpCode::LocalGet(0),
+                OpCode::GlobalGet(global("base")),
+                OpCode::I32Store {
+                    offset: 4,
+                    alignment: 2
+                },
+                OpCode::LocalGet(0),
+                OpCode::GlobalGet(global("size")),
+                OpCode::I32Store {
+                    offset: 8,
+                    alignment: 2
+                },
+                OpCode::LocalGet(0),
+                OpCode::I32Const(12),
+                OpCode::I32Add,
+                OpCode::GlobalSet(STACK_POINTER.0),
+                OpCode::LocalGet(0),
+                OpCode::GlobalSet(global("table")),
+                OpCode::GlobalGet(global("limit")),
+                OpCode::Call(functions["twice"].0),
+                OpCode::Drop,
+                OpCode::End
+            ]
+        );
+    }
+
+    #[test]
+    fn test_no_start_function() {
+        let module = &mut Module::default();
+        emit(module, "(define size: i32 (+ 1 2))").unwrap();
+        assert_eq!(module.start, None);
+        assert!(!module.functions.borrow().contains_key("__start"));
+    }
+}
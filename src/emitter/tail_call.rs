@@ -0,0 +1,168 @@
+use super::*;
+
+/// Index of the `end` that closes the block containing the `else` at `pos`.
+fn matching_end(body: &[OpCode], pos: usize) -> usize {
+    let mut depth = 0;
+    for (i, code) in body.iter().enumerate().skip(pos + 1) {
+        match code {
+            OpCode::If(_) | OpCode::Loop(_) => depth += 1,
+            OpCode::End if depth == 0 => return i,
+            OpCode::End => depth -= 1,
+            _ => (),
+        }
+    }
+    unreachable!("unbalanced block")
+}
+
+/// Whether the value left by the instruction at `pos` becomes the result of the
+/// function, that is, only the ends of the enclosing `if` forms and scopes follow it.
+fn is_tail_position(body: &[OpCode], pos: usize) -> bool {
+    let mut i = pos + 1;
+    while i < body.len() {
+        match body[i] {
+            OpCode::End | OpCode::LocalDecl(_) | OpCode::SourceLoc(_) => i += 1,
+            OpCode::Else => i = matching_end(body, i) + 1,
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Number of blocks open at `pos`.
+fn block_depth(body: &[OpCode], pos: usize) -> u32 {
+    body[..pos].iter().fold(0, |depth, code| match code {
+        OpCode::If(_) | OpCode::Loop(_) => depth + 1,
+        OpCode::End => depth - 1,
+        _ => depth,
+    })
+}
+
+/// Rewrites calls in tail position. Self tail calls store their arguments into the
+/// parameters and branch back to a loop wrapping the whole body, and with `tail_calls`
+/// any other tail call becomes a `return_call`.
+pub(super) fn optimize_tail_calls(
+    body: &mut Vec<OpCode>,
+    func_index: u32,
+    params: u32,
+    result_type: Option<WasmPrimitiveType>,
+    options: &CompileOptions,
+) {
+    let tail_calls = (0..body.len())
+        .filter(|pos| matches!(body[*pos], OpCode::Call(_)) && is_tail_position(body, *pos))
+        .collect::<Vec<_>>();
+    let mut is_looped = false;
+    let mut optimized = Vec::with_capacity(body.len());
+    for (pos, code) in body.iter().enumerate() {
+        match code {
+            OpCode::Call(index) if *index == func_index && tail_calls.contains(&pos) => {
+                optimized.extend((0..params).rev().map(OpCode::LocalSet));
+                // The loop encloses every block open here.
+                optimized.push(OpCode::Br(block_depth(body, pos)));
+                is_looped = true;
+            }
+            OpCode::Call(index) if options.tail_calls && tail_calls.contains(&pos) => {
+                optimized.push(OpCode::ReturnCall(*index));
+            }
+            code => optimized.push(code.clone()),
+        }
+    }
+    if is_looped {
+        let end = optimized.pop();
+        optimized.insert(0, OpCode::Loop(result_type));
+        optimized.push(OpCode::End);
+        optimized.extend(end);
+    }
+    *body = optimized;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_self_tail_call() {
+        let module = &mut Module::default();
+        emit(
+            module,
+            "
+        (defn sum: i32 [n: i32 acc: i32]
+            (if (= n 0)
+                acc
+                (sum (- n 1) (+ acc n))))
+        (defn count: i32 [n: i32]
+            (+ 1 (count n)))
+        ",
+        )
+        .unwrap();
+        let functions = module.functions.borrow();
+        assert_eq!(
+            functions["sum"].1.body,
+            vec![
+                OpCode::Loop(Some(WasmPrimitiveType::I32)),
+                OpCode::LocalGet(0),
+                OpCode::I32Const(0),
+                OpCode::I32Eq,
+                OpCode::If(Some(WasmPrimitiveType::I32)),
+                OpCode::LocalGet(1),
+                OpCode::Else,
+                OpCode::LocalGet(0),
+                OpCode::I32Const(1),
+                OpCode::I32Sub,
+                OpCode::LocalGet(1),
+                OpCode::LocalGet(0),
+                OpCode::I32Add,
+                OpCode::LocalSet(1),
+                OpCode::LocalSet(0),
+                OpCode::Br(1),
+                OpCode::End,
+                OpCode::End,
+                OpCode::End
+            ]
+        );
+        assert_eq!(
+            functions["count"].1.body,
+            vec![
+                OpCode::I32Const(1),
+                OpCode::LocalGet(0),
+                OpCode::Call(1),
+                OpCode::I32Add,
+                OpCode::End
+            ]
+        );
+    }
+
+    #[test]
+    fn test_return_call() {
+        let module = &mut Module::with_options(CompileOptions {
+            tail_calls: true,
+            ..CompileOptions::debug()
+        });
+        emit(
+            module,
+            "
+        (defn even: bool [n: i32]
+            (if (= n 0) true (odd (- n 1))))
+        (defn odd: bool [n: i32]
+            (if (= n 0) false (even (- n 1))))
+        ",
+        )
+        .unwrap();
+        assert_eq!(
+            module.functions.borrow()["even"].1.body,
+            vec![
+                OpCode::LocalGet(0),
+                OpCode::I32Const(0),
+                OpCode::I32Eq,
+                OpCode::If(Some(WasmPrimitiveType::I32)),
+                OpCode::I32Const(1),
+                OpCode::Else,
+                OpCode::LocalGet(0),
+                OpCode::I32Const(1),
+                OpCode::I32Sub,
+                OpCode::ReturnCall(1),
+                OpCode::End,
+                OpCode::End
+            ]
+        );
+    }
+}
@@ -0,0 +1,239 @@
+use super::{
+    interpreter::{Instance, Trap},
+    intrinsic_ops::emit_intrinsic_exp,
+    source_map::line_column,
+    validator::validate,
+    *,
+};
+use anyhow::{bail, ensure};
+use std::time::{Duration, Instant};
+
+/// Prefix of the names the tests are exported under.
+const TEST_PREFIX: &str = "__test/";
+
+/// `(deftest name forms...)` defines a test, which passes unless `forms` trap. Tests are
+/// only emitted when compiling with `tests`, as functions exported under a hidden name.
+pub(super) fn emit_test(module: &mut Module, ast: &AST, env: &Rc<RefCell<Env>>) -> Result<()> {
+    let list = match ast {
+        AST::List(list) => list,
+        _ => bail!("Invalid argument."),
+    };
+    let name = match list.get(1) {
+        Some(AST::Symbol(name)) => *name,
+        _ => bail!("deftest expects a name"),
+    };
+    let forms = &list[2..];
+    ensure!(!forms.is_empty(), "test {} has no body", name);
+    if !module.options.tests {
+        return Ok(());
+    }
+    let export_name = format!("{}{}", TEST_PREFIX, env.borrow().qualify(name));
+    ensure!(
+        !module.functions.borrow().contains_key(&export_name),
+        "duplicate test {}",
+        name
+    );
+
+    let test_env = Rc::new(RefCell::new(Env::extend_function(env.clone())));
+    module.source_loc = source_map::source_loc(module, ast);
+    let mut body = Vec::from_iter(module.source_loc.clone());
+    let t = emit_scope(module, &mut body, forms, test_env.clone())?;
+    for _ in get_primitive_types(t).iter().flatten() {
+        body.push(OpCode::Drop);
+    }
+    body.push(OpCode::End);
+    module.source_loc = None;
+
+    let index = add_generated_func(module, export_name.clone(), &test_env.borrow(), body);
+    module.exports.push(Export {
+        export_type: ExportKind::Func,
+        name: export_name,
+        index,
+    });
+    Ok(())
+}
+
+/// `(assert= actual expected)` traps unless `actual` equals `expected`, reporting both
+/// forms to the panic hook like `assert`. As the hook only takes a message, the values
+/// they evaluated to are not part of it.
+pub(super) fn emit_assert_eq(
+    module: &mut Module,
+    codes: &mut Vec<OpCode>,
+    ast: &AST,
+    env: Rc<RefCell<Env>>,
+) -> Result<Rc<Type>> {
+    let args = match ast {
+        AST::List(list) => &list[1..],
+        _ => bail!("Invalid argument."),
+    };
+    ensure!(args.len() == 2, "assert= expects 2 arguments, found {}", args.len());
+    emit_intrinsic_exp(module, IntrinsicOperator::Eq, codes, args, env)?;
+    let message = match (
+        module.spans.get(&args[0]),
+        module.spans.get(&args[1]),
+    ) {
+        (Some(actual), Some(expected)) => format!(
+            "assert= failed: {} != {}",
+            &module.source[actual],
+            &module.source[expected]
+        ),
+        _ => "assert= failed".to_string(),
+    };
+    codes.push(OpCode::If(None));
+    codes.push(OpCode::Else);
+    panic::emit_failure(module, codes, &message, ast)?;
+    codes.push(OpCode::End);
+    Ok(Rc::new(Type::Unit))
+}
+
+struct TestResult {
+    name: String,
+    result: Result<(), Trap>,
+    duration: Duration,
+}
+
+/// Runs each test of `module` in a new instance, in the order they were defined.
+fn run_module_tests(module: &Module) -> Vec<TestResult> {
+    module
+        .exports
+        .iter()
+        .filter_map(|export| export.name.strip_prefix(TEST_PREFIX).map(|name| (name, &export.name)))
+        .map(|(name, export_name)| {
+            let start = Instant::now();
+            let result = Instance::new(module)
+                .and_then(|mut instance| instance.invoke(export_name, &[]))
+                .map(|_| ());
+            TestResult {
+                name: name.to_string(),
+                result,
+                duration: start.elapsed(),
+            }
+        })
+        .collect()
+}
+
+/// Compiles the tests of the program at `path`, runs them and reports the results on
+/// stdout. Returns whether they all passed.
+pub fn run_tests(path: &Path, options: &CompileOptions) -> Result<bool> {
+    let source = std::fs::read_to_string(path)?;
+    let module = &mut Module::with_options(CompileOptions {
+        tests: true,
+        source_map: true,
+        ..options.clone()
+    });
+    emit_with_path(module, &source, path)?;
+    validate(module)?;
+
+    let start = Instant::now();
+    let results = run_module_tests(module);
+    let elapsed = start.elapsed();
+    println!("running {} tests", results.len());
+    for TestResult { name, result, duration } in &results {
+        let outcome = if result.is_ok() { "ok" } else { "FAILED" };
+        println!("test {} ... {} ({:.2?})", name, outcome, duration);
+    }
+    let failures = results.iter().filter(|test| test.result.is_err()).collect::<Vec<_>>();
+    if !failures.is_empty() {
+        println!("\nfailures:");
+        for TestResult { name, result, .. } in &failures {
+            let trap = result.as_ref().unwrap_err();
+            match trap.source_offset {
+                Some(offset) => {
+                    let (line, column) = line_column(&source, offset as usize);
+                    println!("    {}: {} at {}:{}:{}", name, trap, path.display(), line + 1, column + 1);
+                }
+                None => println!("    {}: {}", name, trap),
+            }
+        }
+    }
+    println!(
+        "\ntest result: {}. {} passed; {} failed; finished in {:.2?}",
+        if failures.is_empty() { "ok" } else { "FAILED" },
+        results.len() - failures.len(),
+        failures.len(),
+        elapsed
+    );
+    Ok(failures.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::panic::PANIC_HOOK, *};
+
+    const SOURCE: &str = "
+        (defn add_two: i32 [a: i32 b: i32] (+ a b))
+        (deftest addition
+            (assert= (add_two 1 2) 3))
+        (deftest broken
+            (add_two 1 2)
+            (assert= (add_two 1 2) 4))
+        ";
+
+    #[test]
+    fn test_deftest() {
+        let module = &mut Module::with_options(CompileOptions {
+            tests: true,
+            ..CompileOptions::debug()
+        });
+        emit(module, SOURCE).unwrap();
+        let functions = module.functions.borrow();
+        let (index, addition) = &functions["__test/addition"];
+        assert!(module.exports.iter().any(|export| export.name == "__test/addition" && export.index == *index));
+        assert_eq!(
+            addition.body,
+            vec![
+                OpCode::I32Const(1),
+                OpCode::I32Const(2),
+                OpCode::Call(functions["add_two"].0),
+                OpCode::I32Const(3),
+                OpCode::I32Eq,
+                OpCode::If(None),
+                OpCode::Else,
+                OpCode::I32Const(0),
+                OpCode::I32Const(34),
+                OpCode::I32Const(4),
+                OpCode::Call(functions[PANIC_HOOK].0),
+                OpCode::Unreachable,
+                OpCode::End,
+                OpCode::End
+            ]
+        );
+        assert_eq!(module.data, b"assert= failed: (add_two 1 2) != 3assert= failed: (add_two 1 2) != 4");
+        assert_eq!(functions["__test/broken"].1.body[3], OpCode::Drop);
+    }
+
+    #[test]
+    fn test_tests_stripped() {
+        let module = &mut Module::default();
+        emit(module, SOURCE).unwrap();
+        assert_eq!(module.functions.borrow().len(), 1);
+        assert!(module.exports.iter().all(|export| !export.name.starts_with(TEST_PREFIX)));
+    }
+
+    #[test]
+    fn test_run_tests() {
+        for options in [CompileOptions::debug(), CompileOptions::release()] {
+            let module = &mut Module::with_options(CompileOptions {
+                tests: true,
+                source_map: true,
+                ..options
+            });
+            emit(module, SOURCE).unwrap();
+            validate(module).unwrap();
+            let results = run_module_tests(module);
+            let outcomes = results
+                .iter()
+                .map(|test| (test.name.as_str(), test.result.clone().map_err(|trap| trap.message)))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                outcomes,
+                vec![
+                    ("addition", Ok(())),
+                    ("broken", Err("panicked: assert= failed: (add_two 1 2) != 4".to_string()))
+                ]
+            );
+            let offset = results[1].result.as_ref().unwrap_err().source_offset;
+            assert_eq!(offset, SOURCE.find("(assert= (add_two 1 2) 4)").map(|offset| offset as u32));
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use super::*;
+use std::collections::{HashMap, HashSet};
+
+/// Items dropped by `remove_unused`, by kind.
+#[derive(Debug, Default, PartialEq)]
+pub struct Removed {
+    pub functions: Vec<String>,
+    pub globals: Vec<String>,
+}
+
+fn references(body: &[OpCode], calls: &mut Vec<u32>, globals: &mut HashSet<u32>) {
+    for code in body {
+        match code {
+            OpCode::Call(index) | OpCode::ReturnCall(index) => calls.push(*index),
+            OpCode::GlobalGet(index) | OpCode::GlobalSet(index) => {
+                globals.insert(*index);
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Gives the kept items consecutive indices in their original order.
+fn remap<'a>(kept: impl Iterator<Item = &'a u32>) -> HashMap<u32, u32> {
+    let mut kept = kept.copied().collect::<Vec<_>>();
+    kept.sort();
+    kept.into_iter()
+        .enumerate()
+        .map(|(new, old)| (old, new as u32))
+        .collect()
+}
+
+/// Gives the functions the indices in `function_indices` and updates their calls and
+/// global accesses.
+fn renumber(
+    functions: &mut HashMap<String, (u32, Function)>,
+    function_indices: &HashMap<u32, u32>,
+    global_indices: &HashMap<u32, u32>,
+) {
+    for (index, function) in functions.values_mut() {
+        *index = function_indices[index];
+        for code in function.body.iter_mut() {
+            match code {
+                OpCode::Call(index) | OpCode::ReturnCall(index) => *index = function_indices[index],
+                OpCode::GlobalGet(index) | OpCode::GlobalSet(index) => {
+                    *index = global_indices[index]
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Updates the start function and the exports after `renumber`.
+fn renumber_references(
+    start: &mut Option<u32>,
+    exports: &mut [Export],
+    function_indices: &HashMap<u32, u32>,
+    global_indices: &HashMap<u32, u32>,
+) {
+    if let Some(start) = start.as_mut() {
+        *start = function_indices[start];
+    }
+    for export in exports.iter_mut() {
+        match export.export_type {
+            ExportKind::Func => export.index = function_indices[&export.index],
+            ExportKind::Global => export.index = global_indices[&export.index],
+            ExportKind::Memory => (),
+        }
+    }
+}
+
+/// Renumbers the functions so that the imported ones come first, as Wasm requires.
+pub(super) fn imports_first(module: &mut Module) {
+    let mut functions = module.functions.borrow_mut();
+    let mut order = functions
+        .values()
+        .map(|(index, function)| (function.import.is_none(), *index))
+        .collect::<Vec<_>>();
+    order.sort();
+    let function_indices = order
+        .into_iter()
+        .enumerate()
+        .map(|(new, (_, old))| (old, new as u32))
+        .collect::<HashMap<_, _>>();
+    let global_indices = module
+        .globals
+        .borrow()
+        .values()
+        .map(|(index, _)| (*index, *index))
+        .collect::<HashMap<_, _>>();
+    renumber(&mut functions, &function_indices, &global_indices);
+    renumber_references(&mut module.start, &mut module.exports, &function_indices, &global_indices);
+}
+
+/// Drops the functions and globals that are not reachable from the exports or the start
+/// function and renumbers the remaining ones. The stack pointer and heap base are always kept.
+pub(super) fn remove_unused(module: &mut Module) -> Removed {
+    let mut functions = module.functions.borrow_mut();
+    let mut globals = module.globals.borrow_mut();
+    let bodies = functions
+        .values()
+        .map(|(index, function)| (*index, &function.body))
+        .collect::<HashMap<_, _>>();
+
+    let mut live_functions = HashSet::new();
+    let mut live_globals = HashSet::from([STACK_POINTER.0, HEAP_BASE.0]);
+    let mut worklist = Vec::from_iter(module.start);
+    for export in &module.exports {
+        match export.export_type {
+            ExportKind::Func => worklist.push(export.index),
+            ExportKind::Global => {
+                live_globals.insert(export.index);
+            }
+            ExportKind::Memory => (),
+        }
+    }
+    while let Some(index) = worklist.pop() {
+        if live_functions.insert(index) {
+            references(bodies[&index], &mut worklist, &mut live_globals);
+        }
+    }
+
+    let function_indices = remap(live_functions.iter());
+    let global_indices = remap(live_globals.iter());
+    let mut removed = Removed::default();
+    functions.retain(|name, (index, _)| {
+        let live = function_indices.contains_key(index);
+        if !live {
+            removed.functions.push(name.clone());
+        }
+        live
+    });
+    globals.retain(|name, (index, _)| {
+        let live = global_indices.contains_key(index);
+        if !live {
+            removed.globals.push(name.clone());
+        }
+        live
+    });
+    removed.functions.sort();
+    removed.globals.sort();
+
+    renumber(&mut functions, &function_indices, &global_indices);
+    for (index, _) in globals.values_mut() {
+        *index = global_indices[index];
+    }
+    renumber_references(&mut module.start, &mut module.exports, &function_indices, &global_indices);
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remove_unused() {
+        let module = &mut Module::default();
+        emit(
+            module,
+            "
+        (define unused: i32 1)
+        (define scale: i32 2)
+        (defn helper: i32 []
+            scale)
+        (defn dead: i32 []
+            (+ unused (helper)))
+        (export defn main: i32 []
+            (helper))
+        ",
+        )
+        .unwrap();
+        let removed = remove_unused(module);
+        assert_eq!(
+            removed,
+            Removed {
+                functions: vec!["dead".to_string()],
+                globals: vec!["unused".to_string()],
+            }
+        );
+        let functions = module.functions.borrow();
+        assert_eq!(functions["helper"].0, 0);
+        assert_eq!(functions["main"].0, 1);
+        assert_eq!(
+            functions["helper"].1.body,
+            vec![OpCode::GlobalGet(2), OpCode::End]
+        );
+        assert_eq!(functions["main"].1.body, vec![OpCode::Call(0), OpCode::End]);
+        assert_eq!(module.globals.borrow()["scale"].0, 2);
+        assert_eq!(module.exports[0].index, 1);
+    }
+}
@@ -0,0 +1,505 @@
+use super::*;
+use anyhow::{bail, ensure, Context, Result};
+use std::collections::HashMap;
+use WasmPrimitiveType::{F32, I32, I64};
+
+/// Operand types popped and pushed by an instruction without immediate indices or
+/// control flow.
+fn instruction_type(
+    code: &OpCode,
+) -> Option<(&'static [WasmPrimitiveType], &'static [WasmPrimitiveType])> {
+    Some(match code {
+        OpCode::I32Const(_) => (&[], &[I32]),
+        OpCode::I64Const(_) => (&[], &[I64]),
+        OpCode::F32Const(_) => (&[], &[F32]),
+        OpCode::I32Load { .. } | OpCode::I32Load8U { .. } => (&[I32], &[I32]),
+        OpCode::I64Load { .. } => (&[I32], &[I64]),
+        OpCode::F32Load { .. } => (&[I32], &[F32]),
+        OpCode::I32Store { .. } | OpCode::I32Store8 { .. } => (&[I32, I32], &[]),
+        OpCode::I64Store { .. } => (&[I32, I64], &[]),
+        OpCode::F32Store { .. } => (&[I32, F32], &[]),
+        OpCode::I32Add
+        | OpCode::I32Sub
+        | OpCode::I32Mul
+        | OpCode::I32DivS
+        | OpCode::I32DivU
+        | OpCode::I32RemS
+        | OpCode::I32RemU
+        | OpCode::I32And
+        | OpCode::I32Or
+        | OpCode::I32Xor
+        | OpCode::I32Shl
+        | OpCode::I32ShrS
+        | OpCode::I32ShrU
+        | OpCode::I32Rotl
+        | OpCode::I32Rotr
+        | OpCode::I32Eq
+        | OpCode::I32Ne
+        | OpCode::I32GtS
+        | OpCode::I32GtU
+        | OpCode::I32GeS
+        | OpCode::I32GeU
+        | OpCode::I32LtS
+        | OpCode::I32LtU
+        | OpCode::I32LeS
+        | OpCode::I32LeU => (&[I32, I32], &[I32]),
+        OpCode::I32Clz | OpCode::I32Ctz | OpCode::I32Popcnt => (&[I32], &[I32]),
+        OpCode::I64Add
+        | OpCode::I64Sub
+        | OpCode::I64Mul
+        | OpCode::I64DivS
+        | OpCode::I64DivU
+        | OpCode::I64RemS
+        | OpCode::I64RemU
+        | OpCode::I64And
+        | OpCode::I64Or
+        | OpCode::I64Xor
+        | OpCode::I64Shl
+        | OpCode::I64ShrS
+        | OpCode::I64ShrU
+        | OpCode::I64Rotl
+        | OpCode::I64Rotr => (&[I64, I64], &[I64]),
+        OpCode::I64Eq
+        | OpCode::I64Ne
+        | OpCode::I64LtS
+        | OpCode::I64LtU
+        | OpCode::I64GtS
+        | OpCode::I64GtU
+        | OpCode::I64LeS
+        | OpCode::I64LeU
+        | OpCode::I64GeS
+        | OpCode::I64GeU => (&[I64, I64], &[I32]),
+        OpCode::I64Clz | OpCode::I64Ctz | OpCode::I64Popcnt => (&[I64], &[I64]),
+        OpCode::F32Add
+        | OpCode::F32Sub
+        | OpCode::F32Mul
+        | OpCode::F32Div
+        | OpCode::F32Min
+        | OpCode::F32Max
+        | OpCode::F32Copysign => (&[F32, F32], &[F32]),
+        OpCode::F32Eq | OpCode::F32Gt | OpCode::F32Ge | OpCode::F32Lt | OpCode::F32Le => {
+            (&[F32, F32], &[I32])
+        }
+        OpCode::F32Abs
+        | OpCode::F32Neg
+        | OpCode::F32Ceil
+        | OpCode::F32Floor
+        | OpCode::F32Trunc
+        | OpCode::F32Nearest
+        | OpCode::F32Sqrt => (&[F32], &[F32]),
+        OpCode::F32ConvertI32S | OpCode::F32ConvertI32U | OpCode::F32ReinterpretI32 => {
+            (&[I32], &[F32])
+        }
+        OpCode::F32ConvertI64S | OpCode::F32ConvertI64U => (&[I64], &[F32]),
+        OpCode::I32WrapI64 => (&[I64], &[I32]),
+        OpCode::I32TruncF32S
+        | OpCode::I32TruncF32U
+        | OpCode::I32TruncSatF32S
+        | OpCode::I32TruncSatF32U
+        | OpCode::I32ReinterpretF32 => (&[F32], &[I32]),
+        OpCode::I64ExtendI32S | OpCode::I64ExtendI32U => (&[I32], &[I64]),
+        OpCode::I64TruncF32S
+        | OpCode::I64TruncF32U
+        | OpCode::I64TruncSatF32S
+        | OpCode::I64TruncSatF32U => (&[F32], &[I64]),
+        _ => return None,
+    })
+}
+
+fn global_type(global: &Global) -> WasmPrimitiveType {
+    match global.value {
+        GlobalValue::I32(_) => I32,
+        GlobalValue::I64(_) => I64,
+        GlobalValue::F32(_) => F32,
+    }
+}
+
+#[derive(PartialEq)]
+enum FrameKind {
+    Function,
+    Loop,
+    If,
+    Else,
+}
+
+struct Frame {
+    kind: FrameKind,
+    result: Option<WasmPrimitiveType>,
+    height: usize,
+    unreachable: bool,
+}
+
+/// Checks a function body against the operand stack, following the validation
+/// algorithm of the Wasm spec. `None` on the stack stands for a value of unknown
+/// type in unreachable code.
+struct FunctionValidator<'a> {
+    signatures: &'a HashMap<u32, &'a Signature>,
+    functions: &'a HashMap<u32, u32>,
+    globals: &'a HashMap<u32, &'a Global>,
+    locals: Vec<WasmPrimitiveType>,
+    results: &'a [WasmPrimitiveType],
+    stack: Vec<Option<WasmPrimitiveType>>,
+    frames: Vec<Frame>,
+}
+
+impl FunctionValidator<'_> {
+    fn frame(&mut self) -> &mut Frame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn pop(&mut self) -> Result<Option<WasmPrimitiveType>> {
+        let frame = self.frames.last().unwrap();
+        if self.stack.len() == frame.height {
+            ensure!(frame.unreachable, "operand stack underflow");
+            return Ok(None);
+        }
+        Ok(self.stack.pop().unwrap())
+    }
+
+    fn pop_expect(&mut self, expected: WasmPrimitiveType) -> Result<()> {
+        match self.pop()? {
+            Some(actual) if actual != expected => {
+                bail!("expected {:?} on the stack, found {:?}", expected, actual)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn pop_all(&mut self, types: &[WasmPrimitiveType]) -> Result<()> {
+        for t in types.iter().rev() {
+            self.pop_expect(*t)?;
+        }
+        Ok(())
+    }
+
+    fn set_unreachable(&mut self) {
+        let height = self.frame().height;
+        self.stack.truncate(height);
+        self.frame().unreachable = true;
+    }
+
+    fn signature(&self, func_index: u32) -> Result<&Signature> {
+        let signature_index = self
+            .functions
+            .get(&func_index)
+            .with_context(|| format!("call to unknown function {}", func_index))?;
+        Ok(self.signatures[signature_index])
+    }
+
+    fn local(&self, index: u32) -> Result<WasmPrimitiveType> {
+        self.locals
+            .get(index as usize)
+            .copied()
+            .with_context(|| format!("unknown local {}", index))
+    }
+
+    fn global(&self, index: u32) -> Result<&Global> {
+        self.globals
+            .get(&index)
+            .copied()
+            .with_context(|| format!("unknown global {}", index))
+    }
+
+    /// Pops the result of the innermost block and checks that nothing else is left.
+    fn end_block(&mut self) -> Result<Frame> {
+        if let Some(result) = self.frame().result {
+            self.pop_expect(result)?;
+        }
+        let frame = self.frames.pop().unwrap();
+        ensure!(
+            self.stack.len() == frame.height,
+            "{} values left on the stack at the end of a block",
+            self.stack.len() - frame.height
+        );
+        Ok(frame)
+    }
+
+    fn push_block(&mut self, kind: FrameKind, result: Option<WasmPrimitiveType>) {
+        self.frames.push(Frame {
+            kind,
+            result,
+            height: self.stack.len(),
+            unreachable: false,
+        });
+    }
+
+    fn validate(&mut self, body: &[OpCode]) -> Result<()> {
+        self.push_block(FrameKind::Function, self.results.first().copied());
+        for (pos, code) in body.iter().enumerate() {
+            ensure!(
+                !self.frames.is_empty(),
+                "instructions after the end of the function"
+            );
+            self.validate_instruction(code)
+                .with_context(|| format!("at instruction {} ({:?})", pos, code))?;
+        }
+        ensure!(self.frames.is_empty(), "missing end of the function");
+        Ok(())
+    }
+
+    fn validate_instruction(&mut self, code: &OpCode) -> Result<()> {
+        if let Some((params, results)) = instruction_type(code) {
+            self.pop_all(params)?;
+            self.stack.extend(results.iter().map(|t| Some(*t)));
+            return Ok(());
+        }
+        match code {
+            OpCode::LocalDecl(_) | OpCode::SourceLoc(_) => (),
+            OpCode::Unreachable => self.set_unreachable(),
+            OpCode::Drop => {
+                self.pop()?;
+            }
+            OpCode::Select => {
+                self.pop_expect(I32)?;
+                let second = self.pop()?;
+                let first = self.pop()?;
+                ensure!(
+                    first.is_none() || second.is_none() || first == second,
+                    "select operands differ: {:?} and {:?}",
+                    first,
+                    second
+                );
+                self.stack.push(first.or(second));
+            }
+            OpCode::Loop(result) => self.push_block(FrameKind::Loop, *result),
+            OpCode::If(result) => {
+                self.pop_expect(I32)?;
+                self.push_block(FrameKind::If, *result);
+            }
+            OpCode::Else => {
+                ensure!(self.frame().kind == FrameKind::If, "else outside of an if");
+                let frame = self.end_block()?;
+                self.push_block(FrameKind::Else, frame.result);
+            }
+            OpCode::End => {
+                let frame = self.end_block()?;
+                ensure!(
+                    frame.kind != FrameKind::If || frame.result.is_none(),
+                    "if with a result has no else"
+                );
+                if frame.kind != FrameKind::Function {
+                    self.stack.extend(frame.result.map(Some));
+                }
+            }
+            OpCode::Br(depth) => {
+                let target = self
+                    .frames
+                    .len()
+                    .checked_sub(*depth as usize + 1)
+                    .context("branch out of the function")?;
+                let frame = &self.frames[target];
+                // Branching to a loop jumps back to its start, which takes no values.
+                if frame.kind != FrameKind::Loop {
+                    if let Some(result) = frame.result {
+                        self.pop_expect(result)?;
+                    }
+                }
+                self.set_unreachable();
+            }
+            OpCode::Call(index) => {
+                let signature = self.signature(*index)?.clone();
+                self.pop_all(&signature.params)?;
+                self.stack
+                    .extend(signature.results.iter().map(|t| Some(*t)));
+            }
+            OpCode::ReturnCall(index) => {
+                let signature = self.signature(*index)?.clone();
+                ensure!(
+                    signature.results == self.results,
+                    "tail call to function {} with different results",
+                    index
+                );
+                self.pop_all(&signature.params)?;
+                self.set_unreachable();
+            }
+            OpCode::LocalGet(index) => {
+                let t = self.local(*index)?;
+                self.stack.push(Some(t));
+            }
+            OpCode::LocalSet(index) => {
+                let t = self.local(*index)?;
+                self.pop_expect(t)?;
+            }
+            OpCode::LocalTee(index) => {
+                let t = self.local(*index)?;
+                self.pop_expect(t)?;
+                self.stack.push(Some(t));
+            }
+            OpCode::GlobalGet(index) => {
+                let t = global_type(self.global(*index)?);
+                self.stack.push(Some(t));
+            }
+            OpCode::GlobalSet(index) => {
+                let global = self.global(*index)?;
+                ensure!(global.is_mutable, "global {} is immutable", index);
+                let t = global_type(global);
+                self.pop_expect(t)?;
+            }
+            _ => unreachable!("{:?} has no instruction type", code),
+        }
+        Ok(())
+    }
+}
+
+/// Checks that `module` encodes to a valid Wasm binary. A failure is a bug in the
+/// emitter, so it is reported as an internal compiler error.
+pub(super) fn validate(module: &Module) -> Result<()> {
+    let signatures = module
+        .signatures
+        .iter()
+        .map(|(signature, index)| (*index as u32, signature))
+        .collect::<HashMap<_, _>>();
+    let module_functions = module.functions.borrow();
+    let functions = module_functions
+        .values()
+        .map(|(index, func)| (*index, func.signature_index))
+        .collect::<HashMap<_, _>>();
+    let module_globals = module.globals.borrow();
+    let globals = module_globals
+        .values()
+        .map(|(index, global)| (*index, global))
+        .collect::<HashMap<_, _>>();
+
+    for (name, (_, func)) in module_functions.iter() {
+        let signature = signatures.get(&func.signature_index).with_context(|| {
+            format!(
+                "internal compiler error in function {}: unknown signature {}",
+                name, func.signature_index
+            )
+        })?;
+        if func.import.is_some() {
+            ensure!(
+                func.body.is_empty(),
+                "internal compiler error: imported function {} has a body",
+                name
+            );
+            continue;
+        }
+        // Locals are declared grouped by type, in the order the encoder writes them.
+        let mut locals = signature.params.clone();
+        for t in [I32, I64, F32] {
+            locals.extend(
+                func.body
+                    .iter()
+                    .filter(|code| **code == OpCode::LocalDecl(t))
+                    .map(|_| t),
+            );
+        }
+        FunctionValidator {
+            signatures: &signatures,
+            functions: &functions,
+            globals: &globals,
+            locals,
+            results: &signature.results,
+            stack: Vec::new(),
+            frames: Vec::new(),
+        }
+        .validate(&func.body)
+        .with_context(|| format!("internal compiler error in function {}", name))?;
+    }
+    // The index space starts with the imported functions.
+    let first_defined = module_functions
+        .values()
+        .filter(|(_, func)| func.import.is_none())
+        .map(|(index, _)| *index)
+        .min();
+    if let Some((name, _)) = module_functions.iter().find(|(_, (index, func))| {
+        func.import.is_some() && first_defined.is_some_and(|first| *index > first)
+    }) {
+        bail!("internal compiler error: imported function {} follows defined ones", name);
+    }
+    for export in &module.exports {
+        let (exists, kind) = match export.export_type {
+            ExportKind::Func => (functions.contains_key(&export.index), "function"),
+            ExportKind::Memory => (export.index == 0, "memory"),
+            ExportKind::Global => (globals.contains_key(&export.index), "global"),
+        };
+        ensure!(
+            exists,
+            "internal compiler error: export {} refers to unknown {} {}",
+            export.name,
+            kind,
+            export.index
+        );
+    }
+    if let Some(start) = module.start {
+        let signature = functions
+            .get(&start)
+            .and_then(|index| signatures.get(index))
+            .with_context(|| format!("internal compiler error: unknown start function {}", start))?;
+        ensure!(
+            signature.params.is_empty() && signature.results.is_empty(),
+            "internal compiler error: start function {} takes or returns values",
+            start
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_module() {
+        let module = &mut Module::with_options(CompileOptions {
+            tail_calls: true,
+            ..CompileOptions::release()
+        });
+        emit(
+            module,
+            "
+        (define scale: f32 2.0)
+        (defn sum: i32 [n: i32 acc: i32]
+            (if (= n 0) acc (sum (- n 1) (+ acc n))))
+        (export defn main: f32 [arr: [f32] i: i32 x: i64]
+            (let [total (sum i 0)
+                  wide (+ x 1)]
+                (set-at! arr 0 (as f32 wide))
+                (* scale (get arr i) (as f32 total))))
+        ",
+        )
+        .unwrap();
+        validate(module).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_body() {
+        let module = &mut Module::default();
+        emit(
+            module,
+            "
+        (defn broken: i32 [a: i32]
+            a)
+        ",
+        )
+        .unwrap();
+        let check = |body: Vec<OpCode>| {
+            module
+                .functions
+                .borrow_mut()
+                .get_mut("broken")
+                .unwrap()
+                .1
+                .body = body;
+            format!("{:#}", validate(module).unwrap_err())
+        };
+        assert!(check(vec![OpCode::LocalGet(0), OpCode::Drop, OpCode::End])
+            .starts_with("internal compiler error in function broken"));
+        assert!(check(vec![
+            OpCode::LocalGet(0),
+            OpCode::If(None),
+            OpCode::F32Const(1.0),
+            OpCode::End,
+            OpCode::LocalGet(0),
+            OpCode::End
+        ])
+        .contains("values left on the stack"));
+        assert!(check(vec![OpCode::F32Const(1.0), OpCode::End]).contains("expected I32"));
+        assert!(check(vec![OpCode::LocalGet(1), OpCode::End]).contains("unknown local 1"));
+        assert!(
+            check(vec![OpCode::I32Const(1), OpCode::GlobalSet(3), OpCode::End])
+                .contains("unknown global 3")
+        );
+    }
+}
@@ -5,9 +5,133 @@ use crate::{
     parser::AST,
     resolver::{get_size, Type},
 };
-use anyhow::{ensure, Result};
+use anyhow::{bail, ensure, Result};
 use std::{cell::RefCell, rc::Rc};
 
+/// Every array starts with an i32 holding its length, followed by the elements.
+pub(super) const ARRAY_HEADER_SIZE: u32 = 4;
+
+fn emit_store(codes: &mut Vec<OpCode>, elm_type: &Type, offset: u32) {
+    match elm_type {
+        Type::I32 | Type::U32 => codes.push(OpCode::I32Store {
+            offset,
+            alignment: 2,
+        }),
+        Type::I64 | Type::U64 => codes.push(OpCode::I64Store {
+            offset,
+            alignment: 3,
+        }),
+        Type::F32 => codes.push(OpCode::F32Store {
+            offset,
+            alignment: 2,
+        }),
+        Type::Bool => codes.push(OpCode::I32Store8 {
+            offset,
+            alignment: 0,
+        }),
+        Type::Unit | Type::Error | Type::Never => (),
+        Type::Array(_) => codes.push(OpCode::I32Store {
+            offset,
+            alignment: 2,
+        }),
+    }
+}
+
+fn emit_load(codes: &mut Vec<OpCode>, elm_type: &Type, offset: u32) {
+    match elm_type {
+        Type::I32 | Type::U32 => codes.push(OpCode::I32Load {
+            offset,
+            alignment: 2,
+        }),
+        Type::I64 | Type::U64 => codes.push(OpCode::I64Load {
+            offset,
+            alignment: 3,
+        }),
+        Type::F32 => codes.push(OpCode::F32Load {
+            offset,
+            alignment: 2,
+        }),
+        Type::Bool => codes.push(OpCode::I32Load8U {
+            offset,
+            alignment: 0,
+        }),
+        Type::Unit | Type::Error | Type::Never => (),
+        Type::Array(_) => codes.push(OpCode::I32Load {
+            offset,
+            alignment: 2,
+        }),
+    }
+}
+
+/// Emits the array and returns its element type.
+fn emit_array_obj(
+    module: &mut Module,
+    codes: &mut Vec<OpCode>,
+    vec_ast: &AST,
+    env: Rc<RefCell<Env>>,
+) -> Result<Rc<Type>> {
+    let vec_type = emit_obj(module, codes, vec_ast, env)?;
+    match &*vec_type {
+        Type::Array(elm_type) => {
+            ensure!(**elm_type != Type::Unit, "cannot access elements of an empty array");
+            Ok(elm_type.clone())
+        }
+        t => bail!("array expected, found {}", t),
+    }
+}
+
+/// Traps unless the index on top of the stack is less than the length of the array
+/// held in `array_local`. The index is consumed.
+fn emit_bounds_check(codes: &mut Vec<OpCode>, array_local: u32) {
+    codes.push(OpCode::LocalGet(array_local));
+    codes.push(OpCode::I32Load {
+        offset: 0,
+        alignment: 2,
+    });
+    // Unsigned comparison also rejects negative indices.
+    codes.push(OpCode::I32GeU);
+    codes.push(OpCode::If(None));
+    codes.push(OpCode::Unreachable);
+    codes.push(OpCode::End);
+}
+
+/// Leaves `array + index * size_of(element)` on the stack.
+/// Loads and stores add `ARRAY_HEADER_SIZE` through their offset immediate.
+fn emit_element_addr(
+    module: &mut Module,
+    codes: &mut Vec<OpCode>,
+    vec_ast: &AST,
+    index_ast: &AST,
+    env: Rc<RefCell<Env>>,
+) -> Result<Rc<Type>> {
+    let elm_type = emit_array_obj(module, codes, vec_ast, env.clone())?;
+    let array_local = if module.options.bounds_check {
+        let local = env.borrow().new_local();
+        codes.push(OpCode::LocalDecl(WasmPrimitiveType::I32));
+        codes.push(OpCode::LocalTee(local));
+        Some(local)
+    } else {
+        None
+    };
+    let index_type = emit_obj(module, codes, index_ast, env.clone())?;
+    ensure!(
+        *index_type == Type::I32,
+        "array index must be i32, found {}",
+        index_type
+    );
+    if let Some(array_local) = array_local {
+        let index_local = env.borrow().new_local();
+        codes.push(OpCode::LocalDecl(WasmPrimitiveType::I32));
+        codes.push(OpCode::LocalTee(index_local));
+        codes.push(OpCode::LocalGet(index_local));
+        emit_bounds_check(codes, array_local);
+    }
+    codes.push(OpCode::I32Const(get_size(elm_type.clone()) as i32));
+    codes.push(OpCode::I32Mul);
+    codes.push(OpCode::I32Add);
+    Ok(elm_type)
+}
+
 pub(super) fn emit_vector(
     module: &mut Module,
     codes: &mut Vec<OpCode>,
@@ -17,13 +141,8 @@ pub(super) fn emit_vector(
     let mut offset: u32 = 0;
     let mut last_type: Option<Rc<Type>> = None;
 
-    // TODO: Infer type by adding unknown type
-    if items.len() == 0 {
-        return Ok(Rc::new(Type::Array(Rc::new(Type::Unit))));
-    }
-    let stack_pointer_local_addr = codes.iter().filter(|x| 
-        if let OpCode::LocalDecl(_) = x {true} else {false}
-    ).count() as u32;
+    // An empty array still has a header, so that its length can be read.
+    let stack_pointer_local_addr = env.borrow().new_local();
     codes.push(OpCode::LocalDecl(WasmPrimitiveType::I32));
     codes.push(OpCode::GlobalGet(STACK_POINTER.0));
     codes.push(OpCode::LocalTee(stack_pointer_local_addr));
@@ -32,7 +151,7 @@ pub(super) fn emit_vector(
         offset,
         alignment: 2,
     });
-    offset += 4 as u32;
+    offset += ARRAY_HEADER_SIZE;
 
     for item in items {
         codes.push(OpCode::LocalGet(stack_pointer_local_addr));
@@ -48,25 +167,7 @@ pub(super) fn emit_vector(
             last_type = Some(current_type.clone());
         }
         // TODO: Consider alignment
-        match *current_type {
-            Type::I32 => codes.push(OpCode::I32Store {
-                offset,
-                alignment: 2,
-            }),
-            Type::F32 => codes.push(OpCode::F32Store {
-                offset,
-                alignment: 2,
-            }),
-            Type::Bool => codes.push(OpCode::I32Store8 {
-                offset,
-                alignment: 0,
-            }),
-            Type::Unit => (),
-            Type::Array(_) => codes.push(OpCode::I32Store {
-                offset,
-                alignment: 2,
-            }),
-        }
+        emit_store(codes, &current_type, offset);
         offset += get_size(current_type.clone());
     }
     let stack_cnt = &env.borrow().stack_cnt;
@@ -75,8 +176,11 @@ pub(super) fn emit_vector(
     codes.push(OpCode::I32Const(offset as i32));
     codes.push(OpCode::I32Add);
     codes.push(OpCode::GlobalSet(STACK_POINTER.0));
-    codes.push(OpCode::I32Const(stack_pointer_local_addr as i32));
-    Ok(Rc::new(Type::Array(last_type.unwrap())))
+    codes.push(OpCode::LocalGet(stack_pointer_local_addr));
+    // TODO: Infer the element type of an empty array by adding unknown type
+    Ok(Rc::new(Type::Array(
+        last_type.unwrap_or_else(|| Rc::new(Type::Unit)),
+    )))
 }
 
 pub(super) fn emit_index_get(
@@ -86,43 +190,82 @@ pub(super) fn emit_index_get(
     vec_ast: &AST,
     env: Rc<RefCell<Env>>,
 ) -> Result<Rc<Type>> {
-    let vec_type = emit_obj(module, codes, vec_ast, env.clone())?;
-    match &*vec_type {
-        Type::I32 => {
-            codes.push(OpCode::I32Load {
-                offset: 4 * index,
-                alignment: 2,
-            });
-            Ok(Rc::new(Type::I32))
-        }
-        Type::F32 => {
-            codes.push(OpCode::F32Load {
-                offset: 4 * index,
-                alignment: 2,
-            });
-            Ok(Rc::new(Type::F32))
-        }
-        Type::Bool => {
-            codes.push(OpCode::I32Load8U {
-                offset: index,
-                alignment: 0,
-            });
-            Ok(Rc::new(Type::I32))
-        }
-        Type::Unit => Ok(Rc::new(Type::Unit)),
-        Type::Array(elm_type) => {
-            codes.push(OpCode::I32Load {
-                offset: 4 * index,
-                alignment: 2,
-            });
-            Ok(elm_type.clone())
-        }
+    let elm_type = emit_array_obj(module, codes, vec_ast, env.clone())?;
+    if module.options.bounds_check {
+        let array_local = env.borrow().new_local();
+        codes.push(OpCode::LocalDecl(WasmPrimitiveType::I32));
+        codes.push(OpCode::LocalTee(array_local));
+        codes.push(OpCode::I32Const(index as i32));
+        emit_bounds_check(codes, array_local);
     }
+    emit_load(
+        codes,
+        &elm_type,
+        ARRAY_HEADER_SIZE + index * get_size(elm_type.clone()),
+    );
+    Ok(elm_type)
+}
+
+pub(super) fn emit_len(
+    module: &mut Module,
+    codes: &mut Vec<OpCode>,
+    args: &[AST],
+    env: Rc<RefCell<Env>>,
+) -> Result<Rc<Type>> {
+    ensure!(args.len() == 1, "len expects 1 form, found {}", args.len());
+    let vec_type = emit_obj(module, codes, &args[0], env)?;
+    ensure!(
+        matches!(*vec_type, Type::Array(_)),
+        "array expected, found {}",
+        vec_type
+    );
+    codes.push(OpCode::I32Load {
+        offset: 0,
+        alignment: 2,
+    });
+    Ok(Rc::new(Type::I32))
+}
+
+pub(super) fn emit_get(
+    module: &mut Module,
+    codes: &mut Vec<OpCode>,
+    args: &[AST],
+    env: Rc<RefCell<Env>>,
+) -> Result<Rc<Type>> {
+    ensure!(args.len() == 2, "get expects 2 forms, found {}", args.len());
+    let elm_type = emit_element_addr(module, codes, &args[0], &args[1], env)?;
+    emit_load(codes, &elm_type, ARRAY_HEADER_SIZE);
+    Ok(elm_type)
+}
+
+pub(super) fn emit_set_at(
+    module: &mut Module,
+    codes: &mut Vec<OpCode>,
+    args: &[AST],
+    env: Rc<RefCell<Env>>,
+) -> Result<Rc<Type>> {
+    ensure!(args.len() == 3, "set-at! expects 3 forms, found {}", args.len());
+    let elm_type = emit_element_addr(module, codes, &args[0], &args[1], env.clone())?;
+    let value_type = emit_obj(module, codes, &args[2], env)?;
+    ensure!(
+        *value_type == *elm_type,
+        "mismatched types. expected {}, found {}",
+        elm_type,
+        value_type
+    );
+    emit_store(codes, &elm_type, ARRAY_HEADER_SIZE);
+    Ok(Rc::new(Type::Unit))
 }
 
 #[cfg(test)]
 mod tests {
-    use super::*;
+    use super::{
+        super::{
+            interpreter::{Instance, Value},
+            validator::validate,
+        },
+        *,
+    };
     #[test]
     fn test_creating_vector() {
         let module = &mut Module::default();
@@ -165,7 +308,7 @@ mod tests {
                 OpCode::I32Const(16),
                 OpCode::I32Add,
                 OpCode::GlobalSet(0),
-                OpCode::I32Const(0),
+                OpCode::LocalGet(0),
                 OpCode::GlobalGet(STACK_POINTER.0),
                 OpCode::I32Const(16),
                 OpCode::I32Sub,
@@ -202,12 +345,144 @@ mod tests {
             *body,
             vec![
                 OpCode::LocalGet(0),
+                OpCode::LocalDecl(WasmPrimitiveType::I32),
+                OpCode::LocalTee(1),
+                OpCode::I32Const(0),
+                OpCode::LocalGet(1),
                 OpCode::I32Load {
                     offset: 0,
                     alignment: 2
                 },
+                OpCode::I32GeU,
+                OpCode::If(None),
+                OpCode::Unreachable,
+                OpCode::End,
+                OpCode::I32Load {
+                    offset: 4,
+                    alignment: 2
+                },
                 OpCode::End
             ]
         )
     }
+    #[test]
+    fn test_len_get_set() {
+        let module = &mut Module::with_options(CompileOptions::release());
+        let source = "
+        (export defn size: i32
+            [arr: [f32]]
+            (len arr))
+        (export defn nth: bool
+            [arr: [bool] i: i32]
+            (get arr i))
+        (export defn put
+            [arr: [f32] i: i32 v: f32]
+            (set-at! arr i v))
+        ";
+        emit(module, source).unwrap();
+        let functions = module.functions.borrow();
+        assert_eq!(
+            functions["size"].1.body,
+            vec![
+                OpCode::LocalGet(0),
+                OpCode::I32Load {
+                    offset: 0,
+                    alignment: 2
+                },
+                OpCode::End
+            ]
+        );
+        assert_eq!(
+            functions["nth"].1.body,
+            vec![
+                OpCode::LocalGet(0),
+                OpCode::LocalGet(1),
+                OpCode::I32Const(4),
+                OpCode::I32Mul,
+                OpCode::I32Add,
+                OpCode::I32Load8U {
+                    offset: 4,
+                    alignment: 0
+                },
+                OpCode::End
+            ]
+        );
+        assert_eq!(
+            functions["put"].1.body,
+            vec![
+                OpCode::LocalGet(0),
+                OpCode::LocalGet(1),
+                OpCode::I32Const(4),
+                OpCode::I32Mul,
+                OpCode::I32Add,
+                OpCode::LocalGet(2),
+                OpCode::F32Store {
+                    offset: 4,
+                    alignment: 2
+                },
+                OpCode::End
+            ]
+        );
+    }
+    #[test]
+    fn test_bounds_check() {
+        let module = &mut Module::with_options(CompileOptions::debug());
+        let source = "
+        (defn nth: i32
+            [arr: [i32] i: i32]
+            (get arr i))
+        ";
+        emit(module, source).unwrap();
+        let functions = module.functions.borrow();
+        assert_eq!(
+            functions["nth"].1.body,
+            vec![
+                OpCode::LocalGet(0),
+                OpCode::LocalDecl(WasmPrimitiveType::I32),
+                OpCode::LocalTee(2),
+                OpCode::LocalGet(1),
+                OpCode::LocalDecl(WasmPrimitiveType::I32),
+                OpCode::LocalTee(3),
+                OpCode::LocalGet(3),
+                OpCode::LocalGet(2),
+                OpCode::I32Load {
+                    offset: 0,
+                    alignment: 2
+                },
+                OpCode::I32GeU,
+                OpCode::If(None),
+                OpCode::Unreachable,
+                OpCode::End,
+                OpCode::I32Const(4),
+                OpCode::I32Mul,
+                OpCode::I32Add,
+                OpCode::I32Load {
+                    offset: 4,
+                    alignment: 2
+                },
+                OpCode::End
+            ]
+        );
+    }
+    #[test]
+    fn test_empty_vector() {
+        let module = &mut Module::with_options(CompileOptions::debug());
+        emit(module, "(export defn size: i32 [] (len []))").unwrap();
+        validate(module).unwrap();
+        let instance = &mut Instance::new(module).unwrap();
+        assert_eq!(instance.invoke("size", &[]), Ok(vec![Value::I32(0)]));
+        let module = &mut Module::with_options(CompileOptions::debug());
+        let error = emit(module, "(export defn first: i32 [] (get [] 0))").unwrap_err();
+        assert_eq!(error.to_string(), "cannot access elements of an empty array");
+    }
+    #[test]
+    fn test_index_type_error() {
+        let module = &mut Module::default();
+        let source = "
+        (defn nth: i32
+            [arr: [i32] i: f32]
+            (get arr i))
+        ";
+        assert!(emit(module, source).is_err());
+    }
 }
@@ -18,31 +18,122 @@ pub struct Variable {
 pub struct Env {
     parent: Option<Rc<RefCell<Env>>>,
     vars: HashMap<String, Variable>,
-    pub stack_cnt: Cell<u32>
+    pub stack_cnt: Cell<u32>,
+    // Shared by every scope of a function so that locals never collide.
+    local_cnt: Rc<Cell<u32>>,
+    // Names bound to the locals of the function, in binding order.
+    local_names: Rc<RefCell<Vec<(u32, String)>>>,
+    // Only used in the root scope: the namespace of the file being emitted, and the
+    // namespaces it required by alias.
+    namespace: Option<String>,
+    aliases: HashMap<String, String>,
 }
 
+/// The namespace and aliases of a file, restored once a required file is emitted.
+pub type NamespaceScope = (Option<String>, HashMap<String, String>);
+
 impl Env {
     pub fn extend(parent: Rc<RefCell<Self>>) -> Env {
+        let local_cnt = parent.borrow().local_cnt.clone();
+        let local_names = parent.borrow().local_names.clone();
         Env {
             vars: HashMap::new(),
             parent: Some(parent),
-            stack_cnt: Cell::new(0)
+            stack_cnt: Cell::new(0),
+            local_cnt,
+            local_names,
+            ..Default::default()
         }
     }
+
+    /// Creates the root scope of a function body, whose local indices start from 0.
+    pub fn extend_function(parent: Rc<RefCell<Self>>) -> Env {
+        Env {
+            vars: HashMap::new(),
+            parent: Some(parent),
+            stack_cnt: Cell::new(0),
+            local_cnt: Rc::new(Cell::new(0)),
+            local_names: Rc::new(RefCell::new(Vec::new())),
+            ..Default::default()
+        }
+    }
+
+    /// Reserves the next local index of the enclosing function.
+    pub fn new_local(&self) -> u32 {
+        let index = self.local_cnt.get();
+        self.local_cnt.set(index + 1);
+        index
+    }
     pub fn get(&self, name: &str) -> Option<Variable> {
-        match self.vars.get(name) {
-            Some(value) => Some(value.clone()),
+        match &self.parent {
+            Some(parent) => match self.vars.get(name) {
+                Some(value) => Some(value.clone()),
+                None => parent.borrow().get(name),
+            },
+            // Definitions of the current namespace come before the unqualified ones.
             None => self
-                .parent
-                .as_ref()
-                .and_then(|o| (*o.clone()).borrow().get(name).clone()),
+                .vars
+                .get(&self.qualify(name))
+                .or_else(|| self.vars.get(name))
+                .cloned(),
         }
     }
 
+    /// The name a definition called `name` has in the root scope: `ns/name` inside a
+    /// namespace, with `alias/name` expanded to the namespace the alias stands for.
+    pub fn qualify(&self, name: &str) -> String {
+        if let Some(parent) = &self.parent {
+            return parent.borrow().qualify(name);
+        }
+        match (name.split_once('/'), &self.namespace) {
+            (Some((alias, rest)), _) => match self.aliases.get(alias) {
+                Some(namespace) => format!("{}/{}", namespace, rest),
+                None => name.to_string(),
+            },
+            (None, Some(namespace)) => format!("{}/{}", namespace, name),
+            (None, None) => name.to_string(),
+        }
+    }
+
+    /// Makes `namespace` the namespace of the root scope, without aliases, and returns
+    /// the previous one for `exit_namespace`.
+    pub fn enter_namespace(&mut self, namespace: Option<String>) -> NamespaceScope {
+        (
+            std::mem::replace(&mut self.namespace, namespace),
+            std::mem::take(&mut self.aliases),
+        )
+    }
+
+    pub fn exit_namespace(&mut self, (namespace, aliases): NamespaceScope) {
+        self.namespace = namespace;
+        self.aliases = aliases;
+    }
+
+    pub fn set_namespace(&mut self, namespace: &str) {
+        self.namespace = Some(namespace.to_string());
+    }
+
+    pub fn namespace(&self) -> Option<&str> {
+        self.namespace.as_deref()
+    }
+
+    pub fn add_alias(&mut self, alias: &str, namespace: &str) {
+        self.aliases.insert(alias.to_string(), namespace.to_string());
+    }
+
     pub fn set(&mut self, name: &str, val: Variable) -> Option<Variable> {
+        if let Pointer::Local(index) = val.pointer {
+            self.local_names.borrow_mut().push((index, name.to_string()));
+        }
         self.vars.insert(name.to_string(), val)
     }
 
+    /// Names bound to the locals of the enclosing function so far.
+    pub fn local_names(&self) -> Vec<(u32, String)> {
+        self.local_names.borrow().clone()
+    }
+
+    #[allow(dead_code)]
     pub fn count_local_vars(&self) -> usize {
         let mut ret = self
             .vars
@@ -83,4 +174,47 @@ mod tests {
         new_env.borrow_mut().set("c", Variable { pointer: Pointer::Local(2), t: Rc::new(Type::I32) });
         assert_eq!(new_env.borrow().count_local_vars(), 3);
     }
+
+    #[test]
+    fn test_new_local() {
+        let env = Env::create();
+        let func_env = Rc::new(RefCell::new(Env::extend_function(env)));
+        assert_eq!(func_env.borrow().new_local(), 0);
+        let let_env = Rc::new(RefCell::new(Env::extend(func_env.clone())));
+        assert_eq!(let_env.borrow().new_local(), 1);
+        assert_eq!(func_env.borrow().new_local(), 2);
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let env = Env::create();
+        let var = |index| Variable { pointer: Pointer::Global(index), t: Rc::new(Type::I32) };
+        env.borrow_mut().set("pi", var(0));
+        let outer = env.borrow_mut().enter_namespace(Some("math".to_string()));
+        let name = env.borrow().qualify("pi");
+        env.borrow_mut().set(&name, var(1));
+        assert_eq!(env.borrow().get("pi"), Some(var(1)));
+        env.borrow_mut().exit_namespace(outer);
+
+        env.borrow_mut().add_alias("m", "math");
+        let func_env = Rc::new(RefCell::new(Env::extend_function(env.clone())));
+        assert_eq!(func_env.borrow().qualify("m/pi"), "math/pi");
+        assert_eq!(func_env.borrow().get("m/pi"), Some(var(1)));
+        assert_eq!(func_env.borrow().get("pi"), Some(var(0)));
+        assert_eq!(func_env.borrow().get("x/pi"), None);
+    }
+
+    #[test]
+    fn test_local_names() {
+        let env = Env::create();
+        let func_env = Rc::new(RefCell::new(Env::extend_function(env)));
+        func_env.borrow_mut().set("a", Variable { pointer: Pointer::Local(0), t: Rc::new(Type::I32) });
+        let let_env = Rc::new(RefCell::new(Env::extend(func_env.clone())));
+        let_env.borrow_mut().set("b", Variable { pointer: Pointer::Local(1), t: Rc::new(Type::I32) });
+        let_env.borrow_mut().set("g", Variable { pointer: Pointer::Global(0), t: Rc::new(Type::I32) });
+        assert_eq!(
+            func_env.borrow().local_names(),
+            vec![(0, "a".to_string()), (1, "b".to_string())]
+        );
+    }
 }
\ No newline at end of file
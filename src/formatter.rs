@@ -0,0 +1,518 @@
+use crate::{
+    lexer::{tokenize_spanned, Token},
+    parser::{parse_source, primitive_type_from_name},
+};
+use anyhow::{bail, ensure, Result};
+use std::ops::Range;
+
+/// Forms are broken over several lines when they would not fit in this many columns.
+const MAX_WIDTH: usize = 80;
+/// Indentation of the body of a form, relative to its opening parenthesis.
+const INDENT: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Delimiter {
+    Paren,
+    Bracket,
+}
+
+impl Delimiter {
+    fn open(self) -> char {
+        match self {
+            Delimiter::Paren => '(',
+            Delimiter::Bracket => '[',
+        }
+    }
+
+    fn close(self) -> char {
+        match self {
+            Delimiter::Paren => ')',
+            Delimiter::Bracket => ']',
+        }
+    }
+}
+
+/// A node of the concrete syntax tree. Unlike `AST`, it keeps the comments and the
+/// empty lines of the source.
+#[derive(Debug, PartialEq)]
+enum Node<'a> {
+    /// The canonical text of a token, or of a `name: type` annotation.
+    Atom(String),
+    Comment(&'a str),
+    List(Delimiter, Vec<Item<'a>>),
+}
+
+#[derive(Debug, PartialEq)]
+struct Item<'a> {
+    node: Node<'a>,
+    /// Preceded by an empty line.
+    blank_before: bool,
+    /// On the same line as the token before it.
+    trailing: bool,
+}
+
+fn is_comment(item: &Item) -> bool {
+    matches!(item.node, Node::Comment(_))
+}
+
+/// Whether `token` can be written next to another one without space between them.
+fn is_atom(token: &Token) -> bool {
+    !matches!(
+        token,
+        Token::LParen
+            | Token::RParen
+            | Token::LBracket
+            | Token::RBracket
+            | Token::Colon
+            | Token::Comment(_)
+    )
+}
+
+struct Reader<'a> {
+    source: &'a str,
+    tokens: Vec<(Token<'a>, Range<usize>)>,
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn peek(&self, offset: usize) -> Option<Token<'a>> {
+        self.tokens.get(self.pos + offset).map(|(token, _)| *token)
+    }
+
+    /// The text between the current token and the one before it.
+    fn gap(&self) -> &'a str {
+        let end = match self.pos {
+            0 => 0,
+            pos => self.tokens[pos - 1].1.end,
+        };
+        &self.source[end..self.tokens[self.pos].1.start]
+    }
+
+    /// Reads the items up to `close`, or to the end of the source.
+    fn read_items(&mut self, close: Option<Token<'a>>) -> Result<Vec<Item<'a>>> {
+        let mut items = Vec::new();
+        loop {
+            match self.peek(0) {
+                None => match close {
+                    Some(close) => bail!("expected {} before the end of the file", close),
+                    None => return Ok(items),
+                },
+                Some(token) if Some(token) == close => {
+                    self.pos += 1;
+                    return Ok(items);
+                }
+                Some(token @ (Token::RParen | Token::RBracket)) => bail!("unexpected {}", token),
+                _ => (),
+            }
+            let gap = self.gap();
+            let blank_before = gap.matches('\n').count() > 1;
+            let trailing = self.pos > 0 && !gap.contains('\n');
+            items.push(Item {
+                node: self.read_node()?,
+                blank_before,
+                trailing,
+            });
+        }
+    }
+
+    /// Whether the tokens left start with a colon followed by a type, like the parser
+    /// decides whether a symbol is annotated.
+    fn annotation_follows(&self) -> bool {
+        self.peek(0) == Some(Token::Colon)
+            && match self.peek(1) {
+                Some(Token::Symbol(name)) => primitive_type_from_name(name).is_some(),
+                Some(Token::LBracket) => true,
+                _ => false,
+            }
+    }
+
+    fn read_type(&mut self) -> Result<String> {
+        let token = self.peek(0);
+        self.pos += 1;
+        match token {
+            Some(Token::Symbol(name)) if primitive_type_from_name(name).is_some() => {
+                Ok(name.to_string())
+            }
+            Some(Token::LBracket) => {
+                let item_type = self.read_type()?;
+                ensure!(self.peek(0) == Some(Token::RBracket), "expected ']'");
+                self.pos += 1;
+                Ok(format!("[{}]", item_type))
+            }
+            _ => bail!("expected a type"),
+        }
+    }
+
+    fn read_node(&mut self) -> Result<Node<'a>> {
+        let (token, span) = self.tokens[self.pos].clone();
+        self.pos += 1;
+        Ok(match token {
+            Token::LParen => Node::List(Delimiter::Paren, self.read_items(Some(Token::RParen))?),
+            Token::LBracket => {
+                Node::List(Delimiter::Bracket, self.read_items(Some(Token::RBracket))?)
+            }
+            Token::Comment(text) => Node::Comment(text),
+            Token::Symbol(name) if self.annotation_follows() => {
+                self.pos += 1;
+                Node::Atom(format!("{}: {}", name, self.read_type()?))
+            }
+            Token::Colon => match self.peek(0) {
+                Some(Token::Symbol(name)) => {
+                    self.pos += 1;
+                    Node::Atom(format!(":{}", name))
+                }
+                _ => bail!("expected a keyword after colon"),
+            },
+            _ => {
+                // Tokens written without space between them, such as `-1`, stay together.
+                let mut text = token.to_string();
+                let mut end = span.end;
+                while let Some((next, next_span)) = self.tokens.get(self.pos) {
+                    if next_span.start != end || !is_atom(next) {
+                        break;
+                    }
+                    text.push_str(&next.to_string());
+                    end = next_span.end;
+                    self.pos += 1;
+                }
+                Node::Atom(text)
+            }
+        })
+    }
+}
+
+fn head<'b>(items: &'b [Item]) -> Option<&'b str> {
+    match items.first().map(|item| &item.node) {
+        Some(Node::Atom(text)) => Some(text),
+        _ => None,
+    }
+}
+
+fn is_atom_item(item: Option<&Item>, text: &str) -> bool {
+    matches!(item.map(|item| &item.node), Some(Node::Atom(t)) if t == text)
+}
+
+/// Whether a list is laid out over several lines even when it would fit on one: function
+/// and test bodies, and `let` forms with more than one binding.
+fn always_breaks(delimiter: Delimiter, items: &[Item]) -> bool {
+    delimiter == Delimiter::Paren
+        && match head(items) {
+            Some("defn" | "deftest" | "init") => true,
+            Some("export") => is_atom_item(items.get(1), "defn"),
+            Some("let") => matches!(
+                items.get(1).map(|item| &item.node),
+                Some(Node::List(Delimiter::Bracket, bindings)) if bindings.len() > 2
+            ),
+            _ => false,
+        }
+}
+
+/// `node` on a single line, unless it has comments or a list that always breaks.
+fn flat(node: &Node) -> Option<String> {
+    match node {
+        Node::Atom(text) => Some(text.clone()),
+        Node::Comment(_) => None,
+        Node::List(delimiter, items) => {
+            if always_breaks(*delimiter, items) {
+                return None;
+            }
+            let items = items
+                .iter()
+                .map(|item| flat(&item.node))
+                .collect::<Option<Vec<_>>>()?;
+            Some(format!("{}{}{}", delimiter.open(), items.join(" "), delimiter.close()))
+        }
+    }
+}
+
+/// The number of items kept on the line of the opening delimiter of a broken list, and
+/// the column of the items after them.
+fn layout(delimiter: Delimiter, items: &[Item], column: usize) -> (usize, usize) {
+    let body = column + INDENT;
+    // A function header runs up to its argument vector.
+    let header = || {
+        items
+            .iter()
+            .position(|item| matches!(item.node, Node::List(Delimiter::Bracket, _)))
+            .map_or(2, |pos| pos + 1)
+    };
+    if delimiter == Delimiter::Bracket {
+        return (1, column + 1);
+    }
+    match head(items) {
+        Some("defn") => (header(), body),
+        Some("export") if is_atom_item(items.get(1), "defn") => (header(), body),
+        Some("export") => (3, body),
+        Some("define" | "defmut" | "let" | "if" | "deftest" | "ns") => (2, body),
+        Some("init") => (1, body),
+        // Arguments are aligned with the first one.
+        Some(head) => (2, column + head.chars().count() + 2),
+        None => (1, column + 1),
+    }
+}
+
+/// The column `text` ends at, when it starts at `column`.
+fn end_column(text: &str, column: usize) -> usize {
+    match text.rsplit_once('\n') {
+        Some((_, last)) => last.chars().count(),
+        None => column + text.chars().count(),
+    }
+}
+
+fn render(node: &Node, column: usize) -> String {
+    match node {
+        Node::Atom(text) => text.clone(),
+        Node::Comment(text) => text.to_string(),
+        Node::List(delimiter, items) => match flat(node) {
+            Some(text) if column + text.chars().count() <= MAX_WIDTH => text,
+            _ => render_broken(*delimiter, items, column),
+        },
+    }
+}
+
+/// The bindings of a `let`, one pair per line with the values aligned. Comments get lines
+/// of their own between the pairs, unless they end the line of one. Those between a name
+/// and its value move before the pair.
+fn render_bindings(node: &Node, column: usize) -> String {
+    let items = match node {
+        Node::List(Delimiter::Bracket, items) if items.len() > 2 => items,
+        _ => return render(node, column),
+    };
+    let bindings = items.iter().filter(|item| !is_comment(item)).collect::<Vec<_>>();
+    if bindings.is_empty() || bindings.len() % 2 != 0 {
+        return render(node, column);
+    }
+    let names = match bindings
+        .iter()
+        .step_by(2)
+        .map(|item| flat(&item.node))
+        .collect::<Option<Vec<_>>>()
+    {
+        Some(names) => names,
+        None => return render(node, column),
+    };
+    let width = names.iter().map(|name| name.chars().count()).max().unwrap();
+    let new_line = |out: &mut String| {
+        out.push('\n');
+        out.push_str(&" ".repeat(column + 1));
+    };
+    let mut out = String::from("[");
+    let mut pairs = 0;
+    let mut has_name = false;
+    let mut moved_comments = Vec::new();
+    for item in items {
+        if is_comment(item) {
+            if has_name {
+                moved_comments.push(item);
+            } else {
+                if item.trailing {
+                    out.push(' ');
+                } else {
+                    new_line(&mut out);
+                }
+                out.push_str(&render(&item.node, column + 1));
+            }
+        } else if !has_name {
+            has_name = true;
+        } else {
+            for comment in moved_comments.drain(..) {
+                new_line(&mut out);
+                out.push_str(&render(&comment.node, column + 1));
+            }
+            if out.len() > 1 {
+                new_line(&mut out);
+            }
+            out.push_str(&format!("{:width$} ", names[pairs], width = width));
+            out.push_str(&render(&item.node, column + width + 2));
+            pairs += 1;
+            has_name = false;
+        }
+    }
+    // A comment runs to the end of the line.
+    if items.last().is_some_and(is_comment) {
+        new_line(&mut out);
+    }
+    out.push(']');
+    out
+}
+
+fn render_broken(delimiter: Delimiter, items: &[Item], column: usize) -> String {
+    let (inline, indent) = layout(delimiter, items, column);
+    let mut out = delimiter.open().to_string();
+    let mut rest = items;
+    for (i, item) in items.iter().enumerate().take(inline) {
+        if is_comment(item) {
+            break;
+        }
+        if i > 0 {
+            out.push(' ');
+        }
+        let item_column = end_column(&out, column);
+        if i == 1 && head(items) == Some("let") {
+            out.push_str(&render_bindings(&item.node, item_column));
+        } else {
+            out.push_str(&render(&item.node, item_column));
+        }
+        rest = &items[i + 1..];
+    }
+    for item in rest {
+        if item.trailing && is_comment(item) {
+            out.push(' ');
+        } else {
+            out.push('\n');
+            if item.blank_before {
+                out.push('\n');
+            }
+            out.push_str(&" ".repeat(indent));
+        }
+        out.push_str(&render(&item.node, indent));
+    }
+    // A comment runs to the end of the line.
+    if items.last().is_some_and(is_comment) {
+        out.push('\n');
+        out.push_str(&" ".repeat(indent));
+    }
+    out.push(delimiter.close());
+    out
+}
+
+/// Formats `source` canonically, keeping its comments and collapsing runs of empty lines
+/// into one.
+pub fn format_source(source: &str) -> Result<String> {
+    let ast = parse_source(source)?;
+    let mut reader = Reader {
+        source,
+        tokens: tokenize_spanned(source)?,
+        pos: 0,
+    };
+    let items = reader.read_items(None)?;
+    let mut out = String::new();
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            if item.trailing && is_comment(item) {
+                out.push(' ');
+            } else {
+                out.push('\n');
+                if item.blank_before {
+                    out.push('\n');
+                }
+            }
+        }
+        out.push_str(&render(&item.node, 0));
+    }
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    ensure!(
+        parse_source(&out)? == ast,
+        "internal error: the formatted source does not parse to the same program"
+    );
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        assert_eq!(
+            format_source(
+                "(defn   add_two :i32 [a : i32,b:i32]   (+ a b))
+                (define scale:f32 2.5)(export defn main: i32 []
+                  (let [total (add_two 1 -2) x 3]
+                    (if (> total 0) total
+                        x)))"
+            )
+            .unwrap(),
+            "(defn add_two: i32 [a: i32 b: i32]
+    (+ a b))
+(define scale: f32 2.5)
+(export defn main: i32 []
+    (let [total (add_two 1 -2)
+          x     3]
+        (if (> total 0) total x)))
+"
+        );
+    }
+
+    #[test]
+    fn test_long_forms() {
+        assert_eq!(
+            format_source(
+                "(defn pick: f32 [i: i32] (if (> i 100) (sqrt (as f32 (* i i i i i i i))) (get [1.0 2.0 3.0 4.0 5.0 6.0 7.0 8.0] i)))"
+            )
+            .unwrap(),
+            "(defn pick: f32 [i: i32]
+    (if (> i 100)
+        (sqrt (as f32 (* i i i i i i i)))
+        (get [1.0 2.0 3.0 4.0 5.0 6.0 7.0 8.0] i)))
+"
+        );
+    }
+
+    #[test]
+    fn test_comments() {
+        let source = "; Math helpers.
+(ns math)
+
+
+(defn square: i32 [x: i32] ; squares x
+    ; the body
+    (* x x))
+(memory :initial 4) ; four pages
+";
+        assert_eq!(
+            format_source(source).unwrap(),
+            "; Math helpers.
+(ns math)
+
+(defn square: i32 [x: i32] ; squares x
+    ; the body
+    (* x x))
+(memory :initial 4) ; four pages
+"
+        );
+    }
+
+    #[test]
+    fn test_comments_in_bindings() {
+        let source = "(defn f: i32 []
+    (let [; the inputs
+          a 1 ; first
+          bb 2
+          ; derived
+          c ; sum
+            (+ a bb)]
+        c))
+";
+        let formatted = format_source(source).unwrap();
+        assert_eq!(
+            formatted,
+            "(defn f: i32 []
+    (let [ ; the inputs
+          a  1 ; first
+          bb 2
+          ; derived
+          ; sum
+          c  (+ a bb)]
+        c))
+"
+        );
+        assert_eq!(format_source(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn test_idempotent() {
+        let source = include_str!("../sample.wisp");
+        let formatted = format_source(source).unwrap();
+        assert_eq!(format_source(&formatted).unwrap(), formatted);
+        assert!(formatted.contains("(defn call-first []\n    (first [1 2 3]))"));
+    }
+
+    #[test]
+    fn test_invalid_source() {
+        assert!(format_source("(defn f: i32 [] 1").is_err());
+        assert!(format_source("(defn f: i32 [] 1))").is_err());
+    }
+}
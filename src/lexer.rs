@@ -1,11 +1,12 @@
-use std::fmt::{Display};
+use std::{fmt::Display, ops::Range};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Token<'a> {
     Symbol(&'a str),
     NumberLiteral(&'a str),
+    StringLiteral(&'a str),
     Plus,
     Minus,
     Asterisk,
@@ -24,7 +25,23 @@ pub enum Token<'a> {
     False,
     And,
     Or,
-    Not
+    Not,
+    Mod,
+    Rem,
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    Shl,
+    Shr,
+    UShr,
+    Rotl,
+    Rotr,
+    Clz,
+    Ctz,
+    Popcnt,
+    /// A comment from `;` to the end of the line, which only `tokenize_spanned` keeps.
+    Comment(&'a str),
 }
 
 impl<'a> Display for Token<'a> {
@@ -32,6 +49,8 @@ impl<'a> Display for Token<'a> {
         match self {
             Token::Symbol(s) => write!(f, "{}", s),
             Token::NumberLiteral(s) => write!(f, "{}", s),
+            Token::StringLiteral(s) => write!(f, "\"{}\"", s),
+            Token::Comment(s) => write!(f, "{}", s),
             Token::Plus => write!(f, "+"),
             Token::Minus => write!(f, "-"),
             Token::Asterisk => write!(f, "*"),
@@ -50,20 +69,48 @@ impl<'a> Display for Token<'a> {
             Token::Le => write!(f, "<="),
             Token::And => write!(f, "and"),
             Token::Or => write!(f, "or"),
-            Token::Not => write!(f, "not")
+            Token::Not => write!(f, "not"),
+            Token::Mod => write!(f, "mod"),
+            Token::Rem => write!(f, "rem"),
+            Token::BitAnd => write!(f, "bit-and"),
+            Token::BitOr => write!(f, "bit-or"),
+            Token::BitXor => write!(f, "bit-xor"),
+            Token::BitNot => write!(f, "bit-not"),
+            Token::Shl => write!(f, "shl"),
+            Token::Shr => write!(f, "shr"),
+            Token::UShr => write!(f, "ushr"),
+            Token::Rotl => write!(f, "rotl"),
+            Token::Rotr => write!(f, "rotr"),
+            Token::Clz => write!(f, "clz"),
+            Token::Ctz => write!(f, "ctz"),
+            Token::Popcnt => write!(f, "popcnt"),
         }
     }
 }
 
-const SPECIAL_CHARS: &'static [char] = &['(', ')', ':', ',', '[', ']'];
+const SPECIAL_CHARS: &'static [char] = &['(', ')', ':', ',', '[', ']', ';'];
 
+#[allow(dead_code)]
 pub fn tokenize(source: &str) -> Result<Vec<Token>> {
+    Ok(tokenize_spanned(source)?
+        .into_iter()
+        .map(|(token, _)| token)
+        .filter(|token| !matches!(token, Token::Comment(_)))
+        .collect())
+}
+
+/// Tokenizes `source` with the byte range of each token, keeping the comments.
+pub fn tokenize_spanned(source: &str) -> Result<Vec<(Token<'_>, Range<usize>)>> {
     let mut ret = Vec::new();
     let mut src = source;
     loop {
         if let Some(c) = src.chars().next() {
             let mut eaten = 1;
             let token = match c {
+                ';' => {
+                    eaten = src.find('\n').unwrap_or(src.len());
+                    Token::Comment(src[..eaten].trim_end())
+                }
                 '\n' => {
                     src = &src[1..];
                     continue;
@@ -72,6 +119,13 @@ pub fn tokenize(source: &str) -> Result<Vec<Token>> {
                     src = &src[1..];
                     continue;
                 }
+                '"' => match src[1..].find('"') {
+                    Some(len) => {
+                        eaten = len + 2;
+                        Token::StringLiteral(&src[1..len + 1])
+                    }
+                    None => bail!("unterminated string literal"),
+                },
                 '(' => Token::LParen,
                 ')' => Token::RParen,
                 '[' => Token::LBracket,
@@ -100,7 +154,10 @@ pub fn tokenize(source: &str) -> Result<Vec<Token>> {
                 }
                 _ => {
                     if c.is_digit(10) {
-                        eaten = src.find(|c: char| c != '.' && !c.is_digit(10)).unwrap();
+                        // Digits may be followed by a type suffix such as `u32`.
+                        eaten = src
+                            .find(|c: char| c != '.' && !c.is_ascii_alphanumeric())
+                            .unwrap_or(src.len());
                         let value_str = &src[0..eaten];
                         Token::NumberLiteral(value_str)
                     } else {
@@ -108,7 +165,7 @@ pub fn tokenize(source: &str) -> Result<Vec<Token>> {
                             .find(|c: char| {
                                 c.is_whitespace() || SPECIAL_CHARS.contains(&c)
                             })
-                            .unwrap();
+                            .unwrap_or(src.len());
                         let name = &src[0..eaten];
                         match name {
                             "true" => Token::True,
@@ -116,12 +173,27 @@ pub fn tokenize(source: &str) -> Result<Vec<Token>> {
                             "and" => Token::And,
                             "or" => Token::Or,
                             "not" => Token::Not,
+                            "mod" => Token::Mod,
+                            "rem" => Token::Rem,
+                            "bit-and" => Token::BitAnd,
+                            "bit-or" => Token::BitOr,
+                            "bit-xor" => Token::BitXor,
+                            "bit-not" => Token::BitNot,
+                            "shl" => Token::Shl,
+                            "shr" => Token::Shr,
+                            "ushr" => Token::UShr,
+                            "rotl" => Token::Rotl,
+                            "rotr" => Token::Rotr,
+                            "clz" => Token::Clz,
+                            "ctz" => Token::Ctz,
+                            "popcnt" => Token::Popcnt,
                             _ => Token::Symbol(name),
                         }
                     }
                 }
             };
-            ret.push(token);
+            let start = source.len() - src.len();
+            ret.push((token, start..start + eaten));
             src = &src[eaten..];
         } else {
             break;
@@ -206,6 +278,40 @@ mod tests {
         ])
     }
 
+    #[test]
+    fn test_string_literal() {
+        let tokens = tokenize("(require \"lib/math.wisp\" :as m)").unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::LParen,
+                Token::Symbol("require"),
+                Token::StringLiteral("lib/math.wisp"),
+                Token::Colon,
+                Token::Symbol("as"),
+                Token::Symbol("m"),
+                Token::RParen,
+            ]
+        );
+        assert!(tokenize("(require \"math.wisp)").is_err());
+    }
+
+    #[test]
+    fn test_number_suffix() {
+        let tokens = tokenize("(+ 10u32 2.5 7i64)").unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::LParen,
+                Token::Plus,
+                Token::NumberLiteral("10u32"),
+                Token::NumberLiteral("2.5"),
+                Token::NumberLiteral("7i64"),
+                Token::RParen
+            ]
+        )
+    }
+
     #[test]
     fn test_sub() {
         let tokens = tokenize("(- a 1)").unwrap();
@@ -242,4 +348,23 @@ mod tests {
             Token::RParen,
         ])
     }
+
+    #[test]
+    fn test_comments() {
+        let source = "; sum\n(+ a 1) ; one  \n;";
+        assert_eq!(
+            tokenize_spanned(source).unwrap(),
+            vec![
+                (Token::Comment("; sum"), 0..5),
+                (Token::LParen, 6..7),
+                (Token::Plus, 7..8),
+                (Token::Symbol("a"), 9..10),
+                (Token::NumberLiteral("1"), 11..12),
+                (Token::RParen, 12..13),
+                (Token::Comment("; one"), 14..21),
+                (Token::Comment(";"), 22..23),
+            ]
+        );
+        assert_eq!(tokenize(source).unwrap().len(), 5);
+    }
 }
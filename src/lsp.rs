@@ -0,0 +1,582 @@
+use crate::{
+    diagnostic::{errors, Diagnostic},
+    emitter::{emit, emit_with_path, CompileOptions, Module},
+    parser::{parse_source_recovering, Spans, AST},
+    resolver::Type,
+};
+use anyhow::{anyhow, Context, Result};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    io::{BufRead, Write},
+    ops::Range,
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// `DiagnosticSeverity.Error`.
+const ERROR_SEVERITY: i64 = 1;
+/// `CompletionItemKind.Function`.
+const FUNCTION_KIND: i64 = 3;
+/// `TextDocumentSyncKind.Full`: every change sends the whole document.
+const FULL_SYNC: i64 = 1;
+
+/// Reads a message framed by a `Content-Length` header, or `None` at the end of the input.
+fn read_message(reader: &mut impl BufRead) -> Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = Some(value.trim().parse::<usize>()?);
+            }
+        }
+    }
+    let mut body = vec![0; length.context("message without Content-Length")?];
+    reader.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+fn write_message(writer: &mut impl Write, message: &Value) -> Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Byte offset of an LSP position, whose character is counted in UTF-16 code units.
+fn offset_at(text: &str, position: &Value) -> Result<usize> {
+    let line = position["line"].as_u64().context("position without line")? as usize;
+    let character = position["character"].as_u64().context("position without character")?;
+    let line_start = match line {
+        0 => 0,
+        _ => match text.match_indices('\n').nth(line - 1) {
+            Some((newline, _)) => newline + 1,
+            None => return Ok(text.len()),
+        },
+    };
+    let mut units = 0;
+    for (i, c) in text[line_start..].char_indices() {
+        if units >= character || c == '\n' {
+            return Ok(line_start + i);
+        }
+        units += c.len_utf16() as u64;
+    }
+    Ok(text.len())
+}
+
+fn position(text: &str, offset: usize) -> Value {
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    json!({
+        "line": before.matches('\n').count(),
+        "character": before[line_start..].encode_utf16().count(),
+    })
+}
+
+fn range(text: &str, span: &Range<usize>) -> Value {
+    json!({ "start": position(text, span.start), "end": position(text, span.end) })
+}
+
+/// The path of a `file:` URI.
+fn file_path(uri: &str) -> Option<PathBuf> {
+    let path = uri.strip_prefix("file://")?;
+    let mut bytes = Vec::new();
+    let mut i = 0;
+    while i < path.len() {
+        let escaped = path.as_bytes()[i] == b'%';
+        match path.get(i + 1..i + 3).filter(|_| escaped) {
+            Some(hex) if u8::from_str_radix(hex, 16).is_ok() => {
+                bytes.push(u8::from_str_radix(hex, 16).unwrap());
+                i += 3;
+            }
+            _ => {
+                bytes.push(path.as_bytes()[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+/// What compiling a document found out.
+struct Analysis {
+    diagnostics: Vec<(Range<usize>, String)>,
+    symbol_types: Vec<(Range<usize>, Rc<Type>)>,
+    /// The functions that can be called, with their signature.
+    functions: Vec<(String, String)>,
+}
+
+/// Compiles `text`, read from `path` if it is a file, to find its errors, the types of its
+/// symbols and its functions.
+fn analyze(path: Option<&Path>, text: &str) -> Analysis {
+    let module = &mut Module::with_options(CompileOptions {
+        symbol_types: true,
+        tests: true,
+        ..CompileOptions::debug()
+    });
+    // A bug in the compiler must not bring the server down.
+    let result = catch_unwind(AssertUnwindSafe(|| match path {
+        Some(path) => emit_with_path(module, text, path),
+        None => emit(module, text),
+    }))
+    .unwrap_or_else(|_| Err(anyhow!("internal compiler error")));
+    let diagnostics = match &result {
+        Ok(()) => Vec::new(),
+        Err(error) => errors(error)
+            .into_iter()
+            .map(|error| {
+                let span = match error.downcast_ref::<Diagnostic>() {
+                    Some(diagnostic) => diagnostic.span.clone(),
+                    None => 0..0,
+                };
+                (span, format!("{:#}", error))
+            })
+            .collect(),
+    };
+    let mut functions = module
+        .functions
+        .borrow()
+        .iter()
+        .filter(|(name, (_, func))| func.import.is_none() && !name.starts_with("__"))
+        .map(|(name, (_, func))| {
+            let args = func.arg_types.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+            (name.clone(), format!("[{}] {}", args.join(" "), func.result_type))
+        })
+        .collect::<Vec<_>>();
+    functions.sort();
+    Analysis {
+        diagnostics,
+        symbol_types: std::mem::take(&mut module.symbol_types),
+        functions,
+    }
+}
+
+/// The name and range of a symbol bound by a definition.
+fn binding<'a>(spans: &Spans, ast: &AST<'a>) -> Option<(&'a str, Range<usize>)> {
+    match ast {
+        AST::Symbol(name) | AST::SymbolWithAnnotation(name, _) => Some((name, spans.get(ast)?)),
+        _ => None,
+    }
+}
+
+/// Resolves the symbol under the cursor to its binding, following the scoping of the
+/// emitter: function arguments, then `let` bindings, then the definitions of the file.
+struct DefinitionFinder<'a, 's> {
+    spans: &'s Spans,
+    offset: usize,
+    scopes: Vec<(&'a str, Range<usize>)>,
+    globals: HashMap<&'a str, Range<usize>>,
+}
+
+impl<'a> DefinitionFinder<'a, '_> {
+    fn lookup(&self, name: &str) -> Option<Range<usize>> {
+        match self.scopes.iter().rev().find(|(bound, _)| *bound == name) {
+            Some((_, span)) => Some(span.clone()),
+            None => self.globals.get(name).cloned(),
+        }
+    }
+
+    /// The binding, if the cursor is on its name.
+    fn on_binding(&self, ast: &AST<'a>) -> Option<Range<usize>> {
+        binding(self.spans, ast)
+            .map(|(_, span)| span)
+            .filter(|span| span.contains(&self.offset))
+    }
+
+    fn visit_all(&mut self, forms: &[AST<'a>]) -> Option<Range<usize>> {
+        forms.iter().find_map(|form| self.visit(form))
+    }
+
+    fn visit(&mut self, ast: &AST<'a>) -> Option<Range<usize>> {
+        match ast {
+            AST::Symbol(name) | AST::SymbolWithAnnotation(name, _) => {
+                let span = self.spans.get(ast)?;
+                span.contains(&self.offset).then(|| self.lookup(name))?
+            }
+            AST::List(list) => match list.as_slice() {
+                [AST::Symbol("defn"), rest @ ..]
+                | [AST::Symbol("export"), AST::Symbol("defn"), rest @ ..] => {
+                    let args = rest.iter().position(|form| matches!(form, AST::Vector(_)))?;
+                    if let Some(span) = rest[..args].iter().find_map(|form| self.on_binding(form)) {
+                        return Some(span);
+                    }
+                    let outer = self.scopes.len();
+                    let found = match &rest[args] {
+                        AST::Vector(args) => args.iter().find_map(|arg| {
+                            self.scopes.extend(binding(self.spans, arg));
+                            self.on_binding(arg)
+                        }),
+                        _ => None,
+                    };
+                    let found = found.or_else(|| self.visit_all(&rest[args + 1..]));
+                    self.scopes.truncate(outer);
+                    found
+                }
+                [AST::Symbol("let"), AST::Vector(bindings), body @ ..] => {
+                    let outer = self.scopes.len();
+                    // Each value sees the bindings before it.
+                    let found = bindings.chunks(2).find_map(|pair| {
+                        if let Some(span) = pair.get(1).and_then(|value| self.visit(value)) {
+                            return Some(span);
+                        }
+                        self.scopes.extend(binding(self.spans, &pair[0]));
+                        self.on_binding(&pair[0])
+                    });
+                    let found = found.or_else(|| self.visit_all(body));
+                    self.scopes.truncate(outer);
+                    found
+                }
+                forms => self.visit_all(forms),
+            },
+            AST::Vector(forms) => self.visit_all(forms),
+            _ => None,
+        }
+    }
+}
+
+/// The range of the definition of the symbol at `offset` in `source`, if it is bound by a
+/// `defn`, `define` or `defmut` of the file, a function argument or a `let`. The forms
+/// that do not parse are skipped, as the document is often being edited.
+fn find_definition(source: &str, offset: usize) -> Option<Range<usize>> {
+    let (ast, spans, _) = parse_source_recovering(source);
+    let toplevels = match &ast {
+        AST::Module(toplevels) => toplevels,
+        _ => return None,
+    };
+    let mut globals = HashMap::new();
+    for toplevel in toplevels {
+        if let AST::List(list) = toplevel {
+            if let [AST::Symbol("defn" | "define" | "defmut"), name, ..]
+            | [AST::Symbol("export"), AST::Symbol("defn" | "define" | "defmut"), name, ..] =
+                list.as_slice()
+            {
+                globals.extend(binding(&spans, name));
+            }
+        }
+    }
+    let mut finder = DefinitionFinder {
+        spans: &spans,
+        offset,
+        scopes: Vec::new(),
+        globals,
+    };
+    finder.visit_all(toplevels)
+}
+
+struct Document {
+    text: String,
+    analysis: Analysis,
+}
+
+#[derive(Default)]
+struct Server {
+    documents: HashMap<String, Document>,
+    shut_down: bool,
+}
+
+impl Server {
+    /// Handles a message from the client and returns the messages to send back.
+    fn handle(&mut self, message: &Value) -> Vec<Value> {
+        let method = match message["method"].as_str() {
+            Some(method) => method,
+            // A response, to a request the server never sends.
+            None => return Vec::new(),
+        };
+        let params = &message["params"];
+        let id = match message.get("id") {
+            Some(id) => id,
+            None => return self.notify(method, params).into_iter().collect(),
+        };
+        let result = match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": FULL_SYNC,
+                    "hoverProvider": true,
+                    "definitionProvider": true,
+                    "completionProvider": {},
+                },
+                "serverInfo": { "name": "wisp" },
+            })),
+            "shutdown" => {
+                self.shut_down = true;
+                Ok(Value::Null)
+            }
+            "textDocument/hover" => self.hover(params),
+            "textDocument/definition" => self.definition(params),
+            "textDocument/completion" => self.completion(params),
+            _ => return vec![error_response(id, METHOD_NOT_FOUND, format!("unknown method {}", method))],
+        };
+        vec![match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(error) => error_response(id, INVALID_PARAMS, format!("{:#}", error)),
+        }]
+    }
+
+    /// Handles a notification, which may publish diagnostics.
+    fn notify(&mut self, method: &str, params: &Value) -> Option<Value> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        let text = match method {
+            "textDocument/didOpen" => params["textDocument"]["text"].as_str()?,
+            "textDocument/didChange" => params["contentChanges"].as_array()?.last()?["text"].as_str()?,
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                return Some(publish_diagnostics(uri, None));
+            }
+            _ => return None,
+        };
+        let document = Document {
+            analysis: analyze(file_path(uri).as_deref(), text),
+            text: text.to_string(),
+        };
+        let notification = publish_diagnostics(uri, Some(&document));
+        self.documents.insert(uri.to_string(), document);
+        Some(notification)
+    }
+
+    /// The document and offset a request is about.
+    fn document_position(&self, params: &Value) -> Result<(&Document, usize)> {
+        let uri = params["textDocument"]["uri"].as_str().context("request without document")?;
+        let document = self
+            .documents
+            .get(uri)
+            .with_context(|| format!("{} is not open", uri))?;
+        let offset = offset_at(&document.text, &params["position"])?;
+        Ok((document, offset))
+    }
+
+    fn hover(&self, params: &Value) -> Result<Value> {
+        let (document, offset) = self.document_position(params)?;
+        let symbol_types = &document.analysis.symbol_types;
+        Ok(match symbol_types.iter().find(|(span, _)| span.contains(&offset)) {
+            Some((span, t)) => json!({
+                "contents": {
+                    "kind": "plaintext",
+                    "value": format!("{}: {}", &document.text[span.clone()], t),
+                },
+                "range": range(&document.text, span),
+            }),
+            None => Value::Null,
+        })
+    }
+
+    fn definition(&self, params: &Value) -> Result<Value> {
+        let (document, offset) = self.document_position(params)?;
+        Ok(match find_definition(&document.text, offset) {
+            Some(span) => json!({
+                "uri": params["textDocument"]["uri"],
+                "range": range(&document.text, &span),
+            }),
+            None => Value::Null,
+        })
+    }
+
+    fn completion(&self, params: &Value) -> Result<Value> {
+        let (document, _) = self.document_position(params)?;
+        let items = document
+            .analysis
+            .functions
+            .iter()
+            .map(|(name, signature)| json!({ "label": name, "kind": FUNCTION_KIND, "detail": signature }))
+            .collect();
+        Ok(Value::Array(items))
+    }
+}
+
+fn error_response(id: &Value, code: i64, message: String) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+fn publish_diagnostics(uri: &str, document: Option<&Document>) -> Value {
+    let diagnostics = document.map_or_else(Vec::new, |document| {
+        document
+            .analysis
+            .diagnostics
+            .iter()
+            .map(|(span, message)| {
+                json!({
+                    "range": range(&document.text, span),
+                    "severity": ERROR_SEVERITY,
+                    "source": "wisp",
+                    "message": message,
+                })
+            })
+            .collect()
+    });
+    json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": { "uri": uri, "diagnostics": diagnostics },
+    })
+}
+
+/// Serves the Language Server Protocol over stdin and stdout until the client exits.
+/// Returns whether the client asked the server to shut down first, as it should.
+pub fn run_server() -> Result<bool> {
+    let mut reader = std::io::stdin().lock();
+    let mut writer = std::io::stdout().lock();
+    let mut server = Server::default();
+    while let Some(message) = read_message(&mut reader)? {
+        if message["method"] == "exit" {
+            break;
+        }
+        for reply in server.handle(&message) {
+            write_message(&mut writer, &reply)?;
+        }
+    }
+    Ok(server.shut_down)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URI: &str = "untitled:test.wisp";
+
+    const SOURCE: &str = "(define scale: i32 3)
+(defn add_two: i32 [a: i32 b: i32] (+ a b))
+(export defn main: i32 []
+    (let [total (add_two 1 2)
+          x     (* total scale)]
+        (+ x total)))
+";
+
+    fn open(server: &mut Server, text: &str) -> Value {
+        let messages = server.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": URI, "languageId": "wisp", "version": 1, "text": text } },
+        }));
+        assert_eq!(messages.len(), 1);
+        messages[0]["params"]["diagnostics"].clone()
+    }
+
+    fn request(server: &mut Server, method: &str, line: usize, character: usize) -> Value {
+        let messages = server.handle(&json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": {
+                "textDocument": { "uri": URI },
+                "position": { "line": line, "character": character },
+            },
+        }));
+        messages[0]["result"].clone()
+    }
+
+    fn span_range(line: usize, start: usize, end: usize) -> Value {
+        json!({
+            "start": { "line": line, "character": start },
+            "end": { "line": line, "character": end },
+        })
+    }
+
+    #[test]
+    fn test_messages() {
+        let mut bytes = Vec::new();
+        write_message(&mut bytes, &json!({ "id": 1, "method": "shutdown" })).unwrap();
+        write_message(&mut bytes, &json!({ "method": "exit" })).unwrap();
+        assert!(bytes.starts_with(b"Content-Length: 28\r\n\r\n{"));
+        let reader = &mut bytes.as_slice();
+        assert_eq!(read_message(reader).unwrap().unwrap()["method"], "shutdown");
+        assert_eq!(read_message(reader).unwrap().unwrap()["method"], "exit");
+        assert!(read_message(reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_positions() {
+        let text = "(a)\n(é 😀 b)";
+        let offset = text.find('b').unwrap();
+        let b = json!({ "line": 1, "character": 6 });
+        assert_eq!(offset_at(text, &b).unwrap(), offset);
+        assert_eq!(position(text, offset), b);
+        assert_eq!(offset_at(text, &json!({ "line": 0, "character": 9 })).unwrap(), 3);
+        assert_eq!(file_path("file:///home/me/my%20file.wisp"), Some(PathBuf::from("/home/me/my file.wisp")));
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let server = &mut Server::default();
+        assert_eq!(open(server, SOURCE), json!([]));
+        let diagnostics = open(server, "(defn f: i32 [a: i32]\n    (+ a y))");
+        assert_eq!(
+            diagnostics,
+            json!([{
+                "range": span_range(1, 9, 10),
+                "severity": ERROR_SEVERITY,
+                "source": "wisp",
+                "message": "Symbol y not found in this scope",
+            }])
+        );
+        let diagnostics = open(server, "(defn f: i32 [a: i32]\n    (+ a 1]))");
+        assert_eq!(diagnostics[0]["range"], span_range(1, 10, 11));
+        assert_eq!(diagnostics[0]["message"], "Parse error. Unexpected ]");
+        let diagnostics = open(server, "(defn f: i32 [] (+ 1 y))\n(define g: i32 (f true))");
+        let ranges = diagnostics.as_array().unwrap().iter().map(|d| d["range"].clone()).collect::<Vec<_>>();
+        assert_eq!(ranges, vec![span_range(0, 21, 22), span_range(1, 15, 23)]);
+    }
+
+    #[test]
+    fn test_hover() {
+        let server = &mut Server::default();
+        open(server, SOURCE);
+        assert_eq!(
+            request(server, "textDocument/hover", 4, 25),
+            json!({
+                "contents": { "kind": "plaintext", "value": "scale: i32" },
+                "range": span_range(4, 25, 30),
+            })
+        );
+        assert_eq!(request(server, "textDocument/hover", 5, 11)["contents"]["value"], "x: i32");
+        assert_eq!(request(server, "textDocument/hover", 5, 9), Value::Null);
+    }
+
+    #[test]
+    fn test_definition() {
+        let server = &mut Server::default();
+        open(server, SOURCE);
+        let definition = |server: &mut Server, line, character| {
+            request(server, "textDocument/definition", line, character)["range"].clone()
+        };
+        // A function argument.
+        assert_eq!(definition(server, 1, 38), span_range(1, 20, 21));
+        // A function.
+        assert_eq!(definition(server, 3, 18), span_range(1, 6, 13));
+        // A global.
+        assert_eq!(definition(server, 4, 26), span_range(0, 8, 13));
+        // `let` bindings, including one used by a later binding.
+        assert_eq!(definition(server, 4, 19), span_range(3, 10, 15));
+        assert_eq!(definition(server, 5, 11), span_range(4, 10, 11));
+        assert_eq!(definition(server, 4, 10), span_range(4, 10, 11));
+        assert_eq!(definition(server, 5, 9), Value::Null);
+        // A form being edited does not hide the others.
+        open(server, &format!("{}(defn broken: i32 [] (+ 1\n", SOURCE));
+        assert_eq!(definition(server, 3, 18), span_range(1, 6, 13));
+        assert_eq!(definition(server, 4, 19), span_range(3, 10, 15));
+    }
+
+    #[test]
+    fn test_completion() {
+        let server = &mut Server::default();
+        open(server, SOURCE);
+        assert_eq!(
+            request(server, "textDocument/completion", 0, 0),
+            json!([
+                { "label": "add_two", "kind": FUNCTION_KIND, "detail": "[i32 i32] i32" },
+                { "label": "main", "kind": FUNCTION_KIND, "detail": "[] i32" },
+            ])
+        );
+        let messages = server.handle(&json!({ "jsonrpc": "2.0", "id": 2, "method": "workspace/symbol" }));
+        assert_eq!(messages[0]["error"]["code"], METHOD_NOT_FOUND);
+    }
+}
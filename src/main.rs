@@ -1,7 +1,11 @@
-use anyhow::{ensure, Result};
+use anyhow::{bail, ensure, Context, Result};
 use std::{fs::File, io::{BufWriter}, path::{Path, PathBuf}};
 
-use crate::emitter::compile_into_wasm;
+use crate::emitter::{
+    compile_into_wasm, decoder::decode, disasm::disassemble, encoder::compile_with_source_map,
+    testing::run_tests, CompileOptions,
+};
+use crate::{diagnostic::report, formatter::format_source, lsp::run_server};
 
 mod lexer;
 mod parser;
@@ -9,19 +13,115 @@ mod env;
 mod emitter;
 mod resolver;
 mod compiler;
+mod diagnostic;
+mod formatter;
+mod lsp;
 
 fn main() -> Result<()> {
-    let args: Vec<String> = std::env::args().collect();
-    ensure!(args.len() > 1, "wispc needs 1 or more args.");
-    let source_path = Path::new(&args[1]);
-    let target_path = if args.len() > 2 {
-        PathBuf::from(&args[2])
+    let mut options = CompileOptions::default();
+    let mut source_map = false;
+    let mut check = false;
+    let mut paths = Vec::new();
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--release" => options = CompileOptions::release(),
+            "--bounds-check" => options.bounds_check = true,
+            "--no-bounds-check" => options.bounds_check = false,
+            "-v" | "--verbose" => options.verbose = true,
+            _ if arg.starts_with("--inline-threshold=") => {
+                options.inline_threshold = arg["--inline-threshold=".len()..].parse()?
+            }
+            "--tail-calls" => options.tail_calls = true,
+            "--strip-names" => options.name_section = false,
+            "--strip-panic-messages" => options.panic_messages = false,
+            "--source-map" => source_map = true,
+            "--check" => check = true,
+            _ if arg.starts_with("--export-memory=") => {
+                options.memory_export = Some(arg["--export-memory=".len()..].to_string())
+            }
+            "--no-export-memory" => options.memory_export = None,
+            _ if arg.starts_with("--initial-pages=") => {
+                options.memory.initial = arg["--initial-pages=".len()..].parse()?
+            }
+            _ if arg.starts_with("--max-pages=") => {
+                options.memory.max = Some(arg["--max-pages=".len()..].parse()?)
+            }
+            _ if arg.starts_with("--stack-base=") => {
+                options.memory.stack_base = arg["--stack-base=".len()..].parse()?
+            }
+            _ if arg.starts_with("--stack-size=") => {
+                options.memory.stack_size = arg["--stack-size=".len()..].parse()?
+            }
+            "--shared-memory" => options.memory.shared = true,
+            "--import-memory" => options.memory.import = true,
+            "-O" => options.opt_level = 2,
+            "-O0" | "-O1" | "-O2" => options.opt_level = arg[2..].parse()?,
+            _ if arg.starts_with('-') => bail!("unknown option {}", arg),
+            _ => paths.push(arg),
+        }
+    }
+    ensure!(!paths.is_empty(), "wispc needs 1 or more args.");
+    if paths[0] == "disasm" {
+        ensure!(paths.len() == 2, "disasm needs a .wasm file.");
+        let (module, memory) = decode(&std::fs::read(&paths[1])?)?;
+        print!("{}", disassemble(&module, memory.as_ref()));
+        return Ok(());
+    }
+    if paths[0] == "test" {
+        ensure!(paths.len() == 2, "test needs a .wisp file.");
+        if !run_tests(Path::new(&paths[1]), &options)? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if paths[0] == "lsp" {
+        // The exit code tells whether the client shut the server down properly.
+        let shut_down = run_server()?;
+        std::process::exit(if shut_down { 0 } else { 1 });
+    }
+    if paths[0] == "fmt" {
+        ensure!(paths.len() > 1, "fmt needs 1 or more .wisp files.");
+        // With --check, files are listed instead of rewritten.
+        let mut unformatted = false;
+        for path in &paths[1..] {
+            let source = std::fs::read_to_string(path)?;
+            let formatted = format_source(&source).with_context(|| format!("cannot format {}", path))?;
+            if formatted == source {
+                continue;
+            }
+            if check {
+                println!("{}", path);
+                unformatted = true;
+            } else {
+                std::fs::write(path, formatted)?;
+            }
+        }
+        if unformatted {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    let source_path = Path::new(&paths[0]);
+    let target_path = if paths.len() > 1 {
+        PathBuf::from(&paths[1])
     } else {
-        Path::new(&args[1]).with_extension("wasm")
+        source_path.with_extension("wasm")
     };
-    let source = std::fs::read_to_string(&source_path)?;
+    let source = std::fs::read_to_string(source_path)?;
     let target_file = File::create(&target_path)?;
     let mut writer = BufWriter::new(target_file);
-    compile_into_wasm(&mut writer, &source)?;
+    let result = if source_map {
+        // The map sits next to the module, which refers to it by file name.
+        let map_path = PathBuf::from(format!("{}.map", target_path.display()));
+        let map_url = map_path.file_name().unwrap().to_string_lossy();
+        compile_with_source_map(&mut writer, &source, &options, source_path, &map_url)
+            .and_then(|map| Ok(std::fs::write(&map_path, map)?))
+    } else {
+        compile_into_wasm(&mut writer, &source, &options, source_path)
+    };
+    if let Err(error) = result {
+        eprint!("{}", report(&error, source_path, &source));
+        std::process::exit(1);
+    }
     Ok(())
-}
\ No newline at end of file
+}
@@ -1,9 +1,16 @@
-use crate::lexer::{tokenize, Token};
-use anyhow::{anyhow, ensure, Context, Result};
+use crate::{
+    diagnostic::Diagnostic,
+    lexer::{tokenize_spanned, Token},
+};
+use anyhow::{anyhow, bail, ensure, Context, Error, Result};
+use std::{collections::HashMap, ops::Range};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum TypeAST {
     I32,
+    U32,
+    I64,
+    U64,
     F32,
     Bool,
     Unit,
@@ -14,6 +21,9 @@ pub enum TypeAST {
 pub enum AST<'a> {
     Module(Vec<AST<'a>>),
     NumberLiteral(&'a str),
+    StringLiteral(&'a str),
+    /// A symbol prefixed with a colon, such as `:as`.
+    Keyword(&'a str),
     BoolLiteral(bool),
     Symbol(&'a str),
     SymbolWithAnnotation(&'a str, TypeAST),
@@ -29,15 +39,41 @@ pub enum AST<'a> {
     And,
     Or,
     Not,
+    Mod,
+    Rem,
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    Shl,
+    Shr,
+    UShr,
+    Rotl,
+    Rotr,
+    Clz,
+    Ctz,
+    Popcnt,
     List(Vec<AST<'a>>),
     Vector(Vec<AST<'a>>),
 }
 
+pub fn primitive_type_from_name(name: &str) -> Option<TypeAST> {
+    Some(match name {
+        "i32" => TypeAST::I32,
+        "u32" => TypeAST::U32,
+        "i64" => TypeAST::I64,
+        "u64" => TypeAST::U64,
+        "f32" => TypeAST::F32,
+        "bool" => TypeAST::Bool,
+        _ => return None,
+    })
+}
+
 fn parse_type(tokens: &mut Vec<Token>) -> Result<TypeAST> {
     Ok(match tokens.pop() {
-        Some(Token::Symbol("i32")) => TypeAST::I32,
-        Some(Token::Symbol("f32")) => TypeAST::F32,
-        Some(Token::Symbol("bool")) => TypeAST::Bool,
+        Some(Token::Symbol(name)) if primitive_type_from_name(name).is_some() => {
+            primitive_type_from_name(name).unwrap()
+        }
         Some(Token::LBracket) => {
             let item_type = parse_type(tokens)?;
             ensure!(!tokens.is_empty(), "not enough tokens");
@@ -45,24 +81,90 @@ fn parse_type(tokens: &mut Vec<Token>) -> Result<TypeAST> {
             tokens.pop();
             TypeAST::Array(Box::new(item_type))
         }
-        _ => todo!(),
+        _ => bail!("Parse error. Expected a type"),
     })
 }
 
-pub fn parse<'a>(tokens: &mut Vec<Token<'a>>) -> Result<AST<'a>> {
+/// Whether the tokens left, which are reversed, start with a colon followed by a type
+/// rather than a keyword such as `:as`.
+fn starts_annotation(tokens: &[Token]) -> bool {
+    match tokens {
+        [.., Token::Symbol(name), Token::Colon] => primitive_type_from_name(name).is_some(),
+        [.., Token::LBracket, Token::Colon] => true,
+        _ => false,
+    }
+}
+
+/// Byte ranges of the nodes of a parsed source, recorded by the parser. A node is looked up
+/// by its address, which it keeps while its AST is alive as it is stored in the buffer of
+/// its parent, so the root has no range. The range of an annotated symbol only covers its
+/// name.
+#[derive(Debug, Default)]
+pub struct Spans(HashMap<usize, Range<usize>>);
+
+impl Spans {
+    /// Pairs the nodes under `root`, in the order they start, with the ranges of `spanned`
+    /// recorded by `parse` for them.
+    fn new(root: &AST, spanned: &[(Token, Range<usize>)], node_tokens: &[NodeTokens]) -> Self {
+        fn visit<'t>(ast: &'t AST<'t>, order: &mut Vec<&'t AST<'t>>) {
+            if let AST::Module(forms) | AST::List(forms) | AST::Vector(forms) = ast {
+                for form in forms {
+                    order.push(form);
+                    visit(form, order);
+                }
+            }
+        }
+        let mut order = Vec::new();
+        visit(root, &mut order);
+        let token = |left: usize| &spanned[spanned.len() - left].1;
+        let spans = order
+            .into_iter()
+            .zip(node_tokens)
+            .map(|(ast, &(first, after))| {
+                let span = match ast {
+                    AST::SymbolWithAnnotation(..) => token(first).clone(),
+                    _ => token(first).start..token(after + 1).end,
+                };
+                (ast as *const AST as usize, span)
+            })
+            .collect();
+        Spans(spans)
+    }
+
+    /// Byte range of `ast`, if it is a node of the AST the spans were recorded for.
+    pub fn get(&self, ast: &AST) -> Option<Range<usize>> {
+        self.0.get(&(ast as *const AST as usize)).cloned()
+    }
+}
+
+/// The tokens of a node, as the number of tokens left before its first one and after its
+/// last one.
+type NodeTokens = (usize, usize);
+
+/// Parses a node, recording its tokens in `node_tokens` before those of its children.
+pub fn parse<'a>(tokens: &mut Vec<Token<'a>>, node_tokens: &mut Vec<NodeTokens>) -> Result<AST<'a>> {
+    let node = node_tokens.len();
+    node_tokens.push((tokens.len(), tokens.len()));
+    let ast = parse_node(tokens, node_tokens)?;
+    node_tokens[node].1 = tokens.len();
+    Ok(ast)
+}
+
+fn parse_node<'a>(tokens: &mut Vec<Token<'a>>, node_tokens: &mut Vec<NodeTokens>) -> Result<AST<'a>> {
     let first_token = tokens
         .pop()
         .with_context(|| "Parse error. Not enough tokens")?;
     Ok(match first_token {
         Token::LParen => {
             tokens.push(Token::LParen);
-            parse_list(tokens)?
+            parse_list(tokens, node_tokens)?
         }
         Token::LBracket => {
             tokens.push(Token::LBracket);
-            parse_vector(tokens)?
+            parse_vector(tokens, node_tokens)?
         }
         Token::NumberLiteral(val) => AST::NumberLiteral(val),
+        Token::StringLiteral(val) => AST::StringLiteral(val),
         Token::Plus => AST::Add,
         Token::Minus => AST::Sub,
         Token::Asterisk => AST::Mul,
@@ -73,29 +175,46 @@ pub fn parse<'a>(tokens: &mut Vec<Token<'a>>) -> Result<AST<'a>> {
         Token::Lt => AST::Lt,
         Token::Le => AST::Le,
         Token::Symbol(name) => {
-            if let Some(Token::Colon) = tokens.last() {
+            if starts_annotation(tokens) {
                 tokens.pop();
                 AST::SymbolWithAnnotation(name, parse_type(tokens)?)
             } else {
                 AST::Symbol(name)
             }
         }
-        Token::RParen => unreachable!(),
-        Token::RBracket => {
-            dbg!(&tokens);
-            unreachable!()
-        }
-        Token::Colon => unreachable!("Colon should be processed in Symbol arm"),
+        Token::RParen | Token::RBracket => bail!("Parse error. Unexpected {}", first_token),
+        // `parse_source` drops comments.
+        Token::Comment(_) => unreachable!(),
+        // A colon after a symbol is processed in the Symbol arm as an annotation.
+        Token::Colon => match tokens.pop() {
+            Some(Token::Symbol(name)) => AST::Keyword(name),
+            _ => bail!("Parse error. Expected a keyword after colon"),
+        },
         Token::True => AST::BoolLiteral(true),
         Token::False => AST::BoolLiteral(false),
         Token::And => AST::And,
         Token::Or => AST::Or,
         Token::Not => AST::Not,
+        Token::Mod => AST::Mod,
+        Token::Rem => AST::Rem,
+        Token::BitAnd => AST::BitAnd,
+        Token::BitOr => AST::BitOr,
+        Token::BitXor => AST::BitXor,
+        Token::BitNot => AST::BitNot,
+        Token::Shl => AST::Shl,
+        Token::Shr => AST::Shr,
+        Token::UShr => AST::UShr,
+        Token::Rotl => AST::Rotl,
+        Token::Rotr => AST::Rotr,
+        Token::Clz => AST::Clz,
+        Token::Ctz => AST::Ctz,
+        Token::Popcnt => AST::Popcnt,
     })
 }
 
 fn parse_sorrounded_by<'a>(
     tokens: &mut Vec<Token<'a>>,
+    node_tokens: &mut Vec<NodeTokens>,
     open: Token,
     close: Token,
 ) -> Result<Vec<AST<'a>>> {
@@ -108,48 +227,110 @@ fn parse_sorrounded_by<'a>(
     );
     tokens.pop();
     let mut nodes = Vec::new();
-    while let Some(token) = tokens.last() {
+    loop {
+        let token = tokens
+            .last()
+            .with_context(|| format!("Parse error. Unclosed {}", open))?;
         if *token == close {
             tokens.pop();
             break;
         }
-        let node = parse(tokens)?;
+        ensure!(
+            !matches!(token, Token::RParen | Token::RBracket),
+            "Parse error. Unexpected {}",
+            token
+        );
+        let node = parse(tokens, node_tokens)?;
         nodes.push(node);
     }
     Result::Ok(nodes)
 }
 
-fn parse_vector<'a>(tokens: &mut Vec<Token<'a>>) -> Result<AST<'a>> {
+fn parse_vector<'a>(tokens: &mut Vec<Token<'a>>, node_tokens: &mut Vec<NodeTokens>) -> Result<AST<'a>> {
     Ok(AST::Vector(parse_sorrounded_by(
         tokens,
+        node_tokens,
         Token::LBracket,
         Token::RBracket,
     )?))
 }
 
-fn parse_list<'a>(tokens: &mut Vec<Token<'a>>) -> Result<AST<'a>> {
+fn parse_list<'a>(tokens: &mut Vec<Token<'a>>, node_tokens: &mut Vec<NodeTokens>) -> Result<AST<'a>> {
     Ok(AST::List(parse_sorrounded_by(
         tokens,
+        node_tokens,
         Token::LParen,
         Token::RParen,
     )?))
 }
 
-fn parse_module<'a>(tokens: &mut Vec<Token<'a>>) -> Result<AST<'a>> {
+/// Parses the toplevel forms of `source`, whose tokens are `spanned`. A form that does not
+/// parse is skipped up to the next one starting a line, so that the errors of every form
+/// are found.
+fn parse_module<'a>(
+    source: &str,
+    spanned: &[(Token<'a>, Range<usize>)],
+) -> (AST<'a>, Spans, Vec<Error>) {
+    let mut tokens = spanned.iter().rev().map(|(token, _)| *token).collect::<Vec<_>>();
     let mut lists = Vec::new();
+    let mut node_tokens = Vec::new();
+    let mut errors = Vec::new();
     while !tokens.is_empty() {
-        if tokens.last() != Some(&Token::LParen) {
-            return Err(anyhow!("Toplevel forms must be a list."));
+        let start = spanned.len() - tokens.len();
+        let parsed = node_tokens.len();
+        let result = match tokens.last() {
+            Some(Token::LParen) => parse(&mut tokens, &mut node_tokens),
+            _ => Err(anyhow!("Toplevel forms must be a list.")),
+        };
+        let error = match result {
+            Ok(list) => {
+                lists.push(list);
+                continue;
+            }
+            Err(error) => error,
+        };
+        node_tokens.truncate(parsed);
+        // The error is located at the first token left, or at the end of the source.
+        let span = match spanned.get(spanned.len() - tokens.len()) {
+            Some((_, span)) => span.clone(),
+            None => source.len()..source.len(),
+        };
+        errors.push(Diagnostic::locate(error, Some(span)));
+        let next = (start + 1..spanned.len())
+            .find(|&i| {
+                let (token, span) = &spanned[i];
+                *token == Token::LParen && (span.start == 0 || source[..span.start].ends_with('\n'))
+            })
+            .unwrap_or(spanned.len());
+        tokens = spanned[next..].iter().rev().map(|(token, _)| *token).collect();
+    }
+    let ast = AST::Module(lists);
+    let spans = Spans::new(&ast, spanned, &node_tokens);
+    (ast, spans, errors)
+}
+
+/// Parses `source`, recovering from errors. Returns the forms that parsed with their spans,
+/// and the errors of the others.
+pub fn parse_source_recovering(source: &str) -> (AST<'_>, Spans, Vec<Error>) {
+    match tokenize_spanned(source).with_context(|| format!("tokenize error")) {
+        Ok(spanned) => {
+            let spanned = spanned
+                .into_iter()
+                .filter(|(token, _)| !matches!(token, Token::Comment(_)))
+                .collect::<Vec<_>>();
+            parse_module(source, &spanned)
         }
-        lists.push(parse_list(tokens)?)
+        Err(error) => (AST::Module(Vec::new()), Spans::default(), vec![error]),
     }
-    Ok(AST::Module(lists))
 }
 
-pub fn parse_source(source: &str) -> Result<AST> {
-    let mut tokens = tokenize(source).with_context(|| format!("tokenize error"))?;
-    tokens.reverse();
-    parse_module(&mut tokens)
+/// Parses `source`, failing with its first error.
+pub fn parse_source(source: &str) -> Result<AST<'_>> {
+    let (ast, _, errors) = parse_source_recovering(source);
+    match errors.into_iter().next() {
+        Some(error) => Err(error),
+        None => Ok(ast),
+    }
 }
 
 #[cfg(test)]
@@ -189,6 +370,35 @@ mod tests {
         )
     }
     #[test]
+    fn test_require() {
+        let ast = parse_source("(ns app) (require \"math.wisp\" :as m)").unwrap();
+        assert_eq!(
+            ast,
+            AST::Module(vec![
+                AST::List(vec![AST::Symbol("ns"), AST::Symbol("app")]),
+                AST::List(vec![
+                    AST::Symbol("require"),
+                    AST::StringLiteral("math.wisp"),
+                    AST::Keyword("as"),
+                    AST::Symbol("m")
+                ])
+            ])
+        )
+    }
+    #[test]
+    fn test_keyword_after_symbol() {
+        let ast = parse_source("(memory :initial 4 [x :f32])").unwrap();
+        assert_eq!(
+            ast,
+            AST::Module(vec![AST::List(vec![
+                AST::Symbol("memory"),
+                AST::Keyword("initial"),
+                AST::NumberLiteral("4"),
+                AST::Vector(vec![AST::SymbolWithAnnotation("x", TypeAST::F32)])
+            ])])
+        )
+    }
+    #[test]
     fn test_bool() {
         let ast = parse_source(
             "
@@ -216,6 +426,14 @@ mod tests {
         assert_eq!(ast, TypeAST::Array(Box::new(TypeAST::I32)))
     }
     #[test]
+    fn test_parse_integer_types() {
+        let tokens = &mut vec![Token::LBracket, Token::Symbol("u64"), Token::RBracket];
+        tokens.reverse();
+        assert_eq!(parse_type(tokens).unwrap(), TypeAST::Array(Box::new(TypeAST::U64)));
+        let tokens = &mut vec![Token::Symbol("u32")];
+        assert_eq!(parse_type(tokens).unwrap(), TypeAST::U32);
+    }
+    #[test]
     fn test_array_arg() {
         let ast = parse_source(
             "
@@ -238,4 +456,74 @@ mod tests {
             ])])
         )
     }
+
+    #[test]
+    fn test_error_location() {
+        let span = |source| {
+            let error = parse_source(source).unwrap_err();
+            error.downcast_ref::<Diagnostic>().unwrap().span.clone()
+        };
+        assert_eq!(span("(defn f [] ; ]\n  (+ 1 2]))"), 23..24);
+        assert_eq!(span("(a) b"), 4..5);
+        assert_eq!(span("(a :"), 4..4);
+        assert_eq!(span("(a (b)"), 6..6);
+    }
+
+    #[test]
+    fn test_spans() {
+        let source = "(defn f [] (+ 1 2]))
+(defn g: i32 [x: [i32]] ; ]
+  (+ x :as \"s\"))";
+        let (ast, spans, errors) = parse_source_recovering(source);
+        assert_eq!(errors.len(), 1);
+        fn forms<'t, 'a>(ast: &'t AST<'a>) -> &'t [AST<'a>] {
+            match ast {
+                AST::Module(forms) | AST::List(forms) | AST::Vector(forms) => forms,
+                _ => panic!("{:?} has no forms", ast),
+            }
+        }
+        let toplevels = forms(&ast);
+        let list = forms(&toplevels[0]);
+        let args = forms(&list[2]);
+        let body = forms(&list[3]);
+        let text = |ast| &source[spans.get(ast).unwrap()];
+        assert_eq!(text(&toplevels[0]), &source[21..]);
+        assert_eq!(text(&list[1]), "g");
+        assert_eq!(text(&list[2]), "[x: [i32]]");
+        assert_eq!(text(&args[0]), "x");
+        assert_eq!(text(&list[3]), "(+ x :as \"s\")");
+        assert_eq!(text(&body[0]), "+");
+        assert_eq!(text(&body[2]), ":as");
+        assert_eq!(text(&body[3]), "\"s\"");
+        // Only the nodes of the AST have a span, not equal ones.
+        assert_eq!(spans.get(&body[1].clone()), None);
+        assert_eq!(spans.get(&ast), None);
+    }
+
+    #[test]
+    fn test_recovery() {
+        let source = "(defn f [] (+ 1 2]))
+(defn g [] 3)
+(defn h [] (+ 1
+(define x 1)";
+        let (ast, _, errors) = parse_source_recovering(source);
+        assert_eq!(
+            ast,
+            AST::Module(vec![
+                AST::List(vec![AST::Symbol("defn"), AST::Symbol("g"), AST::Vector(vec![]), AST::NumberLiteral("3")]),
+                AST::List(vec![AST::Symbol("define"), AST::Symbol("x"), AST::NumberLiteral("1")]),
+            ])
+        );
+        let errors = errors
+            .iter()
+            .map(|error| (error.to_string(), error.downcast_ref::<Diagnostic>().unwrap().span.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                ("Parse error. Unexpected ]".to_string(), 17..18),
+                ("Parse error. Unclosed (".to_string(), 63..63),
+            ]
+        );
+    }
 }
@@ -10,19 +10,45 @@ pub struct TypeEnv {
 #[derive(Hash, PartialEq, Eq, Debug)]
 pub enum Type {
     I32,
+    U32,
+    I64,
+    U64,
     F32,
     Bool,
     Unit,
-    Array(Rc<Type>)
+    Array(Rc<Type>),
+    /// The type of a form that failed to compile, which lets the compiler go on to find the
+    /// errors after it.
+    Error,
+    /// The type of forms that never return, such as `panic`. It takes the type of the other
+    /// branch of an `if`.
+    Never,
+}
+
+impl Type {
+    pub fn is_integer(&self) -> bool {
+        matches!(self, Type::I32 | Type::U32 | Type::I64 | Type::U64)
+    }
+    pub fn is_unsigned(&self) -> bool {
+        matches!(self, Type::U32 | Type::U64)
+    }
+    pub fn is_64bit(&self) -> bool {
+        matches!(self, Type::I64 | Type::U64)
+    }
 }
 
 impl Display for Type {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Type::I32 => write!(f, "i32"),
+            Type::U32 => write!(f, "u32"),
+            Type::I64 => write!(f, "i64"),
+            Type::U64 => write!(f, "u64"),
             Type::F32 => write!(f, "f32"),
             Type::Bool => write!(f, "bool"),
             Type::Unit => write!(f, "()"),
+            Type::Error => write!(f, "<error>"),
+            Type::Never => write!(f, "!"),
             Type::Array(a) => {
                 write!(f, "[")?;
                 a.fmt(f)?;
@@ -37,6 +63,9 @@ pub fn resolve_type<'a>(t: &TypeAST, type_env: &TypeEnv) -> Result<Rc<Type>> {
     Ok(match t {
         // ToDo: Optimization
         TypeAST::I32 => Rc::new(Type::I32),
+        TypeAST::U32 => Rc::new(Type::U32),
+        TypeAST::I64 => Rc::new(Type::I64),
+        TypeAST::U64 => Rc::new(Type::U64),
         TypeAST::F32 => Rc::new(Type::F32),
         TypeAST::Bool => Rc::new(Type::Bool),
         TypeAST::Unit => Rc::new(Type::Unit),
@@ -49,23 +78,27 @@ pub fn resolve_type<'a>(t: &TypeAST, type_env: &TypeEnv) -> Result<Rc<Type>> {
 
 pub fn get_size(t: Rc<Type>) -> u32 {
     match *t {
-        Type::I32 => 4,
+        Type::I32 | Type::U32 => 4,
+        Type::I64 | Type::U64 => 8,
         Type::F32 => 4,
         Type::Bool => 4,
-        Type::Unit => 0,
+        Type::Unit | Type::Error | Type::Never => 0,
         Type::Array(_) => 4, // size of pointer
     }
 }
 
 pub fn get_primitive_types(t: Rc<Type>) -> Vec<Option<WasmPrimitiveType>> {
     match *t {
-        Type::I32 | Type::Bool => {
+        Type::I32 | Type::U32 | Type::Bool => {
             vec![Some(WasmPrimitiveType::I32)]
         }
+        Type::I64 | Type::U64 => {
+            vec![Some(WasmPrimitiveType::I64)]
+        }
         Type::F32 => {
             vec![Some(WasmPrimitiveType::F32)]
         }
-        Type::Unit => {
+        Type::Unit | Type::Error | Type::Never => {
             vec![None]
         }
         Type::Array(_) => vec![Some(WasmPrimitiveType::I32)], // pointer,